@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Proves to the host that the agent knows the token of the VM, see `libakari::vsock_auth`.
+
+use std::{io::ErrorKind, path::Path};
+
+use libakari::vsock_auth::{AuthToken, Error};
+
+// Read the token on each connection as the server writes a new one when it starts.
+// The agent does not authenticate without the token, e.g. with an older server.
+pub fn load(path: &Path) -> Option<AuthToken> {
+    match AuthToken::load(path) {
+        Ok(token) => Some(token),
+        Err(Error::Io(e)) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log::warn!("Failed to read the token {:?}: {}", path, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Drops the cached contents of the files that changed on the host.
+//! The shared directories cache the file contents in the guest, so a binary replaced on the
+//! host may still run with the old contents.
+
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+#[cfg(target_os = "linux")]
+fn drop_pages(file: &File, _len: usize) -> std::io::Result<()> {
+    let res = unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+    if res != 0 {
+        return Err(std::io::Error::from_raw_os_error(res));
+    }
+    Ok(())
+}
+
+// macOS has no fadvise. Invalidating a mapping of the whole file drops its cached pages.
+#[cfg(not(target_os = "linux"))]
+fn drop_pages(file: &File, len: usize) -> std::io::Result<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    unsafe {
+        let addr = libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        let res = libc::msync(addr, len, libc::MS_INVALIDATE);
+        libc::munmap(addr, len);
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn invalidate_file(path: &Path) -> Result<()> {
+    // Looking the path up again refreshes the cached attributes, and the removed files are done.
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_file() {
+        return Ok(());
+    }
+    let file = File::open(path)?;
+    drop_pages(&file, metadata.len() as usize)?;
+    Ok(())
+}
+
+pub fn invalidate(id: &str, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        if let Err(e) = invalidate_file(path) {
+            log::warn!("Failed to invalidate {:?} of {}: {}", path, id, e);
+        }
+    }
+    log::info!("Invalidated {} cached paths of {}", paths.len(), id);
+    Ok(())
+}
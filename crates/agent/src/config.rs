@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Configuration of the agent, see `libakari::agent_config` for where it comes from.
+//! The settings sent by the server replace the configuration while the agent runs.
+
+use std::{str::FromStr, sync::RwLock};
+
+use anyhow::Result;
+use libakari::agent_config::{AgentConfig, AgentSettings};
+use log::LevelFilter;
+
+pub struct Config(RwLock<AgentConfig>);
+
+impl Config {
+    pub fn new(config: AgentConfig) -> Self {
+        Self(RwLock::new(config))
+    }
+
+    pub fn get(&self) -> AgentConfig {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn apply(&self, settings: &AgentSettings) -> Result<()> {
+        let mut config = self.0.write().unwrap_or_else(|e| e.into_inner());
+        let mut next = config.clone();
+        next.apply(settings);
+        apply_log_level(&next.log_level)?;
+        *config = next;
+        log::info!("Applied the settings of the server: {:?}", settings);
+        Ok(())
+    }
+}
+
+// Let the configuration set the level unless RUST_LOG is set.
+pub fn init_logger(level: &str) -> Result<()> {
+    let rust_log = std::env::var_os("RUST_LOG").is_some();
+    let mut builder = env_logger::Builder::from_default_env();
+    if !rust_log {
+        builder.filter_level(LevelFilter::Trace);
+    }
+    builder.init();
+    if !rust_log {
+        apply_log_level(level)?;
+    }
+    Ok(())
+}
+
+fn apply_log_level(level: &str) -> Result<()> {
+    let level = LevelFilter::from_str(level)
+        .map_err(|_| anyhow::anyhow!("Invalid log level: {}", level))?;
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+// Return the kernel command line.
+#[cfg(target_os = "linux")]
+pub fn boot_args() -> Result<String> {
+    Ok(std::fs::read_to_string("/proc/cmdline")?)
+}
+
+// Return the boot-args of NVRAM.
+#[cfg(not(target_os = "linux"))]
+pub fn boot_args() -> Result<String> {
+    let name = std::ffi::CString::new("kern.bootargs")?;
+    let mut len = 0;
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            std::ptr::null_mut(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut buf = vec![0u8; len];
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    buf.truncate(len);
+    Ok(String::from_utf8_lossy(&buf)
+        .trim_end_matches('\0')
+        .to_string())
+}
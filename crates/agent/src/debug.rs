@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Commands run in the guest OS outside the containers to diagnose the guest, e.g. its
+//! virtiofs mounts or networking. The host only sends them when its configuration allows it.
+
+use std::{
+    io::{Read, Write},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{container_rpc::ContainerResponse, framing::WriteTo, stdio::StdioStream};
+
+use crate::reaper::Reaper;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Forward the output of the pipe until it is closed.
+fn forward(mut pipe: impl Read, stream: StdioStream, tx: mpsc::Sender<ContainerResponse>) {
+    let mut buf = [0u8; 8192];
+    loop {
+        match pipe.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => {
+                if tx
+                    .send(ContainerResponse::Output(stream, buf[..n].to_vec()))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Wait for the child. The reaper may have reaped it already.
+fn wait(child: &mut Child, reaper: &Reaper) -> i32 {
+    loop {
+        if let Some(code) = reaper.try_wait(child.id()) {
+            return code;
+        }
+        match child.try_wait() {
+            Ok(Some(status)) => return status.code().unwrap_or(-1),
+            Ok(None) | Err(_) => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+// Run the command and stream its output and its exit code to the host.
+pub fn exec(mut stream: impl Write, args: Vec<String>, reaper: &Reaper) -> Result<()> {
+    let Some((program, args)) = args.split_first() else {
+        anyhow::bail!("No command to run");
+    };
+    log::info!("Running the debug command {:?} {:?}", program, args);
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let (tx, rx) = mpsc::channel();
+    if let Some(stdout) = child.stdout.take() {
+        let tx = tx.clone();
+        std::thread::spawn(move || forward(stdout, StdioStream::Stdout, tx));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let tx = tx.clone();
+        std::thread::spawn(move || forward(stderr, StdioStream::Stderr, tx));
+    }
+    drop(tx);
+
+    for res in rx {
+        if let Err(e) = res.write_to(&mut stream) {
+            // Nobody reads the output once the host has gone.
+            let _ = child.kill();
+            wait(&mut child, reaper);
+            return Err(e.into());
+        }
+    }
+    let code = wait(&mut child, reaper);
+    ContainerResponse::Exited(code).write_to(&mut stream)?;
+    Ok(())
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Main and auxiliary processes executed in the containers.
+//! The table is persisted so that a restarted agent can re-adopt the running processes.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    process::Child,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use libakari::{
+    exec::ExecProcess, guest_user::GuestUser, priority::ProcessPriority, timeout::ExitReason,
+    vm_rpc::VmStatus,
+};
+use oci_spec::runtime::Process;
+
+use crate::reaper::Reaper;
+
+// Exec ID of the main process of a container, like the init process of containerd.
+pub const MAIN_EXEC_ID: &str = "";
+
+struct Entry {
+    record: ExecProcess,
+    // None if the process was adopted from the persisted table and is not a child of this agent.
+    // The child is kept to hold its stdio but is waited by the reaper.
+    child: Option<Child>,
+}
+
+impl Entry {
+    fn is_running(&self) -> bool {
+        matches!(self.record.status, VmStatus::Running)
+    }
+
+    // Update the record if the process exited.
+    fn reap(&mut self, reaper: &Reaper) {
+        if !self.is_running() {
+            return;
+        }
+        let Some(pid) = self.record.pid else {
+            return;
+        };
+        if self.child.is_some() {
+            if let Some(code) = reaper.try_wait(pid) {
+                self.record.finish(Some(code as u32));
+            }
+        } else if !is_alive(pid) {
+            self.record.finish(None);
+        }
+    }
+
+    fn kill(&mut self) {
+        if !self.is_running() {
+            return;
+        }
+        if let Some(pid) = self.record.pid {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+        self.record.finish(None);
+    }
+}
+
+fn is_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+// Exec processes keyed by the container ID and the exec ID.
+pub struct ExecTable {
+    containers: HashMap<String, HashMap<String, Entry>>,
+    // When the processes of the containers are killed. They are not persisted, so the clock
+    // restarts when the host sets them again.
+    deadlines: HashMap<String, Instant>,
+    // Max runtimes of the created containers, which are armed when the containers start.
+    max_runtimes: HashMap<String, Duration>,
+    // Main processes of the created containers, which are spawned when the containers start.
+    main_processes: HashMap<String, Process>,
+    // Priorities of the containers from their specs, which the exec processes run with.
+    // They are set again when the host creates the containers after the agent restarts.
+    priorities: HashMap<String, ProcessPriority>,
+    // Dedicated users of the containers, which the exec processes run as.
+    // They are set again when the host restores the containers after the agent restarts.
+    users: HashMap<String, GuestUser>,
+    reaper: Reaper,
+}
+
+impl ExecTable {
+    // Load the table persisted by the previous agent and re-adopt the processes that are still running.
+    pub fn load(path: &Path, reaper: Reaper) -> Result<Self> {
+        let mut table = Self {
+            containers: HashMap::new(),
+            deadlines: HashMap::new(),
+            max_runtimes: HashMap::new(),
+            main_processes: HashMap::new(),
+            priorities: HashMap::new(),
+            users: HashMap::new(),
+            reaper,
+        };
+        if !path.exists() {
+            return Ok(table);
+        }
+        let records: HashMap<String, Vec<ExecProcess>> =
+            serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        for (id, records) in records {
+            let execs = table.containers.entry(id.clone()).or_default();
+            for record in records {
+                let mut entry = Entry {
+                    record,
+                    child: None,
+                };
+                entry.reap(&table.reaper);
+                if entry.is_running() {
+                    log::info!(
+                        "Adopted exec {} of container {} (pid: {:?})",
+                        entry.record.exec_id,
+                        id,
+                        entry.record.pid
+                    );
+                }
+                execs.insert(entry.record.exec_id.clone(), entry);
+            }
+        }
+        Ok(table)
+    }
+
+    // Persist the table. The file is replaced atomically so that a crash never leaves it broken.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let records: HashMap<&String, Vec<&ExecProcess>> = self
+            .containers
+            .iter()
+            .map(|(id, execs)| (id, execs.values().map(|entry| &entry.record).collect()))
+            .collect();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, serde_json::to_string(&records)?)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    // Exec is not supported on Linux guests yet.
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn insert(&mut self, id: &str, exec_id: &str, child: Child) -> Result<()> {
+        self.track(id, exec_id, child.id(), Some(child))
+    }
+
+    // Track a process that libcontainer spawned. Its exit code is not available like the one of
+    // an adopted process.
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn adopt(&mut self, id: &str, exec_id: &str, pid: u32) -> Result<()> {
+        self.track(id, exec_id, pid, None)
+    }
+
+    fn track(&mut self, id: &str, exec_id: &str, pid: u32, child: Option<Child>) -> Result<()> {
+        let execs = self.containers.entry(id.to_string()).or_default();
+        if execs.contains_key(exec_id) {
+            anyhow::bail!("Exec {} already exists in container {}", exec_id, id);
+        }
+        let mut record = ExecProcess::new(exec_id);
+        record.start(pid);
+        execs.insert(exec_id.to_string(), Entry { record, child });
+        Ok(())
+    }
+
+    // libcontainer keeps the main process on Linux guests.
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn set_main_process(&mut self, id: &str, process: Process) {
+        self.main_processes.insert(id.to_string(), process);
+    }
+
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn take_main_process(&mut self, id: &str) -> Option<Process> {
+        self.main_processes.remove(id)
+    }
+
+    // Update the records of the exited processes.
+    pub fn reap(&mut self) {
+        let reaper = &self.reaper;
+        self.containers
+            .values_mut()
+            .flat_map(|execs| execs.values_mut())
+            .for_each(|entry| entry.reap(reaper));
+    }
+
+    pub fn remove(&mut self, id: &str, exec_id: &str) -> Result<ExecProcess> {
+        let mut entry = self
+            .containers
+            .get_mut(id)
+            .and_then(|execs| execs.remove(exec_id))
+            .ok_or_else(|| anyhow::anyhow!("Exec {} not found in container {}", exec_id, id))?;
+        entry.kill();
+        Ok(entry.record)
+    }
+
+    // Return the number of the running processes of each container.
+    pub fn process_counts(&self) -> BTreeMap<String, usize> {
+        self.containers
+            .iter()
+            .map(|(id, execs)| {
+                let running = execs.values().filter(|entry| entry.is_running()).count();
+                (id.clone(), running)
+            })
+            .collect()
+    }
+
+    // Return the PIDs of the running processes of each container.
+    pub fn running_pids(&self) -> BTreeMap<String, Vec<u32>> {
+        self.containers
+            .iter()
+            .map(|(id, execs)| {
+                let pids = execs
+                    .values()
+                    .filter(|entry| entry.is_running())
+                    .filter_map(|entry| entry.record.pid)
+                    .collect();
+                (id.clone(), pids)
+            })
+            .collect()
+    }
+
+    // Kill the processes of the container when the runtime elapsed.
+    pub fn set_max_runtime(&mut self, id: &str, max_runtime: Duration) {
+        log::info!("Container {} is killed after {:?}", id, max_runtime);
+        self.deadlines
+            .insert(id.to_string(), Instant::now() + max_runtime);
+    }
+
+    // Kill the processes of the container when the runtime elapsed after it starts.
+    pub fn defer_max_runtime(&mut self, id: &str, max_runtime: Duration) {
+        self.max_runtimes.insert(id.to_string(), max_runtime);
+    }
+
+    // Arm the max runtime deferred at the create of the container.
+    pub fn arm_max_runtime(&mut self, id: &str) {
+        if let Some(max_runtime) = self.max_runtimes.remove(id) {
+            self.set_max_runtime(id, max_runtime);
+        }
+    }
+
+    pub fn set_priority(&mut self, id: &str, priority: ProcessPriority) {
+        log::info!("Container {} runs with {:?}", id, priority);
+        self.priorities.insert(id.to_string(), priority);
+    }
+
+    pub fn priority(&self, id: &str) -> Option<ProcessPriority> {
+        self.priorities.get(id).copied()
+    }
+
+    // Dedicated users are not supported on Linux guests.
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn set_user(&mut self, id: &str, user: GuestUser) {
+        self.users.insert(id.to_string(), user);
+    }
+
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn user(&self, id: &str) -> Option<&GuestUser> {
+        self.users.get(id)
+    }
+
+    #[cfg_attr(target_os = "linux", allow(dead_code))]
+    pub fn remove_user(&mut self, id: &str) -> Option<GuestUser> {
+        self.users.remove(id)
+    }
+
+    pub fn users(&self) -> BTreeMap<String, GuestUser> {
+        self.users
+            .iter()
+            .map(|(id, user)| (id.clone(), user.clone()))
+            .collect()
+    }
+
+    // Kill the processes of the containers that ran out of time.
+    // Return true if any process was killed.
+    pub fn enforce_deadlines(&mut self) -> bool {
+        let now = Instant::now();
+        let expired: Vec<String> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut killed = false;
+        for id in expired {
+            self.deadlines.remove(&id);
+            let Some(execs) = self.containers.get_mut(&id) else {
+                continue;
+            };
+            for (exec_id, entry) in execs.iter_mut() {
+                if entry.is_running() {
+                    log::warn!("Killing exec {:?} of container {}: timed out", exec_id, id);
+                    entry.kill();
+                    entry.record.exit_reason = Some(ExitReason::Timeout);
+                    killed = true;
+                }
+            }
+        }
+        killed
+    }
+
+    // Kill and remove all the processes of the container.
+    pub fn remove_container(&mut self, id: &str) {
+        self.deadlines.remove(id);
+        self.max_runtimes.remove(id);
+        self.main_processes.remove(id);
+        self.priorities.remove(id);
+        let Some(execs) = self.containers.remove(id) else {
+            return;
+        };
+        for (exec_id, mut entry) in execs {
+            if entry.is_running() {
+                log::info!("Killing exec {} of container {}", exec_id, id);
+                entry.kill();
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Hosts files of the network groups.
+//! Each group has a hosts file that resolves its aliases to the loopback addresses. The
+//! containers of macOS guests run on the root filesystem of the guest, so the aliases also go to
+//! a block of /etc/hosts. Linux containers get the file of their group on /etc/hosts instead.
+
+use std::path::Path;
+
+use anyhow::Result;
+use libakari::network_group::guest_hosts_path;
+
+#[cfg(not(target_os = "linux"))]
+const HOSTS_PATH: &str = "/etc/hosts";
+#[cfg(not(target_os = "linux"))]
+const BEGIN_MARKER: &str = "# BEGIN akari network group";
+#[cfg(not(target_os = "linux"))]
+const END_MARKER: &str = "# END akari network group";
+
+fn entries(aliases: &[String]) -> String {
+    let aliases = aliases.join(" ");
+    format!("127.0.0.1\t{}\n::1\t{}\n", aliases, aliases)
+}
+
+// Replace the file so that the resolver never reads a partial file.
+fn replace(path: &Path, contents: &str) -> Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid hosts file {:?}", path))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Replace the block of the group in /etc/hosts, and leave the other lines as they are.
+#[cfg(not(target_os = "linux"))]
+fn set_block(group: &str, aliases: &[String]) -> Result<()> {
+    // /etc is a symlink to /private/etc, and the rename must not replace the link.
+    let path = std::fs::canonicalize(HOSTS_PATH)?;
+    let begin = format!("{} {}", BEGIN_MARKER, group);
+    let end = format!("{} {}", END_MARKER, group);
+    let mut hosts = String::new();
+    let mut in_block = false;
+    for line in std::fs::read_to_string(&path)?.lines() {
+        if line == begin || line == end {
+            in_block = line == begin;
+        } else if !in_block {
+            hosts.push_str(line);
+            hosts.push('\n');
+        }
+    }
+    if !aliases.is_empty() {
+        hosts.push_str(&format!("{}\n{}{}\n", begin, entries(aliases), end));
+    }
+    replace(&path, &hosts)
+}
+
+pub fn set(group: &str, aliases: &[String]) -> Result<()> {
+    let path = guest_hosts_path(group);
+    if aliases.is_empty() {
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    } else {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let hosts = format!("127.0.0.1\tlocalhost\n::1\tlocalhost\n{}", entries(aliases));
+        replace(&path, &hosts)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    set_block(group, aliases)?;
+    log::info!("Resolving {:?} in network group {}", aliases, group);
+    Ok(())
+}
+
+// Bind-mount the hosts file of the group on /etc/hosts of the container.
+#[cfg(target_os = "linux")]
+pub fn mount(spec: &mut oci_spec::runtime::Spec, group: &str) -> Result<()> {
+    use oci_spec::runtime::{LinuxNamespaceType, MountBuilder};
+
+    // The loopback addresses only reach the others in the network namespace of the guest.
+    let private_network = spec
+        .linux()
+        .as_ref()
+        .and_then(|linux| linux.namespaces().as_ref())
+        .is_some_and(|namespaces| {
+            namespaces
+                .iter()
+                .any(|namespace| namespace.typ() == LinuxNamespaceType::Network)
+        });
+    if private_network {
+        anyhow::bail!(
+            "Network group {} needs the network namespace of the guest",
+            group
+        );
+    }
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    mounts.push(
+        MountBuilder::default()
+            .destination("/etc/hosts")
+            .typ("bind")
+            .source(guest_hosts_path(group))
+            .options(vec!["rbind".to_string(), "ro".to_string()])
+            .build()?,
+    );
+    spec.set_mounts(Some(mounts));
+    Ok(())
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Composes the rootfs of the containers from the layer disks with overlayfs.
+//! Each layer is mounted read-only once and shared by the containers that use it. The writes
+//! of a container go to its own upper directory, which is removed with the container.
+
+use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    layer::{guest_layer_device, guest_layer_path, guest_rootfs_dir, guest_rootfs_path},
+};
+
+use crate::shares::is_mounted;
+
+// Filesystems of the layer images, tried in order
+const LAYER_FS_TYPES: &[&str] = &["erofs", "ext4", "squashfs"];
+
+fn cstring(s: impl AsRef<[u8]>) -> Result<CString> {
+    Ok(CString::new(s.as_ref())?)
+}
+
+pub fn mount(
+    source: &Path,
+    target: &Path,
+    fs_type: &str,
+    flags: libc::c_ulong,
+    data: &str,
+) -> Result<()> {
+    let source = cstring(source.as_os_str().as_bytes())?;
+    let target = cstring(target.as_os_str().as_bytes())?;
+    let fs_type = cstring(fs_type)?;
+    let data = cstring(data)?;
+    let res = unsafe {
+        libc::mount(
+            source.as_ptr(),
+            target.as_ptr(),
+            fs_type.as_ptr(),
+            flags,
+            data.as_ptr().cast(),
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn mount_layer(name: &str) -> Result<()> {
+    let target = guest_layer_path(name);
+    if is_mounted(&target) {
+        return Ok(());
+    }
+    std::fs::create_dir_all(&target)?;
+    let device = guest_layer_device(name);
+    let mut errors = Vec::new();
+    for fs_type in LAYER_FS_TYPES {
+        match mount(&device, &target, fs_type, libc::MS_RDONLY, "") {
+            Ok(()) => {
+                log::info!("Mounted layer {} ({}) on {:?}", name, fs_type, target);
+                return Ok(());
+            }
+            Err(e) => errors.push(format!("{}: {}", fs_type, e)),
+        }
+    }
+    anyhow::bail!(
+        "Failed to mount layer {} from {:?}: {}",
+        name,
+        device,
+        errors.join(", ")
+    )
+}
+
+pub fn mount_rootfs(id: &ContainerId, layers: &[String]) -> Result<()> {
+    for layer in layers {
+        mount_layer(layer)?;
+    }
+    let merged = guest_rootfs_path(id);
+    if is_mounted(&merged) {
+        return Ok(());
+    }
+    let dir = guest_rootfs_dir(id);
+    let upper = dir.join("upper");
+    let work = dir.join("work");
+    for dir in [&upper, &work, &merged] {
+        std::fs::create_dir_all(dir)?;
+    }
+    // overlayfs lists the lower directories from the top.
+    let lower = layers
+        .iter()
+        .rev()
+        .map(|layer| guest_layer_path(layer).to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(":");
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower,
+        upper.display(),
+        work.display()
+    );
+    mount(Path::new("overlay"), &merged, "overlay", 0, &data)?;
+    log::info!("Composed the rootfs of {} from {:?}", id, layers);
+    Ok(())
+}
+
+pub fn unmount_rootfs(id: &ContainerId) -> Result<()> {
+    let merged = guest_rootfs_path(id);
+    if is_mounted(&merged) {
+        let target = cstring(merged.as_os_str().as_bytes())?;
+        // The processes of the container may still hold the files.
+        if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    match std::fs::remove_dir_all(guest_rootfs_dir(id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
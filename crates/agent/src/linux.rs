@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Experimental Linux container support.
+//! When the agent runs inside a Linux guest, containers are created with
+//! namespaces and cgroups via libcontainer instead of spawning the process directly.
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    network_group::NetworkMember,
+    priority::ProcessPriority,
+    secret::{guest_secrets_path, secrets_volume_destination},
+    storage::ContainerStorage,
+};
+use libcontainer::{
+    container::{builder::ContainerBuilder, Container},
+    syscall::syscall::SyscallType,
+};
+use oci_spec::runtime::{MountBuilder, Spec};
+
+// Directory inside the guest to store the libcontainer state.
+const STATE_ROOT_PATH: &str = "/run/akari/state";
+// Directory inside the guest to store the container bundles.
+const BUNDLE_ROOT_PATH: &str = "/run/akari/bundles";
+
+fn bundle_path(id: &str) -> PathBuf {
+    PathBuf::from(BUNDLE_ROOT_PATH).join(id)
+}
+
+// Write the spec to the guest-local bundle so that libcontainer can load it.
+fn prepare_bundle(id: &str, config: &Spec) -> Result<PathBuf> {
+    let bundle = bundle_path(id);
+    std::fs::create_dir_all(&bundle)?;
+    // The host rewrites the rootfs to its guest path. A relative path would be resolved against
+    // this bundle, which has no rootfs.
+    if let Some(root) = config.root() {
+        if root.path().is_relative() {
+            anyhow::bail!(
+                "The rootfs of {} is not a guest path: {:?}",
+                id,
+                root.path()
+            );
+        }
+    }
+    let config_json = serde_json::to_string_pretty(config)?;
+    std::fs::write(bundle.join("config.json"), config_json)?;
+    Ok(bundle)
+}
+
+// Bind-mount the storage of the container at its destination.
+fn mount_storage(spec: &mut Spec, id: &ContainerId, storage: &ContainerStorage) -> Result<()> {
+    let source = crate::storage::create(id, storage.quota)?;
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    mounts.push(
+        MountBuilder::default()
+            .destination(storage.destination.clone())
+            .typ("bind")
+            .source(source)
+            .options(vec!["rbind".to_string(), "rw".to_string()])
+            .build()?,
+    );
+    spec.set_mounts(Some(mounts));
+    Ok(())
+}
+
+// Bind-mount the secrets volume that the host mounted before the create.
+fn mount_secrets(spec: &mut Spec, id: &ContainerId, destination: PathBuf) -> Result<()> {
+    let mut mounts = spec.mounts().clone().unwrap_or_default();
+    mounts.push(
+        MountBuilder::default()
+            .destination(destination)
+            .typ("bind")
+            .source(guest_secrets_path(id))
+            .options(vec!["rbind".to_string(), "ro".to_string()])
+            .build()?,
+    );
+    spec.set_mounts(Some(mounts));
+    Ok(())
+}
+
+pub fn create(id: &ContainerId, mut config: Spec, priority: Option<ProcessPriority>) -> Result<()> {
+    let annotations = config.annotations().clone().unwrap_or_default();
+    if let Some(member) = NetworkMember::from_annotations(id, &annotations)? {
+        crate::hosts::mount(&mut config, &member.group)?;
+    }
+    if let Some(storage) = ContainerStorage::from_annotations(&annotations)? {
+        mount_storage(&mut config, id, &storage)?;
+    }
+    if let Some(destination) = secrets_volume_destination(&annotations)? {
+        mount_secrets(&mut config, id, destination)?;
+    }
+    let bundle = prepare_bundle(id, &config)?;
+    std::fs::create_dir_all(STATE_ROOT_PATH)?;
+
+    let container = ContainerBuilder::new(id.to_string(), SyscallType::default())
+        .with_root_path(STATE_ROOT_PATH)?
+        .validate_id()?
+        .as_init(&bundle)
+        .with_systemd(false)
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to create container {}: {}", id, e))?;
+
+    log::info!(
+        "Created Linux container {} (pid: {:?})",
+        id,
+        container.pid()
+    );
+
+    if let (Some(priority), Some(pid)) = (priority, container.pid()) {
+        crate::priority::apply(pid.as_raw(), &priority)?;
+    }
+
+    Ok(())
+}
+
+fn load(id: &ContainerId) -> Result<Container> {
+    Container::load(PathBuf::from(STATE_ROOT_PATH).join(id.to_string()))
+        .map_err(|e| anyhow::anyhow!("Failed to load container {}: {}", id, e))
+}
+
+// Start the init process of the container, which waits since the create.
+pub fn start(id: &ContainerId) -> Result<()> {
+    let mut container = load(id)?;
+    container
+        .start()
+        .map_err(|e| anyhow::anyhow!("Failed to start container {}: {}", id, e))?;
+    log::info!("Started Linux container {}", id);
+    Ok(())
+}
+
+// Return the pid of the init process of the container.
+pub fn pid(id: &ContainerId) -> Result<i32> {
+    load(id)?
+        .pid()
+        .map(|pid| pid.as_raw())
+        .ok_or_else(|| anyhow::anyhow!("Container {} is not running", id))
+}
@@ -3,20 +3,148 @@
 
 //! Akari Guest Agent
 //! This is a daemon that listens for requests from the host.
+//! On macOS guests the container process is spawned directly. On Linux guests
+//! (experimental) the container is created with namespaces and cgroups via libcontainer.
 
+mod auth;
+mod cache;
+mod config;
+mod debug;
+mod exec;
+mod hosts;
+#[cfg(target_os = "linux")]
+mod layers;
+#[cfg(target_os = "linux")]
+mod linux;
+mod priority;
+mod probe;
+mod reaper;
+#[cfg(target_os = "linux")]
+mod resources;
+mod secret;
+mod shares;
+mod shutdown;
+#[cfg(not(target_os = "linux"))]
+mod snapshot;
+mod stats;
+mod storage;
+mod transfer;
+#[cfg(not(target_os = "linux"))]
+mod user;
+mod watchdog;
+
+use std::{
+    fs::OpenOptions,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+#[cfg(not(target_os = "linux"))]
 use std::{
-    collections::HashMap,
-    io::Read,
+    os::unix::process::CommandExt,
     process::{Command, Stdio},
 };
 
 use anyhow::Result;
-use libakari::container_rpc::ContainerCommand;
-use oci_spec::runtime::Spec;
+use clap::Parser;
+use libakari::{
+    agent_config::{AgentConfig, AgentFeatures, DEFAULT_AGENT_CONFIG_PATH},
+    console::{AgentTransport, ConsoleRequest},
+    container_rpc::{ContainerCommand, ContainerResponse},
+    framing::{self, read_chunked, WriteTo, MAX_MESSAGE_SIZE},
+    priority::ProcessPriority,
+    timeout::{parse_max_runtime, MAX_RUNTIME_ANNOTATION},
+    vsock::{Handshake, VsockPort, VsockPorts},
+    vsock_auth,
+};
+#[cfg(not(target_os = "linux"))]
+use libakari::{
+    container_id::ContainerId,
+    guest_user::GuestUser,
+    secret::{guest_secrets_path, sanitize_env, secrets_volume_destination},
+    storage::ContainerStorage,
+    volume::cache_volumes,
+};
+#[cfg(not(target_os = "linux"))]
+use oci_spec::runtime::{Process, Spec};
 use vsock::{VsockAddr, VsockListener, VMADDR_CID_ANY};
 
-fn create(config: Spec) -> Result<()> {
-    let process = config.process().as_ref().unwrap();
+use config::Config;
+use exec::{ExecTable, MAIN_EXEC_ID};
+use reaper::Reaper;
+#[cfg(not(target_os = "linux"))]
+use user::Credentials;
+
+// The options override the configuration file and the boot arguments.
+#[derive(clap::Parser)]
+struct Opts {
+    /// Configuration file
+    #[clap(long, default_value = DEFAULT_AGENT_CONFIG_PATH)]
+    config: PathBuf,
+    /// Vsock port to listen on
+    #[clap(long)]
+    port: Option<VsockPort>,
+    /// First vsock port assigned to the containers
+    #[clap(long)]
+    container_port_base: Option<VsockPort>,
+    /// Directory of the state of the agent, e.g. the process table
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// Data volume to snapshot before risky operations and to report the free space of
+    #[clap(long)]
+    data_volume: Option<PathBuf>,
+    /// Named virtio-console port to also serve the commands on if it exists
+    #[clap(long)]
+    console_port: Option<PathBuf>,
+}
+
+impl Opts {
+    // Read the file, then apply the boot arguments and the options.
+    fn load_config(&self, boot_args: Option<&str>) -> Result<AgentConfig> {
+        let mut config = AgentConfig::load(&self.config)?;
+        if let Some(args) = boot_args {
+            config.apply_boot_args(args)?;
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(port) = self.container_port_base {
+            config.container_port_base = port;
+        }
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+        if let Some(data_volume) = &self.data_volume {
+            config.data_volume = data_volume.clone();
+        }
+        if let Some(console_port) = &self.console_port {
+            config.console_port = console_port.clone();
+        }
+        Ok(config)
+    }
+}
+
+// Refuse the commands of the features disabled in the configuration.
+fn check_feature(features: &AgentFeatures, cmd: &ContainerCommand) -> Result<()> {
+    let enabled = match cmd {
+        ContainerCommand::DebugExec(_) => features.debug_exec,
+        ContainerCommand::Provision(_) => features.provision,
+        ContainerCommand::Snapshot(_) | ContainerCommand::Rollback(_) => features.snapshots,
+        _ => true,
+    };
+    if !enabled {
+        anyhow::bail!("{:?} is disabled in the agent configuration", cmd);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn command(
+    process: &Process,
+    priority: Option<ProcessPriority>,
+    dedicated_user: Option<&GuestUser>,
+) -> Result<Command> {
     let cwd = process.cwd();
     let args = process.args().as_ref().unwrap();
     let env = process.env();
@@ -29,50 +157,469 @@ fn create(config: Spec) -> Result<()> {
     cmd.current_dir(cwd);
     cmd.args(args);
     if let Some(env) = env {
-        // Create hashmap by parsing env strings like "key=value"
-        let envs: HashMap<String, String> = env
-            .iter()
-            .map(|e| {
-                let mut split = e.splitn(2, '=');
-                (
-                    split.next().unwrap().to_string(),
-                    split.next().unwrap().to_string(),
-                )
-            })
-            .collect();
-        cmd.envs(envs);
+        // Malformed entries are dropped instead of aborting the agent.
+        cmd.envs(sanitize_env(env));
+    }
+    // Lowering the nice value needs root, so it is applied before the credentials are dropped.
+    if let Some(priority) = priority {
+        unsafe { cmd.pre_exec(move || priority::apply(&priority)) };
+    }
+    // The dedicated user replaces the user of the spec so that the containers never share a uid.
+    let credentials = match dedicated_user {
+        Some(dedicated_user) => Some(Credentials::from(dedicated_user)),
+        None => user::resolve(process.user())?,
+    };
+    if let Some(credentials) = credentials {
+        let has_home = env.iter().flatten().any(|var| var.starts_with("HOME="));
+        if let Some(home) = credentials.home.as_ref().filter(|_| !has_home) {
+            cmd.env("HOME", home);
+        }
+        // The groups are set before the IDs, which std cannot do.
+        unsafe { cmd.pre_exec(move || credentials.apply()) };
     }
     cmd.stdout(Stdio::piped());
     cmd.stderr(Stdio::piped());
     cmd.stdin(Stdio::piped());
+    Ok(cmd)
+}
+
+// The main process is spawned by the Start command.
+#[cfg(not(target_os = "linux"))]
+fn create(execs: &mut ExecTable, id: &ContainerId, config: Spec) -> Result<()> {
+    let process = config
+        .process()
+        .clone()
+        .ok_or_else(|| anyhow::anyhow!("Container {} has no process", id))?;
+    // Check the process before the container is reported as created.
+    command(&process, execs.priority(id), execs.user(id))?;
+
+    // Link the storage into place like the cache volumes.
+    let annotations = config.annotations().clone().unwrap_or_default();
+    if let Some(storage) = ContainerStorage::from_annotations(&annotations)? {
+        let target = storage::create(id, storage.quota)?;
+        if storage.destination.symlink_metadata().is_ok() {
+            log::warn!(
+                "Storage destination already exists: {:?}",
+                storage.destination
+            );
+        } else {
+            if let Some(parent) = storage.destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::os::unix::fs::symlink(target, &storage.destination)?;
+        }
+    }
 
+    // The host mounts the secrets volume before the container is created.
+    if let Some(destination) = secrets_volume_destination(&annotations)? {
+        if destination.symlink_metadata().is_ok() {
+            log::warn!(
+                "Secrets volume destination already exists: {:?}",
+                destination
+            );
+        } else {
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::os::unix::fs::symlink(guest_secrets_path(id), &destination)?;
+        }
+    }
+
+    // macOS has no bind mounts, so link the cache volumes into place.
+    for volume in cache_volumes(&config)? {
+        if volume.destination.symlink_metadata().is_ok() {
+            log::warn!(
+                "Cache volume destination already exists: {:?}",
+                volume.destination
+            );
+            continue;
+        }
+        if let Some(parent) = volume.destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::os::unix::fs::symlink(volume.guest_path(), &volume.destination)?;
+    }
+
+    execs.set_main_process(id, process);
     Ok(())
 }
 
-fn handle_cmd(cmd: ContainerCommand) -> Result<()> {
+#[cfg(not(target_os = "linux"))]
+fn start(execs: &mut ExecTable, id: &str) -> Result<()> {
+    let process = execs
+        .take_main_process(id)
+        .ok_or_else(|| anyhow::anyhow!("Container {} is not created", id))?;
+    let child = command(&process, execs.priority(id), execs.user(id))?.spawn()?;
+    log::info!("Started container {} (pid: {})", id, child.id());
+    execs.insert(id, MAIN_EXEC_ID, child)?;
+    execs.arm_max_runtime(id);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn exec(execs: &mut ExecTable, id: &str, exec_id: &str, process: Process) -> Result<()> {
+    let child = command(&process, execs.priority(id), execs.user(id))?.spawn()?;
+    log::info!(
+        "Started exec {} of container {} (pid: {})",
+        exec_id,
+        id,
+        child.id()
+    );
+    execs.insert(id, exec_id, child)
+}
+
+fn handle_cmd(
+    execs: &mut ExecTable,
+    agent_config: &Config,
+    cmd: ContainerCommand,
+) -> Result<ContainerResponse> {
+    execs.reap();
+    let agent = agent_config.get();
+    check_feature(&agent.features, &cmd)?;
+    if shutdown::draining()
+        && matches!(
+            cmd,
+            ContainerCommand::Create(..) | ContainerCommand::Start(_) | ContainerCommand::Exec(..)
+        )
+    {
+        anyhow::bail!("The agent is shutting down");
+    }
+    if let ContainerCommand::Create(id, config) = &cmd {
+        let containers = execs.process_counts();
+        if let Some(max) = agent.max_containers {
+            if !containers.contains_key(id.as_str()) && containers.len() >= max {
+                anyhow::bail!("The agent already runs the maximum of {} containers", max);
+            }
+        }
+        let annotations = config.annotations().clone().unwrap_or_default();
+        if let Some(max_runtime) = annotations.get(MAX_RUNTIME_ANNOTATION) {
+            execs.defer_max_runtime(id, parse_max_runtime(max_runtime)?);
+        }
+        if let Some(priority) = ProcessPriority::from_annotations(&annotations)? {
+            execs.set_priority(id, priority);
+        }
+    }
     match cmd {
-        ContainerCommand::Create(config) => create(*config),
-        ContainerCommand::Delete => todo!(),
-        ContainerCommand::Kill => todo!(),
-        ContainerCommand::Start => todo!(),
-        ContainerCommand::State => todo!(),
+        ContainerCommand::Stats => {
+            let stats = stats::collect(execs, &agent.data_volume)?;
+            return Ok(ContainerResponse::Stats(stats));
+        }
+        ContainerCommand::Health => return Ok(ContainerResponse::Health(probe::health())),
+        ContainerCommand::StartProbes(id, probes) => {
+            probe::start(&id, probes);
+            Ok(())
+        }
+        ContainerCommand::StopProbes(id) => {
+            probe::stop(&id);
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::CreateUser(id) => {
+            let user = match execs.user(&id) {
+                Some(user) => user.clone(),
+                None => user::create_dedicated(&id)?,
+            };
+            execs.set_user(&id, user.clone());
+            return Ok(ContainerResponse::User(user));
+        }
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::DeleteUser(id) => {
+            let user = match execs.remove_user(&id) {
+                Some(user) => Some(user),
+                None => user::find_dedicated(&id)?,
+            };
+            match user {
+                Some(user) => user::delete_dedicated(&id, &user),
+                None => Ok(()),
+            }
+        }
+        // The user namespaces of libcontainer isolate the containers on Linux guests.
+        #[cfg(target_os = "linux")]
+        ContainerCommand::CreateUser(_) | ContainerCommand::DeleteUser(_) => {
+            anyhow::bail!("Dedicated users are only supported on macOS guests")
+        }
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::Create(id, config) => create(execs, &id, *config),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::Create(id, config) => linux::create(&id, *config, execs.priority(&id)),
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::Exec(id, exec_id, process) => exec(execs, &id, &exec_id, *process),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::Exec(..) => anyhow::bail!("Exec is not supported on Linux guests yet"),
+        ContainerCommand::DeleteExec(id, exec_id) => {
+            let record = execs.remove(&id, &exec_id)?;
+            log::info!("Deleted exec {:?}", record);
+            Ok(())
+        }
+        ContainerCommand::DebugExec(_)
+        | ContainerCommand::Provision(_)
+        | ContainerCommand::ReceiveBundle(_) => {
+            anyhow::bail!("Streaming commands are served only on vsock")
+        }
+        ContainerCommand::RemoveBundle(id) => transfer::remove(&id),
+        ContainerCommand::RemoveStorage(id) => storage::remove(&id),
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::MountSecrets(id, volume) => {
+            secret::mount(&id, &volume, execs.user(id.as_str()).map(|user| user.uid))
+        }
+        #[cfg(target_os = "linux")]
+        ContainerCommand::MountSecrets(id, volume) => secret::mount(&id, &volume, None),
+        ContainerCommand::UnmountSecrets(id) => secret::unmount(&id),
+        ContainerCommand::Configure(settings) => agent_config.apply(&settings),
+        ContainerCommand::Shutdown(_) => unreachable!("Handled by the connection"),
+        ContainerCommand::InvalidateCache(id, paths) => cache::invalidate(&id, &paths),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::MountLayers(id, layers) => layers::mount_rootfs(&id, &layers),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::UnmountLayers(id) => layers::unmount_rootfs(&id),
+        // macOS has no overlay filesystem.
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::MountLayers(..) | ContainerCommand::UnmountLayers(_) => {
+            anyhow::bail!("Layered rootfs is only supported on Linux guests")
+        }
+        ContainerCommand::MountShares(shares) => shares::mount(&shares),
+        ContainerCommand::SetHosts(group, aliases) => hosts::set(&group, &aliases),
+        ContainerCommand::SetMaxRuntime(id, secs) => {
+            execs.set_max_runtime(&id, Duration::from_secs(secs));
+            Ok(())
+        }
+        #[cfg(target_os = "linux")]
+        ContainerCommand::UpdateResources(id, limits) => resources::update(&id, &limits),
+        // The memory is limited by resizing the VM.
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::UpdateResources(_, limits) => {
+            if limits.cpu_shares.is_some() || limits.pids.is_some() {
+                anyhow::bail!("CPU and process limits are only supported on Linux guests");
+            }
+            Ok(())
+        }
+        ContainerCommand::Delete(id) => {
+            execs.remove_container(&id);
+            Ok(())
+        }
+        // The host signals and inspects the processes through the task service of the guest.
+        ContainerCommand::Kill | ContainerCommand::State => {
+            anyhow::bail!("Kill and State are served by the task service of the guest")
+        }
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::Start(id) => start(execs, &id),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::Start(id) => {
+            linux::start(&id)?;
+            execs.adopt(&id, MAIN_EXEC_ID, linux::pid(&id)? as u32)?;
+            execs.arm_max_runtime(&id);
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::Snapshot(name) => snapshot::create(&agent.data_volume, &name),
+        #[cfg(not(target_os = "linux"))]
+        ContainerCommand::Rollback(name) => snapshot::revert(&agent.data_volume, &name),
+        #[cfg(target_os = "linux")]
+        ContainerCommand::Snapshot(_) | ContainerCommand::Rollback(_) => {
+            anyhow::bail!(
+                "Snapshots of {:?} are not supported on Linux guests",
+                agent.data_volume
+            )
+        }
+    }?;
+    Ok(ContainerResponse::Ok)
+}
+
+// Handle the command and persist the process table.
+fn serve_cmd(
+    execs: &Mutex<ExecTable>,
+    agent_config: &Config,
+    cmd: ContainerCommand,
+) -> ContainerResponse {
+    let mut execs = execs.lock().unwrap_or_else(|e| e.into_inner());
+    let res = match handle_cmd(&mut execs, agent_config, cmd) {
+        Ok(res) => res,
+        Err(e) => {
+            log::error!("Failed to handle the command: {}", e);
+            ContainerResponse::Error(e.to_string())
+        }
+    };
+    if let Err(e) = execs.save(&agent_config.get().state_path()) {
+        log::error!("Failed to save the process table: {}", e);
+    }
+    res
+}
+
+// Answer the requests of the host on the console port one by one.
+fn serve_console(
+    execs: &Mutex<ExecTable>,
+    agent_config: &Config,
+    handshake: &Handshake,
+) -> Result<()> {
+    let console_port = agent_config.get().console_port;
+    let mut port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&console_port)?;
+    log::info!("Serving the commands on {:?}", console_port);
+    loop {
+        let req: ConsoleRequest = read_chunked(&mut port, MAX_MESSAGE_SIZE)?;
+        let res = match req {
+            ConsoleRequest::Handshake => handshake.write_to(&mut port),
+            // The console serves one request at a time, so the drain blocks the others.
+            ConsoleRequest::Command(ContainerCommand::Shutdown(wait)) => shutdown::run(
+                execs,
+                &agent_config.get().state_path(),
+                Duration::from_secs(wait.unwrap_or(0)),
+                &mut port,
+            ),
+            ConsoleRequest::Command(cmd) => serve_cmd(execs, agent_config, cmd).write_to(&mut port),
+        };
+        if let Err(e) = res {
+            log::error!("Failed to send the response on the console: {}", e);
+        }
     }
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
+    let opts = Opts::parse();
+    // The boot arguments may set the log level, so the failure is logged after the logger is
+    // set up.
+    let boot_args = config::boot_args();
+    let config = opts.load_config(boot_args.as_deref().ok())?;
+    config::init_logger(&config.log_level)?;
+    if let Err(e) = &boot_args {
+        log::warn!("Failed to read the boot arguments: {}", e);
+    }
+    let ports = VsockPorts {
+        agent_port: config.port,
+        container_port_base: config.container_port_base,
+    };
+
+    let addr = VsockAddr::new(VMADDR_CID_ANY, ports.agent_port.get());
+    let listener = VsockListener::bind(&addr);
+    let console = config.console_port.exists();
+    if let Err(e) = &listener {
+        if !console {
+            anyhow::bail!("Failed to listen on vsock port {}: {}", ports.agent_port, e);
+        }
+        log::warn!("Vsock is unavailable, so only the console is served: {}", e);
+    }
+    // Tell the host the ports so that it can verify them.
+    let handshake = Handshake {
+        ports,
+        transports: [
+            (listener.is_ok(), AgentTransport::Vsock),
+            (console, AgentTransport::Console),
+        ]
+        .into_iter()
+        .filter_map(|(available, transport)| available.then_some(transport))
+        .collect(),
+        auth: false,
+    };
 
-    let addr = VsockAddr::new(VMADDR_CID_ANY, 9999);
-    let listener = VsockListener::bind(&addr)?;
+    // Start the reaper before anything else spawns a thread.
+    let reaper = Reaper::start()?;
+    let state_path = config.state_path();
+    let execs = Arc::new(Mutex::new(ExecTable::load(&state_path, reaper.clone())?));
+    watchdog::start(execs.clone(), state_path)?;
+    let agent_config = Arc::new(Config::new(config));
+
+    let console_thread = console.then(|| {
+        let execs = execs.clone();
+        let agent_config = agent_config.clone();
+        let handshake = handshake.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = serve_console(&execs, &agent_config, &handshake) {
+                log::error!("Stopped serving the console: {}", e);
+            }
+        })
+    });
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(_) => {
+            if let Some(thread) = console_thread {
+                let _ = thread.join();
+            }
+            return Ok(());
+        }
+    };
 
     for stream in listener.incoming() {
         let mut stream = stream?;
         log::info!("Accepted a new connection from {}", stream.peer_addr()?);
 
-        let mut buf = [0; 1024];
-        let n = stream.read(&mut buf)?;
-        let cmd = serde_json::from_slice(&buf[..n])?;
-        handle_cmd(cmd)?;
+        let token = auth::load(&agent_config.get().auth_token);
+        let handshake = Handshake {
+            auth: token.is_some(),
+            ..handshake.clone()
+        };
+        handshake.write_to(&mut stream)?;
+        if let Some(token) = &token {
+            match vsock_auth::answer(&mut stream, token) {
+                Ok(()) => {}
+                // The host closed the connection after the handshake.
+                Err(framing::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => continue,
+                Err(e) => {
+                    log::error!("Failed to authenticate to the host: {}", e);
+                    continue;
+                }
+            }
+        }
+
+        // Commands are sent in chunks as the OCI spec can be large.
+        let cmd = match read_chunked(&mut stream, MAX_MESSAGE_SIZE) {
+            Ok(cmd) => cmd,
+            // The host closed the connection after the handshake.
+            Err(framing::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => continue,
+            Err(e) => {
+                log::error!("Failed to read the command: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = check_feature(&agent_config.get().features, &cmd) {
+            let _ = ContainerResponse::Error(e.to_string()).write_to(&mut stream);
+            continue;
+        }
+        // The debug command streams its output, so it runs without blocking the other commands.
+        if let ContainerCommand::DebugExec(args) = cmd {
+            let reaper = reaper.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = debug::exec(stream, args, &reaper) {
+                    log::error!("Failed to run the debug command: {}", e);
+                }
+            });
+            continue;
+        }
+        // The provisioning script may take long, e.g. to install the developer tools.
+        if let ContainerCommand::Provision(script) = cmd {
+            let reaper = reaper.clone();
+            std::thread::spawn(move || {
+                let args = vec!["/bin/sh".to_string(), script.to_string_lossy().into_owned()];
+                if let Err(e) = debug::exec(stream, args, &reaper) {
+                    log::error!("Failed to run the provisioning script: {}", e);
+                }
+            });
+            continue;
+        }
+        // Keep serving the other commands while the containers are drained.
+        if let ContainerCommand::Shutdown(wait) = cmd {
+            let execs = execs.clone();
+            let state_path = agent_config.get().state_path();
+            std::thread::spawn(move || {
+                let wait = Duration::from_secs(wait.unwrap_or(0));
+                shutdown::run(&execs, &state_path, wait, stream)
+            });
+            continue;
+        }
+        // The bundle follows the command, so it is unpacked while it arrives.
+        if let ContainerCommand::ReceiveBundle(id) = cmd {
+            std::thread::spawn(move || {
+                if let Err(e) = transfer::receive(stream, &id) {
+                    log::error!("Failed to receive the bundle of {}: {}", id, e);
+                }
+            });
+            continue;
+        }
+        let res = serve_cmd(&execs, &agent_config, cmd);
+        if let Err(e) = res.write_to(&mut stream) {
+            log::error!("Failed to send the response: {}", e);
+        }
     }
 
     Ok(())
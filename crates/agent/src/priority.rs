@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Scheduling priority of the container processes.
+//! All guests get the nice value. macOS guests also get the disk I/O policy of the QoS class,
+//! and the background class runs in the Darwin background band, which throttles the CPU and
+//! the I/O of the process and its children.
+
+use std::io;
+
+use libakari::{priority::ProcessPriority, scheduling::QosClass};
+
+fn check(res: libc::c_int) -> io::Result<()> {
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Apply the priority to the current process. Called between fork and exec, so it only makes
+// async-signal-safe calls.
+#[cfg(not(target_os = "linux"))]
+pub fn apply(priority: &ProcessPriority) -> io::Result<()> {
+    check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, priority.nice) })?;
+    let policy = match priority.qos {
+        QosClass::UserInteractive | QosClass::UserInitiated => libc::IOPOL_IMPORTANT,
+        QosClass::Default => return Ok(()),
+        QosClass::Utility => libc::IOPOL_UTILITY,
+        QosClass::Background => {
+            return check(unsafe {
+                libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG)
+            })
+        }
+    };
+    check(unsafe { libc::setiopolicy_np(libc::IOPOL_TYPE_DISK, libc::IOPOL_SCOPE_PROCESS, policy) })
+}
+
+// Apply the priority to the init process of a container. The processes it spawns inherit it.
+#[cfg(target_os = "linux")]
+pub fn apply(pid: libc::pid_t, priority: &ProcessPriority) -> io::Result<()> {
+    check(unsafe { libc::setpriority(libc::PRIO_PROCESS, pid as libc::id_t, priority.nice) })?;
+    // Values of <linux/ioprio.h>
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    // The best-effort levels go from 0 (highest) to 7.
+    let best_effort = |level| (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | level;
+    let ioprio = match priority.qos {
+        QosClass::UserInteractive => best_effort(0),
+        QosClass::UserInitiated => best_effort(2),
+        QosClass::Default => return Ok(()),
+        QosClass::Utility => best_effort(7),
+        QosClass::Background => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+    };
+    let res = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, pid, ioprio) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
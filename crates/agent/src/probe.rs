@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Readiness and liveness probes of the containers.
+//! Each probe runs in a thread of its own until the host stops the probes of the container.
+//! The results are kept in memory, so the host starts the probes again after the agent restarts.
+
+use std::{
+    collections::BTreeMap,
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use libakari::probe::{ContainerHealth, Probe, ProbeAction, ProbeStatus, Probes};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+struct Entry {
+    health: ContainerHealth,
+    stop: Arc<AtomicBool>,
+}
+
+static PROBES: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug)]
+enum ProbeKind {
+    Readiness,
+    Liveness,
+}
+
+// Run the probe once. It times out after its interval.
+fn check(probe: &Probe) -> Result<()> {
+    let timeout = Duration::from_secs(probe.interval);
+    match &probe.action {
+        ProbeAction::Exec(args) => {
+            let mut child = Command::new(&args[0])
+                .args(&args[1..])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()?;
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) = child.try_wait()? {
+                    anyhow::ensure!(status.success(), "{:?} exited with {}", args, status);
+                    return Ok(());
+                }
+                if Instant::now() >= deadline {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    anyhow::bail!("{:?} timed out", args);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+        ProbeAction::Tcp(port) => {
+            let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, *port));
+            TcpStream::connect_timeout(&addr, timeout)?;
+            Ok(())
+        }
+    }
+}
+
+fn set_status(id: &str, kind: ProbeKind, status: ProbeStatus) {
+    let mut probes = PROBES.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(entry) = probes.get_mut(id) else {
+        return;
+    };
+    let current = match kind {
+        ProbeKind::Readiness => &mut entry.health.readiness,
+        ProbeKind::Liveness => &mut entry.health.liveness,
+    };
+    if *current != Some(status) {
+        log::info!("{:?} probe of {}: {:?}", kind, id, status);
+        *current = Some(status);
+    }
+}
+
+fn run(id: String, kind: ProbeKind, probe: Probe, stop: Arc<AtomicBool>) {
+    let mut failures = 0;
+    while !stop.load(Ordering::SeqCst) {
+        let result = check(&probe);
+        // The results after the stop would go to the probes that replaced these.
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        match result {
+            Ok(()) => {
+                failures = 0;
+                set_status(&id, kind, ProbeStatus::Success);
+            }
+            Err(e) => {
+                log::debug!("{:?} probe of {} failed: {}", kind, id, e);
+                failures += 1;
+                if failures >= probe.threshold {
+                    set_status(&id, kind, ProbeStatus::Failure);
+                }
+            }
+        }
+        std::thread::sleep(Duration::from_secs(probe.interval));
+    }
+}
+
+// Start the probes of the container, replacing the running ones.
+pub fn start(id: &str, probes: Probes) {
+    stop(id);
+    let stop = Arc::new(AtomicBool::new(false));
+    let health = ContainerHealth {
+        readiness: probes.readiness.as_ref().map(|_| ProbeStatus::Unknown),
+        liveness: probes.liveness.as_ref().map(|_| ProbeStatus::Unknown),
+    };
+    let entry = Entry {
+        health,
+        stop: stop.clone(),
+    };
+    PROBES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .insert(id.to_string(), entry);
+    let probes = [
+        (ProbeKind::Readiness, probes.readiness),
+        (ProbeKind::Liveness, probes.liveness),
+    ];
+    for (kind, probe) in probes {
+        if let Some(probe) = probe {
+            let id = id.to_string();
+            let stop = stop.clone();
+            std::thread::spawn(move || run(id, kind, probe, stop));
+        }
+    }
+}
+
+pub fn stop(id: &str) {
+    let entry = PROBES.lock().unwrap_or_else(|e| e.into_inner()).remove(id);
+    if let Some(entry) = entry {
+        entry.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+pub fn health() -> BTreeMap<String, ContainerHealth> {
+    PROBES
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|(id, entry)| (id.clone(), entry.health))
+        .collect()
+}
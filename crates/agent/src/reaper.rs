@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Reaps the exited children on SIGCHLD so that they do not become zombies in the long-lived guest.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+
+// Exit statuses that nobody asked for, e.g. of the double-forked grandchildren, are dropped
+// beyond this limit.
+const MAX_EXIT_STATUSES: usize = 4096;
+
+#[derive(Default)]
+struct ExitStatuses {
+    codes: HashMap<u32, i32>,
+    order: VecDeque<u32>,
+}
+
+impl ExitStatuses {
+    fn insert(&mut self, pid: u32, code: i32) {
+        if self.codes.insert(pid, code).is_none() {
+            self.order.push_back(pid);
+        }
+        while self.order.len() > MAX_EXIT_STATUSES {
+            if let Some(pid) = self.order.pop_front() {
+                self.codes.remove(&pid);
+            }
+        }
+    }
+
+    fn take(&mut self, pid: u32) -> Option<i32> {
+        let code = self.codes.remove(&pid)?;
+        self.order.retain(|p| *p != pid);
+        Some(code)
+    }
+}
+
+#[derive(Clone)]
+pub struct Reaper {
+    statuses: Arc<Mutex<ExitStatuses>>,
+}
+
+extern "C" fn on_sigchld(_: libc::c_int) {}
+
+fn sigchld_set() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGCHLD);
+        set
+    }
+}
+
+// Convert the wait status to an exit code. A process killed by a signal exits with 128 + signal.
+fn exit_code(status: libc::c_int) -> Option<i32> {
+    if libc::WIFEXITED(status) {
+        Some(libc::WEXITSTATUS(status))
+    } else if libc::WIFSIGNALED(status) {
+        Some(128 + libc::WTERMSIG(status))
+    } else {
+        None
+    }
+}
+
+impl Reaper {
+    // Start the reaper thread. This must be called before any other thread is spawned so that
+    // SIGCHLD is blocked in all the threads and delivered only to the reaper.
+    pub fn start() -> Result<Self> {
+        let set = sigchld_set();
+        unsafe {
+            // SIGCHLD may be discarded while it is ignored by default, so install a handler.
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sigchld as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_NOCLDSTOP | libc::SA_RESTART;
+            if libc::sigaction(libc::SIGCHLD, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+            if ret != 0 {
+                return Err(io::Error::from_raw_os_error(ret).into());
+            }
+        }
+
+        // Adopt the orphaned grandchildren of the containers so that they are reaped as well.
+        #[cfg(target_os = "linux")]
+        if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let reaper = Self {
+            statuses: Arc::new(Mutex::new(ExitStatuses::default())),
+        };
+        let statuses = reaper.statuses.clone();
+        std::thread::Builder::new()
+            .name("reaper".to_string())
+            .spawn(move || loop {
+                let mut sig = 0;
+                if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+                    continue;
+                }
+                Self::reap_all(&statuses);
+            })?;
+        Ok(reaper)
+    }
+
+    fn reap_all(statuses: &Mutex<ExitStatuses>) {
+        loop {
+            let mut status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+            if pid <= 0 {
+                return;
+            }
+            let Some(code) = exit_code(status) else {
+                continue;
+            };
+            log::debug!("Reaped process {} (exit code: {})", pid, code);
+            statuses
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(pid as u32, code);
+        }
+    }
+
+    // Return the exit code if the process has been reaped. The status is returned only once.
+    pub fn try_wait(&self, pid: u32) -> Option<i32> {
+        self.statuses
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take(pid)
+    }
+}
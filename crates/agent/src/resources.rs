@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Resource limits of the running Linux containers, written to their cgroups.
+//! macOS has no per-process limits, so the server resizes the VM instead.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use libakari::{container_id::ContainerId, resources::ResourceLimits};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Return the cgroup v2 directory of the process.
+fn cgroup_path(pid: i32) -> Result<PathBuf> {
+    let cgroups = std::fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+    // The unified hierarchy has the ID 0.
+    let path = cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or_else(|| anyhow!("Process {} is not in a cgroup v2 hierarchy", pid))?;
+    Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+// Convert the CPU shares of cgroup v1 to the weight of cgroup v2 like runc.
+fn cpu_weight(shares: u64) -> u64 {
+    1 + (shares.clamp(2, 262144) - 2) * 9999 / 262142
+}
+
+pub fn update(id: &ContainerId, limits: &ResourceLimits) -> Result<()> {
+    let cgroup = cgroup_path(crate::linux::pid(id)?)?;
+    let files = [
+        ("memory.max", limits.memory),
+        ("cpu.weight", limits.cpu_shares.map(cpu_weight)),
+        ("pids.max", limits.pids),
+    ];
+    for (file, value) in files {
+        if let Some(value) = value {
+            std::fs::write(cgroup.join(file), value.to_string())?;
+        }
+    }
+    log::info!("Updated the resources of {}: {:?}", id, limits);
+    Ok(())
+}
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! In-memory secrets volumes of the containers.
+//! The secrets are written to a RAM disk that is remounted read-only, so they never reach a disk
+//! of the guest or a directory shared with the host. macOS guests use an HFS+ RAM disk of
+//! hdiutil and Linux guests a tmpfs. Detaching the volume frees its memory.
+
+#[cfg(not(target_os = "linux"))]
+use std::process::Command;
+use std::{
+    fs::{OpenOptions, Permissions},
+    io::Write,
+    os::unix::fs::{chown, OpenOptionsExt, PermissionsExt},
+    path::Path,
+};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    secret::{guest_secrets_path, SecretsVolume},
+};
+
+#[cfg(not(target_os = "linux"))]
+use crate::storage::run;
+use crate::{shares::is_mounted, storage::detach};
+
+const SECTOR_SIZE: u64 = 512;
+// Smallest volume that fits the metadata of the filesystems
+const MIN_SIZE: u64 = 4 * 1024 * 1024;
+// Room for the metadata of each file
+const FILE_OVERHEAD: u64 = 4096;
+
+#[cfg(not(target_os = "linux"))]
+fn attach(id: &ContainerId, size: u64, target: &Path) -> Result<()> {
+    let output = Command::new("/usr/bin/hdiutil")
+        .args(["attach", "-nomount"])
+        .arg(format!("ram://{}", size / SECTOR_SIZE))
+        .output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "hdiutil failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let mounted = run(Command::new("/sbin/newfs_hfs")
+        .arg("-v")
+        .arg(id.as_str())
+        .arg(&device))
+    .and_then(|()| {
+        run(Command::new("/sbin/mount")
+            .args(["-t", "hfs", "-o", "nobrowse,nosuid,nodev"])
+            .arg(&device)
+            .arg(target))
+    });
+    if let Err(e) = mounted {
+        let _ = run(Command::new("/usr/bin/hdiutil")
+            .args(["detach", "-quiet", "-force"])
+            .arg(&device));
+        return Err(e);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn attach(_id: &ContainerId, size: u64, target: &Path) -> Result<()> {
+    crate::layers::mount(
+        Path::new("tmpfs"),
+        target,
+        "tmpfs",
+        libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        &format!("size={},mode=0700", size),
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn seal(target: &Path) -> Result<()> {
+    run(Command::new("/sbin/mount").args(["-u", "-r"]).arg(target))
+}
+
+#[cfg(target_os = "linux")]
+fn seal(target: &Path) -> Result<()> {
+    crate::layers::mount(
+        Path::new("tmpfs"),
+        target,
+        "",
+        libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+        "",
+    )
+}
+
+// Write the secrets readable only by the owner, which is the user of the container if it has one.
+fn write_files(target: &Path, volume: &SecretsVolume, owner: Option<u32>) -> Result<()> {
+    for (name, value) in &volume.files {
+        let path = target.join(name);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o400)
+            .open(&path)?
+            .write_all(value.as_bytes())?;
+        chown(&path, owner, None)?;
+    }
+    chown(target, owner, None)?;
+    std::fs::set_permissions(target, Permissions::from_mode(0o500))?;
+    Ok(())
+}
+
+// Mount a new volume with the secrets. The volume of a retried create is replaced.
+pub fn mount(id: &ContainerId, volume: &SecretsVolume, owner: Option<u32>) -> Result<()> {
+    let target = guest_secrets_path(id);
+    if is_mounted(&target) {
+        detach(&target)?;
+    }
+    std::fs::create_dir_all(&target)?;
+    let size = (volume.size() + volume.files.len() as u64 * FILE_OVERHEAD)
+        .max(MIN_SIZE)
+        .next_multiple_of(SECTOR_SIZE);
+    attach(id, size, &target)
+        .map_err(|e| anyhow::anyhow!("Failed to create the secrets volume of {}: {}", id, e))?;
+    if let Err(e) = write_files(&target, volume, owner).and_then(|()| seal(&target)) {
+        let _ = detach(&target);
+        anyhow::bail!("Failed to write the secrets of {}: {}", id, e);
+    }
+    log::info!(
+        "Mounted {} secrets of {} on {:?}",
+        volume.files.len(),
+        id,
+        target
+    );
+    Ok(())
+}
+
+pub fn unmount(id: &ContainerId) -> Result<()> {
+    let target = guest_secrets_path(id);
+    if is_mounted(&target) {
+        detach(&target)?;
+    }
+    match std::fs::remove_dir(&target) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
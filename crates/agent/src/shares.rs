@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Mounts the shared directories that the guest does not automount.
+//! Each of them is a virtiofs device of its own that is mounted by its tag at the mount point
+//! in vm.json, so that the host translates the paths of the containers to the same place.
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+// A mount point is on another device than its parent.
+pub fn is_mounted(path: &Path) -> bool {
+    match (
+        std::fs::metadata(path),
+        path.parent().map(std::fs::metadata),
+    ) {
+        (Ok(metadata), Some(Ok(parent))) => metadata.dev() != parent.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn mount_virtiofs(tag: &str, target: &Path) -> Result<()> {
+    crate::layers::mount(Path::new(tag), target, "virtiofs", 0, "")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_virtiofs(tag: &str, target: &Path) -> Result<()> {
+    let status = std::process::Command::new("/sbin/mount_virtiofs")
+        .arg(tag)
+        .arg(target)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("mount_virtiofs failed with {}", status);
+    }
+    Ok(())
+}
+
+// Mount the shares by their tags. The mounts stay when the agent restarts.
+pub fn mount(shares: &[(String, PathBuf)]) -> Result<()> {
+    for (tag, target) in shares {
+        if is_mounted(target) {
+            continue;
+        }
+        std::fs::create_dir_all(target)?;
+        mount_virtiofs(tag, target)
+            .map_err(|e| anyhow::anyhow!("Failed to mount share {} on {:?}: {}", tag, target, e))?;
+        log::info!("Mounted share {} on {:?}", tag, target);
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Graceful shutdown requested by the host before it stops the VM.
+//! The agent refuses new containers and exec processes, waits for the running processes until
+//! the deadline, saves the process table and exits. The processes left at the deadline go down
+//! with the VM.
+
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use libakari::{container_rpc::ContainerResponse, framing::WriteTo};
+
+use crate::exec::ExecTable;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+pub fn draining() -> bool {
+    DRAINING.load(Ordering::SeqCst)
+}
+
+fn drain(execs: &Mutex<ExecTable>, state_path: &Path, wait: Duration) -> Result<()> {
+    DRAINING.store(true, Ordering::SeqCst);
+    log::info!("Draining the containers for up to {:?}", wait);
+    let deadline = Instant::now() + wait;
+    loop {
+        let running: usize = {
+            let mut execs = execs.lock().unwrap_or_else(|e| e.into_inner());
+            execs.reap();
+            execs.process_counts().values().sum()
+        };
+        if running == 0 {
+            break;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("{} processes are still running at the deadline", running);
+            break;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    let execs = execs.lock().unwrap_or_else(|e| e.into_inner());
+    execs.save(state_path)
+}
+
+// Drain the processes, answer the host and exit. The agent exits even if the state could not be
+// saved, as the host stops the VM next.
+pub fn run(
+    execs: &Mutex<ExecTable>,
+    state_path: &Path,
+    wait: Duration,
+    mut writer: impl std::io::Write,
+) -> ! {
+    let res = match drain(execs, state_path, wait) {
+        Ok(()) => ContainerResponse::Ok,
+        Err(e) => {
+            log::error!("Failed to save the process table: {}", e);
+            ContainerResponse::Error(e.to_string())
+        }
+    };
+    if let Err(e) = res.write_to(&mut writer) {
+        log::error!("Failed to answer the shutdown: {}", e);
+    }
+    log::info!("Shutting down the agent");
+    std::process::exit(0)
+}
@@ -0,0 +1,52 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! APFS snapshots of the guest data volume.
+//! A snapshot is taken before risky operations so that the operator can roll back.
+//! Reverting to a snapshot takes effect when the volume is mounted again, i.e. after the VM restarts.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    os::{
+        fd::AsRawFd,
+        raw::{c_char, c_int},
+    },
+    path::Path,
+};
+
+use anyhow::Result;
+
+extern "C" {
+    fn fs_snapshot_create(dirfd: c_int, name: *const c_char, flags: u32) -> c_int;
+    fn fs_snapshot_revert(dirfd: c_int, name: *const c_char, flags: u32) -> c_int;
+}
+
+fn snapshot_op(
+    volume: &Path,
+    name: &str,
+    op: unsafe extern "C" fn(c_int, *const c_char, u32) -> c_int,
+) -> Result<()> {
+    let dir = File::open(volume)?;
+    let name_c = CString::new(name)?;
+    if unsafe { op(dir.as_raw_fd(), name_c.as_ptr(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+pub fn create(volume: &Path, name: &str) -> Result<()> {
+    snapshot_op(volume, name, fs_snapshot_create)?;
+    log::info!("Created snapshot {} of {:?}", name, volume);
+    Ok(())
+}
+
+pub fn revert(volume: &Path, name: &str) -> Result<()> {
+    snapshot_op(volume, name, fs_snapshot_revert)?;
+    log::info!(
+        "Reverted {:?} to snapshot {}; restart the VM to apply",
+        volume,
+        name
+    );
+    Ok(())
+}
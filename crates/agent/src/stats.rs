@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Resource usage of the guest, reported to the host to decide whether the shared VM has room.
+
+use std::{collections::BTreeMap, ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+use anyhow::Result;
+use libakari::metrics::{GuestStats, ProcessUsage};
+
+use crate::exec::ExecTable;
+
+fn cpus() -> u32 {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    cpus.max(1) as u32
+}
+
+fn load_average() -> Result<[f64; 3]> {
+    let mut load = [0f64; 3];
+    if unsafe { libc::getloadavg(load.as_mut_ptr(), 3) } != 3 {
+        anyhow::bail!("Failed to get the load average");
+    }
+    Ok(load)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sysctl<T: Copy + Default>(name: &str) -> Result<T> {
+    let name_c = CString::new(name)?;
+    let mut value = T::default();
+    let mut len = std::mem::size_of::<T>();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name_c.as_ptr(),
+            &mut value as *mut T as *mut libc::c_void,
+            &mut len,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(value)
+}
+
+// Return the total and the free bytes of the memory.
+#[cfg(not(target_os = "linux"))]
+fn memory() -> Result<(u64, u64)> {
+    let total: u64 = sysctl("hw.memsize")?;
+    let free_pages: u32 = sysctl("vm.page_free_count")?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    Ok((total, free_pages as u64 * page_size))
+}
+
+// Return the total and the free bytes of the memory.
+#[cfg(target_os = "linux")]
+fn memory() -> Result<(u64, u64)> {
+    let mut info: libc::sysinfo = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sysinfo(&mut info) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let unit = info.mem_unit as u64;
+    Ok((info.totalram as u64 * unit, info.freeram as u64 * unit))
+}
+
+// Return the total and the available bytes of the filesystem.
+pub fn disk(path: &Path) -> Result<(u64, u64)> {
+    let path_c = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path_c.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let block_size = stat.f_frsize as u64;
+    Ok((
+        stat.f_blocks as u64 * block_size,
+        stat.f_bavail as u64 * block_size,
+    ))
+}
+
+// Return the numerator and the denominator that convert Mach absolute time to nanoseconds.
+#[cfg(not(target_os = "linux"))]
+#[allow(deprecated)]
+fn timebase() -> (u64, u64) {
+    let mut info = libc::mach_timebase_info { numer: 0, denom: 0 };
+    if unsafe { libc::mach_timebase_info(&mut info) } != 0 || info.denom == 0 {
+        return (1, 1);
+    }
+    (info.numer as u64, info.denom as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_usage(pid: u32) -> Result<ProcessUsage> {
+    let mut info: libc::rusage_info_v2 = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        libc::proc_pid_rusage(
+            pid as libc::c_int,
+            libc::RUSAGE_INFO_V2,
+            &mut info as *mut libc::rusage_info_v2 as *mut libc::rusage_info_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    // The CPU times are in Mach absolute time units.
+    let (numer, denom) = timebase();
+    Ok(ProcessUsage {
+        cpu_time: (info.ri_user_time + info.ri_system_time) * numer / denom,
+        memory: info.ri_resident_size,
+        disk_read: info.ri_diskio_bytesread,
+        disk_written: info.ri_diskio_byteswritten,
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn process_usage(pid: u32) -> Result<ProcessUsage> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    // The command name may contain spaces, so the fields are counted from its closing paren.
+    let fields: Vec<&str> = stat
+        .rsplit_once(')')
+        .map(|(_, rest)| rest.split_whitespace().collect())
+        .unwrap_or_default();
+    let field = |n: usize| {
+        fields
+            .get(n - 3)
+            .and_then(|f| f.parse::<u64>().ok())
+            .unwrap_or(0)
+    };
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as u64;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+    let mut usage = ProcessUsage {
+        // utime and stime
+        cpu_time: (field(14) + field(15)) * 1_000_000_000 / ticks,
+        // rss
+        memory: field(24) * page_size,
+        ..Default::default()
+    };
+    // The I/O counters are readable only by the owner of the process.
+    if let Ok(io) = std::fs::read_to_string(format!("/proc/{}/io", pid)) {
+        for line in io.lines() {
+            match line.split_once(": ") {
+                Some(("read_bytes", n)) => usage.disk_read = n.parse().unwrap_or(0),
+                Some(("write_bytes", n)) => usage.disk_written = n.parse().unwrap_or(0),
+                _ => {}
+            }
+        }
+    }
+    Ok(usage)
+}
+
+// Sum the usage of the running processes of each container.
+fn usage(execs: &ExecTable) -> BTreeMap<String, ProcessUsage> {
+    execs
+        .running_pids()
+        .into_iter()
+        .map(|(id, pids)| {
+            let mut usage = ProcessUsage::default();
+            // A process may exit while it is measured.
+            for pid in pids {
+                if let Ok(process) = process_usage(pid) {
+                    usage.add(&process);
+                }
+            }
+            (id, usage)
+        })
+        .collect()
+}
+
+pub fn collect(execs: &ExecTable, data_volume: &Path) -> Result<GuestStats> {
+    let (memory_total, memory_free) = memory()?;
+    let (disk_total, disk_free) = disk(data_volume)?;
+    Ok(GuestStats {
+        cpus: cpus(),
+        load_average: load_average()?,
+        memory_total,
+        memory_free,
+        disk_total,
+        disk_free,
+        processes: execs.process_counts(),
+        usage: usage(execs),
+        storage: crate::storage::usage(),
+        users: execs.users(),
+    })
+}
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Writable storage of the containers with quotas.
+//! The storage of each container is a sparse disk image of the quota size mounted in its own
+//! directory, so the image only takes the space that is written. macOS guests use an APFS
+//! sparse image and Linux guests an ext4 image on a loop device.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    storage::{guest_storage_dir, guest_storage_path, guest_storage_root, StorageUsage},
+};
+
+use crate::shares::is_mounted;
+
+pub fn run(cmd: &mut Command) -> Result<()> {
+    let output = cmd.output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{:?} failed with {}: {}",
+            cmd.get_program(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach(id: &ContainerId, dir: &Path, quota: u64, target: &Path) -> Result<()> {
+    let image = dir.join("disk.sparseimage");
+    if !image.exists() {
+        run(Command::new("/usr/bin/hdiutil")
+            .args(["create", "-quiet", "-type", "SPARSE", "-fs", "APFS"])
+            .arg("-size")
+            .arg(format!("{}k", quota / 1024))
+            .arg("-volname")
+            .arg(id.as_str())
+            .arg(&image))?;
+    }
+    run(Command::new("/usr/bin/hdiutil")
+        .args(["attach", "-quiet", "-nobrowse", "-owners", "on"])
+        .arg("-mountpoint")
+        .arg(target)
+        .arg(&image))
+}
+
+#[cfg(target_os = "linux")]
+fn attach(_id: &ContainerId, dir: &Path, quota: u64, target: &Path) -> Result<()> {
+    let image = dir.join("disk.img");
+    if !image.exists() {
+        // The file is sparse until the blocks are written.
+        std::fs::File::create(&image)?.set_len(quota)?;
+        run(Command::new("mkfs.ext4").args(["-q", "-F"]).arg(&image))?;
+    }
+    run(Command::new("mount")
+        .args(["-o", "loop"])
+        .arg(&image)
+        .arg(target))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn detach(target: &Path) -> Result<()> {
+    // The processes of the container may still hold the files.
+    run(Command::new("/usr/bin/hdiutil")
+        .args(["detach", "-quiet", "-force"])
+        .arg(target))
+}
+
+#[cfg(target_os = "linux")]
+pub fn detach(target: &Path) -> Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let target = CString::new(target.as_os_str().as_bytes())?;
+    // The loop device is released once the processes of the container close the files.
+    if unsafe { libc::umount2(target.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+// Mount the storage of the container and return its mount point. The image is kept when the
+// agent restarts, so the storage is mounted again with its contents.
+pub fn create(id: &ContainerId, quota: u64) -> Result<PathBuf> {
+    let target = guest_storage_path(id);
+    if is_mounted(&target) {
+        return Ok(target);
+    }
+    std::fs::create_dir_all(&target)?;
+    attach(id, &guest_storage_dir(id), quota, &target)
+        .map_err(|e| anyhow::anyhow!("Failed to create the storage of {}: {}", id, e))?;
+    log::info!(
+        "Mounted the storage of {} ({} bytes) on {:?}",
+        id,
+        quota,
+        target
+    );
+    Ok(target)
+}
+
+pub fn remove(id: &ContainerId) -> Result<()> {
+    let target = guest_storage_path(id);
+    if is_mounted(&target) {
+        detach(&target)?;
+    }
+    match std::fs::remove_dir_all(guest_storage_dir(id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// Return the usage of the mounted storages by the container IDs.
+pub fn usage() -> BTreeMap<String, StorageUsage> {
+    let Ok(entries) = std::fs::read_dir(guest_storage_root()) else {
+        return BTreeMap::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let id = ContainerId::new(entry.file_name().into_string().ok()?).ok()?;
+            let target = guest_storage_path(&id);
+            if !is_mounted(&target) {
+                return None;
+            }
+            let (total, free) = crate::stats::disk(&target).ok()?;
+            Some((
+                id.to_string(),
+                StorageUsage {
+                    total,
+                    used: total.saturating_sub(free),
+                },
+            ))
+        })
+        .collect()
+}
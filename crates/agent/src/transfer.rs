@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Unpacks the bundles streamed by the host into guest-local storage.
+
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    container_rpc::ContainerResponse,
+    framing::WriteTo,
+    transfer::{guest_bundle_path, ChunkReader},
+};
+
+fn unpack(stream: impl Read, id: &ContainerId) -> Result<()> {
+    // The create may be retried with the same ID.
+    remove(id)?;
+    let path = guest_bundle_path(id);
+    std::fs::create_dir_all(&path)?;
+    let mut archive = tar::Archive::new(ChunkReader::new(stream));
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_ownerships(true);
+    archive.unpack(&path)?;
+    archive.into_inner().finish()?;
+    log::info!("Unpacked the bundle of {} into {:?}", id, path);
+    Ok(())
+}
+
+// Unpack the bundle on the stream and answer the host.
+pub fn receive<S: Read + Write>(mut stream: S, id: &ContainerId) -> Result<()> {
+    let res = match unpack(&mut stream, id) {
+        Ok(()) => ContainerResponse::Ok,
+        Err(e) => {
+            log::error!("Failed to unpack the bundle of {}: {}", id, e);
+            let _ = remove(id);
+            ContainerResponse::Error(e.to_string())
+        }
+    };
+    res.write_to(&mut stream)?;
+    Ok(())
+}
+
+pub fn remove(id: &ContainerId) -> Result<()> {
+    match std::fs::remove_dir_all(guest_bundle_path(id)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,336 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Resolves the user of the container processes.
+//! A username in process.user is looked up in the user database of the guest, which is served by
+//! Directory Services on macOS, and the process runs with the IDs and the groups of the entry.
+//! The containers with a dedicated user run as the user that the agent creates for them instead.
+
+use std::{
+    ffi::{c_char, c_int, CStr, CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    process::Command,
+};
+
+use anyhow::Result;
+use libakari::{container_id::ContainerId, guest_user::GuestUser};
+use oci_spec::runtime::User;
+
+// Range of the uids of the dedicated users, above the ones that macOS gives to the accounts
+const FIRST_DEDICATED_UID: u32 = 30000;
+const DEDICATED_UIDS: u32 = 10000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("User {0:?} does not exist in the guest")]
+    UnknownUser(String),
+    #[error("Invalid username {0:?}")]
+    InvalidName(String),
+    #[error("Failed to look up user {0:?}: {1}")]
+    Lookup(String, std::io::Error),
+}
+
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    // Supplementary groups
+    pub groups: Vec<u32>,
+    // Home directory of the user entry
+    pub home: Option<PathBuf>,
+}
+
+impl Credentials {
+    // Switch the IDs of the current process. Called in the child before exec, so it must not
+    // allocate.
+    pub fn apply(&self) -> std::io::Result<()> {
+        let groups = self.groups.as_ptr() as *const libc::gid_t;
+        if unsafe { libc::setgroups(self.groups.len() as _, groups) } != 0
+            || unsafe { libc::setgid(self.gid) } != 0
+            || unsafe { libc::setuid(self.uid) } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+struct Passwd {
+    uid: u32,
+    gid: u32,
+    home: PathBuf,
+}
+
+fn lookup(name: &CStr) -> Result<Option<Passwd>, std::io::Error> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf: Vec<c_char> = vec![0; 4096];
+    let mut result = std::ptr::null_mut();
+    loop {
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut pwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match ret {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(std::io::Error::from_raw_os_error(ret)),
+        }
+    }
+    if result.is_null() {
+        return Ok(None);
+    }
+    let home = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Ok(Some(Passwd {
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+    }))
+}
+
+fn uid_exists(uid: u32) -> Result<bool, std::io::Error> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf: Vec<c_char> = vec![0; 4096];
+    let mut result = std::ptr::null_mut();
+    loop {
+        let ret =
+            unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match ret {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(std::io::Error::from_raw_os_error(ret)),
+        }
+    }
+    Ok(!result.is_null())
+}
+
+fn gid_exists(gid: u32) -> Result<bool, std::io::Error> {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf: Vec<c_char> = vec![0; 4096];
+    let mut result = std::ptr::null_mut();
+    loop {
+        let ret =
+            unsafe { libc::getgrgid_r(gid, &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+        match ret {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            _ => return Err(std::io::Error::from_raw_os_error(ret)),
+        }
+    }
+    Ok(!result.is_null())
+}
+
+// Return the groups that the user is a member of, up to the number that the kernel allows.
+fn group_list(name: &CStr, gid: u32) -> Vec<u32> {
+    let max = unsafe { libc::sysconf(libc::_SC_NGROUPS_MAX) }.max(1) as usize;
+    let mut groups: Vec<c_int> = vec![0; max];
+    let mut len = max as c_int;
+    // The list is truncated if the user is a member of more groups.
+    unsafe { libc::getgrouplist(name.as_ptr(), gid as c_int, groups.as_mut_ptr(), &mut len) };
+    groups.truncate((len.max(0) as usize).min(max));
+    groups.into_iter().map(|group| group as u32).collect()
+}
+
+// Return the credentials to run the process with, or None to keep the ones of the agent.
+pub fn resolve(user: &User) -> Result<Option<Credentials>, Error> {
+    let additional_gids = user.additional_gids().clone().unwrap_or_default();
+    let Some(name) = user.username().as_ref().filter(|name| !name.is_empty()) else {
+        if user.uid() == 0 && user.gid() == 0 && additional_gids.is_empty() {
+            return Ok(None);
+        }
+        return Ok(Some(Credentials {
+            uid: user.uid(),
+            gid: user.gid(),
+            groups: additional_gids,
+            home: None,
+        }));
+    };
+
+    let c_name = CString::new(name.as_str()).map_err(|_| Error::InvalidName(name.clone()))?;
+    let passwd = lookup(&c_name)
+        .map_err(|e| Error::Lookup(name.clone(), e))?
+        .ok_or_else(|| Error::UnknownUser(name.clone()))?;
+    let mut groups = group_list(&c_name, passwd.gid);
+    for gid in additional_gids {
+        if !groups.contains(&gid) {
+            groups.push(gid);
+        }
+    }
+    log::info!(
+        "Resolved user {} to uid {} gid {} groups {:?}",
+        name,
+        passwd.uid,
+        passwd.gid,
+        groups
+    );
+    Ok(Some(Credentials {
+        uid: passwd.uid,
+        gid: passwd.gid,
+        groups,
+        home: Some(passwd.home),
+    }))
+}
+
+impl From<&GuestUser> for Credentials {
+    fn from(user: &GuestUser) -> Self {
+        Self {
+            uid: user.uid,
+            gid: user.uid,
+            groups: vec![user.uid],
+            home: None,
+        }
+    }
+}
+
+fn dscl(args: &[&str]) -> Result<String> {
+    let output = Command::new("/usr/bin/dscl").arg(".").args(args).output()?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "dscl {:?} failed with {}: {}",
+            args,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// The full name records the container so that the user is found again after the agent restarts.
+fn real_name(id: &ContainerId) -> String {
+    format!("akari container {}", id)
+}
+
+// Start from the uid that the ID hashes to so that the same container gets the same uid.
+fn preferred_uid(id: &ContainerId) -> u32 {
+    let hash = id
+        .bytes()
+        .fold(0u32, |hash, b| hash.wrapping_mul(31).wrapping_add(b as u32));
+    hash % DEDICATED_UIDS
+}
+
+// Return the name of the first record in the output of `dscl -search`. Each match starts with
+// the name of the record followed by the attribute, e.g. `_akari30001\t\tRealName = (...)`.
+fn first_search_result(output: &str) -> Option<&str> {
+    output.split_whitespace().next()
+}
+
+// Find the dedicated user of the container, e.g. created before the agent restarted.
+pub fn find_dedicated(id: &ContainerId) -> Result<Option<GuestUser>> {
+    let output = dscl(&["-search", "/Users", "RealName", &real_name(id)])?;
+    let Some(name) = first_search_result(&output) else {
+        return Ok(None);
+    };
+    let c_name = CString::new(name).map_err(|_| Error::InvalidName(name.to_string()))?;
+    let passwd = lookup(&c_name)
+        .map_err(|e| Error::Lookup(name.to_string(), e))?
+        .ok_or_else(|| Error::UnknownUser(name.to_string()))?;
+    Ok(Some(GuestUser {
+        name: name.to_string(),
+        uid: passwd.uid,
+    }))
+}
+
+// Create the dedicated user of the container, or return the one created before.
+pub fn create_dedicated(id: &ContainerId) -> Result<GuestUser> {
+    if let Some(user) = find_dedicated(id)? {
+        return Ok(user);
+    }
+    let real_name = real_name(id);
+    let start = preferred_uid(id);
+    for offset in 0..DEDICATED_UIDS {
+        let uid = FIRST_DEDICATED_UID + (start + offset) % DEDICATED_UIDS;
+        // The uid is also the gid of the group of the user.
+        if uid_exists(uid)? || gid_exists(uid)? {
+            continue;
+        }
+        let name = format!("_akari{}", uid);
+        create_records(&name, uid, &real_name)?;
+        log::info!("Created user {} (uid {}) for container {}", name, uid, id);
+        return Ok(GuestUser { name, uid });
+    }
+    anyhow::bail!("No uid is left for the dedicated user of {}", id)
+}
+
+// Create the group and the user records, and delete the created ones if a step fails so that no
+// half-created user is left.
+fn create_records(name: &str, uid: u32, real_name: &str) -> Result<()> {
+    let group = format!("/Groups/{}", name);
+    let user = format!("/Users/{}", name);
+    let uid = uid.to_string();
+    let steps: [&[&str]; 8] = [
+        &["-create", &group],
+        &["-create", &group, "PrimaryGroupID", &uid],
+        &["-create", &user],
+        &["-create", &user, "UniqueID", &uid],
+        &["-create", &user, "PrimaryGroupID", &uid],
+        &["-create", &user, "RealName", real_name],
+        &["-create", &user, "UserShell", "/usr/bin/false"],
+        &["-create", &user, "NFSHomeDirectory", "/var/empty"],
+    ];
+    let mut created = Vec::new();
+    for args in steps {
+        if let Err(e) = dscl(args) {
+            for record in created.into_iter().rev() {
+                if let Err(e) = dscl(&["-delete", record]) {
+                    log::warn!("Failed to delete {}: {}", record, e);
+                }
+            }
+            return Err(e);
+        }
+        // The steps with only the path create the records.
+        if let [_, record] = args {
+            created.push(*record);
+        }
+    }
+    Ok(())
+}
+
+// Kill the processes that are left with the uid and remove the user, so that the uid is never
+// reused while they run.
+pub fn delete_dedicated(id: &ContainerId, user: &GuestUser) -> Result<()> {
+    // pkill exits with 1 if no process matched.
+    let status = Command::new("/usr/bin/pkill")
+        .args(["-KILL", "-U"])
+        .arg(user.uid.to_string())
+        .status()?;
+    if !matches!(status.code(), Some(0 | 1)) {
+        anyhow::bail!("Failed to kill the processes of {}: {}", user.name, status);
+    }
+    dscl(&["-delete", &format!("/Users/{}", user.name)])?;
+    dscl(&["-delete", &format!("/Groups/{}", user.name)])?;
+    log::info!("Deleted user {} of container {}", user.name, id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preferred_uid_of_id() {
+        let id = ContainerId::new("a").unwrap();
+        assert_eq!(preferred_uid(&id), 97);
+        let id = ContainerId::new("ab").unwrap();
+        assert_eq!(preferred_uid(&id), 97 * 31 + 98);
+        // The same container gets the same uid, and a long ID stays in the range.
+        let id = "c".repeat(64);
+        let uid = preferred_uid(&ContainerId::new(id.clone()).unwrap());
+        assert_eq!(preferred_uid(&ContainerId::new(id).unwrap()), uid);
+        assert!(uid < DEDICATED_UIDS);
+    }
+
+    #[test]
+    fn search_result() {
+        let output = "_akari30097\t\tRealName = (\n    \"akari container a\"\n)\n";
+        assert_eq!(first_search_result(output), Some("_akari30097"));
+        let output = "_akari30097\t\tRealName = (\n    \"akari container a\"\n)\n\
+                      _akari30098\t\tRealName = (\n    \"akari container a\"\n)\n";
+        assert_eq!(first_search_result(output), Some("_akari30097"));
+        assert_eq!(first_search_result(""), None);
+        assert_eq!(first_search_result("\n"), None);
+    }
+}
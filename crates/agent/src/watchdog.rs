@@ -0,0 +1,32 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Kills the containers that run longer than their maximum runtime.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::exec::ExecTable;
+
+// How often the deadlines are checked.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn start(execs: Arc<Mutex<ExecTable>>, state_path: PathBuf) -> Result<()> {
+    std::thread::Builder::new()
+        .name("watchdog".to_string())
+        .spawn(move || loop {
+            std::thread::sleep(INTERVAL);
+            let mut execs = execs.lock().unwrap_or_else(|e| e.into_inner());
+            if execs.enforce_deadlines() {
+                if let Err(e) = execs.save(&state_path) {
+                    log::error!("Failed to save the process table: {}", e);
+                }
+            }
+        })?;
+    Ok(())
+}
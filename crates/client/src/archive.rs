@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Archives of the VM to distribute a provisioned guest.
+//! An archive is a tar stream compressed with zstd. It holds vm.json and the disk images under
+//! `storage/`, which vm.json refers to by the relative paths. The paths are resolved against the
+//! root directory on import.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use libakari::{
+    event::unix_timestamp,
+    path::vm_config_path,
+    progress::Progress,
+    vm_config::{load_vm_config, MacosVmConfig},
+};
+
+use crate::{
+    commands::error::Error,
+    progress::{ProgressBar, ProgressReader},
+};
+
+const VM_CONFIG_ENTRY: &str = "vm.json";
+const STORAGE_DIR: &str = "storage";
+// The disk images are large, so favor the speed over the ratio.
+const COMPRESSION_LEVEL: i32 = 3;
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// Only `storage/<name>` is extracted so that the archive cannot write outside the root directory.
+fn is_storage_entry(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!(
+        (components.next(), components.next(), components.next()),
+        (Some(Component::Normal(dir)), Some(Component::Normal(_)), None) if dir == STORAGE_DIR
+    )
+}
+
+// Fill the buffer unless the reader reaches EOF.
+fn read_block(reader: &mut impl Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Skip the zero blocks so that the disk image stays sparse.
+fn copy_sparse(reader: &mut impl Read, path: &Path) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    let mut buf = vec![0; BLOCK_SIZE];
+    let mut len = 0;
+    loop {
+        let n = read_block(reader, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        if buf[..n].iter().all(|&b| b == 0) {
+            file.seek(SeekFrom::Current(n as i64))?;
+        } else {
+            file.write_all(&buf[..n])?;
+        }
+        len += n as u64;
+    }
+    file.set_len(len)
+}
+
+pub fn export(root_path: &Path, path: &Path) -> Result<(), Error> {
+    let mut vm_config = load_vm_config(&vm_config_path(root_path))?;
+
+    // Number the files as the disk images of different directories may share the name.
+    let mut files = Vec::new();
+    let mut progress = Progress::new("export", "vm.json");
+    for (i, storage) in vm_config.storage.iter_mut().enumerate() {
+        let name = storage
+            .file
+            .file_name()
+            .ok_or_else(|| Error::InvalidArchive(format!("Invalid storage {:?}", storage.file)))?;
+        let entry = Path::new(STORAGE_DIR).join(format!("{}-{}", i, name.to_string_lossy()));
+        let metadata = std::fs::metadata(&storage.file)?;
+        *progress.total.get_or_insert(0) += metadata.len();
+        files.push((storage.file.clone(), entry.clone(), metadata));
+        storage.file = entry;
+    }
+
+    let encoder = zstd::Encoder::new(File::create(path)?, COMPRESSION_LEVEL)?;
+    let mut builder = tar::Builder::new(encoder);
+    let json = serde_json::to_vec_pretty(&vm_config)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(unix_timestamp());
+    builder.append_data(&mut header, VM_CONFIG_ENTRY, json.as_slice())?;
+    let mut bar = ProgressBar::new();
+    for (file, entry, metadata) in files {
+        progress.phase = file.display().to_string();
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        let reader = ProgressReader::new(File::open(&file)?, &mut progress, &mut bar);
+        builder.append_data(&mut header, &entry, reader)?;
+    }
+    builder.into_inner()?.finish()?;
+    bar.finish(&progress);
+    Ok(())
+}
+
+pub fn import(root_path: &Path, path: &Path, force: bool) -> Result<(), Error> {
+    let config_path = vm_config_path(root_path);
+    if config_path.exists() && !force {
+        return Err(Error::VmConfigExists(config_path));
+    }
+
+    // The compressed size is known beforehand, so track the archive read.
+    let file = File::open(path)?;
+    let mut progress = Progress::new("import", "extracting");
+    progress.total = Some(file.metadata()?.len());
+    let mut bar = ProgressBar::new();
+    let reader = ProgressReader::new(file, &mut progress, &mut bar);
+    let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+    let mut vm_config: Option<MacosVmConfig> = None;
+    let mut files: Vec<PathBuf> = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        if entry_path == Path::new(VM_CONFIG_ENTRY) {
+            let mut json = String::new();
+            entry.read_to_string(&mut json)?;
+            vm_config = Some(serde_json::from_str(&json)?);
+        } else if is_storage_entry(&entry_path) {
+            std::fs::create_dir_all(root_path.join(STORAGE_DIR))?;
+            copy_sparse(&mut entry, &root_path.join(&entry_path))?;
+            files.push(entry_path);
+        } else {
+            return Err(Error::InvalidArchive(format!(
+                "Unexpected entry {:?}",
+                entry_path
+            )));
+        }
+    }
+
+    drop(archive);
+    bar.finish(&progress);
+
+    let mut vm_config = vm_config
+        .ok_or_else(|| Error::InvalidArchive(format!("{} is missing", VM_CONFIG_ENTRY)))?;
+    for storage in &mut vm_config.storage {
+        if !files.contains(&storage.file) {
+            return Err(Error::InvalidArchive(format!(
+                "Storage {:?} is missing",
+                storage.file
+            )));
+        }
+        storage.file = root_path.join(&storage.file);
+    }
+    std::fs::write(&config_path, serde_json::to_string_pretty(&vm_config)?)?;
+    Ok(())
+}
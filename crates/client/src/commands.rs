@@ -1,11 +1,22 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+pub mod bench;
 pub mod connect;
 pub mod create;
+pub mod debug;
 pub mod delete;
 pub mod error;
+pub mod events;
 pub mod kill;
+pub mod prune;
+pub mod ps;
+pub mod reload;
+pub mod replay;
+pub mod run;
 pub mod spec;
 pub mod start;
 pub mod state;
+pub mod top;
+pub mod version;
+pub mod vm;
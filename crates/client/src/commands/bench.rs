@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{container_id::ContainerId, task_options::TaskOptions};
+use libakari_client::AkariClient;
+use serde::Serialize;
+
+use super::{create::create_options, error::Error};
+
+/// Measure the container lifecycle latency and the stdio throughput
+#[derive(Parser, Debug)]
+pub struct Bench {
+    /// Path to the bundle of a container that exits by itself, e.g. `cat > /dev/null`
+    #[clap(short, long, default_value = ".")]
+    bundle: PathBuf,
+    /// Number of the containers to run
+    #[clap(short = 'n', long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    iterations: u64,
+    /// Also stream the bytes into the stdin of each container to measure the throughput
+    #[clap(long)]
+    stdio_bytes: Option<u64>,
+}
+
+// Latency of a phase in milliseconds.
+#[derive(Serialize)]
+struct Latency {
+    min: f64,
+    mean: f64,
+    p50: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Latency {
+    fn new(samples: &mut [Duration]) -> Self {
+        samples.sort();
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let percentile = |p: usize| ms(samples[(samples.len() - 1) * p / 100]);
+        let total: Duration = samples.iter().sum();
+        Self {
+            min: ms(samples[0]),
+            mean: ms(total) / samples.len() as f64,
+            p50: percentile(50),
+            p99: percentile(99),
+            max: ms(samples[samples.len() - 1]),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Throughput {
+    bytes: u64,
+    // bytes per second from the start to the exit of the container
+    bytes_per_second: f64,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Report {
+    iterations: u64,
+    latency: BTreeMap<&'static str, Latency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stdio: Option<Throughput>,
+}
+
+async fn timed<T>(
+    samples: &mut Vec<Duration>,
+    f: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    let started = Instant::now();
+    let res = f.await?;
+    samples.push(started.elapsed());
+    Ok(res)
+}
+
+fn write_stdin(bytes: u64) -> Result<PathBuf, Error> {
+    let path = std::env::temp_dir().join(format!("akari-bench-{}.stdin", std::process::id()));
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    let chunk = [0xa5; 64 * 1024];
+    let mut left = bytes;
+    while left > 0 {
+        let n = std::cmp::min(left, chunk.len() as u64) as usize;
+        file.write_all(&chunk[..n])?;
+        left -= n as u64;
+    }
+    file.flush()?;
+    Ok(path)
+}
+
+pub async fn bench(args: Bench, client: &AkariClient) -> Result<(), Error> {
+    let stdin = match args.stdio_bytes {
+        Some(bytes) => Some(write_stdin(bytes)?),
+        None => None,
+    };
+    let options = create_options(&args.bundle, None, stdin.as_deref(), TaskOptions::default());
+
+    let mut phases: [(&str, Vec<Duration>); 5] = [
+        ("create", Vec::new()),
+        ("start", Vec::new()),
+        ("exit", Vec::new()),
+        ("delete", Vec::new()),
+        ("total", Vec::new()),
+    ];
+    for i in 0..args.iterations {
+        let id = ContainerId::new(format!("akari-bench-{}-{}", std::process::id(), i))?;
+        let started = Instant::now();
+        let [create, start, exit, delete, total] = &mut phases;
+
+        timed(&mut create.1, async {
+            Ok(client.create(&id, &options).await?)
+        })
+        .await?;
+        timed(&mut start.1, async { Ok(client.start(&id).await?) }).await?;
+        timed(&mut exit.1, async { Ok(client.wait(&id, None).await?) }).await?;
+        timed(&mut delete.1, async { Ok(client.delete(&id).await?) }).await?;
+
+        total.1.push(started.elapsed());
+    }
+
+    // The container consumes the stdin between the start and the exit.
+    let stdio = args.stdio_bytes.map(|bytes| {
+        let exit: Duration = phases[2].1.iter().sum();
+        let transferred = bytes * args.iterations;
+        Throughput {
+            bytes: transferred,
+            bytes_per_second: transferred as f64 / exit.as_secs_f64(),
+        }
+    });
+    if let Some(stdin) = stdin {
+        let _ = std::fs::remove_file(stdin);
+    }
+
+    let report = Report {
+        iterations: args.iterations,
+        latency: phases
+            .iter_mut()
+            .map(|(name, samples)| (*name, Latency::new(samples)))
+            .collect(),
+        stdio,
+    };
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -3,26 +3,30 @@
 
 use anyhow::Result;
 use clap::Parser;
-use containerd_shim::{
-    protos::shim::{shim::ConnectRequest, shim_ttrpc_async::TaskClient},
-    Context,
-};
+use containerd_shim::{protos::shim::shim::ConnectRequest, Context};
+
+use libakari::{container_id::ContainerId, vsock::VsockPort};
+use libakari_client::AkariClient;
 
 use super::error::Error;
 
 /// Connect to a running container
 #[derive(Parser, Debug)]
 pub struct Connect {
-    container_id: String,
-    port: u32,
+    container_id: ContainerId,
+    port: VsockPort,
 }
 
-pub async fn connect(args: Connect, client: &TaskClient) -> Result<(), Error> {
+pub async fn connect(args: Connect, client: &AkariClient) -> Result<(), Error> {
     let ctx = Context::default();
     let req = ConnectRequest {
-        id: args.container_id,
+        id: args.container_id.into(),
         ..Default::default()
     };
-    let _ = client.connect(ctx, &req).await.map_err(Error::RpcClient)?;
+    let _ = client
+        .task()
+        .connect(ctx, &req)
+        .await
+        .map_err(Error::RpcClient)?;
     Ok(())
 }
@@ -1,54 +1,45 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+use std::path::Path;
+
 use anyhow::Result;
-use containerd_shim::{
-    protos::shim::{shim::CreateTaskRequest, shim_ttrpc_async::TaskClient},
-    Context,
-};
+use libakari::task_options::TaskOptions;
+use libakari_client::{AkariClient, CreateOptions, Stdio};
 use liboci_cli::Create;
 
 use super::error::Error;
 
-pub async fn create(args: Create, client: &TaskClient) -> Result<(), Error> {
-    let spec_path = args.bundle.join("config.json");
-    if !spec_path.exists() {
-        return Err(Error::ContainerConfigDoesNotExist);
-    }
-    let spec: oci_spec::runtime::Spec = serde_json::from_str(&std::fs::read_to_string(spec_path)?)?;
-
-    // TODO: Needs to convert to the guest path
-    let _rootfs_path = if let Some(root) = spec.root() {
-        if root.path().is_relative() {
-            args.bundle.join(root.path()).canonicalize()?
-        } else {
-            root.path().canonicalize()?
-        }
-    } else {
-        return Err(Error::RootfsPathIsNotSpecified);
-    };
+pub async fn create(args: Create, client: &AkariClient) -> Result<(), Error> {
+    let options = create_options(
+        &args.bundle,
+        args.console_socket.as_deref(),
+        None,
+        TaskOptions::default(),
+    );
+    client.create(&args.container_id, &options).await?;
+    Ok(())
+}
 
-    // TODO: Needs to convert to the guest path
-    let bundle = args.bundle.to_str().unwrap();
-    let (terminal, stdin, stdout) = match args.console_socket {
-        Some(ref console_socket) => (
-            true,
-            console_socket.to_str().unwrap(),
-            console_socket.to_str().unwrap(),
-        ),
-        None => (false, "", ""),
+// The console socket takes the stdin and the stdout of the terminal. The server streams the
+// stdin file into the container and closes the stdin at EOF.
+pub fn create_options(
+    bundle: &Path,
+    console_socket: Option<&Path>,
+    stdin_file: Option<&Path>,
+    task_options: TaskOptions,
+) -> CreateOptions {
+    let console = console_socket.map(|path| Stdio::Uri(path.to_string_lossy().into_owned()));
+    let stdin = match stdin_file {
+        Some(path) => Stdio::File(path.to_path_buf()),
+        None => console.clone().unwrap_or_default(),
     };
-
-    let ctx = Context::default();
-    let req = CreateTaskRequest {
-        id: args.container_id,
-        bundle: bundle.to_string(),
-        terminal,
-        stdin: stdin.to_string(),
-        stdout: stdout.to_string(),
+    CreateOptions {
+        bundle: bundle.to_path_buf(),
+        terminal: console.is_some(),
+        stdin,
+        stdout: console.unwrap_or_default(),
+        task_options,
         ..Default::default()
-    };
-
-    let _ = client.create(ctx, &req).await.map_err(Error::RpcClient)?;
-    Ok(())
+    }
 }
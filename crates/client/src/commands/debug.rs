@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{io::Write, path::Path};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use libakari::{api, path::api_sock_path, stdio::StdioStream};
+
+use super::error::Error;
+
+/// Diagnose the guest
+#[derive(Parser, Debug)]
+pub struct Debug {
+    #[clap(subcommand)]
+    cmd: DebugCmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum DebugCmd {
+    /// Run a command in the guest OS outside the containers (requires debugExec in server.json)
+    ExecGuest {
+        #[clap(trailing_var_arg = true, required = true)]
+        args: Vec<String>,
+    },
+}
+
+pub fn debug(args: Debug, root_path: &Path) -> Result<(), Error> {
+    match args.cmd {
+        DebugCmd::ExecGuest { args } => {
+            let code = api::debug_exec(&api_sock_path(root_path), args, |stream, data| {
+                let res = match stream {
+                    StdioStream::Stderr => std::io::stderr().write_all(data),
+                    _ => std::io::stdout().write_all(data),
+                };
+                if let Err(e) = res {
+                    eprintln!("Failed to write the output: {}", e);
+                }
+            })?;
+            std::io::stdout().flush()?;
+            // Exit with the code of the command like a local shell.
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+    }
+    Ok(())
+}
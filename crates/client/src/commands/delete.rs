@@ -2,20 +2,51 @@
 // Copyright (C) 2024 Akira Moroo
 
 use anyhow::Result;
-use containerd_shim::{
-    protos::shim::{shim::DeleteRequest, shim_ttrpc_async::TaskClient},
-    Context,
-};
-use liboci_cli::Delete;
+use libakari::api::{ApiRequest, ApiResponse, BatchResult};
+use libakari_client::AkariClient;
 
 use super::error::Error;
 
-pub async fn delete(args: Delete, client: &TaskClient) -> Result<(), Error> {
-    let ctx = Context::default();
-    let req = DeleteRequest {
-        id: args.container_id,
-        ..Default::default()
+/// Release any resources held by the container
+#[derive(clap::Parser, Debug)]
+pub struct Delete {
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    container_id: Option<String>,
+    /// Forces deletion of the container if it is still running (using SIGKILL)
+    #[clap(short, long)]
+    force: bool,
+    /// Delete all the containers, e.g. after an aborted CI run
+    #[clap(long)]
+    all: bool,
+}
+
+// Print the containers of the batch and fail if any of them failed.
+pub(super) fn report(res: ApiResponse) -> Result<(), Error> {
+    let ApiResponse::BatchResults(results) = res else {
+        return Ok(());
     };
-    let _ = client.delete(ctx, &req).await.map_err(Error::RpcClient)?;
+    let mut failed = 0;
+    for BatchResult { id, error } in &results {
+        match error {
+            Some(e) => {
+                eprintln!("{}: {}", id, e);
+                failed += 1;
+            }
+            None => println!("{}", id),
+        }
+    }
+    if failed > 0 {
+        return Err(Error::BatchFailed(failed, results.len()));
+    }
+    Ok(())
+}
+
+pub async fn delete(args: Delete, client: &AkariClient) -> Result<(), Error> {
+    if args.all {
+        let req = ApiRequest::DeleteAll { force: args.force };
+        return report(client.api(&req)?);
+    }
+    // Required unless --all
+    client.delete(&args.container_id.unwrap()).await?;
     Ok(())
 }
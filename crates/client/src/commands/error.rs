@@ -3,18 +3,40 @@
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("Container configuration does not exist")]
-    ContainerConfigDoesNotExist,
-    #[error("Root path is not specified")]
-    RootfsPathIsNotSpecified,
     #[error(transparent)]
     VmConfig(#[from] libakari::vm_config::Error),
+    #[error("Invalid VM configuration: {0}")]
+    InvalidVmConfig(anyhow::Error),
+    #[error("VM configuration {0:?} already exists")]
+    VmConfigExists(std::path::PathBuf),
+    #[error("The server is running; stop it before changing the VM disks")]
+    VmRunning,
+    #[error("Upgrade failed: {0}")]
+    Upgrade(anyhow::Error),
+    #[error("Failed to clone the golden VM: {0}")]
+    Clone(anyhow::Error),
+    #[error("Invalid VM archive: {0}")]
+    InvalidArchive(String),
+    #[error(transparent)]
+    Recording(#[from] libakari::asciicast::Error),
+    #[error(transparent)]
+    ContainerId(#[from] libakari::container_id::Error),
+    #[error("{0} of {1} containers failed")]
+    BatchFailed(usize, usize),
+    #[error(transparent)]
+    StartOverrides(#[from] libakari::start::Error),
+    #[error(transparent)]
+    Signal(#[from] libakari::signal::Error),
     #[error(transparent)]
     Io(#[from] std::io::Error),
     #[error(transparent)]
     Api(#[from] libakari::vm_rpc::Error),
     #[error(transparent)]
+    AdminApi(#[from] libakari::api::Error),
+    #[error(transparent)]
     Deserialize(#[from] serde_json::Error),
     #[error(transparent)]
     RpcClient(#[from] ttrpc::Error),
+    #[error(transparent)]
+    Client(#[from] libakari_client::Error),
 }
@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{api::subscribe_events, path::api_sock_path};
+
+use super::error::Error;
+
+/// Stream the server events as JSON lines
+#[derive(Parser, Debug)]
+pub struct Events {}
+
+pub fn events(_args: Events, root_path: &Path) -> Result<(), Error> {
+    subscribe_events(
+        &api_sock_path(root_path),
+        |event| match serde_json::to_string(&event) {
+            Ok(json) => println!("{}", json),
+            Err(e) => eprintln!("Failed to serialize the event: {}", e),
+        },
+    )?;
+    Ok(())
+}
@@ -1,18 +1,50 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+use std::path::Path;
+
 use anyhow::Result;
-use containerd_shim::{api::KillRequest, protos::shim_async::TaskClient, Context};
-use liboci_cli::Kill;
+use libakari::{
+    api::ApiRequest, path::vm_config_path, signal::parse_signal, vm_config::load_vm_config,
+};
+use libakari_client::AkariClient;
+
+use super::{delete::report, error::Error};
 
-use super::error::Error;
+/// Send the specified signal to the container
+#[derive(clap::Parser, Debug)]
+pub struct Kill {
+    #[clap(
+        required_unless_present = "all_containers",
+        conflicts_with = "all_containers"
+    )]
+    container_id: Option<String>,
+    /// Signal name or number, e.g. SIGTERM, TERM or 15. The guest sends the default signal if
+    /// it is omitted.
+    signal: Option<String>,
+    /// Send the signal to all the processes of the container
+    #[allow(dead_code)]
+    #[clap(short, long)]
+    all: bool,
+    /// Kill all the containers that have not stopped
+    #[clap(long)]
+    all_containers: bool,
+}
 
-pub async fn kill(args: Kill, client: &TaskClient) -> Result<(), Error> {
-    let ctx = Context::default();
-    let req = KillRequest {
-        id: args.container_id,
-        ..Default::default()
-    };
-    let _ = client.kill(ctx, &req).await.map_err(Error::RpcClient)?;
+pub async fn kill(args: Kill, client: &AkariClient, root_path: &Path) -> Result<(), Error> {
+    if args.all_containers {
+        return report(client.api(&ApiRequest::KillAll)?);
+    }
+    // Required unless --all-containers
+    let id = args.container_id.unwrap();
+    match args.signal {
+        // The numbers of the signals depend on the guest OS.
+        Some(signal) => {
+            let vm_config = load_vm_config(&vm_config_path(root_path))?;
+            let signal = parse_signal(&signal, &vm_config.os)?;
+            client.signal(&id, signal).await?;
+        }
+        None => client.kill(&id).await?,
+    }
     Ok(())
 }
@@ -0,0 +1,33 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    path::api_sock_path,
+};
+
+use super::error::Error;
+
+/// Delete the stopped containers
+#[derive(Parser, Debug)]
+pub struct Prune {
+    /// Only delete the containers that exited at least this many seconds ago
+    #[clap(long, default_value_t = 0)]
+    older_than: u64,
+}
+
+pub fn prune(args: Prune, root_path: &Path) -> Result<(), Error> {
+    let req = ApiRequest::Prune {
+        older_than: args.older_than,
+    };
+    if let ApiResponse::Pruned(ids) = api::call(&api_sock_path(root_path), &req)? {
+        for id in ids {
+            println!("{}", id);
+        }
+    }
+    Ok(())
+}
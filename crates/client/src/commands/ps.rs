@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    filter::ContainerFilter,
+    network::GUEST_IP_ANNOTATION,
+    path::api_sock_path,
+};
+
+use super::error::Error;
+
+/// List the containers and their exec processes
+#[derive(Parser, Debug)]
+pub struct Ps {
+    /// Show only the containers that match all the filters, e.g. label=ci.job=1234
+    #[clap(short, long)]
+    filter: Vec<ContainerFilter>,
+}
+
+pub fn ps(args: Ps, root_path: &Path) -> Result<(), Error> {
+    // Take the guest pids of all the containers in one request.
+    let req = ApiRequest::BatchState { ids: Vec::new() };
+    let ApiResponse::ContainerStates(mut states) = api::call(&api_sock_path(root_path), &req)?
+    else {
+        return Ok(());
+    };
+    states.retain(|state| args.filter.iter().all(|filter| filter.matches(&state.info)));
+    states.sort_by(|a, b| a.info.id.cmp(&b.info.id));
+    // The dedicated users are reported by the agent, which is unavailable while the VM stops.
+    let users = match api::call(&api_sock_path(root_path), &ApiRequest::GuestStats) {
+        Ok(ApiResponse::GuestStats(stats)) => stats.users,
+        _ => Default::default(),
+    };
+
+    println!(
+        "{:<24} {:<16} {:<10} {:<8} {:<12} {:<16} EXIT CODE",
+        "ID", "EXEC ID", "STATUS", "PID", "USER", "IP"
+    );
+    for state in states {
+        let container = state.info;
+        let status = format!("{:?}", container.status);
+        let pid = state
+            .guest_pid
+            .map(|pid| pid.to_string())
+            .unwrap_or_default();
+        let user = users
+            .get(&container.id)
+            .map(|user| user.name.as_str())
+            .unwrap_or("-");
+        // The processes share the IP address of the guest.
+        let ip = container
+            .annotations
+            .get(GUEST_IP_ANNOTATION)
+            .map(String::as_str)
+            .unwrap_or("-");
+        println!(
+            "{:<24} {:<16} {:<10} {:<8} {:<12} {}",
+            container.id, "-", status, pid, user, ip
+        );
+        for exec in container.execs {
+            let status = format!("{:?}", exec.status);
+            let pid = exec.pid.map(|pid| pid.to_string()).unwrap_or_default();
+            let exit_code = exec
+                .exit_code
+                .map(|code| code.to_string())
+                .unwrap_or_default();
+            println!(
+                "{:<24} {:<16} {:<10} {:<8} {:<12} {:<16} {}",
+                container.id, exec.exec_id, status, pid, user, ip, exit_code
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    path::api_sock_path,
+};
+
+use super::error::Error;
+
+/// Reload the server configuration
+#[derive(Parser, Debug)]
+pub struct Reload {}
+
+pub fn reload(_args: Reload, root_path: &Path) -> Result<(), Error> {
+    if let ApiResponse::Reloaded(settings) =
+        api::call(&api_sock_path(root_path), &ApiRequest::ReloadConfig)?
+    {
+        for setting in settings {
+            println!("Restart the server to apply the changes to {}", setting);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{io::Write, path::Path, time::Duration};
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    asciicast::{self, EventCode},
+    container_id::ContainerId,
+    path::sessions_path,
+};
+
+use super::error::Error;
+
+/// List the recorded terminal sessions of a container or replay one of them
+#[derive(Parser, Debug)]
+pub struct Replay {
+    container_id: ContainerId,
+    /// Name of the recording to replay (default: list the recordings)
+    session: Option<String>,
+    /// Playback speed
+    #[clap(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Shorten the pauses longer than the seconds
+    #[clap(long)]
+    max_wait: Option<f64>,
+}
+
+fn list(dir: &Path) -> Result<(), Error> {
+    let mut names = Vec::new();
+    if dir.exists() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "cast") {
+                if let Some(name) = path.file_name() {
+                    names.push(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+    }
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+pub fn replay(args: Replay, root_path: &Path) -> Result<(), Error> {
+    let dir = sessions_path(root_path, args.container_id.as_str());
+    let Some(session) = args.session else {
+        return list(&dir);
+    };
+    let (_, events) = asciicast::load(&dir.join(session))?;
+
+    // Only the output is written. The input is echoed by the terminal in the output.
+    let speed = if args.speed > 0.0 { args.speed } else { 1.0 };
+    let mut stdout = std::io::stdout().lock();
+    let mut last = 0.0;
+    for asciicast::Event(time, code, data) in events {
+        if code != EventCode::Output {
+            continue;
+        }
+        let mut wait = (time - last).max(0.0);
+        if let Some(max_wait) = args.max_wait {
+            wait = wait.min(max_wait);
+        }
+        last = time;
+        std::thread::sleep(Duration::from_secs_f64(wait / speed));
+        stdout.write_all(data.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    container_id::ContainerId, path::containers_path, port_forward::PortMapping,
+    restart::RestartPolicy, task_options::TaskOptions, timeout::parse_max_runtime,
+};
+use libakari_client::AkariClient;
+
+use super::{create::create_options, error::Error};
+
+/// Create and start a container
+#[derive(Parser, Debug)]
+pub struct Run {
+    /// Path to the bundle directory
+    #[clap(short, long, default_value = ".")]
+    bundle: PathBuf,
+    /// Path to the socket to receive the console file descriptor
+    #[clap(long)]
+    console_socket: Option<PathBuf>,
+    /// Publish a container port to the host ([host_ip:]host_port:container_port[/tcp])
+    #[clap(short, long)]
+    publish: Vec<PortMapping>,
+    /// Restart the container when it exits (no, on-failure[:max_retries], always)
+    #[clap(long)]
+    restart: Option<RestartPolicy>,
+    /// Stream the file into the container stdin and close it at EOF ("-" reads the stdin of this command)
+    #[clap(long)]
+    stdin: Option<PathBuf>,
+    /// Kill the container when it runs longer than the duration (<n>[s|m|h])
+    #[clap(long, value_parser = parse_max_runtime)]
+    max_runtime: Option<Duration>,
+    container_id: ContainerId,
+}
+
+// Save the piped stdin so that the server can stream it after this command exits.
+fn spool_stdin(root_path: &Path, container_id: &ContainerId) -> Result<PathBuf, Error> {
+    let dir = containers_path(root_path).join(container_id.as_str());
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("stdin");
+    let mut file = std::fs::File::create(&path)?;
+    std::io::copy(&mut std::io::stdin().lock(), &mut file)?;
+    Ok(path)
+}
+
+pub async fn run(args: Run, client: &AkariClient) -> Result<(), Error> {
+    let stdin = match args.stdin {
+        Some(path) if path == Path::new("-") => {
+            Some(spool_stdin(client.root_path(), &args.container_id)?)
+        }
+        stdin => stdin,
+    };
+    let options = TaskOptions {
+        ports: args.publish,
+        restart_policy: args.restart,
+        max_runtime: args.max_runtime.map(|max_runtime| max_runtime.as_secs()),
+        ..Default::default()
+    };
+    let options = create_options(
+        &args.bundle,
+        args.console_socket.as_deref(),
+        stdin.as_deref(),
+        options,
+    );
+    client.run(&args.container_id, &options)?;
+    Ok(())
+}
@@ -4,14 +4,43 @@
 use std::path::PathBuf;
 
 use anyhow::Result;
-use oci_spec::runtime::Spec;
+use clap::{Parser, Subcommand};
+use libakari::spec::{validate_spec, Severity};
+use oci_spec::runtime::Spec as OciSpec;
 
-pub fn spec(args: liboci_cli::Spec) -> Result<()> {
+/// Create a new specification file
+#[derive(Parser, Debug)]
+pub struct Spec {
+    #[clap(flatten)]
+    args: liboci_cli::Spec,
+
+    #[clap(subcommand)]
+    cmd: Option<SpecCmd>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SpecCmd {
+    /// Check that config.json of the bundle only uses the features akari supports
+    Validate {
+        /// Path to the bundle
+        #[clap(default_value = ".")]
+        bundle: PathBuf,
+    },
+}
+
+pub fn spec(args: Spec) -> Result<()> {
+    match args.cmd {
+        Some(SpecCmd::Validate { bundle }) => validate(bundle),
+        None => generate(args.args),
+    }
+}
+
+fn generate(args: liboci_cli::Spec) -> Result<()> {
     if args.rootless {
         return Err(anyhow::anyhow!("Rootless containers are not supported"));
     }
 
-    let mut spec = Spec::default();
+    let mut spec = OciSpec::default();
     spec.set_hostname(Some("akari".to_string()));
     spec.set_linux(None);
     spec.set_mounts(None);
@@ -26,3 +55,28 @@ pub fn spec(args: liboci_cli::Spec) -> Result<()> {
 
     Ok(())
 }
+
+fn validate(bundle: PathBuf) -> Result<()> {
+    let config_path = bundle.join("config.json");
+    let spec = OciSpec::load(&config_path)
+        .map_err(|e| anyhow::anyhow!("Failed to load {:?}: {}", config_path, e))?;
+
+    let diagnostics = validate_spec(&spec, &bundle);
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow::anyhow!(
+            "{} errors found in {:?}",
+            errors,
+            config_path
+        ));
+    }
+    println!("{}: OK", config_path.display());
+    Ok(())
+}
@@ -1,18 +1,40 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+use std::path::PathBuf;
+
 use anyhow::Result;
-use containerd_shim::{api::StartRequest, protos::shim_async::TaskClient, Context};
-use liboci_cli::Start;
+use libakari::start::{parse_env_file, StartOverrides};
+use libakari_client::AkariClient;
 
 use super::error::Error;
 
-pub async fn start(args: Start, client: &TaskClient) -> Result<(), Error> {
-    let ctx = Context::default();
-    let req = StartRequest {
-        id: args.container_id,
-        ..Default::default()
+/// Start a previously created container
+#[derive(clap::Parser, Debug)]
+pub struct Start {
+    #[clap(flatten)]
+    base: liboci_cli::Start,
+    /// File of KEY=value lines that override the env of the spec
+    #[clap(long)]
+    env_file: Option<PathBuf>,
+    /// Working directory that overrides the cwd of the spec
+    #[clap(long)]
+    cwd: Option<PathBuf>,
+}
+
+pub async fn start(args: Start, client: &AkariClient) -> Result<(), Error> {
+    let env = match &args.env_file {
+        Some(path) => parse_env_file(&std::fs::read_to_string(path)?)?,
+        None => Vec::new(),
     };
-    let _ = client.start(ctx, &req).await.map_err(Error::RpcClient)?;
+    let overrides = StartOverrides { env, cwd: args.cwd };
+    overrides.validate()?;
+    if overrides.is_empty() {
+        client.start(&args.base.container_id).await?;
+    } else {
+        client
+            .start_with_overrides(&args.base.container_id, &overrides)
+            .await?;
+    }
     Ok(())
 }
@@ -4,12 +4,30 @@
 use std::collections::HashMap;
 
 use anyhow::Result;
-use containerd_shim::{api::StateRequest, protos::shim_async::TaskClient, Context};
-use liboci_cli::State;
+use libakari::{
+    api::{watch_container, ExtendedState},
+    network::GUEST_IP_ANNOTATION,
+    path::api_sock_path,
+    vm_rpc::VmStatus,
+};
+use libakari_client::AkariClient;
 use serde::{Deserialize, Serialize};
 
 use super::error::Error;
 
+/// Output the state of a container
+#[derive(clap::Parser, Debug)]
+pub struct State {
+    #[clap(flatten)]
+    base: liboci_cli::State,
+    /// Also output the details of akari, e.g. the vsock port, the guest pid and the stats
+    #[clap(long)]
+    extended: bool,
+    /// Output the status as a JSON line each time it changes until the container stops
+    #[clap(long, conflicts_with = "extended")]
+    watch: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 enum ContainerStatus {
@@ -34,6 +52,24 @@ impl From<containerd_shim::api::Status> for ContainerStatus {
     }
 }
 
+impl From<&VmStatus> for ContainerStatus {
+    fn from(val: &VmStatus) -> Self {
+        match val {
+            VmStatus::Creating => ContainerStatus::Creating,
+            VmStatus::Created => ContainerStatus::Created,
+            VmStatus::Running => ContainerStatus::Running,
+            VmStatus::Stopped => ContainerStatus::Stopped,
+        }
+    }
+}
+
+// Status change of the container with --watch
+#[derive(Serialize)]
+struct StatusChange<'a> {
+    id: &'a str,
+    status: ContainerStatus,
+}
+
 /// OCI runtime state
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -52,6 +88,9 @@ struct ContainerState {
     // annotations associated with the container
     #[serde(skip_serializing_if = "Option::is_none")]
     annotations: Option<HashMap<String, String>>,
+    // details of the container in the VM, only with --extended (akari extension)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    akari: Option<ExtendedState>,
 }
 
 impl ContainerState {
@@ -63,17 +102,28 @@ impl ContainerState {
             pid: None,
             bundle,
             annotations: None,
+            akari: None,
         }
     }
 }
 
-pub async fn state(args: State, client: &TaskClient) -> Result<(), Error> {
-    let ctx = Context::default();
-    let req = StateRequest {
-        id: args.container_id,
-        ..Default::default()
-    };
-    let response = client.state(ctx, &req).await.map_err(Error::RpcClient)?;
+pub async fn state(args: State, client: &AkariClient) -> Result<(), Error> {
+    if args.watch {
+        let id = &args.base.container_id;
+        watch_container(&api_sock_path(client.root_path()), id, |status| {
+            let change = StatusChange {
+                id,
+                status: status.into(),
+            };
+            match serde_json::to_string(&change) {
+                Ok(json) => println!("{}", json),
+                Err(e) => eprintln!("Failed to serialize the status: {}", e),
+            }
+        })?;
+        return Ok(());
+    }
+
+    let response = client.state(&args.base.container_id).await?;
 
     let status = response.status.unwrap().into();
     let bundle = response.bundle;
@@ -83,6 +133,20 @@ pub async fn state(args: State, client: &TaskClient) -> Result<(), Error> {
         0 => None,
         pid => Some(pid as i32),
     };
+    // The server records the lifecycle timestamps of the container and looks up the guest IP
+    // address.
+    let mut annotations = HashMap::new();
+    let extended = client.extended_state(&state.id);
+    if let Ok(extended) = &extended {
+        annotations.extend(extended.info.timestamps.annotations());
+        if let Some(ip_address) = extended.info.annotations.get(GUEST_IP_ANNOTATION) {
+            annotations.insert(GUEST_IP_ANNOTATION.to_string(), ip_address.clone());
+        }
+    }
+    state.annotations = (!annotations.is_empty()).then_some(annotations);
+    if args.extended {
+        state.akari = Some(extended?);
+    }
 
     println!("{}", serde_json::to_string_pretty(&state)?);
     std::process::exit(0);
@@ -0,0 +1,139 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use clap::Parser;
+use libakari::{
+    api::{self, ApiRequest, ApiResponse, ContainerInfo},
+    metrics::{GuestStats, ProcessUsage},
+    path::api_sock_path,
+};
+
+use super::error::Error;
+
+/// Show the resource usage of the containers until interrupted
+#[derive(Parser, Debug)]
+pub struct Top {
+    /// Seconds between the updates
+    #[clap(short, long, default_value_t = 2)]
+    delay: u64,
+    /// Exit after the number of updates
+    #[clap(short = 'n', long)]
+    iterations: Option<u64>,
+}
+
+// Format the bytes with a binary unit.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+fn render(
+    containers: &[ContainerInfo],
+    stats: &GuestStats,
+    prev: Option<&(Instant, BTreeMap<String, ProcessUsage>)>,
+    now: Instant,
+) {
+    println!(
+        "cpus: {}  load: {:.2} {:.2} {:.2}  memory: {} free of {}  disk: {} free of {}",
+        stats.cpus,
+        stats.load_average[0],
+        stats.load_average[1],
+        stats.load_average[2],
+        format_bytes(stats.memory_free),
+        format_bytes(stats.memory_total),
+        format_bytes(stats.disk_free),
+        format_bytes(stats.disk_total),
+    );
+    println!();
+    println!(
+        "{:<24} {:<10} {:<12} {:>5} {:>7} {:>9} {:>9} {:>9}",
+        "ID", "STATUS", "USER", "PROCS", "CPU%", "MEM", "READ", "WRITE"
+    );
+    for container in containers {
+        let usage = stats.usage.get(&container.id).copied().unwrap_or_default();
+        let processes = stats.processes.get(&container.id).copied().unwrap_or(0);
+        // 100% is one CPU fully used over the interval as in top(1).
+        let cpu = prev
+            .and_then(|(then, usages)| {
+                let elapsed = now.duration_since(*then).as_nanos() as f64;
+                let before = usages.get(&container.id)?;
+                (elapsed > 0.0).then(|| {
+                    usage.cpu_time.saturating_sub(before.cpu_time) as f64 / elapsed * 100.0
+                })
+            })
+            .map(|cpu| format!("{:.1}", cpu))
+            .unwrap_or_else(|| "-".to_string());
+        let status = format!("{:?}", container.status);
+        let user = stats
+            .users
+            .get(&container.id)
+            .map(|user| user.name.as_str())
+            .unwrap_or("-");
+        println!(
+            "{:<24} {:<10} {:<12} {:>5} {:>7} {:>9} {:>9} {:>9}",
+            container.id,
+            status,
+            user,
+            processes,
+            cpu,
+            format_bytes(usage.memory),
+            format_bytes(usage.disk_read),
+            format_bytes(usage.disk_written),
+        );
+    }
+}
+
+pub fn top(args: Top, root_path: &Path) -> Result<(), Error> {
+    let api_sock_path = api_sock_path(root_path);
+    // Redraw in place on a terminal and append the tables otherwise.
+    let redraw = std::io::stdout().is_terminal();
+    let mut prev = None;
+    let mut updates = 0;
+    loop {
+        let mut containers = match api::call(&api_sock_path, &ApiRequest::ListContainers)? {
+            ApiResponse::Containers(containers) => containers,
+            _ => Vec::new(),
+        };
+        containers.sort_by(|a, b| a.id.cmp(&b.id));
+        let stats = match api::call(&api_sock_path, &ApiRequest::GuestStats)? {
+            ApiResponse::GuestStats(stats) => stats,
+            _ => GuestStats::default(),
+        };
+        let now = Instant::now();
+
+        if redraw {
+            print!("\x1b[2J\x1b[H");
+        } else if updates > 0 {
+            println!();
+        }
+        render(&containers, &stats, prev.as_ref(), now);
+        prev = Some((now, stats.usage));
+
+        updates += 1;
+        if args
+            .iterations
+            .is_some_and(|iterations| updates >= iterations)
+        {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_secs(args.delay));
+    }
+}
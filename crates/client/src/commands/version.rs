@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::Path;
+
+use clap::Parser;
+use libakari::{
+    api::{self, ApiRequest, ApiResponse, ServerInfo},
+    build_info,
+    path::api_sock_path,
+};
+use serde::Serialize;
+
+use super::error::Error;
+
+/// Show the version of akari
+#[derive(Parser, Debug)]
+pub struct Version {
+    /// Also show the build and the enabled features of the running server
+    #[clap(long)]
+    server: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionInfo {
+    version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    git_hash: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<ServerInfo>,
+}
+
+pub fn version(args: Version, root_path: &Path) -> Result<(), Error> {
+    let server = match args.server {
+        true => match api::call(&api_sock_path(root_path), &ApiRequest::Info)? {
+            ApiResponse::Info(info) => Some(info),
+            _ => None,
+        },
+        false => None,
+    };
+    let info = VersionInfo {
+        version: build_info::VERSION,
+        git_hash: build_info::GIT_HASH,
+        server,
+    };
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
@@ -0,0 +1,273 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    metrics::GuestStats,
+    path::{api_sock_path, hardware_model_cache_path, vm_config_path},
+    port_forward::PortMapping,
+    provision::{load_provision_status, ProvisionStatus},
+    vm_config::load_vm_config,
+};
+use serde::Serialize;
+
+use super::error::Error;
+use crate::{archive, upgrade};
+
+/// Manage the VM
+#[derive(Parser, Debug)]
+pub struct Vm {
+    #[clap(subcommand)]
+    cmd: VmCmd,
+}
+
+#[derive(Subcommand, Debug)]
+enum VmCmd {
+    /// Open a window that shows the VM display (the server must run with --gui)
+    Gui,
+    /// Stop the VM and delete its configuration and disk images
+    Delete {
+        /// Delete the VM even if it is protected or has containers
+        #[clap(long)]
+        force: bool,
+    },
+    /// Let the agent drain the containers and save its state, then stop the VM
+    Shutdown {
+        /// Seconds to wait for the running containers to exit
+        #[clap(long)]
+        timeout: Option<u64>,
+    },
+    /// Generate the hardware model and the machine identifier missing in vm.json
+    Init {
+        /// Path to the VM configuration (default: vm.json in the root directory)
+        #[clap(long)]
+        config: Option<PathBuf>,
+        /// Fetch the hardware model again instead of using the cached one
+        #[clap(long)]
+        refresh: bool,
+        /// Also replace the existing machine identifier, which makes the guest a new machine
+        #[clap(long)]
+        force: bool,
+    },
+    /// Validate the VM configuration without booting the VM
+    Check {
+        /// Path to the VM configuration (default: vm.json in the root directory)
+        #[clap(long)]
+        config: Option<PathBuf>,
+    },
+    /// Take a snapshot of the guest data volume, e.g. before upgrading the agent
+    Snapshot { name: String },
+    /// Revert the guest data volume to the snapshot (takes effect after the VM restarts)
+    Rollback { name: String },
+    /// Archive vm.json and the disk images of the stopped VM into a file (tar+zstd)
+    Export { path: PathBuf },
+    /// Restore the VM from the archive into the root directory
+    Import {
+        path: PathBuf,
+        /// Replace the existing VM configuration
+        #[clap(long)]
+        force: bool,
+    },
+    /// Save the running VM without containers as the golden VM in the root directory
+    Golden,
+    /// Clone the golden VM into the root directory; the server resumes the clone instead of
+    /// booting it
+    Clone {
+        /// Path to the golden VM (the `golden` directory in the root directory of its server)
+        golden: PathBuf,
+    },
+    /// Update the guest macOS from the restore image and roll back the disks on failure
+    Upgrade {
+        /// Path to the restore image
+        #[clap(long)]
+        ipsw: PathBuf,
+        /// Seconds to wait for the agent after the update
+        #[clap(long, default_value_t = 600)]
+        timeout: u64,
+    },
+    /// Show the VM resources and the containers in it
+    Status {
+        /// Also show the resource usage reported by the guest agent
+        #[clap(short, long)]
+        verbose: bool,
+    },
+    /// Manage the host ports forwarded to the guest independent of the containers
+    Nat {
+        #[clap(subcommand)]
+        cmd: NatCmd,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum NatCmd {
+    /// Forward the host port to the guest port ([host_ip:]host_port:guest_port[/tcp])
+    Add { mapping: PortMapping },
+    /// Stop forwarding the host port
+    #[clap(visible_alias = "rm")]
+    Del { mapping: PortMapping },
+    /// List the rules
+    Ls,
+}
+
+fn nat(api_sock_path: &Path, cmd: NatCmd) -> Result<(), Error> {
+    match cmd {
+        NatCmd::Add { mapping } => {
+            api::call(api_sock_path, &ApiRequest::AddNatRule { mapping })?;
+        }
+        NatCmd::Del { mapping } => {
+            api::call(api_sock_path, &ApiRequest::DeleteNatRule { mapping })?;
+        }
+        NatCmd::Ls => {
+            if let ApiResponse::NatRules(rules) =
+                api::call(api_sock_path, &ApiRequest::ListNatRules)?
+            {
+                for rule in rules {
+                    println!("{}", rule);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VmStatus {
+    cpus: usize,
+    // bytes of the VM memory
+    ram: usize,
+    containers: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    guest: Option<GuestStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provision: Option<ProvisionStatus>,
+}
+
+fn status(root_path: &Path, verbose: bool) -> Result<(), Error> {
+    let api_sock_path = api_sock_path(root_path);
+    let vm_config = load_vm_config(&vm_config_path(root_path))?;
+    let containers = match api::call(&api_sock_path, &ApiRequest::ListContainers)? {
+        ApiResponse::Containers(containers) => containers.len(),
+        _ => 0,
+    };
+    let guest = if verbose {
+        match api::call(&api_sock_path, &ApiRequest::GuestStats)? {
+            ApiResponse::GuestStats(stats) => Some(stats),
+            _ => None,
+        }
+    } else {
+        None
+    };
+    let status = VmStatus {
+        cpus: vm_config.cpus,
+        ram: vm_config.ram,
+        containers,
+        guest,
+        provision: load_provision_status(root_path)?,
+    };
+    println!("{}", serde_json::to_string_pretty(&status)?);
+    Ok(())
+}
+
+fn init(root_path: &Path, config_path: &Path, refresh: bool, force: bool) -> Result<(), Error> {
+    let mut vm_config = load_vm_config(config_path)?;
+    if vm_config.hardware_model.is_empty() || refresh {
+        vm_config.hardware_model =
+            vmm::identity::cached_hardware_model(&hardware_model_cache_path(root_path), refresh)
+                .map_err(|e| Error::InvalidVmConfig(e.into()))?;
+        println!("hardwareModel: generated");
+    }
+    if vm_config.machine_id.is_empty() || force {
+        vm_config.machine_id = vmm::identity::generate_machine_id();
+        println!("machineId: generated");
+    }
+    std::fs::write(config_path, serde_json::to_string_pretty(&vm_config)?)?;
+    Ok(())
+}
+
+// The disk images must not change while they are archived.
+fn check_stopped(api_sock_path: &Path) -> Result<(), Error> {
+    match api::call(api_sock_path, &ApiRequest::ListContainers) {
+        Ok(_) => Err(Error::VmRunning),
+        Err(_) => Ok(()),
+    }
+}
+
+pub fn vm(args: Vm, root_path: &Path) -> Result<(), Error> {
+    let api_sock_path = api_sock_path(root_path);
+    match args.cmd {
+        VmCmd::Gui => {
+            api::call(&api_sock_path, &ApiRequest::ShowWindow)?;
+        }
+        VmCmd::Delete { force } => {
+            api::call(&api_sock_path, &ApiRequest::DeleteVm { force })?;
+        }
+        VmCmd::Shutdown { timeout } => {
+            api::call(&api_sock_path, &ApiRequest::ShutdownVm { timeout })?;
+        }
+        VmCmd::Init {
+            config,
+            refresh,
+            force,
+        } => {
+            let config_path = config.unwrap_or_else(|| vm_config_path(root_path));
+            init(root_path, &config_path, refresh, force)?;
+        }
+        VmCmd::Check { config } => {
+            let config_path = config.unwrap_or_else(|| vm_config_path(root_path));
+            let vm_config = load_vm_config(&config_path)?;
+            // The host cannot write the NVRAM of a macOS guest.
+            if let Some(boot_args) = vm_config.boot.as_ref().and_then(|b| b.boot_args.as_ref()) {
+                println!(
+                    "bootArgs: run `nvram boot-args=\"{}\"` in recoveryOS (boot.recovery) to apply",
+                    boot_args
+                );
+            }
+            let config = vmm::config::Config::from_vm_config(vm_config)
+                .map_err(Error::InvalidVmConfig)?
+                .build();
+            vmm::vm::validate(&config).map_err(|e| Error::InvalidVmConfig(e.into()))?;
+            println!("{}: OK", config_path.display());
+        }
+        VmCmd::Snapshot { name } => {
+            api::call(&api_sock_path, &ApiRequest::SnapshotVm { name })?;
+        }
+        VmCmd::Rollback { name } => {
+            api::call(&api_sock_path, &ApiRequest::RollbackVm { name })?;
+            println!("Restart the VM to apply the rollback");
+        }
+        VmCmd::Export { path } => {
+            check_stopped(&api_sock_path)?;
+            archive::export(root_path, &path)?;
+        }
+        VmCmd::Import { path, force } => {
+            check_stopped(&api_sock_path)?;
+            archive::import(root_path, &path, force)?;
+        }
+        VmCmd::Golden => {
+            api::call(&api_sock_path, &ApiRequest::SaveGolden)?;
+        }
+        VmCmd::Clone { golden } => {
+            let config_path = vm_config_path(root_path);
+            if config_path.exists() {
+                return Err(Error::VmConfigExists(config_path));
+            }
+            std::fs::create_dir_all(root_path)?;
+            vmm::clone::clone_vm(&golden, root_path).map_err(Error::Clone)?;
+        }
+        VmCmd::Upgrade { ipsw, timeout } => {
+            check_stopped(&api_sock_path)?;
+            upgrade::upgrade(root_path, &ipsw, Duration::from_secs(timeout))?;
+        }
+        VmCmd::Status { verbose } => status(root_path, verbose)?,
+        VmCmd::Nat { cmd } => nat(&api_sock_path, cmd)?,
+    }
+    Ok(())
+}
@@ -1,23 +1,40 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+mod archive;
 mod commands;
+mod progress;
+mod upgrade;
 
 use std::path::PathBuf;
 
 use anyhow::Result;
 use clap::Parser;
-use containerd_shim::protos::shim::shim_ttrpc_async::TaskClient;
-use liboci_cli::StandardCmd;
-use ttrpc::asynchronous::Client;
 
-use commands::{connect, create, delete, kill, spec, start, state};
+use commands::{
+    bench, connect, create, debug, delete, events, kill, prune, ps, reload, replay, run, spec,
+    start, state, top, version, vm,
+};
 use libakari::path::{aux_sock_path, root_path};
+use libakari_client::AkariClient;
 
 #[derive(clap::Parser, Debug)]
 pub enum CommonCmd {
-    Spec(liboci_cli::Spec),
+    Spec(spec::Spec),
     Connect(connect::Connect),
+    Debug(debug::Debug),
+    Events(events::Events),
+    Prune(prune::Prune),
+    #[clap(visible_alias = "list")]
+    Ps(ps::Ps),
+    Reload(reload::Reload),
+    Replay(replay::Replay),
+    Run(run::Run),
+    Top(top::Top),
+    Version(version::Version),
+    Vm(vm::Vm),
+    #[clap(hide = true)]
+    Bench(bench::Bench),
 }
 
 // The OCI Command Line Interface document doesn't define any global
@@ -53,10 +70,15 @@ struct Opts {
     subcmd: SubCommand,
 }
 
+// The standard commands of the OCI runtime. Start, State, Kill and Delete take the flags of akari
+// too.
 #[derive(clap::Subcommand)]
 enum SubCommand {
-    #[clap(flatten)]
-    Standard(Box<liboci_cli::StandardCmd>),
+    Create(Box<liboci_cli::Create>),
+    Start(start::Start),
+    State(state::State),
+    Kill(kill::Kill),
+    Delete(delete::Delete),
     #[clap(flatten)]
     Common(Box<CommonCmd>),
 }
@@ -69,20 +91,29 @@ async fn main() -> Result<()> {
 
     let root_path = root_path(opts.global.root)?;
     let aux_sock_path = aux_sock_path(&root_path, opts.global.vmm_sock);
-
-    let client = TaskClient::new(Client::connect(aux_sock_path.to_str().unwrap())?);
+    // Connect to the server only for the commands that need it.
+    let client = || AkariClient::connect_socket(&root_path, &aux_sock_path);
 
     match opts.subcmd {
-        SubCommand::Standard(cmd) => match *cmd {
-            StandardCmd::Create(create) => create::create(create, &client).await?,
-            StandardCmd::Delete(delete) => delete::delete(delete, &client).await?,
-            StandardCmd::Start(start) => start::start(start, &client).await?,
-            StandardCmd::Kill(kill) => kill::kill(kill, &client).await?,
-            StandardCmd::State(state) => state::state(state, &client).await?,
-        },
+        SubCommand::Create(create) => create::create(*create, &client()?).await?,
+        SubCommand::Delete(delete) => delete::delete(delete, &client()?).await?,
+        SubCommand::Start(start) => start::start(start, &client()?).await?,
+        SubCommand::Kill(kill) => kill::kill(kill, &client()?, &root_path).await?,
+        SubCommand::State(state) => state::state(state, &client()?).await?,
         SubCommand::Common(cmd) => match *cmd {
             CommonCmd::Spec(spec) => spec::spec(spec)?,
-            CommonCmd::Connect(connect) => connect::connect(connect, &client).await?,
+            CommonCmd::Connect(connect) => connect::connect(connect, &client()?).await?,
+            CommonCmd::Debug(debug) => debug::debug(debug, &root_path)?,
+            CommonCmd::Events(events) => events::events(events, &root_path)?,
+            CommonCmd::Prune(prune) => prune::prune(prune, &root_path)?,
+            CommonCmd::Ps(ps) => ps::ps(ps, &root_path)?,
+            CommonCmd::Reload(reload) => reload::reload(reload, &root_path)?,
+            CommonCmd::Replay(replay) => replay::replay(replay, &root_path)?,
+            CommonCmd::Run(run) => run::run(run, &client()?).await?,
+            CommonCmd::Top(top) => top::top(top, &root_path)?,
+            CommonCmd::Version(version) => version::version(version, &root_path)?,
+            CommonCmd::Vm(vm) => vm::vm(vm, &root_path)?,
+            CommonCmd::Bench(bench) => bench::bench(bench, &client()?).await?,
         },
     };
 
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Renders the progress of the long operations on the terminal.
+//! The bar is drawn on stderr in place. If stderr is not a terminal, only the phases are printed.
+
+use std::{
+    io::{IsTerminal, Read, Write},
+    time::{Duration, Instant},
+};
+
+use libakari::progress::Progress;
+
+const BAR_WIDTH: usize = 30;
+// Redrawing for every read would flood the terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct ProgressBar {
+    terminal: bool,
+    last_draw: Option<Instant>,
+    last_phase: Option<String>,
+}
+
+impl ProgressBar {
+    pub fn new() -> Self {
+        Self {
+            terminal: std::io::stderr().is_terminal(),
+            last_draw: None,
+            last_phase: None,
+        }
+    }
+
+    pub fn update(&mut self, progress: &Progress) {
+        let phase_changed = self.last_phase.as_deref() != Some(progress.phase.as_str());
+        if !self.terminal {
+            if phase_changed {
+                eprintln!("{}: {}", progress.operation, progress.phase);
+            }
+        } else if phase_changed
+            || self
+                .last_draw
+                .is_none_or(|last_draw| last_draw.elapsed() >= REDRAW_INTERVAL)
+        {
+            self.draw(progress);
+        }
+        if phase_changed {
+            self.last_phase = Some(progress.phase.clone());
+        }
+    }
+
+    fn draw(&mut self, progress: &Progress) {
+        let line = match progress.percent() {
+            Some(percent) => {
+                let filled = (percent / 100.0 * BAR_WIDTH as f64) as usize;
+                format!(
+                    "{}: [{}{}] {:5.1}% {}",
+                    progress.operation,
+                    "#".repeat(filled),
+                    " ".repeat(BAR_WIDTH - filled),
+                    percent,
+                    progress.phase
+                )
+            }
+            None => format!("{}: {}...", progress.operation, progress.phase),
+        };
+        // Return to the start of the line and clear the rest of the previous one.
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}\x1b[K", line);
+        let _ = stderr.flush();
+        self.last_draw = Some(Instant::now());
+    }
+
+    // Draw the final state and move to the next line.
+    pub fn finish(&mut self, progress: &Progress) {
+        if self.terminal {
+            self.draw(progress);
+            eprintln!();
+        } else {
+            eprintln!("{}: done", progress.operation);
+        }
+    }
+}
+
+// Counts the bytes read from the inner reader as the completed work.
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a mut Progress,
+    bar: &'a mut ProgressBar,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, progress: &'a mut Progress, bar: &'a mut ProgressBar) -> Self {
+        Self {
+            inner,
+            progress,
+            bar,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.completed += n as u64;
+        self.bar.update(self.progress);
+        Ok(n)
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! In-place update of the guest macOS.
+//! The disk images are cloned with APFS copy-on-write before the update. The VM then runs in
+//! this process without the server, so no container can start while macOS is installed. The
+//! update succeeds once the agent answers the handshake again. Otherwise the clones are moved
+//! back over the disk images.
+
+use std::{
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use libakari::{
+    framing::ReadFrom,
+    path::vm_config_path,
+    progress::Progress,
+    vm_config::{load_vm_config, MacosVmConfig},
+    vsock::Handshake,
+};
+
+use crate::{commands::error::Error, progress::ProgressBar};
+
+const SNAPSHOT_SUFFIX: &str = "pre-upgrade";
+// How long to wait for each handshake with the agent.
+const HANDSHAKE_INTERVAL: Duration = Duration::from_secs(5);
+
+fn snapshot_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(SNAPSHOT_SUFFIX);
+    PathBuf::from(path)
+}
+
+// Clone the disk images and return the pairs of the image and its clone.
+fn snapshot(vm_config: &MacosVmConfig) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut snapshots = Vec::new();
+    for storage in &vm_config.storage {
+        let snapshot = snapshot_path(&storage.file);
+        // A clone left by an interrupted upgrade is older than the current image.
+        if snapshot.exists() {
+            std::fs::remove_file(&snapshot)?;
+        }
+        vmm::clone::clone_file(&storage.file, &snapshot)?;
+        snapshots.push((storage.file.clone(), snapshot));
+    }
+    Ok(snapshots)
+}
+
+fn rollback(snapshots: &[(PathBuf, PathBuf)]) -> std::io::Result<()> {
+    for (file, snapshot) in snapshots {
+        std::fs::rename(snapshot, file)?;
+    }
+    Ok(())
+}
+
+// Connect to the agent port until the agent answers or the timeout expires.
+fn wait_for_agent(
+    vm: &mut vmm::vm::Vm,
+    vm_config: &MacosVmConfig,
+    timeout: Duration,
+) -> Result<()> {
+    let started = Instant::now();
+    let mut attempt = 0;
+    loop {
+        let path = std::env::temp_dir().join(format!(
+            "akari-upgrade-{}-{}.sock",
+            std::process::id(),
+            attempt
+        ));
+        attempt += 1;
+        vm.connect(vm_config.vsock.agent_port, &path)?;
+        let res = (|| -> Result<Handshake> {
+            let mut stream = UnixStream::connect(&path)?;
+            stream.set_read_timeout(Some(HANDSHAKE_INTERVAL))?;
+            Ok(Handshake::read_from(&mut stream)?)
+        })();
+        vm.disconnect(vm_config.vsock.agent_port)?;
+        let _ = std::fs::remove_file(&path);
+        match res {
+            Ok(handshake) => {
+                vm_config.vsock.verify(&handshake.ports)?;
+                return Ok(());
+            }
+            Err(e) if started.elapsed() >= timeout => {
+                anyhow::bail!("Agent did not come back in {:?}: {}", timeout, e)
+            }
+            Err(_) => std::thread::sleep(HANDSHAKE_INTERVAL),
+        }
+    }
+}
+
+fn install_and_verify(vm_config: &MacosVmConfig, ipsw: &Path, timeout: Duration) -> Result<()> {
+    let config = vmm::config::Config::from_vm_config(vm_config.clone())?
+        .console(None)?
+        .build();
+    let mut vm = vmm::vm::Vm::new(config)?;
+
+    let mut progress = Progress::new("upgrade", "installing");
+    progress.total = Some(100);
+    let mut bar = ProgressBar::new();
+    vm.install(ipsw, |fraction| {
+        progress.completed = (fraction * 100.0) as u64;
+        bar.update(&progress);
+    })?;
+    bar.finish(&progress);
+
+    // The installer leaves the VM stopped, so boot the updated guest to check the agent.
+    eprintln!("upgrade: waiting for the agent");
+    vm.start()?;
+    let res = wait_for_agent(&mut vm, vm_config, timeout);
+    if let Err(e) = vm.kill() {
+        eprintln!("upgrade: failed to stop the VM: {}", e);
+    }
+    res
+}
+
+pub fn upgrade(root_path: &Path, ipsw: &Path, timeout: Duration) -> Result<(), Error> {
+    let vm_config = load_vm_config(&vm_config_path(root_path))?;
+    let snapshots = snapshot(&vm_config).map_err(Error::Upgrade)?;
+
+    if let Err(e) = install_and_verify(&vm_config, ipsw, timeout) {
+        rollback(&snapshots)?;
+        eprintln!("upgrade: rolled back the disk images");
+        return Err(Error::Upgrade(e));
+    }
+    for (_, snapshot) in &snapshots {
+        std::fs::remove_file(snapshot)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::PathBuf;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Container configuration does not exist")]
+    ContainerConfigDoesNotExist,
+    #[error("Root path is not specified")]
+    RootfsPathIsNotSpecified,
+    #[error("Invalid path {0:?}")]
+    InvalidPath(PathBuf),
+    #[error(
+        "The server does not listen on TCP ({0}); forward its sockets instead, e.g. with \
+         `ssh -L <local aux.sock>:<remote aux.sock>`"
+    )]
+    UnsupportedTransport(String),
+    #[error("Unexpected response from the server: {0}")]
+    UnexpectedResponse(String),
+    #[error(transparent)]
+    ContainerId(#[from] libakari::container_id::Error),
+    #[error(transparent)]
+    User(#[from] libakari::user::Error),
+    #[error(transparent)]
+    Api(#[from] libakari::api::Error),
+    #[error(transparent)]
+    Spec(#[from] oci_spec::OciSpecError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serialize(#[from] serde_json::Error),
+    #[error(transparent)]
+    Rpc(#[from] ttrpc::Error),
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Programmatic client of the akari server.
+//! Test harnesses and CI tools can drive the containers with the same requests as the `akari`
+//! command without running it.
+
+mod error;
+mod stdio;
+
+use std::path::{Path, PathBuf};
+
+use containerd_shim::{
+    api::{
+        CreateTaskRequest, DeleteRequest, ExecProcessRequest, KillRequest, StartRequest,
+        StateRequest, StateResponse, WaitRequest,
+    },
+    protos::{
+        protobuf::{well_known_types::any::Any, MessageField},
+        shim_async::TaskClient,
+    },
+    Context,
+};
+use libakari::{
+    api::{self, ApiRequest, ApiResponse, ExtendedState, RunRequest},
+    container_id::ContainerId,
+    path::{api_sock_path, aux_sock_path, data_sock_path, exec_data_sock_path},
+    start::StartOverrides,
+    stdio::StdioStream,
+    task_options::{TaskOptions, TASK_OPTIONS_TYPE_URL},
+    user::check_socket_access,
+};
+use oci_spec::runtime::{Process, Spec};
+use tokio::net::UnixStream;
+use ttrpc::asynchronous::Client;
+
+pub use error::Error;
+pub use stdio::Stdio;
+
+// Type URL of the process spec of the exec requests, as containerd sends it.
+pub const PROCESS_TYPE_URL: &str = "types.containerd.io/opencontainers/runtime-spec/1/Process";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions {
+    // Path to the bundle directory with config.json
+    pub bundle: PathBuf,
+    pub terminal: bool,
+    pub stdin: Stdio,
+    pub stdout: Stdio,
+    pub stderr: Stdio,
+    pub task_options: TaskOptions,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExecOptions {
+    pub terminal: bool,
+    pub stdin: Stdio,
+    pub stdout: Stdio,
+    pub stderr: Stdio,
+}
+
+pub struct AkariClient {
+    root_path: PathBuf,
+    task: TaskClient,
+}
+
+impl AkariClient {
+    // Connect to the server of the root path on its default socket.
+    pub fn connect(root_path: &Path) -> Result<Self> {
+        Self::connect_socket(root_path, &aux_sock_path(root_path, None))
+    }
+
+    pub fn connect_socket(root_path: &Path, aux_sock_path: &Path) -> Result<Self> {
+        let path = aux_sock_path
+            .to_str()
+            .ok_or_else(|| Error::InvalidPath(aux_sock_path.to_path_buf()))?;
+        // ttrpc only serves Unix domain and vsock sockets, so a remote server is reached through
+        // forwarded sockets.
+        if path.starts_with("tcp://") {
+            return Err(Error::UnsupportedTransport(path.to_string()));
+        }
+        check_socket_access(aux_sock_path)?;
+        Ok(Self {
+            root_path: root_path.to_path_buf(),
+            task: TaskClient::new(Client::connect(path)?),
+        })
+    }
+
+    pub fn root_path(&self) -> &Path {
+        &self.root_path
+    }
+
+    // The task service of the server for the requests without a method here.
+    pub fn task(&self) -> &TaskClient {
+        &self.task
+    }
+
+    // Send the admin API request to the server.
+    pub fn api(&self, req: &ApiRequest) -> Result<ApiResponse> {
+        Ok(api::call(&api_sock_path(&self.root_path), req)?)
+    }
+
+    // Create the container from the bundle and return the pid of its process.
+    pub async fn create(&self, id: &str, options: &CreateOptions) -> Result<u32> {
+        let id = ContainerId::new(id)?;
+        let bundle = check_bundle(&options.bundle)?;
+        let task_options = if options.task_options.is_empty() {
+            MessageField::none()
+        } else {
+            MessageField::some(Any {
+                type_url: TASK_OPTIONS_TYPE_URL.to_string(),
+                value: serde_json::to_vec(&options.task_options)?,
+                ..Default::default()
+            })
+        };
+        let req = CreateTaskRequest {
+            id: id.into(),
+            bundle: bundle.to_string_lossy().into_owned(),
+            terminal: options.terminal,
+            stdin: options.stdin.uri()?,
+            stdout: options.stdout.uri()?,
+            stderr: options.stderr.uri()?,
+            options: task_options,
+            ..Default::default()
+        };
+        Ok(self.task.create(Context::default(), &req).await?.pid)
+    }
+
+    // Create and start the container in one request and return the pid of its process. The
+    // server deletes the container again if it fails to start.
+    pub fn run(&self, id: &str, options: &CreateOptions) -> Result<u32> {
+        let id = ContainerId::new(id)?;
+        let req = RunRequest {
+            id: id.into(),
+            bundle: check_bundle(&options.bundle)?,
+            terminal: options.terminal,
+            stdin: options.stdin.uri()?,
+            stdout: options.stdout.uri()?,
+            stderr: options.stderr.uri()?,
+            options: options.task_options.clone(),
+        };
+        match self.api(&ApiRequest::RunContainer(Box::new(req)))? {
+            ApiResponse::Started { pid } => Ok(pid),
+            res => Err(Error::UnexpectedResponse(format!("{:?}", res))),
+        }
+    }
+
+    // Start the container and return the pid of its process.
+    pub async fn start(&self, id: &str) -> Result<u32> {
+        self.start_process(id, "", Context::default()).await
+    }
+
+    // Start the container with the env and the cwd of the spec replaced.
+    pub async fn start_with_overrides(&self, id: &str, overrides: &StartOverrides) -> Result<u32> {
+        let mut ctx = Context::default();
+        overrides.to_metadata(&mut ctx.metadata);
+        self.start_process(id, "", ctx).await
+    }
+
+    async fn start_process(&self, id: &str, exec_id: &str, ctx: Context) -> Result<u32> {
+        let req = StartRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(self.task.start(ctx, &req).await?.pid)
+    }
+
+    // Run the process in the container and return its pid.
+    pub async fn exec(
+        &self,
+        id: &str,
+        exec_id: &str,
+        process: &Process,
+        options: &ExecOptions,
+    ) -> Result<u32> {
+        let req = ExecProcessRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            terminal: options.terminal,
+            stdin: options.stdin.uri()?,
+            stdout: options.stdout.uri()?,
+            stderr: options.stderr.uri()?,
+            spec: MessageField::some(Any {
+                type_url: PROCESS_TYPE_URL.to_string(),
+                value: serde_json::to_vec(process)?,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        self.task.exec(Context::default(), &req).await?;
+        self.start_process(id, exec_id, Context::default()).await
+    }
+
+    // Attach to the output of the container, or of the exec process if `exec_id` is given. The
+    // stream must have been created with `Stdio::Socket`.
+    pub async fn logs(
+        &self,
+        id: &str,
+        exec_id: Option<&str>,
+        stream: StdioStream,
+    ) -> Result<UnixStream> {
+        let path = match exec_id {
+            Some(exec_id) => exec_data_sock_path(&self.root_path, id, exec_id, stream),
+            None => data_sock_path(&self.root_path, id, stream),
+        };
+        Ok(UnixStream::connect(path).await?)
+    }
+
+    // Wait for the container, or the exec process if `exec_id` is given, to exit and return its
+    // exit status.
+    pub async fn wait(&self, id: &str, exec_id: Option<&str>) -> Result<u32> {
+        let req = WaitRequest {
+            id: id.to_string(),
+            exec_id: exec_id.unwrap_or_default().to_string(),
+            ..Default::default()
+        };
+        Ok(self.task.wait(Context::default(), &req).await?.exit_status)
+    }
+
+    pub async fn kill(&self, id: &str) -> Result<()> {
+        self.signal(id, 0).await
+    }
+
+    // Send the signal number of the guest OS to the container. 0 sends the default signal.
+    pub async fn signal(&self, id: &str, signal: u32) -> Result<()> {
+        let req = KillRequest {
+            id: id.to_string(),
+            signal,
+            ..Default::default()
+        };
+        self.task.kill(Context::default(), &req).await?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        let req = DeleteRequest {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        self.task.delete(Context::default(), &req).await?;
+        Ok(())
+    }
+
+    pub async fn state(&self, id: &str) -> Result<StateResponse> {
+        let req = StateRequest {
+            id: id.to_string(),
+            ..Default::default()
+        };
+        Ok(self.task.state(Context::default(), &req).await?)
+    }
+
+    // Return the state of the container with the details of the server and the guest.
+    pub fn extended_state(&self, id: &str) -> Result<ExtendedState> {
+        let req = ApiRequest::ContainerState { id: id.to_string() };
+        match self.api(&req)? {
+            ApiResponse::ContainerState(state) => Ok(state),
+            res => Err(Error::UnexpectedResponse(format!("{:?}", res))),
+        }
+    }
+
+    // Return the extended states of the containers, or of all the containers if `ids` is empty,
+    // in one request.
+    pub fn batch_state(&self, ids: &[&str]) -> Result<Vec<ExtendedState>> {
+        let req = ApiRequest::BatchState {
+            ids: ids.iter().map(|id| id.to_string()).collect(),
+        };
+        match self.api(&req)? {
+            ApiResponse::ContainerStates(states) => Ok(states),
+            res => Err(Error::UnexpectedResponse(format!("{:?}", res))),
+        }
+    }
+}
+
+// Check the bundle before sending it and return its absolute path.
+fn check_bundle(bundle: &Path) -> Result<PathBuf> {
+    let spec_path = bundle.join("config.json");
+    if !spec_path.exists() {
+        return Err(Error::ContainerConfigDoesNotExist);
+    }
+    let spec = Spec::load(&spec_path)?;
+
+    // Check that the rootfs exists. The server translates the host paths to the guest paths.
+    let root = spec
+        .root()
+        .as_ref()
+        .ok_or(Error::RootfsPathIsNotSpecified)?;
+    bundle.join(root.path()).canonicalize()?;
+    Ok(bundle.canonicalize()?)
+}
@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::PathBuf;
+
+use libakari::stdio::{file_uri, socket_uri};
+
+// Where a stdio stream of the process goes
+#[derive(Clone, Debug, Default)]
+pub enum Stdio {
+    #[default]
+    Null,
+    // Redirected to the host file. The input is closed at the end of the file.
+    File(PathBuf),
+    // Exposed on a data socket of the container to attach with `AkariClient::logs`
+    Socket,
+    // Like `Socket`, and the terminal session is recorded.
+    RecordedSocket,
+    // Passed to the server as it is, e.g. the console socket of the OCI runtime CLI
+    Uri(String),
+}
+
+impl Stdio {
+    pub(crate) fn uri(&self) -> std::io::Result<String> {
+        Ok(match self {
+            Stdio::Null => String::new(),
+            // The server runs in another directory.
+            Stdio::File(path) => file_uri(&std::path::absolute(path)?),
+            Stdio::Socket => socket_uri(false).to_string(),
+            Stdio::RecordedSocket => socket_uri(true).to_string(),
+            Stdio::Uri(uri) => uri.clone(),
+        })
+    }
+}
@@ -0,0 +1,84 @@
+/* SPDX-License-Identifier: Apache-2.0 */
+/* Copyright (C) 2024 Akira Moroo */
+
+/*
+ * C API of the akari client.
+ *
+ * The functions return 0, or a file descriptor, on success and -1 on failure.
+ * akari_last_error() describes the last failure on the calling thread.
+ * The strings are NUL-terminated UTF-8 and are only borrowed for the call.
+ * A client may be used from several threads but must not be used after
+ * akari_client_free().
+ */
+
+#ifndef AKARI_H
+#define AKARI_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct akari_client akari_client;
+
+enum akari_stdio_stream {
+    AKARI_STDIN = 0,
+    AKARI_STDOUT = 1,
+    AKARI_STDERR = 2,
+};
+
+/*
+ * Return the message of the last failure on the calling thread, or NULL.
+ * The message is valid until the next failure on the thread.
+ */
+const char *akari_last_error(void);
+
+/*
+ * Connect to the server of the root directory. NULL selects the default root,
+ * $AKARI_ROOT or ~/.akari/run. Return NULL on failure.
+ */
+akari_client *akari_client_connect(const char *root_path);
+
+/* Close the connection. NULL is ignored. */
+void akari_client_free(akari_client *client);
+
+/*
+ * Create the container from the bundle directory with config.json.
+ * Each stdio is a stdio URI or NULL:
+ *   "file:///path/to/file"  redirect to the absolute host path
+ *   "socket://"             expose on a data socket to read with akari_logs()
+ *   "socket://?record"      also record the terminal session
+ * pid receives the pid of the container process unless it is NULL.
+ */
+int akari_create(const akari_client *client, const char *id, const char *bundle,
+                 const char *stdin_uri, const char *stdout_uri, const char *stderr_uri,
+                 bool terminal, uint32_t *pid);
+
+/* Start the container. pid receives its pid unless it is NULL. */
+int akari_start(const akari_client *client, const char *id, uint32_t *pid);
+
+/*
+ * Wait for the container, or the exec process unless exec_id is NULL, to exit.
+ * exit_status receives its exit status unless it is NULL.
+ */
+int akari_wait(const akari_client *client, const char *id, const char *exec_id,
+               uint32_t *exit_status);
+
+/*
+ * Attach to the stdio stream that was created with "socket://" and return its
+ * blocking file descriptor. The caller closes it.
+ */
+int akari_logs(const akari_client *client, const char *id, const char *exec_id,
+               enum akari_stdio_stream stream);
+
+int akari_kill(const akari_client *client, const char *id);
+
+int akari_delete(const akari_client *client, const char *id);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* AKARI_H */
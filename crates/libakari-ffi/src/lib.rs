@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! C ABI of the akari client for the CI scripts that are not written in Rust.
+//! The functions return 0, or a file descriptor, on success and -1 on failure.
+//! `akari_last_error` describes the last failure on the calling thread.
+//! See include/akari.h for the declarations.
+
+// The safety requirements of the pointers are documented in include/akari.h.
+#![allow(clippy::missing_safety_doc)]
+
+#[cfg(feature = "python")]
+mod python;
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    fmt::Display,
+    os::fd::{IntoRawFd, RawFd},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libakari::{path, stdio::StdioStream};
+use libakari_client::{AkariClient, CreateOptions, Stdio};
+use tokio::runtime::Runtime;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("{0} is null")]
+    NullPointer(&'static str),
+    #[error("{0} is not valid UTF-8")]
+    InvalidString(&'static str),
+    #[error("Invalid stdio stream {0}")]
+    InvalidStream(c_int),
+    #[error("Invalid root path: {0}")]
+    RootPath(anyhow::Error),
+    #[error(transparent)]
+    Client(#[from] libakari_client::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// The stdio of the container process. Each stream is a stdio URI of the server, e.g.
+// "file:///path/to/log" or "socket://", or none.
+#[derive(Default)]
+pub struct StdioUris<'a> {
+    pub stdin: Option<&'a str>,
+    pub stdout: Option<&'a str>,
+    pub stderr: Option<&'a str>,
+}
+
+// The client with its own runtime so that the callers need not run an async executor.
+pub struct Client {
+    // Dropped before the runtime that serves its connection
+    client: AkariClient,
+    runtime: Runtime,
+}
+
+impl Client {
+    pub fn connect(root_path: Option<PathBuf>) -> Result<Self, Error> {
+        let root_path = path::root_path(root_path).map_err(Error::RootPath)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+        // The RPC client spawns its reader on the current runtime.
+        let client = {
+            let _guard = runtime.enter();
+            AkariClient::connect(&root_path)?
+        };
+        Ok(Self { client, runtime })
+    }
+
+    pub fn create(
+        &self,
+        id: &str,
+        bundle: &Path,
+        stdio: &StdioUris,
+        terminal: bool,
+    ) -> Result<u32, Error> {
+        let uri = |uri: Option<&str>| {
+            uri.map(|uri| Stdio::Uri(uri.to_string()))
+                .unwrap_or_default()
+        };
+        let options = CreateOptions {
+            bundle: bundle.to_path_buf(),
+            terminal,
+            stdin: uri(stdio.stdin),
+            stdout: uri(stdio.stdout),
+            stderr: uri(stdio.stderr),
+            ..Default::default()
+        };
+        Ok(self.runtime.block_on(self.client.create(id, &options))?)
+    }
+
+    pub fn start(&self, id: &str) -> Result<u32, Error> {
+        Ok(self.runtime.block_on(self.client.start(id))?)
+    }
+
+    pub fn wait(&self, id: &str, exec_id: Option<&str>) -> Result<u32, Error> {
+        Ok(self.runtime.block_on(self.client.wait(id, exec_id))?)
+    }
+
+    // Return a blocking file descriptor of the stream, owned by the caller.
+    pub fn logs(
+        &self,
+        id: &str,
+        exec_id: Option<&str>,
+        stream: StdioStream,
+    ) -> Result<RawFd, Error> {
+        self.runtime.block_on(async {
+            let stream = self.client.logs(id, exec_id, stream).await?.into_std()?;
+            stream.set_nonblocking(false)?;
+            Ok(stream.into_raw_fd())
+        })
+    }
+
+    pub fn kill(&self, id: &str) -> Result<(), Error> {
+        Ok(self.runtime.block_on(self.client.kill(id))?)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), Error> {
+        Ok(self.runtime.block_on(self.client.delete(id))?)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: impl Display) {
+    let message = CString::new(e.to_string().replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Run the call and map its error to -1.
+fn status(f: impl FnOnce() -> Result<c_int, Error>) -> c_int {
+    f().unwrap_or_else(|e| {
+        set_last_error(e);
+        -1
+    })
+}
+
+// Store the value to the out parameter unless it is null.
+unsafe fn output(out: *mut u32, value: u32) -> c_int {
+    if let Some(out) = out.as_mut() {
+        *out = value;
+    }
+    0
+}
+
+unsafe fn optional<'a>(s: *const c_char, name: &'static str) -> Result<Option<&'a str>, Error> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map(Some)
+        .map_err(|_| Error::InvalidString(name))
+}
+
+unsafe fn required<'a>(s: *const c_char, name: &'static str) -> Result<&'a str, Error> {
+    optional(s, name)?.ok_or(Error::NullPointer(name))
+}
+
+unsafe fn client_ref<'a>(client: *const Client) -> Result<&'a Client, Error> {
+    client.as_ref().ok_or(Error::NullPointer("client"))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_client_connect(root_path: *const c_char) -> *mut Client {
+    let res = optional(root_path, "root_path")
+        .and_then(|root_path| Client::connect(root_path.map(PathBuf::from)));
+    match res {
+        Ok(client) => Box::into_raw(Box::new(client)),
+        Err(e) => {
+            set_last_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_client_free(client: *mut Client) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn akari_create(
+    client: *const Client,
+    id: *const c_char,
+    bundle: *const c_char,
+    stdin: *const c_char,
+    stdout: *const c_char,
+    stderr: *const c_char,
+    terminal: bool,
+    pid: *mut u32,
+) -> c_int {
+    status(|| {
+        let stdio = StdioUris {
+            stdin: optional(stdin, "stdin")?,
+            stdout: optional(stdout, "stdout")?,
+            stderr: optional(stderr, "stderr")?,
+        };
+        let bundle = Path::new(required(bundle, "bundle")?);
+        let res = client_ref(client)?.create(required(id, "id")?, bundle, &stdio, terminal)?;
+        Ok(output(pid, res))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_start(
+    client: *const Client,
+    id: *const c_char,
+    pid: *mut u32,
+) -> c_int {
+    status(|| Ok(output(pid, client_ref(client)?.start(required(id, "id")?)?)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_wait(
+    client: *const Client,
+    id: *const c_char,
+    exec_id: *const c_char,
+    exit_status: *mut u32,
+) -> c_int {
+    status(|| {
+        let res = client_ref(client)?.wait(required(id, "id")?, optional(exec_id, "exec_id")?)?;
+        Ok(output(exit_status, res))
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_logs(
+    client: *const Client,
+    id: *const c_char,
+    exec_id: *const c_char,
+    stream: c_int,
+) -> c_int {
+    status(|| {
+        let stream = match stream {
+            0 => StdioStream::Stdin,
+            1 => StdioStream::Stdout,
+            2 => StdioStream::Stderr,
+            _ => return Err(Error::InvalidStream(stream)),
+        };
+        client_ref(client)?.logs(required(id, "id")?, optional(exec_id, "exec_id")?, stream)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_kill(client: *const Client, id: *const c_char) -> c_int {
+    status(|| client_ref(client)?.kill(required(id, "id")?).map(|_| 0))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn akari_delete(client: *const Client, id: *const c_char) -> c_int {
+    status(|| client_ref(client)?.delete(required(id, "id")?).map(|_| 0))
+}
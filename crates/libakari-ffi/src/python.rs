@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! `akari` Python module with the same calls as the C ABI.
+//! The calls release the GIL while they wait for the server.
+
+use std::{os::fd::RawFd, path::PathBuf};
+
+use libakari::stdio::StdioStream;
+use pyo3::{exceptions::PyRuntimeError, prelude::*};
+
+use crate::{Client, Error, StdioUris};
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        PyRuntimeError::new_err(e.to_string())
+    }
+}
+
+#[pyclass(name = "Client", frozen)]
+struct PyClient(Client);
+
+#[pymethods]
+impl PyClient {
+    #[new]
+    #[pyo3(signature = (root_path=None))]
+    fn new(py: Python<'_>, root_path: Option<PathBuf>) -> PyResult<Self> {
+        Ok(Self(py.allow_threads(|| Client::connect(root_path))?))
+    }
+
+    #[pyo3(signature = (id, bundle, stdin=None, stdout=None, stderr=None, terminal=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn create(
+        &self,
+        py: Python<'_>,
+        id: &str,
+        bundle: PathBuf,
+        stdin: Option<&str>,
+        stdout: Option<&str>,
+        stderr: Option<&str>,
+        terminal: bool,
+    ) -> PyResult<u32> {
+        let stdio = StdioUris {
+            stdin,
+            stdout,
+            stderr,
+        };
+        Ok(py.allow_threads(|| self.0.create(id, &bundle, &stdio, terminal))?)
+    }
+
+    fn start(&self, py: Python<'_>, id: &str) -> PyResult<u32> {
+        Ok(py.allow_threads(|| self.0.start(id))?)
+    }
+
+    #[pyo3(signature = (id, exec_id=None))]
+    fn wait(&self, py: Python<'_>, id: &str, exec_id: Option<&str>) -> PyResult<u32> {
+        Ok(py.allow_threads(|| self.0.wait(id, exec_id))?)
+    }
+
+    // Return the file descriptor of the stream for `os.fdopen`.
+    #[pyo3(signature = (id, stream="stdout", exec_id=None))]
+    fn logs(
+        &self,
+        py: Python<'_>,
+        id: &str,
+        stream: &str,
+        exec_id: Option<&str>,
+    ) -> PyResult<RawFd> {
+        let stream = match stream {
+            "stdin" => StdioStream::Stdin,
+            "stdout" => StdioStream::Stdout,
+            "stderr" => StdioStream::Stderr,
+            _ => {
+                return Err(PyRuntimeError::new_err(format!(
+                    "Invalid stdio stream {}",
+                    stream
+                )))
+            }
+        };
+        Ok(py.allow_threads(|| self.0.logs(id, exec_id, stream))?)
+    }
+
+    fn kill(&self, py: Python<'_>, id: &str) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.0.kill(id))?)
+    }
+
+    fn delete(&self, py: Python<'_>, id: &str) -> PyResult<()> {
+        Ok(py.allow_threads(|| self.0.delete(id))?)
+    }
+}
+
+#[pymodule]
+fn akari(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyClient>()
+}
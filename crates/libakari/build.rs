@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::process::Command;
+
+fn main() {
+    // Rebuild when another commit is checked out.
+    println!("cargo:rerun-if-changed=../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../.git/refs");
+    // The source tarballs have no git, so the hash is optional.
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success());
+    if let Some(output) = output {
+        let hash = String::from_utf8_lossy(&output.stdout);
+        println!("cargo:rustc-env=AKARI_GIT_HASH={}", hash.trim());
+    }
+}
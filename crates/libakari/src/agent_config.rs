@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Configuration of the guest agent.
+//! The agent reads the file at `DEFAULT_AGENT_CONFIG_PATH` of the guest, and the `akari.*` boot
+//! arguments override it, e.g. `akari.log_level=debug`. The server may send `AgentSettings` on
+//! its first connection to change the settings that apply without a restart.
+
+use std::{
+    num::ParseIntError,
+    path::{Path, PathBuf},
+    str::ParseBoolError,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    console::DEFAULT_AGENT_CONSOLE_PATH,
+    vsock::{self, VsockPort, DEFAULT_AGENT_PORT, DEFAULT_CONTAINER_PORT_BASE},
+    vsock_auth::guest_token_path,
+};
+
+// Path of the configuration file inside the guest
+pub const DEFAULT_AGENT_CONFIG_PATH: &str = "/etc/akari/agent.json";
+// Prefix of the boot arguments for the agent
+const BOOT_ARG_PREFIX: &str = "akari.";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error(transparent)]
+    Port(#[from] vsock::Error),
+    #[error(transparent)]
+    ParseInt(#[from] ParseIntError),
+    #[error(transparent)]
+    ParseBool(#[from] ParseBoolError),
+    #[error("Unknown boot argument: {0}")]
+    UnknownBootArg(String),
+}
+
+// Commands that the agent serves besides the containers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AgentFeatures {
+    // Run commands in the guest OS outside the containers.
+    pub debug_exec: bool,
+    // Run the provisioning scripts of the host as root.
+    pub provision: bool,
+    // Take and revert the snapshots of the data volume.
+    pub snapshots: bool,
+}
+
+impl Default for AgentFeatures {
+    fn default() -> Self {
+        Self {
+            debug_exec: true,
+            provision: true,
+            snapshots: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AgentConfig {
+    // Vsock port to listen on
+    pub port: VsockPort,
+    // First vsock port assigned to the containers
+    pub container_port_base: VsockPort,
+    // e.g. "debug". RUST_LOG takes precedence if set.
+    pub log_level: String,
+    // Directory of the state of the agent, e.g. the process table
+    pub data_dir: PathBuf,
+    // Data volume to snapshot before risky operations and to report the free space of
+    pub data_volume: PathBuf,
+    // Named virtio-console port to also serve the commands on if it exists
+    pub console_port: PathBuf,
+    // Refuse to create more containers than this
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_containers: Option<usize>,
+    pub features: AgentFeatures,
+    // Token that the server shares to authenticate the agent on vsock
+    pub auth_token: PathBuf,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_AGENT_PORT,
+            container_port_base: DEFAULT_CONTAINER_PORT_BASE,
+            log_level: "info".to_string(),
+            data_dir: PathBuf::from("/var/run/akari"),
+            data_volume: PathBuf::from("/System/Volumes/Data"),
+            console_port: PathBuf::from(DEFAULT_AGENT_CONSOLE_PATH),
+            max_containers: None,
+            features: AgentFeatures::default(),
+            auth_token: guest_token_path(),
+        }
+    }
+}
+
+impl AgentConfig {
+    // Load the file, or the defaults if there is none.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    // Apply the `akari.<key>=<value>` arguments of the kernel command line or the boot-args.
+    // The other arguments belong to the OS.
+    pub fn apply_boot_args(&mut self, args: &str) -> Result<(), Error> {
+        for arg in args.split_whitespace() {
+            let Some((key, value)) = arg
+                .strip_prefix(BOOT_ARG_PREFIX)
+                .and_then(|arg| arg.split_once('='))
+            else {
+                continue;
+            };
+            match key {
+                "port" => self.port = value.parse()?,
+                "container_port_base" => self.container_port_base = value.parse()?,
+                "log_level" => self.log_level = value.to_string(),
+                "data_dir" => self.data_dir = PathBuf::from(value),
+                "data_volume" => self.data_volume = PathBuf::from(value),
+                "console_port" => self.console_port = PathBuf::from(value),
+                "max_containers" => self.max_containers = Some(value.parse()?),
+                "debug_exec" => self.features.debug_exec = value.parse()?,
+                "provision" => self.features.provision = value.parse()?,
+                "snapshots" => self.features.snapshots = value.parse()?,
+                "auth_token" => self.auth_token = PathBuf::from(value),
+                _ => return Err(Error::UnknownBootArg(arg.to_string())),
+            }
+        }
+        Ok(())
+    }
+
+    // Apply the settings sent by the server.
+    pub fn apply(&mut self, settings: &AgentSettings) {
+        if let Some(log_level) = &settings.log_level {
+            self.log_level = log_level.clone();
+        }
+        if let Some(max_containers) = settings.max_containers {
+            self.max_containers = Some(max_containers);
+        }
+        if let Some(features) = settings.features {
+            self.features = features;
+        }
+    }
+
+    // Return the path to persist the process table so that a restarted agent can re-adopt the
+    // processes.
+    pub fn state_path(&self) -> PathBuf {
+        self.data_dir.join("agent.json")
+    }
+}
+
+// Settings of the agent that the server may change at runtime. The unset ones are kept.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AgentSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_containers: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<AgentFeatures>,
+}
+
+impl AgentSettings {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
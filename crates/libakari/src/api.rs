@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Admin API of the server.
+//! The requests that are not part of the containerd shim v2 API are served on `api.sock`.
+
+use std::{
+    collections::BTreeMap,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::EventRecord,
+    exec::ExecProcess,
+    framing::{self, ReadFrom, WriteTo},
+    lifecycle::Timestamps,
+    metrics::{ContainerMetrics, GuestStats, ProcessUsage},
+    port_forward::PortMapping,
+    probe::ContainerHealth,
+    stdio::{DataSocket, StdioStream},
+    task_options::TaskOptions,
+    timeout::ExitReason,
+    user::{self, check_owner},
+    vm_rpc::VmStatus,
+    vsock::VsockPort,
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ApiRequest {
+    // Open a window that shows the VM display.
+    ShowWindow,
+    // Stream the server events until the connection is closed. The warnings of the startup, e.g.
+    // `VmCpusClamped`, are sent first.
+    SubscribeEvents,
+    // Stop the VM and delete its configuration and disk images.
+    // A protected VM is deleted only if `force` is set.
+    #[serde(rename_all = "camelCase")]
+    DeleteVm {
+        force: bool,
+    },
+    // Drain the agent and stop the VM, e.g. before an upgrade. The agent refuses new containers
+    // and waits up to `timeout` seconds for the running ones to exit.
+    #[serde(rename_all = "camelCase")]
+    ShutdownVm {
+        #[serde(default)]
+        timeout: Option<u64>,
+    },
+    // Kill all the containers that have not stopped.
+    KillAll,
+    // Delete all the containers. The running ones are killed first only if `force` is set.
+    #[serde(rename_all = "camelCase")]
+    DeleteAll {
+        force: bool,
+    },
+    // Delete the stopped containers that exited at least `older_than` seconds ago.
+    #[serde(rename_all = "camelCase")]
+    Prune {
+        older_than: u64,
+    },
+    // List the containers and their exec processes.
+    ListContainers,
+    // Report the state of the container beyond the OCI state of the shim API.
+    #[serde(rename_all = "camelCase")]
+    ContainerState {
+        id: String,
+    },
+    // Stream the current status of the container and its changes until it stops.
+    #[serde(rename_all = "camelCase")]
+    WatchContainer {
+        id: String,
+    },
+    // Extended states of the containers, or of all the containers if `ids` is empty, with one
+    // request to the guest for each container in parallel.
+    BatchState {
+        #[serde(default)]
+        ids: Vec<String>,
+    },
+    // Create and start the container in one request. The server deletes the container if it
+    // fails to start, so no half-created container is left behind.
+    RunContainer(Box<RunRequest>),
+    // Reload `server.json` without restarting the server.
+    ReloadConfig,
+    // Take a snapshot of the guest data volume before risky operations.
+    #[serde(rename_all = "camelCase")]
+    SnapshotVm {
+        name: String,
+    },
+    // Revert the guest data volume to the snapshot. This takes effect after the VM restarts.
+    #[serde(rename_all = "camelCase")]
+    RollbackVm {
+        name: String,
+    },
+    // Save the disk images and the machine state of the running VM as the golden VM, which the
+    // VMs cloned with `vmm::clone::clone_vm` resume from instead of booting.
+    SaveGolden,
+    // Report the resource usage of the guest.
+    GuestStats,
+    // Report the version and the pid of the server.
+    Version,
+    // Report the build of the server and the features enabled for the VM.
+    Info,
+    // Run the command in the guest OS outside the containers and stream its output.
+    // The server refuses it unless `debugExec` is set in `server.json`.
+    #[serde(rename_all = "camelCase")]
+    DebugExec {
+        args: Vec<String>,
+    },
+    // Forward the host port to the guest until the rule is deleted. The rule is kept in vm.json.
+    #[serde(rename_all = "camelCase")]
+    AddNatRule {
+        mapping: PortMapping,
+    },
+    #[serde(rename_all = "camelCase")]
+    DeleteNatRule {
+        mapping: PortMapping,
+    },
+    ListNatRules,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ApiResponse {
+    Ok,
+    Error(String),
+    Event(EventRecord),
+    // IDs of the pruned containers
+    Pruned(Vec<String>),
+    BatchResults(Vec<BatchResult>),
+    Containers(Vec<ContainerInfo>),
+    ContainerState(ExtendedState),
+    ContainerStates(Vec<ExtendedState>),
+    ContainerStatus(VmStatus),
+    // Pid of the started container process
+    Started {
+        pid: u32,
+    },
+    // Names of the reloaded settings that take effect only after a restart
+    Reloaded(Vec<String>),
+    GuestStats(GuestStats),
+    Version {
+        version: String,
+        pid: u32,
+    },
+    Info(ServerInfo),
+    Output(StdioStream, Vec<u8>),
+    Exited(i32),
+    NatRules(Vec<PortMapping>),
+}
+
+// Build of the server, to debug the installs that mix the versions of the shim, the server
+// and the agent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerInfo {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_hash: Option<String>,
+    pub pid: u32,
+    // Versions of the workspace crates linked into the server
+    pub crates: BTreeMap<String, String>,
+    // Features enabled for the VM, e.g. `networking` or `linux-guest`
+    pub features: Vec<String>,
+}
+
+// The fields of the shim create request that the clients set
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunRequest {
+    pub id: String,
+    // Absolute path to the bundle on the host
+    pub bundle: PathBuf,
+    #[serde(default)]
+    pub terminal: bool,
+    // Stdio URIs, see `stdio`
+    #[serde(default)]
+    pub stdin: String,
+    #[serde(default)]
+    pub stdout: String,
+    #[serde(default)]
+    pub stderr: String,
+    #[serde(default)]
+    pub options: TaskOptions,
+}
+
+// Result of an operation on one of the containers of a batch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchResult {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerInfo {
+    pub id: String,
+    pub status: VmStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub execs: Vec<ExecProcess>,
+    // Data sockets that clients can attach to the container stdio
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub data_sockets: Vec<DataSocket>,
+    #[serde(default)]
+    pub metrics: ContainerMetrics,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_reason: Option<ExitReason>,
+    // Results of the probes if the container has any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<ContainerHealth>,
+    // Annotations of config.json that label the container, and the guest IP address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    #[serde(flatten)]
+    pub timestamps: Timestamps,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtendedState {
+    #[serde(flatten)]
+    pub info: ContainerInfo,
+    // Name of the VM that runs the container
+    pub vm_id: String,
+    // Vsock port of the task service of the container
+    pub vsock_port: VsockPort,
+    // ID of the container process in the guest. Unknown if the guest is unreachable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_pid: Option<u32>,
+    // Resource usage of the container processes when the state was taken
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<ProcessUsage>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Framing(#[from] framing::Error),
+    #[error(transparent)]
+    User(#[from] user::Error),
+    #[error("Server error: {0}")]
+    Server(String),
+}
+
+// Send the request to the server and wait for the response.
+pub fn call(api_sock_path: &Path, req: &ApiRequest) -> Result<ApiResponse, Error> {
+    check_owner(api_sock_path)?;
+    let mut stream = UnixStream::connect(api_sock_path)?;
+    req.write_to(&mut stream)?;
+    match ApiResponse::read_from(&mut stream)? {
+        ApiResponse::Error(e) => Err(Error::Server(e)),
+        res => Ok(res),
+    }
+}
+
+// Subscribe to the server events and call the handler for each event.
+pub fn subscribe_events(
+    api_sock_path: &Path,
+    mut handler: impl FnMut(EventRecord),
+) -> Result<(), Error> {
+    check_owner(api_sock_path)?;
+    let mut stream = UnixStream::connect(api_sock_path)?;
+    ApiRequest::SubscribeEvents.write_to(&mut stream)?;
+    loop {
+        match ApiResponse::read_from(&mut stream) {
+            Ok(ApiResponse::Event(event)) => handler(event),
+            Ok(ApiResponse::Error(e)) => return Err(Error::Server(e)),
+            Ok(_) => {}
+            Err(framing::Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Ok(())
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Call the handler with the status of the container and its changes until it stops.
+pub fn watch_container(
+    api_sock_path: &Path,
+    id: &str,
+    mut handler: impl FnMut(&VmStatus),
+) -> Result<(), Error> {
+    check_owner(api_sock_path)?;
+    let mut stream = UnixStream::connect(api_sock_path)?;
+    ApiRequest::WatchContainer { id: id.to_string() }.write_to(&mut stream)?;
+    loop {
+        match ApiResponse::read_from(&mut stream)? {
+            ApiResponse::ContainerStatus(status) => {
+                handler(&status);
+                if matches!(status, VmStatus::Stopped) {
+                    return Ok(());
+                }
+            }
+            ApiResponse::Error(e) => return Err(Error::Server(e)),
+            _ => {}
+        }
+    }
+}
+
+// Run the command in the guest, call the handler for each output and return the exit code.
+pub fn debug_exec(
+    api_sock_path: &Path,
+    args: Vec<String>,
+    mut handler: impl FnMut(StdioStream, &[u8]),
+) -> Result<i32, Error> {
+    check_owner(api_sock_path)?;
+    let mut stream = UnixStream::connect(api_sock_path)?;
+    ApiRequest::DebugExec { args }.write_to(&mut stream)?;
+    loop {
+        match ApiResponse::read_from(&mut stream)? {
+            ApiResponse::Output(output, data) => handler(output, &data),
+            ApiResponse::Exited(code) => return Ok(code),
+            ApiResponse::Error(e) => return Err(Error::Server(e)),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,152 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Recording of the terminal sessions in the asciicast v2 format.
+//! A recording starts with the header line followed by one `[time, code, data]` line per event,
+//! so it can also be played with asciinema.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+// The size of the terminal is unknown to the host.
+const DEFAULT_WIDTH: u16 = 80;
+const DEFAULT_HEIGHT: u16 = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    IoError(#[from] std::io::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error("Unsupported asciicast version {0}")]
+    UnsupportedVersion(u32),
+    #[error("Recording has no header")]
+    MissingHeader,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u32,
+    pub width: u16,
+    pub height: u16,
+    // Seconds since the Unix epoch when the recording started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventCode {
+    #[serde(rename = "o")]
+    Output,
+    #[serde(rename = "i")]
+    Input,
+    #[serde(rename = "r")]
+    Resize,
+    #[serde(rename = "m")]
+    Marker,
+}
+
+// Seconds since the start, the event code, and the data.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Event(pub f64, pub EventCode, pub String);
+
+struct Writer {
+    file: File,
+    // Trailing bytes of an incomplete UTF-8 sequence per stream
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+// Appends the events of a session to the recording.
+// The input and the output arrive on different streams, so the recorder is shared.
+pub struct Recorder {
+    writer: Mutex<Writer>,
+    started: Instant,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, title: &str) -> Result<Self, Error> {
+        let mut file = File::create(path)?;
+        let header = Header {
+            version: 2,
+            width: DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs()),
+            title: Some(title.to_string()),
+        };
+        writeln!(file, "{}", serde_json::to_string(&header)?)?;
+        Ok(Self {
+            writer: Mutex::new(Writer {
+                file,
+                pending_output: Vec::new(),
+                pending_input: Vec::new(),
+            }),
+            started: Instant::now(),
+        })
+    }
+
+    // Record the bytes of the stream. The file is written per event so that the recording
+    // survives a killed server.
+    pub fn record(&self, code: EventCode, data: &[u8]) -> Result<(), Error> {
+        let time = self.started.elapsed().as_secs_f64();
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let pending = match code {
+            EventCode::Input => &mut writer.pending_input,
+            _ => &mut writer.pending_output,
+        };
+        pending.extend_from_slice(data);
+        let text = take_utf8(pending);
+        if text.is_empty() {
+            return Ok(());
+        }
+        let line = serde_json::to_string(&Event(time, code, text))?;
+        writeln!(writer.file, "{}", line)?;
+        Ok(())
+    }
+}
+
+// Take the decoded text and keep an incomplete sequence at the end for the next chunk.
+fn take_utf8(pending: &mut Vec<u8>) -> String {
+    let keep = match std::str::from_utf8(pending) {
+        Ok(_) => 0,
+        Err(e) if e.error_len().is_none() => pending.len() - e.valid_up_to(),
+        Err(_) => 0,
+    };
+    let rest = pending.split_off(pending.len() - keep);
+    let text = String::from_utf8_lossy(pending).into_owned();
+    *pending = rest;
+    text
+}
+
+// Load the header and the events of the recording.
+pub fn load(path: &Path) -> Result<(Header, Vec<Event>), Error> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => return Err(Error::MissingHeader),
+    };
+    if header.version != 2 {
+        return Err(Error::UnsupportedVersion(header.version));
+    }
+    let mut events = Vec::new();
+    for line in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line)?);
+    }
+    Ok((header, events))
+}
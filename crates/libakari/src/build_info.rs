@@ -0,0 +1,8 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Build of the binaries, to tell the components of a mixed-version install apart.
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+// Commit of the build, or none if built outside a git checkout
+pub const GIT_HASH: Option<&str> = option_env!("AKARI_GIT_HASH");
@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Control channel to the agent over a named virtio-console port.
+//! It is the fallback for the guests without vsock, such as the older macOS guests.
+//! A console port is a single byte stream without connections, so the host starts each exchange
+//! with a request and waits for its response before sending the next one. The commands and the
+//! responses are the same as on the agent port.
+
+use serde::{Deserialize, Serialize};
+
+use crate::container_rpc::ContainerCommand;
+
+// Name of the console port that the agent serves.
+pub const AGENT_CONSOLE_PORT_NAME: &str = "com.akari.agent";
+
+// Path of the named port in a Linux guest.
+pub const DEFAULT_AGENT_CONSOLE_PATH: &str = "/dev/virtio-ports/com.akari.agent";
+
+// Transports on which the agent accepts the commands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AgentTransport {
+    Vsock,
+    Console,
+}
+
+// Sent by the host on the console port in chunks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ConsoleRequest {
+    // Answered with the handshake as on a new vsock connection.
+    Handshake,
+    // Answered with the response of the command.
+    Command(ContainerCommand),
+}
@@ -1,14 +1,100 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+use std::{collections::BTreeMap, path::PathBuf};
+
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    agent_config::AgentSettings,
+    container_id::ContainerId,
+    guest_user::GuestUser,
+    metrics::GuestStats,
+    probe::{ContainerHealth, Probes},
+    resources::ResourceLimits,
+    secret::SecretsVolume,
+    stdio::StdioStream,
+};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum ContainerCommand {
-    Create(Box<oci_spec::runtime::Spec>),
-    Delete,
+    Create(ContainerId, Box<oci_spec::runtime::Spec>),
+    Delete(ContainerId),
+    // Run an auxiliary process in the container: (container ID, exec ID, process).
+    Exec(ContainerId, String, Box<oci_spec::runtime::Process>),
+    DeleteExec(ContainerId, String),
+    // Kill the processes of the container when the seconds elapsed: (container ID, seconds).
+    SetMaxRuntime(ContainerId, u64),
+    // Change the resource limits of the running container.
+    UpdateResources(ContainerId, ResourceLimits),
+    // Run the probes of the container in the guest until they are stopped.
+    StartProbes(ContainerId, Probes),
+    StopProbes(ContainerId),
+    // Report the results of the probes of the containers.
+    Health,
+    // Create the dedicated user of the container, or return the existing one.
+    // The agent answers with `User`.
+    CreateUser(ContainerId),
+    // Kill the processes of the dedicated user of the container and remove the user.
+    DeleteUser(ContainerId),
     Kill,
-    Start,
+    // Start the main process of the created container.
+    Start(ContainerId),
     State,
+    // Take an APFS snapshot of the guest data volume with the name.
+    Snapshot(String),
+    // Revert the guest data volume to the snapshot. This takes effect after the VM restarts.
+    Rollback(String),
+    // Report the resource usage of the guest.
+    Stats,
+    // Run the command in the guest OS outside the containers.
+    // The agent streams `Output` on vsock and ends with `Exited`.
+    DebugExec(Vec<String>),
+    // Run the provisioning script at the guest path as root.
+    // The agent streams `Output` on vsock and ends with `Exited` like `DebugExec`.
+    Provision(PathBuf),
+    // Drop the cached contents of the guest paths that changed on the host:
+    // (container ID, guest paths).
+    InvalidateCache(ContainerId, Vec<PathBuf>),
+    // Compose the rootfs of the container from the layer disks, lowest first.
+    MountLayers(ContainerId, Vec<String>),
+    // Remove the composed rootfs of the container. The layers stay mounted for the others.
+    UnmountLayers(ContainerId),
+    // Mount the shared directories that are not automounted: (tag, guest path).
+    MountShares(Vec<(String, PathBuf)>),
+    // Resolve the aliases of the network group in the guest: (group, aliases).
+    // No aliases remove the group.
+    SetHosts(String, Vec<String>),
+    // Unpack the bundle that follows the command on vsock into guest-local storage.
+    // The agent answers once the bundle is unpacked.
+    ReceiveBundle(ContainerId),
+    // Remove the streamed bundle of the container.
+    RemoveBundle(ContainerId),
+    // Unmount and remove the storage of the container.
+    RemoveStorage(ContainerId),
+    // Write the secrets to a new in-memory volume of the container and mount it read-only.
+    MountSecrets(ContainerId, SecretsVolume),
+    // Unmount the secrets volume of the container, which frees its memory.
+    UnmountSecrets(ContainerId),
+    // Change the settings of the agent. The server sends them on its first connection.
+    Configure(AgentSettings),
+    // Refuse new containers, wait up to the seconds for the running processes to exit, save the
+    // state and exit. The agent answers before it exits.
+    Shutdown(Option<u64>),
+}
+
+// Result of a command sent by the agent.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContainerResponse {
+    Ok,
+    Error(String),
+    Stats(GuestStats),
+    // Health of the containers with probes by their IDs
+    Health(BTreeMap<String, ContainerHealth>),
+    User(GuestUser),
+    Output(StdioStream, Vec<u8>),
+    // Exit code of the debug command
+    Exited(i32),
 }
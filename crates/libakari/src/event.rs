@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    probe::ContainerHealth, progress::Progress, timeout::ExitReason, vm_rpc::VmStatus,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MemoryPressureLevel {
+    Normal,
+    Warning,
+    Critical,
+}
+
+// Events emitted by the server.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum Event {
+    HostWillSleep,
+    HostDidWake,
+    VmPaused,
+    VmResumed,
+    MemoryPressure(MemoryPressureLevel),
+    // Free memory of the guest crossed a threshold of the server policy.
+    GuestMemoryPressure {
+        level: MemoryPressureLevel,
+        memory_free: u64,
+        memory_total: u64,
+    },
+    // Balloon target of the guest memory in bytes
+    MemoryTargetChanged(u64),
+    // The server started or stopped refusing new containers.
+    RefuseCreateChanged { refuse: bool },
+    ContainerExited {
+        id: String,
+        exit_status: u32,
+        #[serde(default)]
+        reason: ExitReason,
+    },
+    ContainerRestarted { id: String, restart_count: u32 },
+    // The container was created, started, restarted or exited.
+    ContainerStatusChanged { id: String, status: VmStatus },
+    ContainerDeleted { id: String },
+    // The result of a probe of the container changed.
+    ContainerHealthChanged { id: String, health: ContainerHealth },
+    Progress(Progress),
+    VmFailed { reason: String },
+    VmRestarted { restart_count: u32 },
+    // The CPU count of vm.json is out of the range of the host, so the VM has the nearest one.
+    VmCpusClamped { requested: usize, cpus: usize },
+    // The provisioning script exited, or failed to run if the exit code is none.
+    VmProvisioned { exit_code: Option<i32> },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventRecord {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub event: Event,
+}
+
+// Return the seconds since the Unix epoch.
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl EventRecord {
+    pub fn new(event: Event) -> Self {
+        Self {
+            timestamp: unix_timestamp(),
+            event,
+        }
+    }
+}
@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use serde::{Deserialize, Serialize};
+
+use crate::{event::unix_timestamp, lifecycle::Timestamps, timeout::ExitReason, vm_rpc::VmStatus};
+
+// An auxiliary process executed in a container.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecProcess {
+    pub exec_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    pub status: VmStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_reason: Option<ExitReason>,
+    #[serde(flatten)]
+    pub timestamps: Timestamps,
+}
+
+impl ExecProcess {
+    pub fn new(exec_id: &str) -> Self {
+        Self {
+            exec_id: exec_id.to_string(),
+            pid: None,
+            status: VmStatus::Created,
+            exit_code: None,
+            exit_reason: None,
+            timestamps: Timestamps {
+                created_at: Some(unix_timestamp()),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn start(&mut self, pid: u32) {
+        self.status = VmStatus::Running;
+        self.pid = Some(pid);
+        self.timestamps.started_at = Some(unix_timestamp());
+    }
+
+    // Mark the process as exited. The exit code of an adopted process is not available.
+    pub fn finish(&mut self, exit_code: Option<u32>) {
+        self.status = VmStatus::Stopped;
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+        }
+        self.timestamps.finished_at = Some(unix_timestamp());
+    }
+}
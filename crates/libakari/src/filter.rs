@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Filters of the container list.
+//! The labels are the annotations in config.json, e.g. `label=ci.job=1234` matches the
+//! containers annotated with `ci.job: 1234` and `label=ci.job` any value of `ci.job`.
+
+use std::str::FromStr;
+
+use crate::api::ContainerInfo;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid filter {0}; expected label=<key>[=<value>]")]
+    InvalidFilter(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ContainerFilter {
+    Label { key: String, value: Option<String> },
+}
+
+impl FromStr for ContainerFilter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some(("label", label)) if !label.is_empty() => {
+                let (key, value) = match label.split_once('=') {
+                    Some((key, value)) => (key, Some(value.to_string())),
+                    None => (label, None),
+                };
+                if key.is_empty() {
+                    return Err(Error::InvalidFilter(s.to_string()));
+                }
+                Ok(ContainerFilter::Label {
+                    key: key.to_string(),
+                    value,
+                })
+            }
+            _ => Err(Error::InvalidFilter(s.to_string())),
+        }
+    }
+}
+
+impl ContainerFilter {
+    pub fn matches(&self, container: &ContainerInfo) -> bool {
+        match self {
+            ContainerFilter::Label { key, value } => match container.annotations.get(key) {
+                Some(actual) => value.as_ref().is_none_or(|value| value == actual),
+                None => false,
+            },
+        }
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Length-prefixed JSON framing.
+//! Each frame consists of a 32-bit big-endian length followed by the JSON payload.
+//!
+//! Large messages such as OCI specs can be sent in chunks. Each chunk is a frame whose
+//! length has the most significant bit set if more chunks follow.
+
+use std::io::{Read, Write};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+    #[error("Frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("Message too large: {size} bytes exceeds the limit of {max} bytes")]
+    MessageTooLarge { size: usize, max: usize },
+    #[error("Empty chunk before the last chunk")]
+    EmptyChunk,
+}
+
+// Maximum size of a single frame.
+pub const MAX_FRAME_SIZE: usize = 4 * 1024 * 1024;
+// Maximum size of a chunk of a chunked message.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+// Maximum size of a chunked message.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+const MORE_CHUNKS: u32 = 1 << 31;
+
+// Check the length of a frame before allocating its payload.
+pub fn check_frame_len(len: u32) -> Result<usize, Error> {
+    let len = len as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(len));
+    }
+    Ok(len)
+}
+
+// Encode the message into a frame.
+pub fn encode<T: Serialize>(msg: &T) -> Result<Vec<u8>, Error> {
+    let payload = serde_json::to_vec(msg)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(Error::FrameTooLarge(payload.len()));
+    }
+    let len = payload.len() as u32;
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Decode the message from the payload of a frame.
+pub fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, Error> {
+    Ok(serde_json::from_slice(payload)?)
+}
+
+pub trait WriteTo {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error>;
+}
+
+pub trait ReadFrom: Sized {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error>;
+}
+
+impl<T: Serialize> WriteTo for T {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&encode(self)?)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl<T: DeserializeOwned> ReadFrom for T {
+    fn read_from<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let mut payload = vec![0u8; check_frame_len(u32::from_be_bytes(len))?];
+        reader.read_exact(&mut payload)?;
+        decode(&payload)
+    }
+}
+
+// Write the message in chunks of at most `CHUNK_SIZE` bytes.
+pub fn write_chunked<T: Serialize, W: Write>(msg: &T, writer: &mut W) -> Result<(), Error> {
+    let payload = serde_json::to_vec(msg)?;
+    if payload.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge {
+            size: payload.len(),
+            max: MAX_MESSAGE_SIZE,
+        });
+    }
+    // The JSON payload is never empty, so there is at least one chunk.
+    let mut chunks = payload.chunks(CHUNK_SIZE).peekable();
+    while let Some(chunk) = chunks.next() {
+        let mut len = chunk.len() as u32;
+        if chunks.peek().is_some() {
+            len |= MORE_CHUNKS;
+        }
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(chunk)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Read a chunked message of at most `max_size` bytes.
+pub fn read_chunked<T: DeserializeOwned, R: Read>(
+    reader: &mut R,
+    max_size: usize,
+) -> Result<T, Error> {
+    let mut payload = Vec::new();
+    loop {
+        let mut len = [0u8; 4];
+        reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        let chunk_len = (len & !MORE_CHUNKS) as usize;
+        if chunk_len > CHUNK_SIZE {
+            return Err(Error::FrameTooLarge(chunk_len));
+        }
+        // The writer never sends them, and a peer must not keep the reader spinning with them.
+        if chunk_len == 0 && len & MORE_CHUNKS != 0 {
+            return Err(Error::EmptyChunk);
+        }
+        let size = payload.len() + chunk_len;
+        if size > max_size {
+            return Err(Error::MessageTooLarge {
+                size,
+                max: max_size,
+            });
+        }
+        let start = payload.len();
+        payload.resize(size, 0);
+        reader.read_exact(&mut payload[start..])?;
+        if len & MORE_CHUNKS == 0 {
+            break;
+        }
+    }
+    decode(&payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Split a chunked message into the headers and lengths of its chunks.
+    fn chunks(buf: &[u8]) -> Vec<(bool, usize)> {
+        let mut chunks = Vec::new();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap());
+            let chunk_len = (len & !MORE_CHUNKS) as usize;
+            chunks.push((len & MORE_CHUNKS != 0, chunk_len));
+            rest = &rest[4 + chunk_len..];
+        }
+        chunks
+    }
+
+    // A JSON string whose payload is exactly `len` bytes.
+    fn message(len: usize) -> String {
+        "a".repeat(len - 2)
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let msg = vec!["hello".to_string(), "world".to_string()];
+        let mut buf = Vec::new();
+        msg.write_to(&mut buf).unwrap();
+        assert_eq!(&buf[..4], &(buf.len() as u32 - 4).to_be_bytes());
+        assert_eq!(Vec::<String>::read_from(&mut &buf[..]).unwrap(), msg);
+    }
+
+    #[test]
+    fn frame_limit() {
+        assert_eq!(
+            check_frame_len(MAX_FRAME_SIZE as u32).unwrap(),
+            MAX_FRAME_SIZE
+        );
+        assert!(matches!(
+            check_frame_len(MAX_FRAME_SIZE as u32 + 1),
+            Err(Error::FrameTooLarge(_))
+        ));
+        assert!(matches!(
+            encode(&message(MAX_FRAME_SIZE + 1)),
+            Err(Error::FrameTooLarge(_))
+        ));
+
+        let mut buf = (MAX_FRAME_SIZE as u32 + 1).to_be_bytes().to_vec();
+        buf.extend_from_slice(b"\"\"");
+        assert!(matches!(
+            String::read_from(&mut &buf[..]),
+            Err(Error::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        for len in [
+            2,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let msg = message(len);
+            let mut buf = Vec::new();
+            write_chunked(&msg, &mut buf).unwrap();
+            let read: String = read_chunked(&mut &buf[..], MAX_MESSAGE_SIZE).unwrap();
+            assert_eq!(read, msg);
+        }
+    }
+
+    #[test]
+    fn chunked_continuation_flag() {
+        let mut buf = Vec::new();
+        write_chunked(&message(CHUNK_SIZE), &mut buf).unwrap();
+        assert_eq!(chunks(&buf), vec![(false, CHUNK_SIZE)]);
+
+        let mut buf = Vec::new();
+        write_chunked(&message(2 * CHUNK_SIZE + 1), &mut buf).unwrap();
+        assert_eq!(
+            chunks(&buf),
+            vec![(true, CHUNK_SIZE), (true, CHUNK_SIZE), (false, 1)]
+        );
+    }
+
+    #[test]
+    fn chunked_larger_than_frame() {
+        // Chunking lets a message exceed the limit of a single frame.
+        let msg = message(MAX_FRAME_SIZE + 1);
+        assert!(encode(&msg).is_err());
+
+        let mut buf = Vec::new();
+        write_chunked(&msg, &mut buf).unwrap();
+        let chunks = chunks(&buf);
+        assert_eq!(chunks.len(), MAX_FRAME_SIZE / CHUNK_SIZE + 1);
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|&chunk| chunk == (true, CHUNK_SIZE)));
+        assert_eq!(chunks.last(), Some(&(false, 1)));
+        let read: String = read_chunked(&mut &buf[..], MAX_MESSAGE_SIZE).unwrap();
+        assert_eq!(read, msg);
+    }
+
+    #[test]
+    fn chunked_message_limit() {
+        let mut buf = Vec::new();
+        assert!(matches!(
+            write_chunked(&message(MAX_MESSAGE_SIZE + 1), &mut buf),
+            Err(Error::MessageTooLarge { .. })
+        ));
+        assert!(buf.is_empty());
+
+        // The reader stops at the limit before reading the rest of the message.
+        let mut buf = Vec::new();
+        write_chunked(&message(2 * CHUNK_SIZE + 1), &mut buf).unwrap();
+        assert!(matches!(
+            read_chunked::<String, _>(&mut &buf[..], 2 * CHUNK_SIZE),
+            Err(Error::MessageTooLarge { size, max }) if size == 2 * CHUNK_SIZE + 1 && max == 2 * CHUNK_SIZE
+        ));
+    }
+
+    #[test]
+    fn chunked_malformed() {
+        // A chunk larger than the chunk size
+        let buf = (CHUNK_SIZE as u32 + 1).to_be_bytes();
+        assert!(matches!(
+            read_chunked::<String, _>(&mut &buf[..], MAX_MESSAGE_SIZE),
+            Err(Error::FrameTooLarge(_))
+        ));
+
+        // An empty chunk with more chunks following
+        let buf = MORE_CHUNKS.to_be_bytes();
+        assert!(matches!(
+            read_chunked::<String, _>(&mut &buf[..], MAX_MESSAGE_SIZE),
+            Err(Error::EmptyChunk)
+        ));
+
+        // A truncated chunk
+        let mut buf = 4u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(b"\"a");
+        assert!(matches!(
+            read_chunked::<String, _>(&mut &buf[..], MAX_MESSAGE_SIZE),
+            Err(Error::Io(_))
+        ));
+    }
+}
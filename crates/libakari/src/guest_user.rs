@@ -0,0 +1,40 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Dedicated guest users of the containers.
+//! The agent creates a user with a uid of its own for each container that asks for one, so that
+//! the processes of the containers never share a uid, and removes it when the container is
+//! deleted.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// Annotation to run the processes of the container as a dedicated user:
+// `org.akari.dedicated-user=true`.
+pub const DEDICATED_USER_ANNOTATION: &str = "org.akari.dedicated-user";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid value of {DEDICATED_USER_ANNOTATION}: {0:?}, expected true or false")]
+    InvalidValue(String),
+}
+
+pub fn is_enabled(annotations: &HashMap<String, String>) -> Result<bool, Error> {
+    match annotations
+        .get(DEDICATED_USER_ANNOTATION)
+        .map(String::as_str)
+    {
+        Some("true") => Ok(true),
+        Some("false") | None => Ok(false),
+        Some(value) => Err(Error::InvalidValue(value.to_string())),
+    }
+}
+
+// User of a container in the guest. The primary group has the same ID as the user.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestUser {
+    pub name: String,
+    pub uid: u32,
+}
@@ -0,0 +1,103 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Rootfs composed from image layers attached to the VM as read-only disk images.
+//! The layers are `layer` storages of vm.json, and the agent stacks them with overlayfs
+//! instead of reading the rootfs over virtiofs.
+
+use std::path::PathBuf;
+
+use oci_spec::runtime::Spec;
+
+use crate::{container_id::ContainerId, vm_config::MacosVmConfig};
+
+// Annotation to compose the rootfs from the layers, lowest first:
+// `org.akari.rootfs-layers=base,toolchain`.
+pub const ROOTFS_LAYERS_ANNOTATION: &str = "org.akari.rootfs-layers";
+// Storage type of vm.json for the layer disk images
+pub const LAYER_STORAGE_TYPE: &str = "layer";
+
+// The name is the block device identifier of virtio, which is limited to 20 bytes.
+const MAX_LAYER_NAME_LEN: usize = 20;
+// Directory inside the guest where the layers are mounted
+const GUEST_LAYERS_PATH: &str = "/run/akari/layers";
+// Directory inside the guest where the rootfs of the containers are composed
+const GUEST_ROOTFS_PATH: &str = "/run/akari/rootfs";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid layer name: {0}")]
+    InvalidLayerName(String),
+    #[error("Layer {0} is not attached to the VM")]
+    NotAttached(String),
+    #[error("Layer {0} is listed twice")]
+    Duplicate(String),
+}
+
+// Layer names are used as device identifiers and directory names.
+pub fn validate_layer_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_LAYER_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidLayerName(name.to_string()))
+    }
+}
+
+// Return the layers of the rootfs, lowest first, or none if the rootfs is used as it is.
+pub fn rootfs_layers(spec: &Spec) -> Result<Vec<String>, Error> {
+    let Some(value) = spec
+        .annotations()
+        .as_ref()
+        .and_then(|annotations| annotations.get(ROOTFS_LAYERS_ANNOTATION))
+    else {
+        return Ok(Vec::new());
+    };
+    let mut layers: Vec<String> = Vec::new();
+    for name in value.split(',').map(str::trim) {
+        validate_layer_name(name)?;
+        if layers.iter().any(|layer| layer == name) {
+            return Err(Error::Duplicate(name.to_string()));
+        }
+        layers.push(name.to_string());
+    }
+    Ok(layers)
+}
+
+// Check that the layers are attached to the VM. Disks cannot be attached to a running VM.
+pub fn check_attached(layers: &[String], vm_config: &MacosVmConfig) -> Result<(), Error> {
+    for layer in layers {
+        let attached = vm_config.storage.iter().any(|storage| {
+            storage.r#type == LAYER_STORAGE_TYPE && storage.name.as_deref() == Some(layer)
+        });
+        if !attached {
+            return Err(Error::NotAttached(layer.clone()));
+        }
+    }
+    Ok(())
+}
+
+// Return the block device of the layer inside a Linux guest.
+pub fn guest_layer_device(name: &str) -> PathBuf {
+    PathBuf::from("/dev/disk/by-id").join(format!("virtio-{}", name))
+}
+
+// Return the directory where the layer is mounted inside the guest.
+pub fn guest_layer_path(name: &str) -> PathBuf {
+    PathBuf::from(GUEST_LAYERS_PATH).join(name)
+}
+
+// Return the directory with the upper, work and merged directories of the container.
+pub fn guest_rootfs_dir(id: &ContainerId) -> PathBuf {
+    PathBuf::from(GUEST_ROOTFS_PATH).join(id.as_str())
+}
+
+// Return the composed rootfs of the container inside the guest.
+pub fn guest_rootfs_path(id: &ContainerId) -> PathBuf {
+    guest_rootfs_dir(id).join("merged")
+}
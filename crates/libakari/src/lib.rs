@@ -1,7 +1,48 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+pub mod agent_config;
+pub mod api;
+pub mod asciicast;
+pub mod build_info;
+pub mod console;
+pub mod container_id;
 pub mod container_rpc;
+pub mod event;
+pub mod exec;
+pub mod filter;
+pub mod framing;
+pub mod guest_user;
+pub mod layer;
+pub mod lifecycle;
+pub mod metrics;
+pub mod network;
+pub mod network_group;
 pub mod path;
+pub mod port_forward;
+pub mod priority;
+pub mod probe;
+pub mod progress;
+pub mod provision;
+pub mod resources;
+pub mod restart;
+pub mod scheduling;
+pub mod scratch;
+pub mod secret;
+pub mod signal;
+pub mod spec;
+pub mod staging;
+pub mod start;
+pub mod stdio;
+pub mod storage;
+pub mod task_options;
+pub mod timeout;
+pub mod trace;
+pub mod transfer;
+pub mod user;
 pub mod vm_config;
 pub mod vm_rpc;
+pub mod volume;
+pub mod vsock;
+pub mod vsock_auth;
+pub mod watch;
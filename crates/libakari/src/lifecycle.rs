@@ -0,0 +1,39 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Lifecycle timestamps of the containers and the exec processes.
+//! They are reported in seconds since the Unix epoch for the duration metrics and the GC by age.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// Annotations of the OCI state with the timestamps of the container
+pub const CREATED_AT_ANNOTATION: &str = "org.akari.created-at";
+pub const STARTED_AT_ANNOTATION: &str = "org.akari.started-at";
+pub const FINISHED_AT_ANNOTATION: &str = "org.akari.finished-at";
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timestamps {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+impl Timestamps {
+    // Return the annotations of the recorded timestamps.
+    pub fn annotations(&self) -> HashMap<String, String> {
+        [
+            (CREATED_AT_ANNOTATION, self.created_at),
+            (STARTED_AT_ANNOTATION, self.started_at),
+            (FINISHED_AT_ANNOTATION, self.finished_at),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?.to_string())))
+        .collect()
+    }
+}
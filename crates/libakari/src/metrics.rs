@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Metrics gathered by the vsock proxies and the guest agent.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{guest_user::GuestUser, storage::StorageUsage};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProxyMetrics {
+    // Number of the accepted proxy connections
+    pub connections: u64,
+    pub bytes_to_guest: u64,
+    pub bytes_from_guest: u64,
+    // Seconds since the Unix epoch of the last connection or transfer
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_activity: Option<u64>,
+}
+
+impl ProxyMetrics {
+    pub fn merge(&mut self, other: &ProxyMetrics) {
+        self.connections += other.connections;
+        self.bytes_to_guest += other.bytes_to_guest;
+        self.bytes_from_guest += other.bytes_from_guest;
+        self.last_activity = self.last_activity.max(other.last_activity);
+    }
+}
+
+// Cumulative metrics of a container. Idle containers have an old `last_activity`.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ContainerMetrics {
+    // Task RPCs forwarded to the guest
+    pub control: ProxyMetrics,
+    // Stdio streams served on the data sockets
+    pub stdio: ProxyMetrics,
+}
+
+impl ContainerMetrics {
+    pub fn last_activity(&self) -> Option<u64> {
+        self.control.last_activity.max(self.stdio.last_activity)
+    }
+}
+
+// Cumulative resource usage of the processes that the agent runs in a container.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProcessUsage {
+    // Nanoseconds of CPU time in the user and the system mode
+    pub cpu_time: u64,
+    // Bytes of the resident memory
+    pub memory: u64,
+    pub disk_read: u64,
+    pub disk_written: u64,
+}
+
+impl ProcessUsage {
+    pub fn add(&mut self, other: &ProcessUsage) {
+        self.cpu_time += other.cpu_time;
+        self.memory += other.memory;
+        self.disk_read += other.disk_read;
+        self.disk_written += other.disk_written;
+    }
+}
+
+// Guest-wide resource usage reported by the agent.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GuestStats {
+    pub cpus: u32,
+    // Load averages over 1, 5 and 15 minutes
+    pub load_average: [f64; 3],
+    // Bytes of the guest memory
+    pub memory_total: u64,
+    pub memory_free: u64,
+    // Bytes of the guest data volume
+    pub disk_total: u64,
+    pub disk_free: u64,
+    // Running processes of each container
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub processes: BTreeMap<String, usize>,
+    // Resource usage of each container
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub usage: BTreeMap<String, ProcessUsage>,
+    // Usage of the storage of each container with a quota
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<String, StorageUsage>,
+    // Dedicated users of the containers
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub users: BTreeMap<String, GuestUser>,
+}
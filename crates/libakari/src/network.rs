@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{net::Ipv4Addr, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vm_config::MacosVmConfig;
+
+// The DHCP server of the NAT network records the leases here.
+pub const DHCPD_LEASES_PATH: &str = "/var/db/dhcpd_leases";
+// Annotation to expose the guest IP address in the state output.
+pub const GUEST_IP_ANNOTATION: &str = "org.akari.guest.ip";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DhcpLease {
+    pub name: Option<String>,
+    pub ip_address: Ipv4Addr,
+    pub hw_address: String,
+    pub lease: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuestNetworkInfo {
+    pub mac_address: String,
+    pub ip_address: Option<Ipv4Addr>,
+}
+
+// Normalize the MAC address to the lowercase, zero-padded form.
+// The leases file strips leading zeros and may prefix the hardware type (e.g. `1,a:b:c:d:e:f`).
+pub fn normalize_mac_address(mac: &str) -> Option<String> {
+    let mac = mac.split_once(',').map_or(mac, |(_, mac)| mac);
+    let octets = mac
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    if octets.len() != 6 {
+        return None;
+    }
+    Some(
+        octets
+            .iter()
+            .map(|octet| format!("{:02x}", octet))
+            .collect::<Vec<_>>()
+            .join(":"),
+    )
+}
+
+// Parse the leases in the bootpd format:
+// {
+//     name=guest
+//     ip_address=192.168.64.2
+//     hw_address=1,a:b:c:d:e:f
+//     identifier=1,a:b:c:d:e:f
+//     lease=0x66a1b2c3
+// }
+pub fn parse_dhcp_leases(content: &str) -> Vec<DhcpLease> {
+    let mut leases = Vec::new();
+
+    let mut name = None;
+    let mut ip_address = None;
+    let mut hw_address = None;
+    let mut lease = 0;
+    for line in content.lines().map(|line| line.trim()) {
+        match line {
+            "{" => {
+                name = None;
+                ip_address = None;
+                hw_address = None;
+                lease = 0;
+            }
+            "}" => {
+                if let (Some(ip_address), Some(hw_address)) = (ip_address.take(), hw_address.take())
+                {
+                    leases.push(DhcpLease {
+                        name: name.take(),
+                        ip_address,
+                        hw_address,
+                        lease,
+                    });
+                }
+            }
+            _ => match line.split_once('=') {
+                Some(("name", value)) => name = Some(value.to_string()),
+                Some(("ip_address", value)) => ip_address = value.parse().ok(),
+                Some(("hw_address", value)) => hw_address = normalize_mac_address(value),
+                Some(("lease", value)) => {
+                    lease = u64::from_str_radix(value.trim_start_matches("0x"), 16).unwrap_or(0)
+                }
+                _ => {}
+            },
+        }
+    }
+
+    leases
+}
+
+// Find the most recent IP address leased to the MAC address.
+pub fn find_leased_ip(leases_path: &Path, mac: &str) -> Result<Option<Ipv4Addr>, Error> {
+    let Some(mac) = normalize_mac_address(mac) else {
+        return Ok(None);
+    };
+    let content = match std::fs::read_to_string(leases_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(parse_dhcp_leases(&content)
+        .into_iter()
+        .filter(|lease| lease.hw_address == mac)
+        .max_by_key(|lease| lease.lease)
+        .map(|lease| lease.ip_address))
+}
+
+// Discover the network information of the guest from the NAT DHCP leases.
+pub fn guest_network_info(vm_config: &MacosVmConfig) -> Result<Vec<GuestNetworkInfo>, Error> {
+    let mut infos = Vec::new();
+    for network in &vm_config.networks {
+        // The MAC address must be fixed in vm.json to match the lease.
+        let Some(mac_address) = &network.mac_address else {
+            continue;
+        };
+        let ip_address = find_leased_ip(Path::new(DHCPD_LEASES_PATH), mac_address)?;
+        infos.push(GuestNetworkInfo {
+            mac_address: mac_address.clone(),
+            ip_address,
+        });
+    }
+    Ok(infos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multiple_leases() {
+        let content = "\
+{
+\tname=guest
+\tip_address=192.168.64.2
+\thw_address=1,a:b:c:d:e:f
+\tidentifier=1,a:b:c:d:e:f
+\tlease=0x66a1b2c3
+}
+{
+\tip_address=192.168.64.3
+\thw_address=1,2:0:0:0:0:1
+\tlease=0x66a1b2c4
+}
+";
+        let leases = parse_dhcp_leases(content);
+        assert_eq!(leases.len(), 2);
+        assert_eq!(leases[0].name.as_deref(), Some("guest"));
+        assert_eq!(leases[0].ip_address, Ipv4Addr::new(192, 168, 64, 2));
+        assert_eq!(leases[0].hw_address, "0a:0b:0c:0d:0e:0f");
+        assert_eq!(leases[0].lease, 0x66a1b2c3);
+        assert_eq!(leases[1].name, None);
+        assert_eq!(leases[1].ip_address, Ipv4Addr::new(192, 168, 64, 3));
+        assert_eq!(leases[1].hw_address, "02:00:00:00:00:01");
+    }
+
+    #[test]
+    fn parse_malformed_leases() {
+        // Entries without a valid address are skipped, and a bad lease time reads as 0.
+        let content = "\
+{
+\tname=bad-ip
+\tip_address=192.168.64
+\thw_address=1,a:b:c:d:e:f
+}
+{
+\tname=bad-mac
+\tip_address=192.168.64.4
+\thw_address=1,a:b:c
+}
+{
+\tname=bad-lease
+\tip_address=192.168.64.5
+\thw_address=a:b:c:d:e:f
+\tlease=0xzz
+}
+garbage
+{
+\tname=unterminated
+\tip_address=192.168.64.6
+\thw_address=a:b:c:d:e:f
+";
+        let leases = parse_dhcp_leases(content);
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].name.as_deref(), Some("bad-lease"));
+        assert_eq!(leases[0].ip_address, Ipv4Addr::new(192, 168, 64, 5));
+        assert_eq!(leases[0].lease, 0);
+        assert!(parse_dhcp_leases("").is_empty());
+    }
+
+    #[test]
+    fn normalize_mac_addresses() {
+        assert_eq!(
+            normalize_mac_address("1,a:b:c:d:e:f").as_deref(),
+            Some("0a:0b:0c:0d:0e:0f")
+        );
+        assert_eq!(
+            normalize_mac_address("AA:BB:CC:DD:EE:FF").as_deref(),
+            Some("aa:bb:cc:dd:ee:ff")
+        );
+        assert_eq!(normalize_mac_address("a:b:c:d:e"), None);
+        assert_eq!(normalize_mac_address("a:b:c:d:e:g"), None);
+    }
+}
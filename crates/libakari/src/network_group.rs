@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Network groups let the containers resolve each other by name.
+//! The containers share the network of the guest, so the agent resolves the aliases of a group
+//! to the loopback addresses in the hosts file of the guest.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// Annotation to join the container to the network group: `org.akari.network-group=ci`.
+pub const NETWORK_GROUP_ANNOTATION: &str = "org.akari.network-group";
+// Annotation for the names of the container in its group, which default to the container ID:
+// `org.akari.network-aliases=db,postgres`.
+pub const NETWORK_ALIASES_ANNOTATION: &str = "org.akari.network-aliases";
+
+// Directory inside the guest with the hosts file of each group
+const GUEST_HOSTS_PATH: &str = "/run/akari/hosts";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid network group: {0}")]
+    InvalidGroup(String),
+    #[error("Invalid network alias: {0}")]
+    InvalidAlias(String),
+    #[error("Network aliases need a network group")]
+    NoGroup,
+}
+
+// A hostname of letters, digits and hyphens, separated by dots.
+fn is_hostname(name: &str) -> bool {
+    name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+// Membership of a container in a network group.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkMember {
+    pub group: String,
+    pub aliases: Vec<String>,
+}
+
+impl NetworkMember {
+    // Return the membership in the annotations, or none if the container is in no group.
+    pub fn from_annotations(
+        id: &str,
+        annotations: &HashMap<String, String>,
+    ) -> Result<Option<Self>, Error> {
+        let Some(group) = annotations.get(NETWORK_GROUP_ANNOTATION) else {
+            if annotations.contains_key(NETWORK_ALIASES_ANNOTATION) {
+                return Err(Error::NoGroup);
+            }
+            return Ok(None);
+        };
+        // The group names the hosts file in the guest.
+        if !is_hostname(group) {
+            return Err(Error::InvalidGroup(group.clone()));
+        }
+        let aliases = match annotations.get(NETWORK_ALIASES_ANNOTATION) {
+            Some(aliases) => aliases
+                .split(',')
+                .map(|alias| alias.trim().to_string())
+                .collect(),
+            None => vec![id.to_string()],
+        };
+        if let Some(alias) = aliases.iter().find(|alias| !is_hostname(alias)) {
+            return Err(Error::InvalidAlias(alias.clone()));
+        }
+        Ok(Some(Self {
+            group: group.clone(),
+            aliases,
+        }))
+    }
+}
+
+// Return the hosts file of the group inside the guest.
+pub fn guest_hosts_path(group: &str) -> PathBuf {
+    PathBuf::from(GUEST_HOSTS_PATH).join(group)
+}
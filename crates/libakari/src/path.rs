@@ -8,20 +8,20 @@ use std::{
 
 use anyhow::Result;
 
+use crate::{
+    stdio::StdioStream,
+    user::{current_uid, home_dir, ROOT_ENV},
+};
+
 // Return the root path of the runtime.
+// Without an explicit path, each user gets its own root under the home directory
+// so that the servers and VMs of different users are isolated.
 pub fn root_path(path: Option<PathBuf>) -> Result<PathBuf> {
-    match path {
-        Some(path) => Ok(canonicalize(path)?),
-        None => {
-            let mut default_root_path = PathBuf::from("/run/akari"); // FIXME: We cannot use this path
-            if let Ok(home_path) = std::env::var("HOME") {
-                if let Ok(home_path) = canonicalize(home_path) {
-                    default_root_path = home_path.join(".akari/run");
-                }
-            }
-            Ok(default_root_path)
-        }
+    if let Some(path) = path.or_else(|| std::env::var_os(ROOT_ENV).map(PathBuf::from)) {
+        return Ok(canonicalize(path)?);
     }
+    let home_path = home_dir(current_uid())?;
+    Ok(canonicalize(home_path)?.join(".akari/run"))
 }
 
 // Return the path to the auxiliary socket file.
@@ -32,3 +32,110 @@ pub fn aux_sock_path(root_path: &Path, path: Option<PathBuf>) -> PathBuf {
         default_aux_sock_path
     })
 }
+
+// Return the path to the socket of the shim that serves the container in the namespace.
+// The namespaces are kept under their own directory so that they never clash with the state.
+// Socket paths are limited to 104 bytes on macOS, so long IDs need a short root path.
+pub fn shim_sock_path(root_path: &Path, namespace: &str, id: &str) -> PathBuf {
+    root_path
+        .join("shims")
+        .join(namespace)
+        .join(id)
+        .join("shim.sock")
+}
+
+// Return the path to the admin API socket file.
+pub fn api_sock_path(root_path: &Path) -> PathBuf {
+    root_path.join("api.sock")
+}
+
+// Return the path to the server configuration file.
+pub fn server_config_path(root_path: &Path) -> PathBuf {
+    root_path.join("server.json")
+}
+
+// Return the path to the VM configuration file.
+pub fn vm_config_path(root_path: &Path) -> PathBuf {
+    root_path.join("vm.json")
+}
+
+// Return the path to the directory that contains the container states.
+pub fn containers_path(root_path: &Path) -> PathBuf {
+    root_path.join("containers")
+}
+
+// Return the path to the data socket that carries the stdio stream of the container.
+pub fn data_sock_path(root_path: &Path, id: &str, stream: StdioStream) -> PathBuf {
+    containers_path(root_path)
+        .join(id)
+        .join(format!("{}.sock", stream.name()))
+}
+
+// Return the path to the data socket that carries the stdio stream of the exec process.
+pub fn exec_data_sock_path(
+    root_path: &Path,
+    id: &str,
+    exec_id: &str,
+    stream: StdioStream,
+) -> PathBuf {
+    containers_path(root_path)
+        .join(id)
+        .join("execs")
+        .join(exec_id)
+        .join(format!("{}.sock", stream.name()))
+}
+
+// Return the path to the directory that contains the recorded terminal sessions of the container.
+pub fn sessions_path(root_path: &Path, id: &str) -> PathBuf {
+    containers_path(root_path).join(id).join("sessions")
+}
+
+// Return the path to the hardware model cached by `akari vm init`.
+pub fn hardware_model_cache_path(root_path: &Path) -> PathBuf {
+    root_path.join("hardware-model")
+}
+
+// Return the path to the directory that contains the cache volumes.
+pub fn volumes_path(root_path: &Path) -> PathBuf {
+    root_path.join("volumes")
+}
+
+// Return the path to the directory that contains the scratch directories of the containers.
+pub fn scratch_path(root_path: &Path) -> PathBuf {
+    root_path.join("scratch")
+}
+
+// Return the path to the directory that contains the secrets.
+pub fn secrets_path(root_path: &Path) -> PathBuf {
+    root_path.join("secrets")
+}
+
+// Return the path to the directory that contains the operator hooks of each stage.
+pub fn hooks_path(root_path: &Path) -> PathBuf {
+    root_path.join("hooks.d")
+}
+
+// Return the path to the directory that contains the authentication token of the agent.
+pub fn auth_path(root_path: &Path) -> PathBuf {
+    root_path.join("auth")
+}
+
+// Return the path to the directory of the golden VM saved from the VM of the root directory.
+pub fn golden_path(root_path: &Path) -> PathBuf {
+    root_path.join("golden")
+}
+
+// Return the path to the machine state that the server restores instead of booting the guest.
+pub fn restore_state_path(root_path: &Path) -> PathBuf {
+    root_path.join("restore.vzvmsave")
+}
+
+// Return the path to the directory that contains the staged bundles.
+pub fn staging_path(root_path: &Path) -> PathBuf {
+    root_path.join("staging")
+}
+
+// Return the path where the shared directories are automounted in a macOS guest.
+pub fn guest_shared_dir_path() -> PathBuf {
+    PathBuf::from("/Volumes/My Shared Files")
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{fmt, net::Ipv4Addr, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// Annotation to publish the container ports: `org.akari.ports=8080:80,127.0.0.1:2222:22`.
+pub const PUBLISHED_PORTS_ANNOTATION: &str = "org.akari.ports";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid port mapping: {0}")]
+    InvalidPortMapping(String),
+    #[error("Unsupported protocol: {0}")]
+    UnsupportedProtocol(String),
+}
+
+// A host port forwarded to a port in the guest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMapping {
+    pub host_ip: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+// Parse the mapping in the form of `[host_ip:]host_port:guest_port[/tcp]`.
+impl FromStr for PortMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidPortMapping(s.to_string());
+
+        let mapping = match s.split_once('/') {
+            Some((mapping, "tcp")) => mapping,
+            Some((_, protocol)) => return Err(Error::UnsupportedProtocol(protocol.to_string())),
+            None => s,
+        };
+
+        let parts = mapping.split(':').collect::<Vec<_>>();
+        let (host_ip, host_port, guest_port) = match parts.as_slice() {
+            [host_port, guest_port] => (Ipv4Addr::UNSPECIFIED, host_port, guest_port),
+            [host_ip, host_port, guest_port] => (
+                host_ip.parse().map_err(|_| invalid())?,
+                host_port,
+                guest_port,
+            ),
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self {
+            host_ip,
+            host_port: host_port.parse().map_err(|_| invalid())?,
+            guest_port: guest_port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl fmt::Display for PortMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}/tcp",
+            self.host_ip, self.host_port, self.guest_port
+        )
+    }
+}
+
+// Parse the comma-separated mappings of the annotation.
+pub fn parse_port_mappings(s: &str) -> Result<Vec<PortMapping>, Error> {
+    s.split(',')
+        .map(|m| m.trim())
+        .filter(|m| !m.is_empty())
+        .map(PortMapping::from_str)
+        .collect()
+}
@@ -0,0 +1,87 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{collections::HashMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::scheduling::QosClass;
+
+// Annotation to run the processes of the container in the QoS class:
+// `org.akari.priority=background`.
+pub const PRIORITY_ANNOTATION: &str = "org.akari.priority";
+// Annotation to override the nice value of the QoS class: `org.akari.nice=5`.
+pub const NICE_ANNOTATION: &str = "org.akari.nice";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid priority: {0}")]
+    InvalidPriority(String),
+    #[error("Invalid nice value: {0}")]
+    InvalidNice(String),
+}
+
+// Parse the class in the form of `user-interactive`, `user-initiated`, `default`, `utility`
+// or `background`.
+impl FromStr for QosClass {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user-interactive" => Ok(QosClass::UserInteractive),
+            "user-initiated" => Ok(QosClass::UserInitiated),
+            "default" => Ok(QosClass::Default),
+            "utility" => Ok(QosClass::Utility),
+            "background" => Ok(QosClass::Background),
+            _ => Err(Error::InvalidPriority(s.to_string())),
+        }
+    }
+}
+
+// Scheduling priority of the processes of a container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProcessPriority {
+    pub qos: QosClass,
+    // -20 (highest) to 19 (lowest)
+    pub nice: i32,
+}
+
+impl ProcessPriority {
+    pub fn new(qos: QosClass) -> Self {
+        // Spread the classes over the nice values so that Linux guests order them too.
+        let nice = match qos {
+            QosClass::UserInteractive => -10,
+            QosClass::UserInitiated => -5,
+            QosClass::Default => 0,
+            QosClass::Utility => 10,
+            QosClass::Background => 19,
+        };
+        Self { qos, nice }
+    }
+
+    // Return the priority in the annotations, or none if the container runs at the default.
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        let qos = annotations
+            .get(PRIORITY_ANNOTATION)
+            .map(|qos| qos.parse())
+            .transpose()?;
+        let nice = annotations
+            .get(NICE_ANNOTATION)
+            .map(|nice| {
+                nice.parse::<i32>()
+                    .ok()
+                    .filter(|nice| (-20..=19).contains(nice))
+                    .ok_or_else(|| Error::InvalidNice(nice.clone()))
+            })
+            .transpose()?;
+        if qos.is_none() && nice.is_none() {
+            return Ok(None);
+        }
+        let mut priority = Self::new(qos.unwrap_or(QosClass::Default));
+        if let Some(nice) = nice {
+            priority.nice = nice;
+        }
+        Ok(Some(priority))
+    }
+}
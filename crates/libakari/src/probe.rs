@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Readiness and liveness probes of the containers.
+//! The agent runs the probes in the guest and the server polls their results, so that a service
+//! in the VM can be supervised without a container orchestrator.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+// Annotations of the probes as JSON:
+// `org.akari.probe.readiness={"tcp":8080}`,
+// `org.akari.probe.liveness={"exec":["pg_isready"],"interval":5,"threshold":3}`.
+pub const READINESS_PROBE_ANNOTATION: &str = "org.akari.probe.readiness";
+pub const LIVENESS_PROBE_ANNOTATION: &str = "org.akari.probe.liveness";
+
+const DEFAULT_INTERVAL: u64 = 10;
+const DEFAULT_THRESHOLD: u32 = 3;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid probe {0}: {1}")]
+    InvalidProbe(String, serde_json::Error),
+    #[error("Invalid probe {0}: {1}")]
+    InvalidValue(String, String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeAction {
+    // Run the command in the guest. The probe succeeds if it exits with 0.
+    Exec(Vec<String>),
+    // Connect to the TCP port of the guest loopback.
+    Tcp(u16),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probe {
+    #[serde(flatten)]
+    pub action: ProbeAction,
+    // Seconds between the probes, which is also the timeout of each probe
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // Consecutive failures that fail the probe. One success passes it.
+    #[serde(default = "default_threshold")]
+    pub threshold: u32,
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+fn default_threshold() -> u32 {
+    DEFAULT_THRESHOLD
+}
+
+impl Probe {
+    fn parse(annotation: &str, value: &str) -> Result<Self, Error> {
+        let probe: Self = serde_json::from_str(value)
+            .map_err(|e| Error::InvalidProbe(annotation.to_string(), e))?;
+        let invalid = |message: &str| Error::InvalidValue(annotation.to_string(), message.into());
+        if matches!(&probe.action, ProbeAction::Exec(args) if args.is_empty()) {
+            return Err(invalid("the command is empty"));
+        }
+        if probe.interval == 0 {
+            return Err(invalid("the interval must be positive"));
+        }
+        if probe.threshold == 0 {
+            return Err(invalid("the threshold must be positive"));
+        }
+        Ok(probe)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Probes {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness: Option<Probe>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<Probe>,
+}
+
+impl Probes {
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Self, Error> {
+        let probe = |annotation: &str| {
+            annotations
+                .get(annotation)
+                .map(|value| Probe::parse(annotation, value))
+                .transpose()
+        };
+        Ok(Self {
+            readiness: probe(READINESS_PROBE_ANNOTATION)?,
+            liveness: probe(LIVENESS_PROBE_ANNOTATION)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readiness.is_none() && self.liveness.is_none()
+    }
+}
+
+// Result of the probes of a kind. Unknown until the probe passes or fails after the start.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ProbeStatus {
+    #[default]
+    Unknown,
+    Success,
+    Failure,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerHealth {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub readiness: Option<ProbeStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub liveness: Option<ProbeStatus>,
+}
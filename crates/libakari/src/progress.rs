@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Progress of the long operations, e.g. archiving the VM or saving the machine state.
+//! The server streams it as events and the client renders it as a progress bar.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    // Operation, e.g. "export" or "save"
+    pub operation: String,
+    // Current step of the operation
+    pub phase: String,
+    pub completed: u64,
+    // Unset if the operation cannot tell the amount of the work.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    pub fn new(operation: &str, phase: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            phase: phase.to_string(),
+            completed: 0,
+            total: None,
+        }
+    }
+
+    // Return the percentage of the completed work if the total is known.
+    pub fn percent(&self) -> Option<f64> {
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| self.completed.min(total) as f64 * 100.0 / total as f64)
+    }
+}
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! First-boot provisioning of a fresh VM, e.g. to install the developer tools of a golden image.
+//! The script bundle of vm.json is shared with the guest and the agent runs its entry script
+//! once the agent is ready. The result is kept in the root directory so that a provisioned VM
+//! is not provisioned again.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::path::guest_shared_dir_path;
+
+// Name of the shared directory that exposes the script bundle to the guest.
+pub const PROVISION_SHARE_NAME: &str = "provision";
+// Entry script of the bundle, run by `/bin/sh` as root. It finds the rest of the bundle
+// relative to `$0`.
+pub const PROVISION_SCRIPT: &str = "provision.sh";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProvisionStatus {
+    // Exit code of the script, or none if it could not be run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    // Seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+impl ProvisionStatus {
+    pub fn succeeded(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+// Return the path of the provisioning result.
+pub fn provision_status_path(root_path: &Path) -> PathBuf {
+    root_path.join("provision.json")
+}
+
+// Return the path of the output of the script.
+pub fn provision_log_path(root_path: &Path) -> PathBuf {
+    root_path.join("provision.log")
+}
+
+// Return the path of the entry script inside the guest.
+pub fn guest_script_path() -> PathBuf {
+    guest_shared_dir_path()
+        .join(PROVISION_SHARE_NAME)
+        .join(PROVISION_SCRIPT)
+}
+
+// Load the result of the last provisioning, or none if the VM has not been provisioned.
+pub fn load_provision_status(root_path: &Path) -> std::io::Result<Option<ProvisionStatus>> {
+    let path = provision_status_path(root_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&std::fs::read_to_string(path)?)?))
+}
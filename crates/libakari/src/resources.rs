@@ -0,0 +1,67 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Resource limits of the containers updated while they run, e.g. by `ctr task update`.
+
+use oci_spec::runtime::LinuxResources;
+use serde::{Deserialize, Serialize};
+
+// Type URL of the resources of the update requests of containerd, encoded as JSON.
+pub const LINUX_RESOURCES_TYPE_URL: &str =
+    "types.containerd.io/opencontainers/runtime-spec/1/LinuxResources";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unsupported resources type {0}")]
+    UnsupportedType(String),
+    #[error("Invalid resources: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+// The limits that akari applies. The other resources are ignored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ResourceLimits {
+    // Memory limit in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memory: Option<u64>,
+    // Relative CPU weight, 2 to 262144
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u64>,
+    // Maximum number of processes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pids: Option<u64>,
+}
+
+impl ResourceLimits {
+    // Parse the resources of an update request.
+    pub fn parse(type_url: &str, value: &[u8]) -> Result<Self, Error> {
+        if type_url != LINUX_RESOURCES_TYPE_URL {
+            return Err(Error::UnsupportedType(type_url.to_string()));
+        }
+        let resources: LinuxResources = serde_json::from_slice(value)?;
+        Ok(Self::from(&resources))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.cpu_shares.is_none() && self.pids.is_none()
+    }
+}
+
+// Negative values mean no limit in the spec.
+impl From<&LinuxResources> for ResourceLimits {
+    fn from(resources: &LinuxResources) -> Self {
+        Self {
+            memory: resources
+                .memory()
+                .as_ref()
+                .and_then(|memory| memory.limit())
+                .and_then(|limit| u64::try_from(limit).ok()),
+            cpu_shares: resources.cpu().as_ref().and_then(|cpu| cpu.shares()),
+            pids: resources
+                .pids()
+                .as_ref()
+                .and_then(|pids| u64::try_from(pids.limit()).ok()),
+        }
+    }
+}
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+// Annotation to set the restart policy: `org.akari.restart-policy=on-failure:3`.
+pub const RESTART_POLICY_ANNOTATION: &str = "org.akari.restart-policy";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid restart policy: {0}")]
+    InvalidRestartPolicy(String),
+}
+
+// When the server restarts a container after it exits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum RestartPolicy {
+    #[default]
+    No,
+    // Restart if the exit status is non-zero, up to the maximum number of retries if given.
+    OnFailure(Option<u32>),
+    Always,
+}
+
+impl RestartPolicy {
+    pub fn should_restart(&self, exit_status: u32, restart_count: u32) -> bool {
+        match *self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure(max_retries) => {
+                exit_status != 0 && max_retries.is_none_or(|max| restart_count < max)
+            }
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+// Parse the policy in the form of `no`, `on-failure[:max_retries]` or `always`.
+impl FromStr for RestartPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRestartPolicy(s.to_string());
+        match s.split_once(':') {
+            None => match s {
+                "no" => Ok(RestartPolicy::No),
+                "on-failure" => Ok(RestartPolicy::OnFailure(None)),
+                "always" => Ok(RestartPolicy::Always),
+                _ => Err(invalid()),
+            },
+            Some(("on-failure", max_retries)) => Ok(RestartPolicy::OnFailure(Some(
+                max_retries.parse().map_err(|_| invalid())?,
+            ))),
+            Some(_) => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::OnFailure(None) => write!(f, "on-failure"),
+            RestartPolicy::OnFailure(Some(max_retries)) => write!(f, "on-failure:{}", max_retries),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+impl TryFrom<String> for RestartPolicy {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<RestartPolicy> for String {
+    fn from(policy: RestartPolicy) -> Self {
+        policy.to_string()
+    }
+}
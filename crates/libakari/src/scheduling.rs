@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use serde::{Deserialize, Serialize};
+
+// Quality of service classes of the host threads and dispatch queues.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum QosClass {
+    UserInteractive,
+    UserInitiated,
+    Default,
+    Utility,
+    Background,
+}
+
+// Which cores the VM should prefer on Apple Silicon.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CorePreference {
+    // Let the host decide.
+    #[default]
+    Auto,
+    Performance,
+    // Keep the VM off the performance cores so it does not compete with interactive work.
+    Efficiency,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SchedulingPolicy {
+    pub cores: CorePreference,
+    // Overrides the QoS class derived from `cores`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qos: Option<QosClass>,
+}
+
+impl SchedulingPolicy {
+    // macOS has no core affinity API. The scheduler places threads by their QoS class,
+    // and background threads are confined to the efficiency cores.
+    pub fn qos_class(&self) -> QosClass {
+        if let Some(qos) = self.qos {
+            return qos;
+        }
+        match self.cores {
+            CorePreference::Auto => QosClass::Default,
+            CorePreference::Performance => QosClass::UserInitiated,
+            CorePreference::Efficiency => QosClass::Background,
+        }
+    }
+}
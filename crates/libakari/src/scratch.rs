@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Scratch directory of a container on the host disk.
+//! The directory is shared with the guest, and the server removes it when the container is
+//! deleted, so that a CI job gets fast scratch space that never outlives it.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::path::{guest_shared_dir_path, scratch_path};
+
+// Annotation to give the container a scratch directory: `org.akari.scratch=true`.
+pub const SCRATCH_ANNOTATION: &str = "org.akari.scratch";
+// Name of the shared directory that exposes the scratch directories to the guest.
+pub const SCRATCH_SHARE_NAME: &str = "scratch";
+// Environment variable with the path of the scratch directory inside the guest.
+pub const SCRATCH_DIR_ENV: &str = "AKARI_SCRATCH_DIR";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid value of {SCRATCH_ANNOTATION}: {0:?}, expected true or false")]
+    InvalidValue(String),
+}
+
+pub fn is_enabled(annotations: &HashMap<String, String>) -> Result<bool, Error> {
+    match annotations.get(SCRATCH_ANNOTATION).map(String::as_str) {
+        Some("true") => Ok(true),
+        Some("false") | None => Ok(false),
+        Some(value) => Err(Error::InvalidValue(value.to_string())),
+    }
+}
+
+// Return the path of the scratch directory of the container on the host.
+pub fn host_path(root_path: &Path, id: &str) -> PathBuf {
+    scratch_path(root_path).join(id)
+}
+
+// Return the path of the scratch directory of the container inside the guest.
+pub fn guest_path(id: &str) -> PathBuf {
+    guest_shared_dir_path().join(SCRATCH_SHARE_NAME).join(id)
+}
+
+// Return the environment entry that points the container at its scratch directory.
+pub fn env_entry(id: &str) -> String {
+    format!("{}={}", SCRATCH_DIR_ENV, guest_path(id).display())
+}
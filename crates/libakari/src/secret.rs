@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Secrets injected into the container environment.
+//! The values are read from `<root>/secrets/<name>` on the host and sent to the guest with the
+//! task options, so they are never written to the bundle or the guest filesystem.
+//! A container with a secrets volume gets them as files on an in-memory disk of the guest
+//! instead, which the agent mounts read-only and destroys with the container.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{container_id::ContainerId, path::secrets_path, volume::validate_volume_name};
+
+// Annotation prefix to reference a secret: `org.akari.secret.<ENV>=<name>`.
+pub const SECRET_ANNOTATION_PREFIX: &str = "org.akari.secret.";
+// Annotation to expose the secrets as files at the destination instead of the environment:
+// `org.akari.secrets-volume=/run/secrets`. Each file is named after its secret.
+pub const SECRETS_VOLUME_ANNOTATION: &str = "org.akari.secrets-volume";
+
+// Directory inside the guest with the mount point of the volume of each container
+const GUEST_SECRETS_PATH: &str = "/var/run/akari/secrets";
+
+const MASK: &str = "********";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid secret name: {0}")]
+    InvalidSecretName(String),
+    #[error("Invalid environment variable name: {0}")]
+    InvalidEnvName(String),
+    #[error("Secret {0} does not exist")]
+    SecretNotFound(String),
+    #[error("Secrets volume destination must be absolute: {0:?}")]
+    RelativeDestination(PathBuf),
+}
+
+// A secret value bound to an environment variable. The value is masked when formatted.
+#[derive(Clone)]
+pub struct Secret {
+    pub env: String,
+    pub name: String,
+    value: String,
+}
+
+impl Secret {
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    // Return the entry to add to the process environment.
+    pub fn env_entry(&self) -> String {
+        format!("{}={}", self.env, self.value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Secret")
+            .field("env", &self.env)
+            .field("name", &self.name)
+            .field("value", &MASK)
+            .finish()
+    }
+}
+
+fn validate_env_name(env: &str) -> Result<(), Error> {
+    let valid = env
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && env.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidEnvName(env.to_string()))
+    }
+}
+
+// Read the secrets referenced by the annotations.
+pub fn load_secrets(
+    root_path: &Path,
+    annotations: &HashMap<String, String>,
+) -> Result<Vec<Secret>, Error> {
+    let mut secrets = Vec::new();
+    for (key, name) in annotations {
+        let Some(env) = key.strip_prefix(SECRET_ANNOTATION_PREFIX) else {
+            continue;
+        };
+        validate_env_name(env)?;
+        // Secret names are file names, so they follow the same rules as the volume names.
+        validate_volume_name(name).map_err(|_| Error::InvalidSecretName(name.to_string()))?;
+        let path = secrets_path(root_path).join(name);
+        let value = match std::fs::read_to_string(&path) {
+            Ok(value) => value.trim_end_matches('\n').to_string(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(Error::SecretNotFound(name.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        secrets.push(Secret {
+            env: env.to_string(),
+            name: name.to_string(),
+            value,
+        });
+    }
+    secrets.sort_by(|a, b| a.env.cmp(&b.env));
+    Ok(secrets)
+}
+
+// Secret files to write to the volume of a container. The contents are masked when formatted.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SecretsVolume {
+    pub destination: PathBuf,
+    // Contents by the secret names
+    pub files: BTreeMap<String, String>,
+}
+
+impl SecretsVolume {
+    // Return the volume of the secrets, or none if the container has no secrets volume.
+    pub fn new(
+        annotations: &HashMap<String, String>,
+        secrets: &[Secret],
+    ) -> Result<Option<Self>, Error> {
+        let Some(destination) = secrets_volume_destination(annotations)? else {
+            return Ok(None);
+        };
+        let files = secrets
+            .iter()
+            .map(|secret| (secret.name.clone(), secret.value.clone()))
+            .collect();
+        Ok(Some(Self { destination, files }))
+    }
+
+    // Bytes of the contents
+    pub fn size(&self) -> u64 {
+        self.files.values().map(|value| value.len() as u64).sum()
+    }
+}
+
+impl fmt::Debug for SecretsVolume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretsVolume")
+            .field("destination", &self.destination)
+            .field("files", &self.files.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+pub fn secrets_volume_destination(
+    annotations: &HashMap<String, String>,
+) -> Result<Option<PathBuf>, Error> {
+    let Some(destination) = annotations.get(SECRETS_VOLUME_ANNOTATION) else {
+        return Ok(None);
+    };
+    let destination = PathBuf::from(destination);
+    if destination.is_relative() {
+        return Err(Error::RelativeDestination(destination));
+    }
+    Ok(Some(destination))
+}
+
+// Return the path where the secrets volume of the container is mounted inside the guest.
+pub fn guest_secrets_path(id: &ContainerId) -> PathBuf {
+    PathBuf::from(GUEST_SECRETS_PATH).join(id.as_str())
+}
+
+// Replace the secret values in the text so that it can be logged.
+pub fn mask(text: &str, secrets: &[Secret]) -> String {
+    secrets
+        .iter()
+        .filter(|secret| !secret.value.is_empty())
+        .fold(text.to_string(), |text, secret| {
+            text.replace(&secret.value, MASK)
+        })
+}
+
+// Drop the malformed entries of the process environment, keeping the last of the duplicates.
+pub fn sanitize_env(env: &[String]) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for entry in env {
+        let Some((key, value)) = entry.split_once('=') else {
+            continue;
+        };
+        if validate_env_name(key).is_err() {
+            continue;
+        }
+        vars.retain(|(k, _)| k != key);
+        vars.push((key.to_string(), value.to_string()));
+    }
+    vars
+}
@@ -0,0 +1,145 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Signals of the kill requests.
+//! The numbers of some signals differ between the guest OSes, e.g. SIGUSR1 is 30 on macOS and
+//! 10 on Linux, so the names are mapped with the OS of the guest in vm.json.
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Unknown signal: {0}")]
+    UnknownSignal(String),
+    #[error("Signal {0} is not supported by {1} guests")]
+    UnsupportedSignal(String, String),
+}
+
+// OS of the macOS guests in vm.json. The other guests run Linux.
+const DARWIN: &str = "darwin";
+// Linux real-time signals, which have no names of their own
+const LINUX_SIGRTMIN: u32 = 34;
+const LINUX_SIGRTMAX: u32 = 64;
+
+// Names without the SIG prefix with the numbers on macOS and on Linux.
+const SIGNALS: &[(&str, Option<u32>, Option<u32>)] = &[
+    ("HUP", Some(1), Some(1)),
+    ("INT", Some(2), Some(2)),
+    ("QUIT", Some(3), Some(3)),
+    ("ILL", Some(4), Some(4)),
+    ("TRAP", Some(5), Some(5)),
+    ("ABRT", Some(6), Some(6)),
+    ("EMT", Some(7), None),
+    ("FPE", Some(8), Some(8)),
+    ("KILL", Some(9), Some(9)),
+    ("BUS", Some(10), Some(7)),
+    ("SEGV", Some(11), Some(11)),
+    ("SYS", Some(12), Some(31)),
+    ("PIPE", Some(13), Some(13)),
+    ("ALRM", Some(14), Some(14)),
+    ("TERM", Some(15), Some(15)),
+    ("URG", Some(16), Some(23)),
+    ("STOP", Some(17), Some(19)),
+    ("TSTP", Some(18), Some(20)),
+    ("CONT", Some(19), Some(18)),
+    ("CHLD", Some(20), Some(17)),
+    ("TTIN", Some(21), Some(21)),
+    ("TTOU", Some(22), Some(22)),
+    ("IO", Some(23), Some(29)),
+    ("XCPU", Some(24), Some(24)),
+    ("XFSZ", Some(25), Some(25)),
+    ("VTALRM", Some(26), Some(26)),
+    ("PROF", Some(27), Some(27)),
+    ("WINCH", Some(28), Some(28)),
+    ("INFO", Some(29), None),
+    ("USR1", Some(30), Some(10)),
+    ("USR2", Some(31), Some(12)),
+    ("STKFLT", None, Some(16)),
+    ("PWR", None, Some(30)),
+];
+
+fn number_on(os: &str, darwin: Option<u32>, linux: Option<u32>) -> Option<u32> {
+    if os == DARWIN {
+        darwin
+    } else {
+        linux
+    }
+}
+
+// Signals that users send to stop a container: INT, QUIT, KILL and TERM, which have the same
+// numbers on all the guests, or 0 for the default signal of the guest.
+pub fn is_stop_signal(signal: u32) -> bool {
+    matches!(signal, 0 | 2 | 3 | 9 | 15)
+}
+
+// Check that the guest OS has the signal number.
+pub fn check_signal(signal: u32, os: &str) -> Result<(), Error> {
+    let named = SIGNALS
+        .iter()
+        .any(|(_, darwin, linux)| number_on(os, *darwin, *linux) == Some(signal));
+    let realtime = os != DARWIN && (LINUX_SIGRTMIN..=LINUX_SIGRTMAX).contains(&signal);
+    if !named && !realtime {
+        return Err(Error::UnsupportedSignal(signal.to_string(), os.to_string()));
+    }
+    Ok(())
+}
+
+// Parse the signal as a number or a name like runc, e.g. `15`, `SIGTERM`, `TERM` or `term`, and
+// return its number on the guest OS.
+pub fn parse_signal(signal: &str, os: &str) -> Result<u32, Error> {
+    if let Ok(number) = signal.parse::<u32>() {
+        check_signal(number, os)?;
+        return Ok(number);
+    }
+    let upper = signal.to_ascii_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    let (_, darwin, linux) = SIGNALS
+        .iter()
+        .find(|(n, _, _)| *n == name)
+        .ok_or_else(|| Error::UnknownSignal(signal.to_string()))?;
+    number_on(os, *darwin, *linux)
+        .ok_or_else(|| Error::UnsupportedSignal(format!("SIG{}", name), os.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_number_and_names() {
+        for signal in ["15", "SIGTERM", "TERM", "term", "sigterm"] {
+            assert_eq!(parse_signal(signal, DARWIN).unwrap(), 15);
+            assert_eq!(parse_signal(signal, "linux").unwrap(), 15);
+        }
+    }
+
+    #[test]
+    fn parse_number_of_guest() {
+        assert_eq!(parse_signal("SIGUSR1", DARWIN).unwrap(), 30);
+        assert_eq!(parse_signal("SIGUSR1", "linux").unwrap(), 10);
+    }
+
+    #[test]
+    fn parse_unknown_name() {
+        assert!(matches!(
+            parse_signal("SIGFOO", DARWIN),
+            Err(Error::UnknownSignal(name)) if name == "SIGFOO"
+        ));
+    }
+
+    #[test]
+    fn parse_unsupported_signal() {
+        assert!(matches!(
+            parse_signal("PWR", DARWIN),
+            Err(Error::UnsupportedSignal(name, os)) if name == "SIGPWR" && os == DARWIN
+        ));
+        assert!(matches!(
+            parse_signal("INFO", "linux"),
+            Err(Error::UnsupportedSignal(..))
+        ));
+        // Real-time signals exist on Linux only.
+        assert_eq!(parse_signal("40", "linux").unwrap(), 40);
+        assert!(matches!(
+            parse_signal("40", DARWIN),
+            Err(Error::UnsupportedSignal(..))
+        ));
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Checks a container spec against what akari supports.
+
+use std::{fmt, path::Path};
+
+use oci_spec::runtime::Spec;
+
+use crate::volume::{cache_volumes, CACHE_VOLUME_MOUNT_TYPE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    // The field is ignored by akari.
+    Warning,
+    // The container cannot be created.
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn warning(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+// Validate the spec of the bundle. Relative paths are resolved against the bundle.
+pub fn validate_spec(spec: &Spec, bundle: &Path) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    match spec.root() {
+        Some(root) => {
+            let root_path = bundle.join(root.path());
+            if !root_path.exists() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "root.path {:?} does not exist",
+                    root_path
+                )));
+            }
+        }
+        None => diagnostics.push(Diagnostic::error("root is not specified")),
+    }
+
+    match spec.process() {
+        Some(process) => {
+            if process.args().as_ref().is_none_or(|args| args.is_empty()) {
+                diagnostics.push(Diagnostic::error("process.args is empty"));
+            }
+            if process.cwd().is_relative() {
+                diagnostics.push(Diagnostic::error(format!(
+                    "process.cwd {:?} must be absolute",
+                    process.cwd()
+                )));
+            }
+            if let Some(env) = process.env() {
+                for e in env.iter().filter(|e| !e.contains('=')) {
+                    diagnostics.push(Diagnostic::error(format!(
+                        "process.env {:?} is not in the form key=value",
+                        e
+                    )));
+                }
+            }
+            if process.capabilities().is_some() {
+                diagnostics.push(Diagnostic::warning("process.capabilities is not supported"));
+            }
+            if process.rlimits().is_some() {
+                diagnostics.push(Diagnostic::warning("process.rlimits is not supported"));
+            }
+        }
+        None => diagnostics.push(Diagnostic::error("process is not specified")),
+    }
+
+    if let Some(linux) = spec.linux() {
+        if linux.namespaces().as_ref().is_some_and(|ns| !ns.is_empty()) {
+            diagnostics.push(Diagnostic::warning(
+                "linux.namespaces is not supported on macOS guests",
+            ));
+        }
+        if linux.resources().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "linux.resources is not supported on macOS guests",
+            ));
+        }
+        if linux.seccomp().is_some() {
+            diagnostics.push(Diagnostic::warning(
+                "linux.seccomp is not supported on macOS guests",
+            ));
+        }
+    }
+
+    if let Some(mounts) = spec.mounts() {
+        for mount in mounts {
+            if mount.typ().as_deref() != Some(CACHE_VOLUME_MOUNT_TYPE) {
+                diagnostics.push(Diagnostic::warning(format!(
+                    "mount to {:?} of type {:?} is not supported",
+                    mount.destination(),
+                    mount.typ().as_deref().unwrap_or("none")
+                )));
+            }
+        }
+    }
+    if let Err(e) = cache_volumes(spec) {
+        diagnostics.push(Diagnostic::error(e.to_string()));
+    }
+
+    if spec.hooks().is_some() {
+        diagnostics.push(Diagnostic::warning("hooks are not supported"));
+    }
+
+    diagnostics
+}
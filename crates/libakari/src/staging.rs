@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{collections::HashMap, fmt, path::PathBuf, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::path::guest_shared_dir_path;
+
+// Annotation to choose how the rootfs outside the shared directories reaches the guest:
+// `org.akari.staging=copy-into-share`.
+pub const STAGING_ANNOTATION: &str = "org.akari.staging";
+// Prefix of the names of the per-container shares
+pub const ROOTFS_SHARE_PREFIX: &str = "rootfs-";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid staging strategy: {0}")]
+    InvalidStagingStrategy(String),
+}
+
+// How the server stages a rootfs that the guest cannot see through the shared directories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StagingStrategy {
+    // Mirror the tree with hard links into the staging share, where the containers of the same
+    // content share one tree, and symlink it from the staged bundle. This is the default as it
+    // is cheap, but the files written in place on the host change under the containers.
+    #[default]
+    SymlinkIntoShare,
+    // Copy the tree into the staging share for each container, which isolates the container
+    // from the later changes of the host at the cost of the copy.
+    CopyIntoShare,
+    // Share the rootfs of the host as a directory of its own while the container exists.
+    // Nothing is copied, and the container sees the changes of the host immediately.
+    PerContainerShare,
+    // Compose the rootfs in the guest from the layer disks of `org.akari.rootfs-layers`, which
+    // avoids virtiofs for the reads.
+    DiskImage,
+    // Stream the bundle to the guest as a tar over vsock, and the agent unpacks it into
+    // guest-local storage. The guest does not read the host filesystem for the rootfs, e.g.
+    // when virtiofs is unavailable or the host is not trusted, at the cost of the transfer.
+    Stream,
+}
+
+impl StagingStrategy {
+    // Return the strategy in the annotations, or none to use the default of the server.
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        annotations
+            .get(STAGING_ANNOTATION)
+            .map(|strategy| strategy.parse())
+            .transpose()
+    }
+}
+
+impl FromStr for StagingStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "symlink-into-share" => Ok(StagingStrategy::SymlinkIntoShare),
+            "copy-into-share" => Ok(StagingStrategy::CopyIntoShare),
+            "per-container-share" => Ok(StagingStrategy::PerContainerShare),
+            "disk-image" => Ok(StagingStrategy::DiskImage),
+            "stream" => Ok(StagingStrategy::Stream),
+            _ => Err(Error::InvalidStagingStrategy(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for StagingStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StagingStrategy::SymlinkIntoShare => write!(f, "symlink-into-share"),
+            StagingStrategy::CopyIntoShare => write!(f, "copy-into-share"),
+            StagingStrategy::PerContainerShare => write!(f, "per-container-share"),
+            StagingStrategy::DiskImage => write!(f, "disk-image"),
+            StagingStrategy::Stream => write!(f, "stream"),
+        }
+    }
+}
+
+impl TryFrom<String> for StagingStrategy {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<StagingStrategy> for String {
+    fn from(strategy: StagingStrategy) -> Self {
+        strategy.to_string()
+    }
+}
+
+// Return the name of the share of the container with `PerContainerShare`.
+pub fn rootfs_share_name(id: &str) -> String {
+    format!("{}{}", ROOTFS_SHARE_PREFIX, id)
+}
+
+// Return the path of the share of the container inside the guest.
+pub fn guest_rootfs_share_path(id: &str) -> PathBuf {
+    guest_shared_dir_path().join(rootfs_share_name(id))
+}
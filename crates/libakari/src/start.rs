@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Overrides of the process spec applied when a container starts.
+//! One bundle can be started with different parameters, e.g. for the jobs of a CI matrix.
+//! The client sends them in the metadata of the start request. The server merges them with the
+//! spec and forwards the result to the guest under the same key.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+// Metadata key of the start request that carries the overrides as JSON.
+pub const START_OVERRIDES_METADATA: &str = "akari-start-overrides";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid environment entry {0:?}; expected KEY=value")]
+    InvalidEnv(String),
+    #[error("The working directory {0:?} is not absolute")]
+    RelativeCwd(PathBuf),
+    #[error("Invalid start overrides: {0}")]
+    Deserialize(#[from] serde_json::Error),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StartOverrides {
+    // `KEY=value` entries that replace those of the same key in the spec.
+    // The server forwards the whole environment after the merge.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
+
+impl StartOverrides {
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.cwd.is_none()
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if let Some(entry) = self.env.iter().find(|entry| env_key(entry).is_none()) {
+            return Err(Error::InvalidEnv(entry.clone()));
+        }
+        match &self.cwd {
+            Some(cwd) if !cwd.is_absolute() => Err(Error::RelativeCwd(cwd.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    // Return the environment of the spec with the overrides applied in order.
+    pub fn merge_env(&self, env: &[String]) -> Vec<String> {
+        let mut merged = env.to_vec();
+        for entry in &self.env {
+            let key = env_key(entry);
+            match merged.iter_mut().find(|existing| env_key(existing) == key) {
+                Some(existing) => existing.clone_from(entry),
+                None => merged.push(entry.clone()),
+            }
+        }
+        merged
+    }
+
+    // Read the overrides from the metadata of a request, if any.
+    pub fn from_metadata(metadata: &HashMap<String, Vec<String>>) -> Result<Option<Self>, Error> {
+        let Some(value) = metadata
+            .get(START_OVERRIDES_METADATA)
+            .and_then(|values| values.first())
+        else {
+            return Ok(None);
+        };
+        let overrides: Self = serde_json::from_str(value)?;
+        overrides.validate()?;
+        Ok(Some(overrides))
+    }
+
+    pub fn to_metadata(&self, metadata: &mut HashMap<String, Vec<String>>) {
+        // Serializing the struct cannot fail.
+        let value = serde_json::to_string(self).unwrap();
+        metadata.insert(START_OVERRIDES_METADATA.to_string(), vec![value]);
+    }
+}
+
+fn env_key(entry: &str) -> Option<&str> {
+    entry
+        .split_once('=')
+        .map(|(key, _)| key)
+        .filter(|key| !key.is_empty())
+}
+
+// Parse an environment file of `KEY=value` lines. Blank lines and `#` comments are skipped.
+pub fn parse_env_file(content: &str) -> Result<Vec<String>, Error> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            env_key(line)
+                .map(|_| line.to_string())
+                .ok_or_else(|| Error::InvalidEnv(line.to_string()))
+        })
+        .collect()
+}
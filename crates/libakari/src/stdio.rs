@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! URIs of the task stdio.
+//! `file:///path` redirects the stream to a host file and `socket://` exposes the stream on
+//! a data socket of the container. The server rewrites them to `vsock://<port>` so that the
+//! agent serves the stream on the vsock port. `socket://?record` also records the terminal
+//! session under the sessions directory of the container.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::vsock::VsockPort;
+
+const FILE_SCHEME: &str = "file://";
+const SOCKET_URI: &str = "socket://";
+const SOCKET_RECORD_URI: &str = "socket://?record";
+const VSOCK_SCHEME: &str = "vsock://";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StdioStream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl StdioStream {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StdioStream::Stdin => "stdin",
+            StdioStream::Stdout => "stdout",
+            StdioStream::Stderr => "stderr",
+        }
+    }
+}
+
+// A Unix domain socket that carries a stdio stream of the container.
+// Data sockets are separate from `aux.sock` so that large transfers do not block the task RPCs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DataSocket {
+    pub stream: StdioStream,
+    pub path: PathBuf,
+}
+
+pub fn file_uri(path: &Path) -> String {
+    format!("{}{}", FILE_SCHEME, path.display())
+}
+
+pub fn socket_uri(record: bool) -> &'static str {
+    if record {
+        SOCKET_RECORD_URI
+    } else {
+        SOCKET_URI
+    }
+}
+
+// Return the host path if the stdio is redirected to a file.
+pub fn parse_file_uri(uri: &str) -> Option<PathBuf> {
+    uri.strip_prefix(FILE_SCHEME)
+        .filter(|path| path.starts_with('/'))
+        .map(PathBuf::from)
+}
+
+// Return true if the URI is in the file scheme, even with an invalid path.
+pub fn is_file_uri(uri: &str) -> bool {
+    uri.starts_with(FILE_SCHEME)
+}
+
+// Return true if the URI names a vsock port, which only the server assigns.
+pub fn is_vsock_uri(uri: &str) -> bool {
+    uri.starts_with(VSOCK_SCHEME)
+}
+
+// Return true if the stream should be exposed on a data socket.
+pub fn is_socket_uri(uri: &str) -> bool {
+    uri == SOCKET_URI || uri == SOCKET_RECORD_URI
+}
+
+// Return true if the session on the data socket should be recorded.
+pub fn is_record_uri(uri: &str) -> bool {
+    uri == SOCKET_RECORD_URI
+}
+
+pub fn vsock_uri(port: VsockPort) -> String {
+    format!("{}{}", VSOCK_SCHEME, port)
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Writable storage of a container in the guest with a quota.
+//! The agent backs the storage with a sparse disk image of the quota size, so a container that
+//! fills its storage gets ENOSPC instead of exhausting the guest disk of all the containers.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::container_id::ContainerId;
+
+// Annotation to give the container storage of the size at the destination:
+// `org.akari.storage=/scratch:10G`.
+pub const STORAGE_ANNOTATION: &str = "org.akari.storage";
+
+// Directory inside the guest with the disk image and the mount point of each container
+const GUEST_STORAGE_PATH: &str = "/var/lib/akari/storage";
+// Smallest quota that fits the metadata of the filesystems
+const MIN_QUOTA: u64 = 16 * 1024 * 1024;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid storage {0:?}: expected <destination>:<size>")]
+    InvalidStorage(String),
+    #[error("Storage destination must be absolute: {0:?}")]
+    RelativeDestination(PathBuf),
+    #[error("Invalid storage size: {0}")]
+    InvalidSize(String),
+    #[error("Storage size must be at least {MIN_QUOTA} bytes: {0}")]
+    TooSmall(u64),
+}
+
+// Parse the size in bytes with an optional binary suffix, e.g. `512M` or `10G`.
+pub fn parse_size(s: &str) -> Result<u64, Error> {
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| Error::InvalidSize(s.to_string()))
+}
+
+// Storage of a container in the guest.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerStorage {
+    pub destination: PathBuf,
+    // Bytes of the disk image
+    pub quota: u64,
+}
+
+impl ContainerStorage {
+    // Return the storage in the annotations, or none if the container has no storage.
+    pub fn from_annotations(annotations: &HashMap<String, String>) -> Result<Option<Self>, Error> {
+        let Some(value) = annotations.get(STORAGE_ANNOTATION) else {
+            return Ok(None);
+        };
+        let (destination, size) = value
+            .rsplit_once(':')
+            .ok_or_else(|| Error::InvalidStorage(value.clone()))?;
+        let destination = Path::new(destination);
+        if destination.is_relative() {
+            return Err(Error::RelativeDestination(destination.to_path_buf()));
+        }
+        let quota = parse_size(size)?;
+        if quota < MIN_QUOTA {
+            return Err(Error::TooSmall(quota));
+        }
+        Ok(Some(Self {
+            destination: destination.to_path_buf(),
+            quota,
+        }))
+    }
+}
+
+// Usage of the storage of a container.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StorageUsage {
+    // Bytes of the filesystem in the disk image
+    pub total: u64,
+    pub used: u64,
+}
+
+// Return the directory of the storages inside the guest.
+pub fn guest_storage_root() -> PathBuf {
+    PathBuf::from(GUEST_STORAGE_PATH)
+}
+
+// Return the directory of the storage of the container inside the guest.
+pub fn guest_storage_dir(id: &ContainerId) -> PathBuf {
+    guest_storage_root().join(id.as_str())
+}
+
+// Return the path where the storage of the container is mounted inside the guest.
+pub fn guest_storage_path(id: &ContainerId) -> PathBuf {
+    guest_storage_dir(id).join("mnt")
+}
@@ -0,0 +1,40 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use serde::{Deserialize, Serialize};
+
+use crate::{guest_user::GuestUser, port_forward::PortMapping, restart::RestartPolicy};
+
+// Type URL of the CreateTaskRequest options that carry the task options as JSON.
+pub const TASK_OPTIONS_TYPE_URL: &str = "types.akari.io/TaskOptions";
+
+// Options of a container that are not part of the OCI spec.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct TaskOptions {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ports: Vec<PortMapping>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+    // `KEY=value` entries added to the process environment without being written to the bundle.
+    // The server sets them from the secrets when forwarding the request to the guest.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub env: Vec<String>,
+    // Seconds after the start to kill the container
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_runtime: Option<u64>,
+    // Dedicated user that the processes run as instead of the user of the spec.
+    // The server sets it after the agent creates the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<GuestUser>,
+}
+
+impl TaskOptions {
+    pub fn is_empty(&self) -> bool {
+        self.ports.is_empty()
+            && self.restart_policy.is_none()
+            && self.env.is_empty()
+            && self.max_runtime.is_none()
+            && self.user.is_none()
+    }
+}
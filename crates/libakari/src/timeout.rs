@@ -0,0 +1,49 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Maximum runtime of the containers.
+//! The agent kills the processes of a container that runs longer, e.g. a hung CI job.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+// Annotation to set the maximum runtime: `org.akari.max-runtime=30m`.
+pub const MAX_RUNTIME_ANNOTATION: &str = "org.akari.max-runtime";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid max runtime: {0}")]
+    InvalidMaxRuntime(String),
+}
+
+// Why the container exited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExitReason {
+    #[default]
+    Exited,
+    // Killed after running longer than the maximum runtime
+    Timeout,
+}
+
+// Parse the runtime in the form of `<n>[s|m|h]`. The unit defaults to seconds.
+pub fn parse_max_runtime(s: &str) -> Result<Duration, Error> {
+    let invalid = || Error::InvalidMaxRuntime(s.to_string());
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    let secs = match unit {
+        "s" => Some(value),
+        "m" => value.checked_mul(60),
+        "h" => value.checked_mul(60 * 60),
+        _ => None,
+    }
+    .ok_or_else(invalid)?;
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Protocol trace of the task RPCs.
+//! The server and the shim append one JSON line per request with the method, the sizes, the
+//! duration and the status, so that the containerd integration can be debugged without a packet
+//! capture. The payloads are not written as they may contain secrets.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+// Path of the trace file. Enables the trace of the shim and overrides `protocolTrace` of
+// server.json.
+pub const PROTOCOL_TRACE_ENV: &str = "AKARI_PROTOCOL_TRACE";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceRecord {
+    // Milliseconds since the Unix epoch when the request arrived
+    pub timestamp: u64,
+    // "server" or "shim"
+    pub side: String,
+    pub method: String,
+    // ttrpc stream ID of the request
+    pub stream_id: u32,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    pub duration_us: u64,
+    // gRPC status code, e.g. "OK" or "NOT_FOUND"
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl TraceRecord {
+    pub fn new(side: &str, method: &str, stream_id: u32, started: SystemTime) -> Self {
+        Self {
+            timestamp: started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            side: side.to_string(),
+            method: method.to_string(),
+            stream_id,
+            request_bytes: 0,
+            response_bytes: 0,
+            duration_us: 0,
+            status: "OK".to_string(),
+            message: None,
+        }
+    }
+
+    pub fn finish(mut self, duration: Duration) -> Self {
+        self.duration_us = duration.as_micros() as u64;
+        self
+    }
+}
+
+pub struct ProtocolTrace {
+    file: Mutex<File>,
+}
+
+impl ProtocolTrace {
+    // The server and the shims may share the file, so each record is appended with one write.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    // Open the trace file of the environment if it is set.
+    pub fn from_env() -> std::io::Result<Option<Self>> {
+        std::env::var_os(PROTOCOL_TRACE_ENV)
+            .map(|path| Self::open(&PathBuf::from(path)))
+            .transpose()
+    }
+
+    pub fn write(&self, record: &TraceRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&line)
+    }
+}
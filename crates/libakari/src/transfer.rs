@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Bundles streamed to the guest instead of shared with it.
+//! The server sends the bundle as a tar in chunks after `ContainerCommand::ReceiveBundle`, and
+//! the agent unpacks it into guest-local storage. Each chunk is a 32-bit big-endian length
+//! followed by the bytes, and an empty chunk ends the stream.
+
+use std::{
+    io::{self, Read, Write},
+    path::PathBuf,
+};
+
+use crate::{container_id::ContainerId, framing::CHUNK_SIZE};
+
+// Directory inside the guest where the streamed bundles are unpacked
+const GUEST_BUNDLES_PATH: &str = "/var/lib/akari/bundles";
+
+// Return the path of the streamed bundle of the container inside the guest.
+pub fn guest_bundle_path(id: &ContainerId) -> PathBuf {
+    PathBuf::from(GUEST_BUNDLES_PATH).join(id.as_str())
+}
+
+// Writes the bytes in chunks of at most `CHUNK_SIZE` bytes.
+pub struct ChunkWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner }
+    }
+
+    // Write the empty chunk that ends the stream.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would end the stream.
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(CHUNK_SIZE);
+        self.inner.write_all(&(len as u32).to_be_bytes())?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Reads the bytes of the chunks until the empty chunk.
+pub struct ChunkReader<R: Read> {
+    inner: R,
+    // Bytes left in the current chunk
+    remaining: usize,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    // Skip to the end of the stream, e.g. past the padding after the end of a tar.
+    pub fn finish(mut self) -> io::Result<R> {
+        io::copy(&mut self, &mut io::sink())?;
+        Ok(self.inner)
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let mut len = [0u8; 4];
+            self.inner.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as usize;
+            if len == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+            if len > CHUNK_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Chunk too large: {} bytes", len),
+                ));
+            }
+            self.remaining = len;
+        }
+        let len = buf.len().min(self.remaining);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,118 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Per-user isolation of the runtime root.
+//! Each user runs its own server and VM under a root path owned by the user.
+
+use std::{
+    ffi::{CStr, CString},
+    os::unix::fs::MetadataExt,
+    path::Path,
+    path::PathBuf,
+};
+
+// Environment variable to override the root path of the runtime.
+pub const ROOT_ENV: &str = "AKARI_ROOT";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Home directory of uid {0} is not found")]
+    HomeDirectoryNotFound(u32),
+    #[error("{path:?} is owned by uid {owner}, not by the current user (uid {uid})")]
+    NotOwnedByCurrentUser { path: PathBuf, owner: u32, uid: u32 },
+    #[error("Access from uid {peer} is denied (the server is owned by uid {uid})")]
+    CrossUserAccess { peer: u32, uid: u32 },
+    #[error("Group {0} is not found")]
+    GroupNotFound(String),
+}
+
+pub fn current_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+// Return the home directory of the user from the password database.
+// `HOME` is not trusted because it is preserved across `sudo`.
+pub fn home_dir(uid: u32) -> Result<PathBuf, Error> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let ret = unsafe { libc::getpwuid_r(uid, &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() || pwd.pw_dir.is_null() {
+        return Err(Error::HomeDirectoryNotFound(uid));
+    }
+    let dir = unsafe { CStr::from_ptr(pwd.pw_dir) };
+    Ok(PathBuf::from(dir.to_string_lossy().into_owned()))
+}
+
+// Check that the path is owned by the current user.
+pub fn check_owner(path: &Path) -> Result<(), Error> {
+    let owner = std::fs::metadata(path)?.uid();
+    let uid = current_uid();
+    if owner != uid {
+        return Err(Error::NotOwnedByCurrentUser {
+            path: path.to_path_buf(),
+            owner,
+            uid,
+        });
+    }
+    Ok(())
+}
+
+// Return the ID of the group given by its name or its number.
+pub fn group_id(group: &str) -> Result<u32, Error> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| Error::GroupNotFound(group.to_string()))?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 || result.is_null() {
+        return Err(Error::GroupNotFound(group.to_string()));
+    }
+    Ok(grp.gr_gid)
+}
+
+// Return true if the current user is a member of the group.
+fn in_group(gid: u32) -> bool {
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+    let len = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    if len <= 0 {
+        return false;
+    }
+    let mut groups = vec![0 as libc::gid_t; len as usize];
+    let len = unsafe { libc::getgroups(len, groups.as_mut_ptr()) };
+    len > 0 && groups[..len as usize].contains(&gid)
+}
+
+// Check that the current user owns the socket, or may connect to it through its group as the
+// server config allows.
+pub fn check_socket_access(path: &Path) -> Result<(), Error> {
+    let metadata = std::fs::metadata(path)?;
+    if metadata.mode() & 0o060 == 0o060 && in_group(metadata.gid()) {
+        return Ok(());
+    }
+    check_owner(path)
+}
+
+// Check that the peer of a connection is the current user.
+pub fn check_peer(peer: u32) -> Result<(), Error> {
+    let uid = current_uid();
+    if peer != uid {
+        return Err(Error::CrossUserAccess { peer, uid });
+    }
+    Ok(())
+}
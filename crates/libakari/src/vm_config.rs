@@ -1,22 +1,32 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
-use std::path::{Path, PathBuf};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+use crate::{path::guest_shared_dir_path, port_forward::PortMapping, vsock::VsockPorts};
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmStorage {
     pub r#type: String,
     pub file: PathBuf,
+    // Block device identifier seen by the guest. Required for the `layer` storages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmNetwork {
     pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac_address: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -28,26 +38,138 @@ pub struct MacosVmSerial {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmSharedDirectory {
+    // Name of the directory in the guest. Defaults to the file name of the path.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub path: PathBuf,
+    // Share the directory with the other automounted directories of a macOS guest. The others
+    // get a device of their own that the agent mounts at `mountPoint`.
     pub automount: bool,
     pub read_only: bool,
+    // Tag of the virtiofs device of a directory that is not automounted. Defaults to the name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    // Where the agent mounts a directory that is not automounted in the guest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mount_point: Option<PathBuf>,
+}
+
+impl MacosVmSharedDirectory {
+    // Return the name of the directory in the guest.
+    pub fn guest_name(&self) -> Option<&str> {
+        match &self.name {
+            Some(name) => Some(name),
+            None => self.path.file_name().and_then(|name| name.to_str()),
+        }
+    }
+
+    // Return the tag of the device of its own, or none if the directory is automounted.
+    pub fn tag(&self) -> Option<&str> {
+        match self.automount {
+            true => None,
+            false => self.tag.as_deref().or_else(|| self.guest_name()),
+        }
+    }
+
+    // Return where the directory appears in the guest.
+    pub fn guest_path(&self) -> Option<PathBuf> {
+        match self.automount {
+            true => Some(guest_shared_dir_path().join(self.guest_name()?)),
+            false => self.mount_point.clone(),
+        }
+    }
+}
+
+// Graphics device that drives the display.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MacosVmDisplayType {
+    #[default]
+    Mac,
+    // Paravirtualized GPU for Linux guests. Experimental.
+    Virtio,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmDisplay {
+    #[serde(default, skip_serializing_if = "is_mac_display")]
+    pub r#type: MacosVmDisplayType,
     pub dpi: usize,
     pub width: usize,
     pub height: usize,
 }
 
+fn is_mac_display(r#type: &MacosVmDisplayType) -> bool {
+    *r#type == MacosVmDisplayType::Mac
+}
+
+// Boot options for debugging the guest.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MacosVmBoot {
+    // Linux guests boot the kernel directly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kernel: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+    // Print the kernel messages on the serial console.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub debug_console: bool,
+    // macOS guests keep boot-args in their NVRAM, which only recoveryOS can change with
+    // `nvram boot-args=...`. This is the value to set there, e.g. "-v" for verbose boot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_args: Option<String>,
+    // Start macOS guests in recoveryOS.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub recovery: bool,
+}
+
+impl MacosVmBoot {
+    // Return the kernel command line with the debug console.
+    pub fn linux_cmdline(&self) -> String {
+        let mut cmdline = self.cmdline.clone().unwrap_or_default();
+        if self.debug_console {
+            if !cmdline.is_empty() {
+                cmdline.push(' ');
+            }
+            // The first virtio console is the serial port in vm.json.
+            cmdline.push_str("console=hvc0");
+        }
+        cmdline
+    }
+}
+
+// Agent that runs the containers in the guest.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GuestAgent {
+    #[default]
+    Akari,
+    // kata-agent in a Linux guest, reached with its ttrpc AgentService on `vsock.agentPort`,
+    // which is 1024 for kata.
+    Kata,
+}
+
+fn is_akari_agent(agent: &GuestAgent) -> bool {
+    *agent == GuestAgent::Akari
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MacosVmConfig {
     pub version: usize,
+    // Shown to the containers as AKARI_VM_NAME. Defaults to the name of the root directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub serial: Option<MacosVmSerial>,
     pub os: String,
+    // Base64 blobs filled by `akari vm init` if they are missing.
+    #[serde(default)]
     pub hardware_model: String,
+    #[serde(default)]
     pub machine_id: String,
     pub cpus: usize,
     pub ram: usize,
@@ -56,6 +178,25 @@ pub struct MacosVmConfig {
     pub shares: Option<Vec<MacosVmSharedDirectory>>,
     pub displays: Vec<MacosVmDisplay>,
     pub audio: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot: Option<MacosVmBoot>,
+    // Refuse to delete the VM without force.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+    // Must match the flags of the agent in the guest.
+    #[serde(default)]
+    pub vsock: VsockPorts,
+    #[serde(default, skip_serializing_if = "is_akari_agent")]
+    pub guest_agent: GuestAgent,
+    // Host ports forwarded to the guest independent of the containers
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nat_rules: Vec<PortMapping>,
+    // Directory with `provision.sh` to run in the guest on the first boot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provision: Option<PathBuf>,
+    // Settings of the device plugins registered by the code that embeds the vmm crate, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub plugins: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(thiserror::Error, Debug)]
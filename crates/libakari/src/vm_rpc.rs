@@ -5,16 +5,28 @@ use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{vm_config::MacosVmSharedDirectory, vsock::VsockPort};
+
 // Command to control the VM.
 pub enum VmCommand {
     Start,
     Stop,
     Pause,
     Resume,
-    Connect(u32, PathBuf),
-    Disconnect(u32),
-    VsockSend(u32, Vec<u8>),
-    VsockRecv(u32),
+    Save(PathBuf),
+    Restore(PathBuf),
+    Connect(VsockPort, PathBuf),
+    // Connect like `Connect` after the guest end answers the challenge of the VM token. Used for
+    // the container ports, which carry no handshake of their own.
+    ConnectAuthenticated(VsockPort, PathBuf),
+    Disconnect(VsockPort),
+    VsockSend(VsockPort, Vec<u8>),
+    VsockRecv(VsockPort),
+    ShowWindow,
+    // Set the target memory size of the guest in bytes via the memory balloon.
+    SetMemoryTarget(u64),
+    // Replace the directories shared with the running guest.
+    SetShares(Vec<MacosVmSharedDirectory>),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -40,4 +52,8 @@ pub enum Error {
     ThreadNotFound,
     #[error("Failed to send command")]
     VmCommandFailed,
+    #[error("Resource exhausted: {0}")]
+    ResourceExhausted(String),
+    #[error("VM is unavailable: {0}")]
+    VmUnavailable(String),
 }
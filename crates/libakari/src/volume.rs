@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::path::{Path, PathBuf};
+
+use oci_spec::runtime::Spec;
+use serde::{Deserialize, Serialize};
+
+use crate::path::{guest_shared_dir_path, volumes_path};
+
+// Mount type to reference a cache volume from the OCI mounts.
+pub const CACHE_VOLUME_MOUNT_TYPE: &str = "akari-cache";
+// Annotation prefix to reference a cache volume: `org.akari.cache-volume.<name>=<destination>`.
+pub const CACHE_VOLUME_ANNOTATION_PREFIX: &str = "org.akari.cache-volume.";
+// Name of the shared directory that exposes the volumes to the guest.
+pub const VOLUMES_SHARE_NAME: &str = "volumes";
+
+const MAX_VOLUME_NAME_LEN: usize = 64;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Invalid volume name: {0}")]
+    InvalidVolumeName(String),
+    #[error("Cache volume mount to {0:?} does not specify a volume name")]
+    MissingVolumeName(PathBuf),
+    #[error("Cache volume destination must be absolute: {0:?}")]
+    RelativeDestination(PathBuf),
+}
+
+// A named host directory shared read-write across containers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheVolume {
+    pub name: String,
+    pub destination: PathBuf,
+}
+
+impl CacheVolume {
+    pub fn new(name: &str, destination: &Path) -> Result<Self, Error> {
+        validate_volume_name(name)?;
+        if destination.is_relative() {
+            return Err(Error::RelativeDestination(destination.to_path_buf()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            destination: destination.to_path_buf(),
+        })
+    }
+
+    // Return the path of the volume on the host.
+    pub fn host_path(&self, root_path: &Path) -> PathBuf {
+        volumes_path(root_path).join(&self.name)
+    }
+
+    // Return the path of the volume inside the guest.
+    pub fn guest_path(&self) -> PathBuf {
+        guest_shared_dir_path()
+            .join(VOLUMES_SHARE_NAME)
+            .join(&self.name)
+    }
+}
+
+// Volume names are used as directory names, so only allow a conservative charset.
+pub fn validate_volume_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_VOLUME_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::InvalidVolumeName(name.to_string()))
+    }
+}
+
+// Collect the cache volumes referenced by the OCI mounts and annotations.
+pub fn cache_volumes(spec: &Spec) -> Result<Vec<CacheVolume>, Error> {
+    let mut volumes = Vec::new();
+
+    if let Some(mounts) = spec.mounts() {
+        for mount in mounts {
+            if mount.typ().as_deref() != Some(CACHE_VOLUME_MOUNT_TYPE) {
+                continue;
+            }
+            let name = mount
+                .source()
+                .as_ref()
+                .and_then(|source| source.to_str())
+                .ok_or_else(|| Error::MissingVolumeName(mount.destination().clone()))?;
+            volumes.push(CacheVolume::new(name, mount.destination())?);
+        }
+    }
+
+    if let Some(annotations) = spec.annotations() {
+        for (key, value) in annotations {
+            if let Some(name) = key.strip_prefix(CACHE_VOLUME_ANNOTATION_PREFIX) {
+                volumes.push(CacheVolume::new(name, Path::new(value))?);
+            }
+        }
+    }
+
+    volumes.sort_by(|a, b| a.destination.cmp(&b.destination));
+    volumes.dedup();
+
+    Ok(volumes)
+}
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Vsock ports shared by the server and the agent.
+
+use std::{fmt, num::ParseIntError, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::console::AgentTransport;
+
+// Port that the agent listens on.
+pub const DEFAULT_AGENT_PORT: VsockPort = VsockPort(9999);
+// First port assigned to the containers.
+pub const DEFAULT_CONTAINER_PORT_BASE: VsockPort = VsockPort(1234);
+
+// VMADDR_PORT_ANY asks the kernel to pick a port, so it cannot name one.
+const VMADDR_PORT_ANY: u32 = u32::MAX;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Vsock ports do not match: host {host:?}, agent {agent:?}")]
+    PortMismatch { host: VsockPorts, agent: VsockPorts },
+    #[error("Invalid vsock port: {0}")]
+    InvalidPort(u32),
+    #[error(transparent)]
+    ParsePort(#[from] ParseIntError),
+}
+
+// A vsock port. Kept apart from the pids and the other numbers that travel with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub struct VsockPort(u32);
+
+impl VsockPort {
+    pub fn new(port: u32) -> Result<Self, Error> {
+        if port == VMADDR_PORT_ANY {
+            return Err(Error::InvalidPort(port));
+        }
+        Ok(Self(port))
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    // Return the port that follows by the offset.
+    pub fn offset(&self, offset: u32) -> Result<Self, Error> {
+        let port = self
+            .0
+            .checked_add(offset)
+            .ok_or(Error::InvalidPort(u32::MAX))?;
+        Self::new(port)
+    }
+}
+
+impl fmt::Display for VsockPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for VsockPort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Self::new(s.parse()?)
+    }
+}
+
+impl TryFrom<u32> for VsockPort {
+    type Error = Error;
+
+    fn try_from(port: u32) -> Result<Self, Error> {
+        Self::new(port)
+    }
+}
+
+impl From<VsockPort> for u32 {
+    fn from(port: VsockPort) -> u32 {
+        port.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VsockPorts {
+    pub agent_port: VsockPort,
+    pub container_port_base: VsockPort,
+}
+
+impl Default for VsockPorts {
+    fn default() -> Self {
+        Self {
+            agent_port: DEFAULT_AGENT_PORT,
+            container_port_base: DEFAULT_CONTAINER_PORT_BASE,
+        }
+    }
+}
+
+impl VsockPorts {
+    // Check that the agent uses the same ports as the host.
+    pub fn verify(&self, agent: &VsockPorts) -> Result<(), Error> {
+        if self != agent {
+            return Err(Error::PortMismatch {
+                host: *self,
+                agent: *agent,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Sent by the agent when the host connects to the agent port.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Handshake {
+    pub ports: VsockPorts,
+    // Empty if the agent predates the console transport.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transports: Vec<AgentTransport>,
+    // Set if the agent has the token of the VM and expects a challenge before the command.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auth: bool,
+}
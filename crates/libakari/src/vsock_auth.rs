@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Authentication of the agent on the vsock connections.
+//! The server writes a random token to a directory that only the VM shares. After the handshake
+//! of each connection, the server sends a random challenge and the agent answers with the
+//! HMAC-SHA256 of the challenge keyed with the token, so that a process in the guest that cannot
+//! read the token cannot impersonate the agent.
+//! The connections of the container ports carry no handshake, so the guest end answers the
+//! challenge as soon as the host connects, before any data of the task service or the stdio.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    framing::{self, read_chunked, write_chunked, ReadFrom, WriteTo, MAX_MESSAGE_SIZE},
+    path::guest_shared_dir_path,
+};
+
+// Name of the shared directory that exposes the token to the guest.
+pub const AUTH_SHARE_NAME: &str = "akari-auth";
+pub const AUTH_TOKEN_FILE: &str = "token";
+
+// Bytes of the token and the challenges
+const RANDOM_LEN: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Invalid token or proof: not a hex string")]
+    InvalidHex,
+    #[error("The agent failed to prove that it knows the token")]
+    ProofMismatch,
+    #[error(transparent)]
+    Framing(#[from] framing::Error),
+}
+
+// Sent by the server after the handshake if the agent asks for it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthChallenge {
+    pub nonce: String,
+}
+
+// Answer of the agent to the challenge.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthProof {
+    pub proof: String,
+}
+
+pub struct AuthToken(Vec<u8>);
+
+// The token is a secret, so it is never logged.
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
+
+impl AuthToken {
+    pub fn generate() -> Result<Self, Error> {
+        Ok(Self(random_bytes()?))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let hex = std::fs::read_to_string(path)?;
+        Ok(Self(decode_hex(hex.trim())?))
+    }
+
+    // Write the token readable only by the owner.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        file.write_all(encode_hex(&self.0).as_bytes())?;
+        Ok(())
+    }
+
+    // Answer the challenge.
+    pub fn prove(&self, challenge: &AuthChallenge) -> AuthProof {
+        AuthProof {
+            proof: encode_hex(&self.mac(challenge).finalize().into_bytes()),
+        }
+    }
+
+    pub fn verify(&self, challenge: &AuthChallenge, proof: &AuthProof) -> Result<(), Error> {
+        // The comparison takes constant time so that the proof cannot be guessed byte by byte.
+        self.mac(challenge)
+            .verify_slice(&decode_hex(&proof.proof)?)
+            .map_err(|_| Error::ProofMismatch)
+    }
+
+    fn mac(&self, challenge: &AuthChallenge) -> HmacSha256 {
+        // HMAC takes keys of any length.
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("Invalid HMAC key length");
+        mac.update(challenge.nonce.as_bytes());
+        mac
+    }
+}
+
+impl AuthChallenge {
+    pub fn generate() -> Result<Self, Error> {
+        Ok(Self {
+            nonce: encode_hex(&random_bytes()?),
+        })
+    }
+}
+
+// Challenge the guest end of the connection and verify its answer.
+pub fn challenge<S: Read + Write>(stream: &mut S, token: &AuthToken) -> Result<(), Error> {
+    let challenge = AuthChallenge::generate()?;
+    write_chunked(&challenge, stream)?;
+    let proof = AuthProof::read_from(stream)?;
+    token.verify(&challenge, &proof)
+}
+
+// Answer the challenge that the host sends on the connection.
+pub fn answer<S: Read + Write>(stream: &mut S, token: &AuthToken) -> Result<(), framing::Error> {
+    let challenge: AuthChallenge = read_chunked(stream, MAX_MESSAGE_SIZE)?;
+    token.prove(&challenge).write_to(stream)
+}
+
+// Return the path of the token inside the guest.
+pub fn guest_token_path() -> PathBuf {
+    guest_shared_dir_path()
+        .join(AUTH_SHARE_NAME)
+        .join(AUTH_TOKEN_FILE)
+}
+
+fn random_bytes() -> Result<Vec<u8>, Error> {
+    let mut bytes = vec![0; RANDOM_LEN];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Error> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(Error::InvalidHex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| Error::InvalidHex))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    fn exchange(host_token: AuthToken, guest_token: AuthToken) -> Result<(), Error> {
+        let (mut host, mut guest) = UnixStream::pair()?;
+        let guest = std::thread::spawn(move || answer(&mut guest, &guest_token));
+        let res = challenge(&mut host, &host_token);
+        guest.join().unwrap()?;
+        res
+    }
+
+    #[test]
+    fn answer_challenge() {
+        let token = AuthToken::generate().unwrap();
+        let copy = AuthToken(token.0.clone());
+        exchange(token, copy).unwrap();
+    }
+
+    #[test]
+    fn refuse_other_token() {
+        let res = exchange(
+            AuthToken::generate().unwrap(),
+            AuthToken::generate().unwrap(),
+        );
+        assert!(matches!(res, Err(Error::ProofMismatch)));
+    }
+}
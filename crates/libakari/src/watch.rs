@@ -0,0 +1,6 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+// Annotation to reload the rootfs of the running container when it changes on the host:
+// `org.akari.watch-rootfs=true`.
+pub const WATCH_ROOTFS_ANNOTATION: &str = "org.akari.watch-rootfs";
@@ -0,0 +1,297 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Handshake and commands with the guest agent.
+//! The agent is reached on its vsock port. If it does not answer there but on the console port,
+//! the commands are sent on the console instead. The container task services still use vsock.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{
+    console::{AgentTransport, ConsoleRequest},
+    container_rpc::{ContainerCommand, ContainerResponse},
+    framing,
+    vm_rpc::VmCommand,
+    vsock::{Handshake, VsockPort, VsockPorts},
+    vsock_auth::{AuthChallenge, AuthProof, AuthToken},
+};
+use log::{debug, error, info, warn};
+use serde::de::DeserializeOwned;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::Mutex,
+    time::timeout,
+};
+
+use crate::ContainerService;
+
+pub async fn read_frame<T: DeserializeOwned>(stream: &mut UnixStream) -> Result<T> {
+    let len = framing::check_frame_len(stream.read_u32().await?)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(framing::decode(&payload)?)
+}
+
+// Host end of the console port of the agent.
+pub struct AgentConsole {
+    // The port has no connections, so one exchange runs at a time.
+    stream: Mutex<UnixStream>,
+    // Set when the agent answers on the console but not on vsock.
+    selected: AtomicBool,
+}
+
+impl AgentConsole {
+    pub fn new(stream: UnixStream) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+            selected: AtomicBool::new(false),
+        }
+    }
+
+    async fn exchange<T: DeserializeOwned>(
+        &self,
+        req: &ConsoleRequest,
+        interval: Option<Duration>,
+    ) -> Result<T> {
+        let mut stream = self.stream.lock().await;
+        // Discard the late response of an exchange that timed out.
+        let mut stale = [0u8; 4096];
+        while let Ok(n) = stream.try_read(&mut stale) {
+            if n == 0 {
+                anyhow::bail!("Agent console is closed");
+            }
+            warn!("Discarded {} stale bytes on the agent console", n);
+        }
+
+        let mut buf = Vec::new();
+        framing::write_chunked(req, &mut buf)?;
+        stream.write_all(&buf).await?;
+        match interval {
+            Some(interval) => timeout(interval, read_frame(&mut stream)).await?,
+            None => read_frame(&mut stream).await,
+        }
+    }
+}
+
+// Token of the VM that the agent proves to know on each connection.
+pub struct AgentAuth {
+    token: Arc<AuthToken>,
+}
+
+impl AgentAuth {
+    pub fn new(token: Arc<AuthToken>) -> Self {
+        Self { token }
+    }
+}
+
+// Challenge the agent. The server always shares the token, so an agent that does not ask for the
+// challenge in the handshake is refused.
+async fn authenticate(
+    service: &ContainerService,
+    stream: &mut UnixStream,
+    handshake: &Handshake,
+    interval: Duration,
+) -> Result<()> {
+    if !handshake.auth {
+        anyhow::bail!("The agent did not authenticate the connection");
+    }
+    let challenge = AuthChallenge::generate()?;
+    let mut buf = Vec::new();
+    framing::write_chunked(&challenge, &mut buf)?;
+    stream.write_all(&buf).await?;
+    let proof: AuthProof = timeout(interval, read_frame(stream)).await??;
+    service.agent_auth.token.verify(&challenge, &proof)?;
+    debug!("The agent is authenticated");
+    Ok(())
+}
+
+// Connect to the agent and read its handshake.
+async fn connect(
+    service: &ContainerService,
+    agent_port: VsockPort,
+    interval: Duration,
+) -> Result<(UnixStream, Handshake)> {
+    // TODO: Use root_path
+    let vsock_path = PathBuf::from(format!("/tmp/akari_vsock_{}", agent_port));
+    let _ = std::fs::remove_file(&vsock_path);
+    service
+        .vm
+        .call(VmCommand::Connect(agent_port, vsock_path.clone()))
+        .await?;
+
+    let mut stream = timeout(interval, UnixStream::connect(&vsock_path)).await??;
+    let handshake = timeout(interval, read_frame(&mut stream)).await??;
+    authenticate(service, &mut stream, &handshake, interval).await?;
+    Ok((stream, handshake))
+}
+
+async fn handshake(
+    service: &ContainerService,
+    agent_port: VsockPort,
+    interval: Duration,
+) -> Result<Handshake> {
+    Ok(connect(service, agent_port, interval).await?.1)
+}
+
+// kata-agent only runs the containers, so the commands of the akari agent are refused.
+fn check_agent(service: &ContainerService, cmd: &ContainerCommand) -> Result<()> {
+    if service.kata.is_some() {
+        anyhow::bail!("{:?} is not supported by kata-agent", cmd);
+    }
+    Ok(())
+}
+
+// Send the command to the agent and return its response.
+pub async fn request(
+    service: &ContainerService,
+    cmd: &ContainerCommand,
+) -> Result<ContainerResponse> {
+    check_agent(service, cmd)?;
+    let res = match service
+        .agent_console
+        .as_ref()
+        .filter(|console| console.selected.load(Ordering::SeqCst))
+    {
+        Some(console) => {
+            console
+                .exchange(&ConsoleRequest::Command(cmd.clone()), None)
+                .await?
+        }
+        None => {
+            let interval =
+                Duration::from_secs(service.config.borrow().agent.handshake_interval.max(1));
+            let (mut stream, _) =
+                connect(service, service.vm_config.vsock.agent_port, interval).await?;
+
+            let mut buf = Vec::new();
+            framing::write_chunked(cmd, &mut buf)?;
+            stream.write_all(&buf).await?;
+            read_frame(&mut stream).await?
+        }
+    };
+    match res {
+        ContainerResponse::Error(e) => Err(anyhow::anyhow!("Agent error: {}", e)),
+        res => Ok(res),
+    }
+}
+
+// Send the command to the agent on vsock and return the stream to read its responses from.
+// The console port carries one response per command, so it cannot stream.
+pub async fn open_stream(service: &ContainerService, cmd: &ContainerCommand) -> Result<UnixStream> {
+    check_agent(service, cmd)?;
+    let interval = Duration::from_secs(service.config.borrow().agent.handshake_interval.max(1));
+    let (mut stream, _) = connect(service, service.vm_config.vsock.agent_port, interval).await?;
+    let mut buf = Vec::new();
+    framing::write_chunked(cmd, &mut buf)?;
+    stream.write_all(&buf).await?;
+    Ok(stream)
+}
+
+// Send the command to the agent and wait for the result.
+pub async fn send_command(service: &ContainerService, cmd: &ContainerCommand) -> Result<()> {
+    request(service, cmd).await?;
+    Ok(())
+}
+
+// Ask the agent to drain the containers, save its state and exit before the VM stops.
+pub async fn shutdown(service: &ContainerService, wait: Duration) -> Result<()> {
+    // kata-agent is stopped with the VM.
+    if service.kata.is_some() {
+        return Ok(());
+    }
+    info!("Shutting down the agent");
+    let cmd = ContainerCommand::Shutdown(Some(wait.as_secs()));
+    // The agent answers once the processes have exited or the deadline has passed.
+    let interval = Duration::from_secs(service.config.borrow().agent.handshake_interval.max(1));
+    timeout(wait + interval, send_command(service, &cmd))
+        .await
+        .map_err(|_| anyhow::anyhow!("Agent did not shut down in {:?}", wait + interval))?
+}
+
+// Send the agent settings of the server configuration.
+pub async fn configure(service: &ContainerService) {
+    let settings = service.config.borrow().agent_settings.clone();
+    if settings.is_empty() {
+        return;
+    }
+    if let Err(e) = send_command(service, &ContainerCommand::Configure(settings)).await {
+        error!("Failed to configure the agent: {}", e);
+    }
+}
+
+// Ask the agent to mount the shared directories that the guest does not automount.
+pub async fn mount_shares(service: &ContainerService) {
+    let shares: Vec<_> = service
+        .vm_config
+        .shares
+        .iter()
+        .flatten()
+        .filter_map(|share| Some((share.tag()?.to_string(), share.guest_path()?)))
+        .collect();
+    if shares.is_empty() {
+        return;
+    }
+    if let Err(e) = send_command(service, &ContainerCommand::MountShares(shares)).await {
+        error!("Failed to mount the shared directories: {}", e);
+    }
+}
+
+// Wait for the agent and check that it uses the ports in vm.json.
+// Return true once the agent is ready.
+pub async fn verify_ports(service: ContainerService) -> bool {
+    let ports: VsockPorts = service.vm_config.vsock;
+    let mut attempts = 0;
+    // The agent starts some time after the VM boots.
+    loop {
+        // Read the timeouts on each attempt as the configuration may have been reloaded.
+        let timeouts = service.config.borrow().agent.clone();
+        if attempts >= timeouts.handshake_retries {
+            break;
+        }
+        attempts += 1;
+        let interval = Duration::from_secs(timeouts.handshake_interval.max(1));
+        let res = match handshake(&service, ports.agent_port, interval).await {
+            Ok(handshake) => Ok((AgentTransport::Vsock, handshake)),
+            Err(e) => match &service.agent_console {
+                Some(console) => {
+                    debug!("Agent is not ready on vsock: {}", e);
+                    console
+                        .exchange(&ConsoleRequest::Handshake, Some(interval))
+                        .await
+                        .map(|handshake| (AgentTransport::Console, handshake))
+                }
+                None => Err(e),
+            },
+        };
+        match res {
+            Ok((transport, handshake)) => {
+                match ports.verify(&handshake.ports) {
+                    Ok(()) => info!(
+                        "Agent is ready on {:?} (agent port {}, transports {:?})",
+                        transport, ports.agent_port, handshake.transports
+                    ),
+                    Err(e) => error!("{}", e),
+                }
+                if let Some(console) = &service.agent_console {
+                    console
+                        .selected
+                        .store(transport == AgentTransport::Console, Ordering::SeqCst);
+                }
+                return true;
+            }
+            Err(e) => debug!("Agent is not ready: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+    error!("Agent did not respond on vsock port {}", ports.agent_port);
+    false
+}
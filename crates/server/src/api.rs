@@ -0,0 +1,529 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Admin API server.
+//! Serves the requests that are not part of the containerd shim v2 API.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Result;
+use containerd_shim::{api::StateRequest, Context};
+use futures::future::join_all;
+use libakari::{
+    api::{ApiRequest, ApiResponse, ContainerInfo, ExtendedState, ServerInfo},
+    build_info,
+    container_rpc::{ContainerCommand, ContainerResponse},
+    event::Event,
+    framing,
+    lifecycle::Timestamps,
+    metrics::{ContainerMetrics, ProcessUsage},
+    network::GUEST_IP_ANNOTATION,
+    path::{golden_path, vm_config_path},
+    stdio::DataSocket,
+    user::check_peer,
+    vm_config::{load_vm_config, GuestAgent},
+    vm_rpc::{VmCommand, VmStatus},
+    vsock::VsockPort,
+};
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::broadcast,
+};
+
+use crate::{
+    agent::{self, open_stream, read_frame, request, send_command},
+    batch, nat,
+    prune::prune,
+    reload::reload,
+    run::run,
+    state::{ContainerState, ContainerStateMap},
+    task_client, ContainerService,
+};
+
+async fn read_request(stream: &mut UnixStream) -> Result<ApiRequest> {
+    let len = framing::check_frame_len(stream.read_u32().await?)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    Ok(framing::decode(&payload)?)
+}
+
+async fn write_response(stream: &mut UnixStream, res: &ApiResponse) -> Result<()> {
+    stream.write_all(&framing::encode(res)?).await?;
+    Ok(())
+}
+
+async fn handle_request(service: &ContainerService, req: ApiRequest) -> Result<ApiResponse> {
+    debug!("API request: {:?}", req);
+    match req {
+        ApiRequest::SubscribeEvents
+        | ApiRequest::WatchContainer { .. }
+        | ApiRequest::DebugExec { .. } => {
+            unreachable!("Handled by the connection")
+        }
+        ApiRequest::ShowWindow => {
+            if !service.gui {
+                return Ok(ApiResponse::Error(
+                    "The server is not running in GUI mode".to_string(),
+                ));
+            }
+            service.vm.call(VmCommand::ShowWindow).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::DeleteVm { force } => delete_vm(service, force).await,
+        ApiRequest::SaveGolden => save_golden(service).await,
+        ApiRequest::ShutdownVm { timeout } => {
+            shutdown_vm(service, Duration::from_secs(timeout.unwrap_or(0))).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::AddNatRule { mapping } => {
+            nat::add(service, mapping).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::DeleteNatRule { mapping } => {
+            nat::delete(service, mapping).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::ListNatRules => Ok(ApiResponse::NatRules(nat::list(service)?)),
+        ApiRequest::Version => Ok(ApiResponse::Version {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+        }),
+        ApiRequest::Info => Ok(ApiResponse::Info(server_info(service))),
+        ApiRequest::ListContainers => {
+            let containers = service
+                .state_map
+                .read()
+                .await
+                .iter()
+                .map(|(id, state)| container_info(service, id, state))
+                .collect();
+            Ok(ApiResponse::Containers(containers))
+        }
+        ApiRequest::ContainerState { id } => container_state(service, &id).await,
+        ApiRequest::BatchState { ids } => batch_state(service, &ids).await,
+        ApiRequest::KillAll => Ok(ApiResponse::BatchResults(batch::kill_all(service).await)),
+        ApiRequest::DeleteAll { force } => Ok(ApiResponse::BatchResults(
+            batch::delete_all(service, force).await,
+        )),
+        ApiRequest::Prune { older_than } => {
+            Ok(ApiResponse::Pruned(prune(service, older_than).await))
+        }
+        ApiRequest::RunContainer(req) => Ok(ApiResponse::Started {
+            pid: run(service, *req).await?,
+        }),
+        ApiRequest::ReloadConfig => Ok(ApiResponse::Reloaded(reload(service)?)),
+        ApiRequest::SnapshotVm { name } => {
+            info!("Taking snapshot {} of the guest data volume", name);
+            send_command(service, &ContainerCommand::Snapshot(name)).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::RollbackVm { name } => {
+            info!("Rolling back the guest data volume to snapshot {}", name);
+            send_command(service, &ContainerCommand::Rollback(name)).await?;
+            Ok(ApiResponse::Ok)
+        }
+        ApiRequest::GuestStats => match request(service, &ContainerCommand::Stats).await? {
+            ContainerResponse::Stats(stats) => Ok(ApiResponse::GuestStats(stats)),
+            res => Err(anyhow::anyhow!(
+                "Unexpected response from the agent: {:?}",
+                res
+            )),
+        },
+    }
+}
+
+fn server_info(service: &ContainerService) -> ServerInfo {
+    let crates = [
+        ("server", env!("CARGO_PKG_VERSION")),
+        ("libakari", build_info::VERSION),
+        ("vmm", vmm::VERSION),
+    ];
+    let vm_config = &service.vm_config;
+    let features = [
+        ("linux-guest", vm_config.os != "darwin"),
+        ("kata", vm_config.guest_agent == GuestAgent::Kata),
+        ("networking", !vm_config.networks.is_empty()),
+        ("provision", vm_config.provision.is_some()),
+        ("gui", service.gui),
+        ("debug-exec", service.config.borrow().debug_exec),
+    ];
+    ServerInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_hash: build_info::GIT_HASH.map(str::to_string),
+        pid: std::process::id(),
+        crates: crates
+            .into_iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect(),
+        features: features
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| name.to_string())
+            .collect(),
+    }
+}
+
+fn container_info(service: &ContainerService, id: &str, state: &ContainerState) -> ContainerInfo {
+    let mut annotations = state.annotations.clone();
+    // Containers share the guest network, so the guest IP address is the container IP address.
+    if let Ok(ip_address) = service.guest_ip() {
+        annotations.insert(GUEST_IP_ANNOTATION.to_string(), ip_address.to_string());
+    }
+    ContainerInfo {
+        id: id.to_string(),
+        status: state.status.clone(),
+        execs: state.execs.values().cloned().collect(),
+        // The shim create response only carries the pid, so the data sockets are advertised
+        // here.
+        data_sockets: state
+            .stdio
+            .iter()
+            .filter(|redirect| redirect.path.is_none() && redirect.exec_id.is_none())
+            .map(|redirect| DataSocket {
+                stream: redirect.stream,
+                path: redirect.data_sock_path(&service.root_path, id),
+            })
+            .collect(),
+        metrics: container_metrics(service, state),
+        exit_reason: state.exit_reason,
+        health: state.health,
+        annotations,
+        timestamps: Timestamps {
+            created_at: state.created_at,
+            started_at: state.started_at,
+            finished_at: state.finished_at,
+        },
+    }
+}
+
+// The fields of the container state taken under the lock of the state map
+struct Snapshot {
+    info: ContainerInfo,
+    vsock_port: VsockPort,
+    vsock_path: PathBuf,
+}
+
+fn snapshot(service: &ContainerService, id: &str, state: &ContainerState) -> Snapshot {
+    Snapshot {
+        info: container_info(service, id, state),
+        vsock_port: state.vsock_port,
+        vsock_path: state.vsock_path.clone(),
+    }
+}
+
+// Report the state of the container with the details from the guest. The guest may be
+// unreachable, e.g. while the VM restarts, so its details are left out on failure.
+async fn container_state(service: &ContainerService, id: &str) -> Result<ApiResponse> {
+    let snapshot = match service.state_map.read().await.get(id) {
+        Some(state) => snapshot(service, id, state),
+        None => return Ok(ApiResponse::Error(format!("Container {} not found", id))),
+    };
+    let stats = guest_usage(service).await.remove(id);
+    Ok(ApiResponse::ContainerState(
+        extended_state(service, snapshot, stats).await,
+    ))
+}
+
+// Report the states of the containers like `container_state`. The guest is asked for the
+// stats once and for the container states in parallel.
+async fn batch_state(service: &ContainerService, ids: &[String]) -> Result<ApiResponse> {
+    let snapshots = {
+        let state_map = service.state_map.read().await;
+        if ids.is_empty() {
+            state_map
+                .iter()
+                .map(|(id, state)| snapshot(service, id, state))
+                .collect::<Vec<_>>()
+        } else {
+            let mut snapshots = Vec::with_capacity(ids.len());
+            for id in ids {
+                match state_map.get(id) {
+                    Some(state) => snapshots.push(snapshot(service, id, state)),
+                    None => return Ok(ApiResponse::Error(format!("Container {} not found", id))),
+                }
+            }
+            snapshots
+        }
+    };
+    let mut usage = guest_usage(service).await;
+    let states = join_all(snapshots.into_iter().map(|snapshot| {
+        let stats = usage.remove(&snapshot.info.id);
+        extended_state(service, snapshot, stats)
+    }))
+    .await;
+    Ok(ApiResponse::ContainerStates(states))
+}
+
+// Resource usage of the processes of each container in the guest
+async fn guest_usage(service: &ContainerService) -> BTreeMap<String, ProcessUsage> {
+    match request(service, &ContainerCommand::Stats).await {
+        Ok(ContainerResponse::Stats(stats)) => stats.usage,
+        Ok(res) => {
+            warn!("Unexpected response from the agent: {:?}", res);
+            BTreeMap::new()
+        }
+        Err(e) => {
+            warn!("Failed to get the guest stats: {}", e);
+            BTreeMap::new()
+        }
+    }
+}
+
+async fn extended_state(
+    service: &ContainerService,
+    snapshot: Snapshot,
+    stats: Option<ProcessUsage>,
+) -> ExtendedState {
+    let id = &snapshot.info.id;
+    let req = StateRequest {
+        id: id.clone(),
+        ..Default::default()
+    };
+    let res = async {
+        let client = task_client(&snapshot.vsock_path)?;
+        client.state(Context::default(), &req).await
+    };
+    let guest_pid = match res.await {
+        Ok(res) => Some(res.pid).filter(|pid| *pid != 0),
+        Err(e) => {
+            warn!("Failed to get the state of {} from the guest: {}", id, e);
+            None
+        }
+    };
+    ExtendedState {
+        info: snapshot.info,
+        vm_id: service.vm_name(),
+        vsock_port: snapshot.vsock_port,
+        guest_pid,
+        stats,
+    }
+}
+
+// Sum up the metrics of the proxies of the container.
+fn container_metrics(service: &ContainerService, state: &ContainerState) -> ContainerMetrics {
+    let mut metrics = ContainerMetrics::default();
+    if let Some(control) = service.connections.metrics(state.vsock_port) {
+        metrics.control = control;
+    }
+    for redirect in &state.stdio {
+        if let Some(stdio) = service.connections.metrics(redirect.port) {
+            metrics.stdio.merge(&stdio);
+        }
+    }
+    metrics
+}
+
+async fn delete_vm(service: &ContainerService, force: bool) -> Result<ApiResponse> {
+    if service.vm_config.protected && !force {
+        return Ok(ApiResponse::Error(
+            "The VM is protected; use --force to delete it".to_string(),
+        ));
+    }
+    let containers = service.state_map.read().await.len();
+    if containers > 0 && !force {
+        return Ok(ApiResponse::Error(format!(
+            "{} containers exist in the VM; use --force to delete it",
+            containers
+        )));
+    }
+
+    info!("Deleting the VM");
+    shutdown_vm(service, Duration::ZERO).await?;
+    for storage in &service.vm_config.storage {
+        std::fs::remove_file(&storage.file)?;
+    }
+    std::fs::remove_file(vm_config_path(&service.root_path))?;
+    Ok(ApiResponse::Ok)
+}
+
+// Save the VM as the golden VM that `vmm::clone::clone_vm` clones. The VM is paused while its
+// disks are cloned so that the disks match the saved machine state.
+async fn save_golden(service: &ContainerService) -> Result<ApiResponse> {
+    let containers = service.state_map.read().await.len();
+    if containers > 0 {
+        return Ok(ApiResponse::Error(format!(
+            "{} containers exist in the VM; delete them before saving the golden VM",
+            containers
+        )));
+    }
+
+    let golden_dir = golden_path(&service.root_path);
+    info!("Saving the golden VM to {:?}", golden_dir);
+    service.vm.call(VmCommand::Pause).await?;
+    let res = save_paused_golden(service, &golden_dir).await;
+    if let Err(e) = service.vm.call(VmCommand::Resume).await {
+        error!("Failed to resume the VM: {}", e);
+    }
+    if let Err(e) = res {
+        let _ = std::fs::remove_dir_all(&golden_dir);
+        return Err(e);
+    }
+    Ok(ApiResponse::Ok)
+}
+
+async fn save_paused_golden(service: &ContainerService, golden_dir: &Path) -> Result<()> {
+    if golden_dir.exists() {
+        std::fs::remove_dir_all(golden_dir)?;
+    }
+    // The on-disk config does not carry the shares that the server adds at startup.
+    let mut vm_config = load_vm_config(&vm_config_path(&service.root_path))?;
+    vm_config.cpus = service.vm_config.cpus;
+    let golden_config = vmm::clone::clone_disks(&vm_config, golden_dir)?;
+    std::fs::write(
+        vm_config_path(golden_dir),
+        serde_json::to_string_pretty(&golden_config)?,
+    )?;
+    let state_path = golden_dir.join(vmm::clone::GOLDEN_STATE_FILE);
+    service.vm.call(VmCommand::Save(state_path)).await?;
+    Ok(())
+}
+
+// Let the agent save its state before the VM stops under the running workloads.
+async fn shutdown_vm(service: &ContainerService, wait: Duration) -> Result<()> {
+    if let Err(e) = agent::shutdown(service, wait).await {
+        warn!("Failed to shut down the agent: {}", e);
+    }
+    info!("Stopping the VM");
+    service.vm.call(VmCommand::Stop).await?;
+    Ok(())
+}
+
+// Stream the events until the subscriber disconnects.
+async fn stream_events(service: &ContainerService, stream: &mut UnixStream) -> Result<()> {
+    let mut rx = service.events.subscribe();
+    for event in service.events.retained() {
+        write_response(stream, &ApiResponse::Event(event)).await?;
+    }
+    loop {
+        match rx.recv().await {
+            Ok(event) => write_response(stream, &ApiResponse::Event(event)).await?,
+            Err(broadcast::error::RecvError::Lagged(n)) => {
+                debug!("Event subscriber lagged behind by {} events", n)
+            }
+            Err(broadcast::error::RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+// Send the status of the container and its changes until it stops, so that the clients need not
+// poll the state.
+async fn stream_container_status(
+    service: &ContainerService,
+    stream: &mut UnixStream,
+    id: &str,
+) -> Result<()> {
+    // Subscribe before reading the status so that no change is missed.
+    let mut rx = service.events.subscribe();
+    let current =
+        |state_map: &ContainerStateMap| state_map.get(id).map(|state| state.status.clone());
+    let Some(mut status) = current(&*service.state_map.read().await) else {
+        let e = format!("Container {} not found", id);
+        return write_response(stream, &ApiResponse::Error(e)).await;
+    };
+    loop {
+        write_response(stream, &ApiResponse::ContainerStatus(status.clone())).await?;
+        if matches!(status, VmStatus::Stopped) {
+            return Ok(());
+        }
+        status = loop {
+            match rx.recv().await.map(|record| record.event) {
+                Ok(Event::ContainerStatusChanged {
+                    id: changed,
+                    status,
+                }) if changed == id => break status,
+                Ok(Event::ContainerDeleted { id: deleted }) if deleted == id => {
+                    let e = format!("Container {} was deleted", id);
+                    return write_response(stream, &ApiResponse::Error(e)).await;
+                }
+                Ok(_) => {}
+                // Read the status again as its changes may have been dropped.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    match current(&*service.state_map.read().await) {
+                        Some(latest) => break latest,
+                        None => {
+                            let e = format!("Container {} was deleted", id);
+                            return write_response(stream, &ApiResponse::Error(e)).await;
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        };
+    }
+}
+
+// Relay the output of the debug command in the guest until it exits.
+async fn stream_debug_exec(
+    service: &ContainerService,
+    stream: &mut UnixStream,
+    args: Vec<String>,
+) -> Result<()> {
+    if !service.config.borrow().debug_exec {
+        let e = "Debug commands are disabled; set debugExec in server.json".to_string();
+        return write_response(stream, &ApiResponse::Error(e)).await;
+    }
+    info!("Running the debug command {:?} in the guest", args);
+    let mut agent = match open_stream(service, &ContainerCommand::DebugExec(args)).await {
+        Ok(agent) => agent,
+        Err(e) => return write_response(stream, &ApiResponse::Error(e.to_string())).await,
+    };
+    loop {
+        let res = match read_frame(&mut agent).await {
+            Ok(ContainerResponse::Output(output, data)) => ApiResponse::Output(output, data),
+            Ok(ContainerResponse::Exited(code)) => {
+                return write_response(stream, &ApiResponse::Exited(code)).await
+            }
+            Ok(ContainerResponse::Error(e)) => {
+                return write_response(stream, &ApiResponse::Error(e)).await
+            }
+            Ok(res) => {
+                let e = format!("Unexpected response from the agent: {:?}", res);
+                return write_response(stream, &ApiResponse::Error(e)).await;
+            }
+            Err(e) => return write_response(stream, &ApiResponse::Error(e.to_string())).await,
+        };
+        write_response(stream, &res).await?;
+    }
+}
+
+async fn handle_connection(service: ContainerService, mut stream: UnixStream) -> Result<()> {
+    // Only the user who owns the server may manage it.
+    if let Err(e) = check_peer(stream.peer_cred()?.uid()) {
+        write_response(&mut stream, &ApiResponse::Error(e.to_string())).await?;
+        return Err(e.into());
+    }
+    let req = read_request(&mut stream).await?;
+    match req {
+        ApiRequest::SubscribeEvents => return stream_events(&service, &mut stream).await,
+        ApiRequest::WatchContainer { id } => {
+            return stream_container_status(&service, &mut stream, &id).await
+        }
+        ApiRequest::DebugExec { args } => {
+            return stream_debug_exec(&service, &mut stream, args).await
+        }
+        _ => {}
+    }
+    let res = match handle_request(&service, req).await {
+        Ok(res) => res,
+        Err(e) => ApiResponse::Error(e.to_string()),
+    };
+    write_response(&mut stream, &res).await
+}
+
+pub async fn serve(listener: UnixListener, service: ContainerService) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(service, stream).await {
+                error!("Failed to handle the API request: {}", e);
+            }
+        });
+    }
+}
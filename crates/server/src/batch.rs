@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Operations on all the containers in one admin API request, e.g. to clean up after an aborted
+//! CI run. The containers are processed with bounded concurrency, and a failure is reported with
+//! the container instead of stopping the batch.
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use containerd_shim::{
+    api::{DeleteRequest, KillRequest},
+    Context, Task as ShimTask,
+};
+use futures::{stream, StreamExt};
+use libakari::{api::BatchResult, vm_rpc::VmStatus};
+use log::info;
+
+use crate::{deadline::local_context, ContainerService};
+
+// Containers processed at the same time
+const MAX_CONCURRENCY: usize = 8;
+// How long a forced delete waits for a killed container to stop
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// IDs of the containers whose status matches, sorted for stable results
+async fn container_ids(service: &ContainerService, f: impl Fn(&VmStatus) -> bool) -> Vec<String> {
+    let mut ids: Vec<_> = service
+        .state_map
+        .read()
+        .await
+        .iter()
+        .filter(|(_, state)| f(&state.status))
+        .map(|(id, _)| id.clone())
+        .collect();
+    ids.sort();
+    ids
+}
+
+async fn for_each<F, Fut>(ids: Vec<String>, f: F) -> Vec<BatchResult>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let mut results: Vec<_> = stream::iter(ids)
+        .map(|id| {
+            let done = f(id.clone());
+            async move {
+                BatchResult {
+                    error: done.await.err().map(|e| e.to_string()),
+                    id,
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENCY)
+        .collect()
+        .await;
+    results.sort_by(|a, b| a.id.cmp(&b.id));
+    results
+}
+
+async fn kill(service: &ContainerService, id: String) -> anyhow::Result<()> {
+    let req = KillRequest {
+        id,
+        ..Default::default()
+    };
+    service.kill(&local_context(), req).await?;
+    Ok(())
+}
+
+async fn wait_stopped(service: &ContainerService, id: &str) -> anyhow::Result<()> {
+    let deadline = Instant::now() + STOP_TIMEOUT;
+    loop {
+        let stopped = service
+            .state_map
+            .read()
+            .await
+            .get(id)
+            .is_none_or(|state| matches!(state.status, VmStatus::Stopped));
+        if stopped {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            anyhow::bail!("Container {} did not stop in {:?}", id, STOP_TIMEOUT);
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+// Kill the containers that have not stopped.
+pub async fn kill_all(service: &ContainerService) -> Vec<BatchResult> {
+    let ids = container_ids(service, |status| !matches!(status, VmStatus::Stopped)).await;
+    info!("Killing {} containers", ids.len());
+    for_each(ids, |id| kill(service, id)).await
+}
+
+// Delete the containers. The running ones fail unless `force` is set to kill them first.
+pub async fn delete_all(service: &ContainerService, force: bool) -> Vec<BatchResult> {
+    let ids = container_ids(service, |_| true).await;
+    info!("Deleting {} containers", ids.len());
+    for_each(ids, |id| async move {
+        if force {
+            let running = service
+                .state_map
+                .read()
+                .await
+                .get(&id)
+                .is_some_and(|state| matches!(state.status, VmStatus::Running));
+            if running {
+                kill(service, id.clone()).await?;
+                wait_stopped(service, &id).await?;
+            }
+        }
+        let req = DeleteRequest {
+            id,
+            ..Default::default()
+        };
+        service.delete_container(Context::default(), &req).await?;
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,318 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Server configuration loaded from `server.json` in the root directory.
+
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Result};
+use libakari::{
+    agent_config::AgentSettings, scheduling::SchedulingPolicy, staging::StagingStrategy,
+    user::group_id,
+};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+// What to do when the host is under memory pressure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MemoryPressureAction {
+    Ignore,
+    // Lower the balloon target to reclaim the guest memory.
+    Balloon,
+    // Pause the VM if no container is running.
+    PauseIdle,
+    // Refuse to create new containers.
+    RefuseCreate,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct MemoryPressurePolicy {
+    pub warning: Vec<MemoryPressureAction>,
+    pub critical: Vec<MemoryPressureAction>,
+    // Target memory size of the guest in MiB when ballooning.
+    pub balloon_target_mib: u64,
+    // Seconds between the polls of the guest memory. The guest is not polled if 0.
+    pub guest_poll_interval: u64,
+    // Percentages of the free guest memory below which the guest is under pressure
+    pub guest_warning_percent: u64,
+    pub guest_critical_percent: u64,
+}
+
+impl Default for MemoryPressurePolicy {
+    fn default() -> Self {
+        Self {
+            warning: vec![MemoryPressureAction::Balloon],
+            critical: vec![
+                MemoryPressureAction::Balloon,
+                MemoryPressureAction::RefuseCreate,
+            ],
+            balloon_target_mib: 2048,
+            guest_poll_interval: 30,
+            guest_warning_percent: 10,
+            guest_critical_percent: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PrunePolicy {
+    // Delete the stopped containers that exited more than `ttl` seconds ago.
+    // Nothing is pruned automatically if unset.
+    pub ttl: Option<u64>,
+    // Seconds between the prune runs
+    pub interval: u64,
+}
+
+impl Default for PrunePolicy {
+    fn default() -> Self {
+        Self {
+            ttl: None,
+            interval: 3600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AgentTimeouts {
+    // Seconds to wait for each handshake with the agent
+    pub handshake_interval: u64,
+    // Number of handshakes to try while the guest boots
+    pub handshake_retries: u32,
+}
+
+impl Default for AgentTimeouts {
+    fn default() -> Self {
+        Self {
+            handshake_interval: 2,
+            handshake_retries: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct VmRestartPolicy {
+    // Restart the VM when its thread exits. The containers in the failed VM are lost.
+    pub enabled: bool,
+    pub max_restarts: u32,
+    // Seconds to wait before each restart
+    pub delay: u64,
+}
+
+impl Default for VmRestartPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_restarts: 3,
+            delay: 5,
+        }
+    }
+}
+
+// What to do with the output when the consumer of a stdio stream falls behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StdioOverflow {
+    // Stop reading from the guest, which blocks the writes of the container.
+    #[default]
+    Block,
+    // Buffer the output on the disk once the memory buffer is full.
+    Spill,
+    // Drop the output and write a marker with the number of dropped bytes.
+    Drop,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StdioBufferPolicy {
+    pub overflow: StdioOverflow,
+    // Bytes buffered in memory per stream
+    pub memory_limit: usize,
+    // Bytes spilled to the disk per stream before the output is dropped
+    pub disk_limit: u64,
+}
+
+impl Default for StdioBufferPolicy {
+    fn default() -> Self {
+        Self {
+            overflow: StdioOverflow::default(),
+            memory_limit: 1024 * 1024,
+            disk_limit: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HookPolicy {
+    // Seconds to wait for each hook before it is killed
+    pub timeout: u64,
+}
+
+impl Default for HookPolicy {
+    fn default() -> Self {
+        Self { timeout: 30 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExecOrphanPolicy {
+    // Signal sent to the exec process when its client disconnects, e.g. 1 (SIGHUP) like a
+    // closed terminal. The process keeps running if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<u32>,
+    // Seconds to wait before the signal
+    pub grace_period: u64,
+    // Seconds to wait for the exit after the signal before the process is killed
+    pub kill_timeout: u64,
+}
+
+impl Default for ExecOrphanPolicy {
+    fn default() -> Self {
+        Self {
+            signal: None,
+            grace_period: 5,
+            kill_timeout: 10,
+        }
+    }
+}
+
+// Access to the aux sockets for other users, e.g. a non-root containerd or the CI users.
+// The sockets are restricted to the current user if unset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SocketPermissions {
+    // Octal mode of the sockets, e.g. "0660"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    // Owning group of the sockets by its name or ID, e.g. "akari"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl SocketPermissions {
+    pub fn mode(&self) -> Result<Option<u32>> {
+        self.mode
+            .as_deref()
+            .map(|mode| match u32::from_str_radix(mode, 8) {
+                Ok(mode) if mode <= 0o777 => Ok(mode),
+                _ => Err(anyhow!("Invalid socket mode: {}", mode)),
+            })
+            .transpose()
+    }
+
+    pub fn group(&self) -> Result<Option<u32>> {
+        Ok(self.group.as_deref().map(group_id).transpose()?)
+    }
+}
+
+// Multiplexer of the VM serial console. The VM connects to the console socket that another
+// process serves if neither is set.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ConsolePolicy {
+    // Append the output of the console to the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log: Option<PathBuf>,
+    // Serve the console on the console socket to the clients that attach to it, e.g. with
+    // `socat - UNIX-CONNECT:console.sock`.
+    pub serve: bool,
+}
+
+impl ConsolePolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.log.is_some() || self.serve
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ServerConfig {
+    // Overrides the log level if set, e.g. "debug".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    pub memory_pressure: MemoryPressurePolicy,
+    // Applied when the VM is created, so changes require a restart.
+    pub scheduling: SchedulingPolicy,
+    pub prune: PrunePolicy,
+    pub agent: AgentTimeouts,
+    // Sent to the agent once it is ready, e.g. to disable its features.
+    pub agent_settings: AgentSettings,
+    // Report the delete of an unknown container as a success so that the retries of the
+    // orchestrators do not fail.
+    pub idempotent_delete: bool,
+    pub vm_restart: VmRestartPolicy,
+    // Allow `akari debug exec-guest` to run commands in the guest OS outside the containers.
+    pub debug_exec: bool,
+    // Append a summary of each task RPC to the file to debug the protocol.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol_trace: Option<PathBuf>,
+    // Applied to the output streams when they are served.
+    pub stdio_buffer: StdioBufferPolicy,
+    // Applied when the aux sockets are bound, so changes require a restart.
+    pub aux_socket: SocketPermissions,
+    // Applied to the executables in `hooks.d` of the root directory.
+    pub hooks: HookPolicy,
+    // Stages the rootfs outside the shared directories unless the container chooses another.
+    pub staging: StagingStrategy,
+    // Applied to the exec processes served after the change.
+    pub exec_orphans: ExecOrphanPolicy,
+    // Applied when the VM is created, so changes require a restart.
+    pub console: ConsolePolicy,
+    // Clamp the CPU count of vm.json to the range of the host instead of failing, so that the
+    // same vm.json runs on Macs with fewer cores. Applied when the VM is created.
+    pub clamp_cpus: bool,
+}
+
+impl ServerConfig {
+    pub fn log_level(&self) -> Result<Option<LevelFilter>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                LevelFilter::from_str(level).map_err(|_| anyhow!("Invalid log level: {}", level))
+            })
+            .transpose()
+    }
+
+    // Names of the settings that differ from `other` and take effect only after a restart.
+    pub fn restart_required(&self, other: &ServerConfig) -> Vec<String> {
+        let mut settings = Vec::new();
+        if self.scheduling != other.scheduling {
+            settings.push("scheduling".to_string());
+        }
+        if self.protocol_trace != other.protocol_trace {
+            settings.push("protocolTrace".to_string());
+        }
+        if self.aux_socket != other.aux_socket {
+            settings.push("auxSocket".to_string());
+        }
+        if self.console != other.console {
+            settings.push("console".to_string());
+        }
+        if self.clamp_cpus != other.clamp_cpus {
+            settings.push("clampCpus".to_string());
+        }
+        settings
+    }
+}
+
+// Load the server configuration. The default configuration is used if the file does not exist.
+pub fn load_server_config(path: &Path) -> Result<ServerConfig> {
+    if !path.exists() {
+        return Ok(ServerConfig::default());
+    }
+    let json_string = std::fs::read_to_string(path)?;
+    let config: ServerConfig = serde_json::from_str(&json_string)?;
+    config.log_level()?;
+    config.aux_socket.mode()?;
+    config.aux_socket.group()?;
+    Ok(config)
+}
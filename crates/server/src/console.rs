@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Multiplexer of the VM serial console.
+//! The output of the console is appended to the log file and sent to each client attached to the
+//! console socket, and the input of the clients is sent to the VM, so that attaching a debugger
+//! does not stop the capture of the log.
+
+use std::{
+    os::unix::{fs::PermissionsExt, net::UnixStream},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixListener,
+    },
+    sync::{broadcast, Mutex},
+};
+
+use crate::{config::ConsolePolicy, remove_stale_socket};
+
+// Chunks of the output buffered for a slow client before it misses them
+const CLIENT_BUFFER_CHUNKS: usize = 1024;
+const READ_BUFFER_SIZE: usize = 4096;
+
+// Start the multiplexer and return the end of the console for the VM.
+pub fn start(policy: &ConsolePolicy, socket: &Path) -> Result<UnixStream> {
+    let (host, guest) = UnixStream::pair()?;
+    host.set_nonblocking(true)?;
+    let host = tokio::net::UnixStream::from_std(host)?;
+    let (reader, writer) = host.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+    let (tx, _) = broadcast::channel(CLIENT_BUFFER_CHUNKS);
+
+    if policy.serve {
+        remove_stale_socket(socket)?;
+        let listener = UnixListener::bind(socket)?;
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+        info!("Serving the VM console on {:?}", socket);
+        tokio::spawn(accept(listener, writer.clone(), tx.clone()));
+    }
+    let log = match &policy.log {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| anyhow::anyhow!("Failed to open the console log {:?}: {}", path, e))?;
+            info!("Logging the VM console to {:?}", path);
+            Some(File::from_std(file))
+        }
+        None => None,
+    };
+    tokio::spawn(capture(reader, writer, log, tx));
+    Ok(guest)
+}
+
+// Read the output of the console until the VM closes it.
+async fn capture(
+    mut reader: OwnedReadHalf,
+    // Dropping the writer would shut down the input of the VM.
+    _writer: Arc<Mutex<OwnedWriteHalf>>,
+    mut log: Option<File>,
+    tx: broadcast::Sender<Arc<[u8]>>,
+) {
+    let mut buf = vec![0; READ_BUFFER_SIZE];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                error!("Failed to read the VM console: {}", e);
+                break;
+            }
+        };
+        if let Some(file) = &mut log {
+            if let Err(e) = file.write_all(&buf[..n]).await {
+                error!("Failed to write the console log: {}", e);
+            }
+        }
+        // No client may be attached.
+        let _ = tx.send(Arc::from(&buf[..n]));
+    }
+    debug!("The VM closed the console");
+}
+
+async fn accept(
+    listener: UnixListener,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    tx: broadcast::Sender<Arc<[u8]>>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                info!("A client attached to the VM console");
+                tokio::spawn(attach(stream, writer.clone(), tx.subscribe()));
+            }
+            Err(e) => {
+                error!("Failed to accept a console client: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+// Relay the console to the client until either side closes it.
+async fn attach(
+    stream: tokio::net::UnixStream,
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    mut rx: broadcast::Receiver<Arc<[u8]>>,
+) {
+    let (mut client_reader, mut client_writer) = stream.into_split();
+    let input = async {
+        let mut buf = vec![0; READ_BUFFER_SIZE];
+        loop {
+            let n = match client_reader.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => n,
+            };
+            if let Err(e) = writer.lock().await.write_all(&buf[..n]).await {
+                error!("Failed to write to the VM console: {}", e);
+                return;
+            }
+        }
+    };
+    let output = async {
+        loop {
+            let chunk = match rx.recv().await {
+                Ok(chunk) => chunk,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("A console client missed {} chunks of the output", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            if client_writer.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+    };
+    tokio::select! {
+        _ = input => {}
+        _ = output => {}
+    }
+    info!("A client detached from the VM console");
+}
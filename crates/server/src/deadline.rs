@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Propagates the deadlines of the callers to the guest and the VM.
+//! A forwarded call is abandoned when its caller has given up instead of leaking it.
+
+use std::time::Duration;
+
+use containerd_shim::{Context, TtrpcContext, TtrpcResult};
+use libakari::vm_rpc::VmCommand;
+
+use crate::ContainerService;
+
+// Return the timeout of the caller if it has one.
+pub fn deadline(ctx: &TtrpcContext) -> Option<Duration> {
+    (ctx.timeout_nano > 0).then(|| Duration::from_nanos(ctx.timeout_nano as u64))
+}
+
+// Context of the call forwarded to the guest with the timeout and the metadata of the caller.
+pub fn forward_context(ctx: &TtrpcContext) -> Context {
+    let mut forwarded = Context::default();
+    forwarded.metadata = ctx.metadata.clone();
+    forwarded.timeout_nano = ctx.timeout_nano;
+    forwarded
+}
+
+// Context of the call that the server makes itself without a caller.
+pub fn local_context() -> TtrpcContext {
+    TtrpcContext {
+        fd: -1,
+        mh: Default::default(),
+        metadata: Default::default(),
+        timeout_nano: 0,
+    }
+}
+
+fn deadline_exceeded(what: &str) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::DEADLINE_EXCEEDED,
+        format!("Deadline exceeded while {}", what),
+    ))
+}
+
+// Run the command in the VM unless the caller gives up before the VM answers.
+pub async fn send_vm_command(
+    service: &ContainerService,
+    ctx: &TtrpcContext,
+    cmd: VmCommand,
+) -> TtrpcResult<()> {
+    service.check_vm()?;
+    let call = service.vm.call(cmd);
+    let result = match deadline(ctx) {
+        Some(deadline) => tokio::time::timeout(deadline, call)
+            .await
+            .map_err(|_| deadline_exceeded("waiting for the VM command"))?,
+        None => call.await,
+    };
+    result.map_err(|e| ttrpc::Error::Others(format!("VM command failed: {}", e)))
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::sync::{Arc, Mutex};
+
+use libakari::event::{Event, EventRecord};
+use log::info;
+use tokio::sync::broadcast;
+
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+// Publishes the server events to the subscribers.
+#[derive(Clone)]
+pub struct EventPublisher {
+    tx: broadcast::Sender<EventRecord>,
+    // Events that every subscriber receives first, e.g. the warnings of the startup, which is
+    // over before any subscriber can connect.
+    retained: Arc<Mutex<Vec<EventRecord>>>,
+}
+
+impl EventPublisher {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            tx,
+            retained: Arc::default(),
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        info!("Event: {:?}", event);
+        // It is fine that there are no subscribers.
+        let _ = self.tx.send(EventRecord::new(event));
+    }
+
+    // Publish the event and replay it to the later subscribers.
+    pub fn publish_retained(&self, event: Event) {
+        info!("Event: {:?}", event);
+        let record = EventRecord::new(event);
+        self.retained
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(record.clone());
+        let _ = self.tx.send(record);
+    }
+
+    pub fn retained(&self) -> Vec<EventRecord> {
+        self.retained
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.tx.subscribe()
+    }
+}
@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Dedicated guest users of the containers.
+//! The agent creates the users, and the server passes them to the guest task with the task
+//! options so that the init and the exec processes of a container run as its own uid.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    container_rpc::{ContainerCommand, ContainerResponse},
+    guest_user::{is_enabled, GuestUser, DEDICATED_USER_ANNOTATION},
+};
+use log::{error, info};
+
+use crate::{
+    agent::{request, send_command},
+    ContainerService,
+};
+
+pub fn enabled(annotations: &HashMap<String, String>) -> bool {
+    // Validated with the request
+    is_enabled(annotations).unwrap_or_default()
+}
+
+pub async fn create(service: &ContainerService, id: &ContainerId) -> Result<GuestUser> {
+    match request(service, &ContainerCommand::CreateUser(id.clone())).await? {
+        ContainerResponse::User(user) => {
+            info!("Container {} runs as {} (uid {})", id, user.name, user.uid);
+            Ok(user)
+        }
+        res => anyhow::bail!("Unexpected response from the agent: {:?}", res),
+    }
+}
+
+pub async fn remove(service: &ContainerService, id: &ContainerId) {
+    let cmd = ContainerCommand::DeleteUser(id.clone());
+    if let Err(e) = send_command(service, &cmd).await {
+        error!("Failed to remove the dedicated user of {}: {}", id, e);
+    }
+}
+
+// Tell the agent the users of the containers again, e.g. after it restarts, so that their exec
+// processes keep running as the users.
+pub async fn restore(service: &ContainerService) {
+    let ids: Vec<_> = service
+        .state_map
+        .read()
+        .await
+        .iter()
+        .filter(|(_, state)| {
+            state
+                .annotations
+                .get(DEDICATED_USER_ANNOTATION)
+                .is_some_and(|value| value == "true")
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+    for id in ids {
+        let result = match ContainerId::new(&id) {
+            Ok(id) => create(service, &id).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Failed to restore the dedicated user of {}: {}", id, e);
+        }
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Operator hooks in `hooks.d` of the root directory, e.g. to set up custom networking or to
+//! audit the containers. The executables in the directory of each stage run in the order of
+//! their names with the container in the environment and as JSON on the stdin.
+//! A failed pre-create hook refuses the create. The failures of the other hooks are logged.
+
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::path::hooks_path;
+use log::{error, info};
+use oci_spec::runtime::Spec;
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{state::ContainerState, ContainerService};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HookStage {
+    PreCreate,
+    PostStart,
+    PostDelete,
+}
+
+impl HookStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HookStage::PreCreate => "pre-create",
+            HookStage::PostStart => "post-start",
+            HookStage::PostDelete => "post-delete",
+        }
+    }
+}
+
+// Container passed to the hooks
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookInput {
+    pub id: String,
+    pub bundle: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<u32>,
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl HookInput {
+    // The container about to be created
+    pub fn from_spec(id: &str, bundle: &Path, spec: &Spec) -> Self {
+        Self {
+            id: id.to_string(),
+            bundle: bundle.to_path_buf(),
+            annotations: spec
+                .annotations()
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn new(id: &str, state: &ContainerState) -> Self {
+        Self {
+            id: id.to_string(),
+            bundle: state.bundle.clone(),
+            pid: None,
+            exit_status: state.exit_status,
+            annotations: state.annotations.clone(),
+        }
+    }
+}
+
+// Return the executables of the stage sorted by their names.
+fn hooks(root_path: &Path, stage: HookStage) -> Result<Vec<PathBuf>> {
+    let dir = hooks_path(root_path).join(stage.name());
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut hooks = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        // Editors and package managers leave hidden files next to the hooks.
+        let hidden = path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+        let executable = std::fs::metadata(&path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0);
+        if !hidden && executable {
+            hooks.push(path);
+        }
+    }
+    hooks.sort();
+    Ok(hooks)
+}
+
+async fn run_hook(hook: &Path, stage: HookStage, input: &HookInput, json: &[u8]) -> Result<()> {
+    let mut cmd = Command::new(hook);
+    cmd.env("AKARI_HOOK_STAGE", stage.name())
+        .env("AKARI_CONTAINER_ID", &input.id)
+        .env("AKARI_BUNDLE", &input.bundle)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(pid) = input.pid {
+        cmd.env("AKARI_PID", pid.to_string());
+    }
+    if let Some(exit_status) = input.exit_status {
+        cmd.env("AKARI_EXIT_STATUS", exit_status.to_string());
+    }
+    let mut child = cmd.spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The hook may exit without reading the input.
+        let _ = stdin.write_all(json).await;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        anyhow::bail!("{:?} failed with {}", hook, status);
+    }
+    Ok(())
+}
+
+// Run the hooks of the stage one by one and stop at the first failure.
+pub async fn run(service: &ContainerService, stage: HookStage, input: &HookInput) -> Result<()> {
+    let hooks = hooks(&service.root_path, stage)?;
+    if hooks.is_empty() {
+        return Ok(());
+    }
+    let json = serde_json::to_vec(input)?;
+    // Read the timeout on each run as the configuration may have been reloaded.
+    let timeout = Duration::from_secs(service.config.borrow().hooks.timeout);
+    for hook in hooks {
+        info!("Running {} hook {:?} for {}", stage.name(), hook, input.id);
+        tokio::time::timeout(timeout, run_hook(&hook, stage, input, &json))
+            .await
+            .map_err(|_| anyhow::anyhow!("{:?} timed out after {:?}", hook, timeout))??;
+    }
+    Ok(())
+}
+
+// Run the hooks of a stage that cannot be refused in the background.
+pub fn spawn(service: &ContainerService, stage: HookStage, input: HookInput) {
+    let service = service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = run(&service, stage, &input).await {
+            error!(
+                "Failed to run the {} hooks of {}: {}",
+                stage.name(),
+                input.id,
+                e
+            );
+        }
+    });
+}
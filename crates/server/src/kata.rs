@@ -0,0 +1,1018 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Adapter for the Linux guests that run kata-agent instead of the akari agent.
+//! The server keeps sending the task requests to the socket of each container. For a kata guest
+//! the socket is served here, and the requests are translated to the ttrpc AgentService of
+//! kata-agent. The stdio is pumped with the stream requests of the agent.
+//! kata does not publish its protocol crate, so the messages are encoded here with only the
+//! fields that the adapter sets. The agent leaves the others to their defaults.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use containerd_shim::{
+    api::{
+        CreateTaskRequest, CreateTaskResponse, DeleteRequest, Empty, ExecProcessRequest,
+        KillRequest, StartRequest, StartResponse, StateRequest, StateResponse, Status,
+        UpdateTaskRequest, WaitRequest, WaitResponse,
+    },
+    protos::shim_async::create_task,
+    DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
+};
+use libakari::{
+    resources::ResourceLimits, stdio::StdioStream, vm_rpc::VmCommand, vsock::VsockPort,
+};
+use log::{debug, error, info, warn};
+use oci_spec::runtime::{LinuxNamespaceType, Process, Spec, User};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+    sync::{watch, Mutex},
+    task::JoinHandle,
+};
+use ttrpc::{asynchronous::Client, asynchronous::Server, proto::Request};
+
+use crate::{path_translator::PathTranslator, vm_handle::VmHandle, ContainerService};
+
+const AGENT_SERVICE: &str = "grpc.AgentService";
+const HEALTH_SERVICE: &str = "grpc.Health";
+// HealthCheckResponse.ServingStatus.SERVING
+const SERVING: u64 = 1;
+// Size of each read of the output streams
+const READ_LEN: u32 = 32 * 1024;
+// Exit status of a process whose exit could not be waited for, as containerd reports it
+const UNKNOWN_EXIT_STATUS: u32 = 255;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Writer of a protobuf message. The empty scalars are skipped as in proto3.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(u64::from(field << 3 | wire_type));
+    }
+
+    fn uint(mut self, field: u32, value: u64) -> Self {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+        self
+    }
+
+    fn bool(self, field: u32, value: bool) -> Self {
+        self.uint(field, value.into())
+    }
+
+    fn bytes(mut self, field: u32, value: &[u8]) -> Self {
+        if !value.is_empty() {
+            self = self.message(field, Message(value.to_vec()));
+        }
+        self
+    }
+
+    fn string(self, field: u32, value: &str) -> Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn strings<'a>(self, field: u32, values: impl IntoIterator<Item = &'a String>) -> Self {
+        values
+            .into_iter()
+            .fold(self, |message, value| message.string(field, value))
+    }
+
+    // Nested messages are written even if they are empty, so that they are set.
+    fn message(mut self, field: u32, value: Message) -> Self {
+        self.key(field, 2);
+        self.varint(value.0.len() as u64);
+        self.0.extend(value.0);
+        self
+    }
+
+    fn map(self, field: u32, entries: &HashMap<String, String>) -> Self {
+        entries.iter().fold(self, |message, (key, value)| {
+            message.message(field, Message::default().string(1, key).string(2, value))
+        })
+    }
+}
+
+// Value of a field read from a protobuf message.
+#[derive(Debug, PartialEq)]
+enum Value {
+    Varint(u64),
+    Bytes(Vec<u8>),
+}
+
+// Read the varint and the length-delimited fields of the message. The fixed-size fields are
+// skipped as the responses used here have none.
+fn decode(mut buf: &[u8]) -> Result<Vec<(u32, Value)>> {
+    fn varint(buf: &mut &[u8]) -> Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = buf
+                .split_first()
+                .ok_or_else(|| anyhow!("Truncated varint"))?;
+            *buf = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("Varint is too long")
+    }
+
+    let mut fields = Vec::new();
+    while !buf.is_empty() {
+        let key = varint(&mut buf)?;
+        let field = (key >> 3) as u32;
+        let value = match key & 7 {
+            0 => Value::Varint(varint(&mut buf)?),
+            2 => {
+                let len = varint(&mut buf)? as usize;
+                if len > buf.len() {
+                    bail!("Truncated field {}", field);
+                }
+                let (value, rest) = buf.split_at(len);
+                buf = rest;
+                Value::Bytes(value.to_vec())
+            }
+            1 => {
+                buf = buf
+                    .get(8..)
+                    .ok_or_else(|| anyhow!("Truncated field {}", field))?;
+                continue;
+            }
+            5 => {
+                buf = buf
+                    .get(4..)
+                    .ok_or_else(|| anyhow!("Truncated field {}", field))?;
+                continue;
+            }
+            wire_type => bail!("Unsupported wire type {} of field {}", wire_type, field),
+        };
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+fn varint_field(payload: &[u8], field: u32) -> Result<u64> {
+    Ok(decode(payload)?
+        .into_iter()
+        .find_map(|(f, value)| match value {
+            Value::Varint(value) if f == field => Some(value),
+            _ => None,
+        })
+        .unwrap_or_default())
+}
+
+fn bytes_field(payload: &[u8], field: u32) -> Result<Vec<u8>> {
+    Ok(decode(payload)?
+        .into_iter()
+        .find_map(|(f, value)| match value {
+            Value::Bytes(value) if f == field => Some(value),
+            _ => None,
+        })
+        .unwrap_or_default())
+}
+
+// oci.User
+fn encode_user(user: &User) -> Message {
+    let gids = user.additional_gids().iter().flatten();
+    gids.fold(
+        Message::default()
+            .uint(1, user.uid().into())
+            .uint(2, user.gid().into()),
+        |message, gid| message.uint(3, (*gid).into()),
+    )
+}
+
+// oci.Process
+fn encode_process(process: &Process) -> Message {
+    Message::default()
+        .bool(1, process.terminal().unwrap_or_default())
+        .message(3, encode_user(process.user()))
+        .strings(4, process.args().iter().flatten())
+        .strings(5, process.env().iter().flatten())
+        .string(6, &process.cwd().to_string_lossy())
+        .bool(9, process.no_new_privileges().unwrap_or_default())
+}
+
+// oci.LinuxResources with the limits that akari applies
+fn encode_resources(limits: &ResourceLimits) -> Message {
+    let mut message = Message::default();
+    if let Some(memory) = limits.memory {
+        message = message.message(2, Message::default().uint(1, memory));
+    }
+    if let Some(shares) = limits.cpu_shares {
+        message = message.message(3, Message::default().uint(1, shares));
+    }
+    if let Some(pids) = limits.pids {
+        message = message.message(4, Message::default().uint(1, pids));
+    }
+    message
+}
+
+// Name of the namespace type in the OCI spec, e.g. "pid"
+fn namespace_type(typ: LinuxNamespaceType) -> String {
+    match serde_json::to_value(typ) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+// oci.Spec with the paths of the guest
+fn encode_spec(spec: &Spec) -> Message {
+    let mut message = Message::default().string(1, spec.version());
+    if let Some(p) = spec.process() {
+        message = message.message(2, encode_process(p));
+    }
+    if let Some(root) = spec.root() {
+        let root = Message::default()
+            .string(1, &root.path().to_string_lossy())
+            .bool(2, root.readonly().unwrap_or_default());
+        message = message.message(3, root);
+    }
+    message = message.string(4, spec.hostname().as_deref().unwrap_or_default());
+    for mount in spec.mounts().iter().flatten() {
+        let source = mount.source().clone().unwrap_or_default();
+        let mount = Message::default()
+            .string(1, &mount.destination().to_string_lossy())
+            .string(2, &source.to_string_lossy())
+            .string(3, mount.typ().as_deref().unwrap_or_default())
+            .strings(4, mount.options().iter().flatten());
+        message = message.message(5, mount);
+    }
+    if let Some(annotations) = spec.annotations() {
+        message = message.map(7, annotations);
+    }
+    if let Some(linux) = spec.linux() {
+        let namespaces = linux.namespaces().iter().flatten().map(|namespace| {
+            let path = namespace.path().clone().unwrap_or_default();
+            Message::default()
+                .string(1, &namespace_type(namespace.typ()))
+                .string(2, &path.to_string_lossy())
+        });
+        let cgroups_path = linux.cgroups_path().clone().unwrap_or_default();
+        let linux_message = namespaces.fold(
+            Message::default().string(5, &cgroups_path.to_string_lossy()),
+            |message, namespace| message.message(6, namespace),
+        );
+        let linux_message = linux_message
+            .strings(10, linux.masked_paths().iter().flatten())
+            .strings(11, linux.readonly_paths().iter().flatten());
+        message = message.message(8, linux_message);
+    }
+    message
+}
+
+// Rewrite the rootfs and the bind mount sources of the spec to the paths of the guest. The agent
+// reads the spec from the request instead of the bundle, so it cannot resolve them.
+pub fn guest_spec(
+    translator: &PathTranslator,
+    bundle: &Path,
+    guest_bundle: &Path,
+    mut spec: Spec,
+) -> Spec {
+    if let Some(mut root) = spec.root().clone() {
+        root.set_path(guest_bundle.join(root.path()));
+        spec.set_root(Some(root));
+    }
+    if let Some(mut mounts) = spec.mounts().clone() {
+        for mount in &mut mounts {
+            let guest = match mount.source() {
+                Some(source) => translator.to_guest(&bundle.join(source)).ok(),
+                None => None,
+            };
+            if guest.is_some() {
+                mount.set_source(guest);
+            }
+        }
+        spec.set_mounts(Some(mounts));
+    }
+    spec
+}
+
+// Connection to kata-agent shared by the containers of the VM.
+pub struct KataAgent {
+    vm: VmHandle,
+    port: VsockPort,
+    sandbox_id: String,
+    // Connected on the first request as the agent starts some time after the VM boots. Dropped
+    // on a transport error to connect again.
+    client: Mutex<Option<Client>>,
+    // Task servers and stdio pumps of each container
+    containers: Mutex<HashMap<String, Served>>,
+}
+
+#[derive(Default)]
+struct Served {
+    servers: Vec<Server>,
+    pumps: Vec<JoinHandle<()>>,
+}
+
+impl KataAgent {
+    pub fn new(vm: VmHandle, port: VsockPort, sandbox_id: &str) -> Self {
+        Self {
+            vm,
+            port,
+            sandbox_id: sandbox_id.to_string(),
+            client: Mutex::new(None),
+            containers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn connect(&self) -> Result<Client> {
+        // TODO: Use root_path
+        let vsock_path = PathBuf::from(format!("/tmp/akari_kata_{}", self.port));
+        let _ = std::fs::remove_file(&vsock_path);
+        self.vm
+            .call(VmCommand::Connect(self.port, vsock_path.clone()))
+            .await?;
+        let path = vsock_path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid socket path: {:?}", vsock_path))?;
+        Ok(Client::connect(path)?)
+    }
+
+    // Send the request without a timeout if none is given, e.g. to wait for the process.
+    async fn request(
+        &self,
+        service: &str,
+        method: &str,
+        message: Message,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let (client, connected) = {
+            let mut client = self.client.lock().await;
+            match client.as_ref() {
+                Some(client) => (client.clone(), false),
+                None => {
+                    let connected = self.connect().await?;
+                    *client = Some(connected.clone());
+                    (connected, true)
+                }
+            }
+        };
+        if connected {
+            // The agent creates the containers in its sandbox. The sandbox outlives the
+            // connections of the server, so it may exist already.
+            let sandbox = Message::default()
+                .string(1, &self.sandbox_id)
+                .string(5, &self.sandbox_id);
+            let timeout = Some(REQUEST_TIMEOUT);
+            if let Err(e) = self
+                .send(&client, AGENT_SERVICE, "CreateSandbox", sandbox, timeout)
+                .await
+            {
+                debug!("Failed to create the kata sandbox: {}", e);
+            }
+        }
+        self.send(&client, service, method, message, timeout).await
+    }
+
+    async fn send(
+        &self,
+        client: &Client,
+        service: &str,
+        method: &str,
+        message: Message,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>> {
+        let req = Request {
+            service: service.to_string(),
+            method: method.to_string(),
+            payload: message.0,
+            timeout_nano: timeout.map_or(0, |timeout| timeout.as_nanos() as i64),
+            ..Default::default()
+        };
+        match client.request(req).await {
+            Ok(res) => Ok(res.payload),
+            Err(ttrpc::Error::RpcStatus(status)) => {
+                bail!("kata-agent {}: {}", method, status.message)
+            }
+            Err(e) => {
+                *self.client.lock().await = None;
+                Err(e.into())
+            }
+        }
+    }
+
+    async fn call(&self, method: &str, message: Message) -> Result<Vec<u8>> {
+        self.request(AGENT_SERVICE, method, message, Some(REQUEST_TIMEOUT))
+            .await
+    }
+
+    // Call the method that blocks until the process writes or exits.
+    async fn call_blocking(&self, method: &str, message: Message) -> Result<Vec<u8>> {
+        self.request(AGENT_SERVICE, method, message, None).await
+    }
+
+    // Check that the agent is serving.
+    pub async fn check(&self) -> Result<()> {
+        let res = self
+            .request(
+                HEALTH_SERVICE,
+                "Check",
+                Message::default(),
+                Some(REQUEST_TIMEOUT),
+            )
+            .await?;
+        match varint_field(&res, 1)? {
+            SERVING => Ok(()),
+            status => bail!("kata-agent is not serving (status {})", status),
+        }
+    }
+
+    // Serve the task requests of the container on the socket.
+    pub async fn serve_task(self: &Arc<Self>, id: &str, spec: Spec, path: &Path) -> Result<()> {
+        let _ = std::fs::remove_file(path);
+        let path = path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid socket path: {:?}", path))?;
+        let task = KataTask {
+            agent: self.clone(),
+            id: id.to_string(),
+            spec,
+            processes: Mutex::new(HashMap::new()),
+        };
+        let task = Box::new(task) as Box<dyn ShimTask + Sync + Send>;
+        let mut server = Server::new()
+            .bind(path)?
+            .register_service(create_task(task.into()));
+        server.start().await?;
+        self.containers
+            .lock()
+            .await
+            .entry(id.to_string())
+            .or_default()
+            .servers
+            .push(server);
+        Ok(())
+    }
+
+    // Pump the stream of the process between the socket and the stream requests of the agent.
+    pub async fn serve_stdio(
+        self: &Arc<Self>,
+        id: &str,
+        exec_id: Option<&str>,
+        stream: StdioStream,
+        path: &Path,
+    ) -> Result<()> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let agent = self.clone();
+        let container_id = id.to_string();
+        let exec_id = exec_id.unwrap_or_default().to_string();
+        let pump = tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                let res = match stream {
+                    StdioStream::Stdin => agent.pump_stdin(&container_id, &exec_id, conn).await,
+                    _ => {
+                        agent
+                            .pump_output(&container_id, &exec_id, stream, conn)
+                            .await
+                    }
+                };
+                match res {
+                    Ok(true) => break,
+                    Ok(false) => (),
+                    Err(e) => {
+                        debug!("Stopped pumping {:?} of {}: {}", stream, container_id, e);
+                        break;
+                    }
+                }
+            }
+        });
+        self.containers
+            .lock()
+            .await
+            .entry(id.to_string())
+            .or_default()
+            .pumps
+            .push(pump);
+        Ok(())
+    }
+
+    // Return whether the stream has ended.
+    async fn pump_stdin(&self, id: &str, exec_id: &str, mut conn: UnixStream) -> Result<bool> {
+        let mut buf = vec![0u8; READ_LEN as usize];
+        loop {
+            let n = conn.read(&mut buf).await?;
+            if n == 0 {
+                let close = Message::default().string(1, id).string(2, exec_id);
+                self.call("CloseStdin", close).await?;
+                return Ok(true);
+            }
+            let mut data = &buf[..n];
+            while !data.is_empty() {
+                let write = Message::default()
+                    .string(1, id)
+                    .string(2, exec_id)
+                    .bytes(3, data);
+                let res = self.call("WriteStdin", write).await?;
+                let written = varint_field(&res, 1)? as usize;
+                data = &data[written.clamp(1, data.len())..];
+            }
+        }
+    }
+
+    // Return whether the stream has ended. The agent fails the read after the process exits.
+    async fn pump_output(
+        &self,
+        id: &str,
+        exec_id: &str,
+        stream: StdioStream,
+        mut conn: UnixStream,
+    ) -> Result<bool> {
+        let method = match stream {
+            StdioStream::Stderr => "ReadStderr",
+            _ => "ReadStdout",
+        };
+        loop {
+            let read = Message::default()
+                .string(1, id)
+                .string(2, exec_id)
+                .uint(3, READ_LEN.into());
+            let data = match self.call_blocking(method, read).await {
+                Ok(res) => bytes_field(&res, 1)?,
+                Err(e) => {
+                    debug!("{:?} of {} ended: {}", stream, id, e);
+                    return Ok(true);
+                }
+            };
+            if data.is_empty() {
+                return Ok(true);
+            }
+            if conn.write_all(&data).await.is_err() {
+                // The consumer went away. The next one reads the rest.
+                return Ok(false);
+            }
+        }
+    }
+
+    // Stop serving the container.
+    pub async fn release(&self, id: &str) {
+        if let Some(served) = self.containers.lock().await.remove(id) {
+            for pump in served.pumps {
+                pump.abort();
+            }
+            // The servers stop when they are dropped.
+            drop(served.servers);
+        }
+    }
+}
+
+// Wait for kata-agent as `agent::verify_ports` does for the akari agent.
+pub async fn wait_ready(service: ContainerService, agent: Arc<KataAgent>) {
+    let mut attempts = 0;
+    loop {
+        let timeouts = service.config.borrow().agent.clone();
+        if attempts >= timeouts.handshake_retries {
+            break;
+        }
+        attempts += 1;
+        let interval = Duration::from_secs(timeouts.handshake_interval.max(1));
+        match tokio::time::timeout(interval, agent.check()).await {
+            Ok(Ok(())) => {
+                info!("kata-agent is ready on vsock port {}", agent.port);
+                return;
+            }
+            Ok(Err(e)) => debug!("kata-agent is not ready: {}", e),
+            Err(_) => debug!("kata-agent did not answer in {:?}", interval),
+        }
+        tokio::time::sleep(interval).await;
+    }
+    error!("kata-agent did not respond on vsock port {}", agent.port);
+}
+
+struct ProcessState {
+    exec_id: String,
+    terminal: bool,
+    // Only the exec processes, which kata runs on the start
+    process: Option<Process>,
+    status: Status,
+    // Set when the process exits
+    exit: watch::Sender<Option<u32>>,
+}
+
+impl ProcessState {
+    fn new(exec_id: &str, terminal: bool, process: Option<Process>) -> Self {
+        Self {
+            exec_id: exec_id.to_string(),
+            terminal,
+            process,
+            status: Status::CREATED,
+            exit: watch::Sender::new(None),
+        }
+    }
+}
+
+// Task service of a container in a kata guest. kata does not report the pids of the guest, so
+// the processes have none.
+struct KataTask {
+    agent: Arc<KataAgent>,
+    id: String,
+    spec: Spec,
+    // By exec ID, where the init process has none
+    processes: Mutex<HashMap<String, ProcessState>>,
+}
+
+fn rpc_error(e: anyhow::Error) -> ttrpc::Error {
+    ttrpc::Error::Others(e.to_string())
+}
+
+fn not_found(exec_id: &str) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::NOT_FOUND,
+        format!("Process {:?} not found", exec_id),
+    ))
+}
+
+impl KataTask {
+    fn process_message(&self, exec_id: &str) -> Message {
+        Message::default().string(1, &self.id).string(2, exec_id)
+    }
+
+    // Record the exit status when the process exits.
+    async fn watch_exit(&self, exec_id: &str) {
+        let exit = match self.processes.lock().await.get_mut(exec_id) {
+            Some(state) => {
+                state.status = Status::RUNNING;
+                state.exit.clone()
+            }
+            None => return,
+        };
+        let agent = self.agent.clone();
+        let wait = self.process_message(exec_id);
+        tokio::spawn(async move {
+            match agent.call_blocking("WaitProcess", wait).await {
+                Ok(res) => {
+                    let status = varint_field(&res, 1).unwrap_or_default() as i32;
+                    exit.send_replace(Some(status as u32));
+                }
+                Err(e) => {
+                    warn!("Failed to wait for the process: {}", e);
+                    // Release the waiters instead of leaving them hanging.
+                    exit.send_replace(Some(UNKNOWN_EXIT_STATUS));
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl ShimTask for KataTask {
+    async fn create(
+        &self,
+        _ctx: &TtrpcContext,
+        req: CreateTaskRequest,
+    ) -> TtrpcResult<CreateTaskResponse> {
+        // kata names the init process after the container.
+        let create = Message::default()
+            .string(1, &self.id)
+            .string(2, &self.id)
+            .message(6, encode_spec(&self.spec));
+        self.agent
+            .call("CreateContainer", create)
+            .await
+            .map_err(rpc_error)?;
+        self.processes
+            .lock()
+            .await
+            .insert(String::new(), ProcessState::new("", req.terminal, None));
+        Ok(CreateTaskResponse::default())
+    }
+
+    async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        if req.exec_id.is_empty() {
+            let start = Message::default().string(1, &self.id);
+            self.agent
+                .call("StartContainer", start)
+                .await
+                .map_err(rpc_error)?;
+        } else {
+            let process = match self.processes.lock().await.get(&req.exec_id) {
+                Some(state) => state.process.clone(),
+                None => return Err(not_found(&req.exec_id)),
+            };
+            let exec = self
+                .process_message(&req.exec_id)
+                .message(4, process.as_ref().map(encode_process).unwrap_or_default());
+            self.agent
+                .call("ExecProcess", exec)
+                .await
+                .map_err(rpc_error)?;
+        }
+        self.watch_exit(&req.exec_id).await;
+        Ok(StartResponse::default())
+    }
+
+    async fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        let process: Process = serde_json::from_slice(&req.spec.value)
+            .map_err(|e| ttrpc::Error::Others(format!("Invalid process spec: {}", e)))?;
+        self.processes.lock().await.insert(
+            req.exec_id.clone(),
+            ProcessState::new(&req.exec_id, req.terminal, Some(process)),
+        );
+        Ok(Empty::default())
+    }
+
+    async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        let signal = match req.signal {
+            0 => libc::SIGKILL as u32,
+            signal => signal,
+        };
+        let kill = self.process_message(&req.exec_id).uint(3, signal.into());
+        self.agent
+            .call("SignalProcess", kill)
+            .await
+            .map_err(rpc_error)?;
+        Ok(Empty::default())
+    }
+
+    async fn wait(&self, _ctx: &TtrpcContext, req: WaitRequest) -> TtrpcResult<WaitResponse> {
+        let mut exit = match self.processes.lock().await.get(&req.exec_id) {
+            Some(state) => state.exit.subscribe(),
+            None => return Err(not_found(&req.exec_id)),
+        };
+        let exit_status = *exit
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        Ok(WaitResponse {
+            exit_status: exit_status.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+
+    async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let processes = self.processes.lock().await;
+        let state = processes
+            .get(&req.exec_id)
+            .ok_or_else(|| not_found(&req.exec_id))?;
+        let exit_status = *state.exit.borrow();
+        Ok(StateResponse {
+            id: self.id.clone(),
+            exec_id: state.exec_id.clone(),
+            status: match exit_status {
+                Some(_) => Status::STOPPED,
+                None => state.status,
+            }
+            .into(),
+            exit_status: exit_status.unwrap_or_default(),
+            terminal: state.terminal,
+            ..Default::default()
+        })
+    }
+
+    async fn update(&self, _ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        let limits = match req.resources.as_ref() {
+            Some(resources) => ResourceLimits::parse(&resources.type_url, &resources.value)
+                .map_err(|e| ttrpc::Error::Others(e.to_string()))?,
+            None => ResourceLimits::default(),
+        };
+        let update = Message::default()
+            .string(1, &self.id)
+            .message(2, encode_resources(&limits));
+        self.agent
+            .call("UpdateContainer", update)
+            .await
+            .map_err(rpc_error)?;
+        Ok(Empty::default())
+    }
+
+    async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        if !self.processes.lock().await.contains_key(&req.exec_id) {
+            return Err(not_found(&req.exec_id));
+        }
+        // The state is kept if the removal fails so that the delete can be retried.
+        if req.exec_id.is_empty() {
+            let remove = Message::default().string(1, &self.id);
+            self.agent
+                .call("RemoveContainer", remove)
+                .await
+                .map_err(rpc_error)?;
+        }
+        let state = self
+            .processes
+            .lock()
+            .await
+            .remove(&req.exec_id)
+            .ok_or_else(|| not_found(&req.exec_id))?;
+        let exit_status = *state.exit.borrow();
+        Ok(DeleteResponse {
+            exit_status: exit_status.unwrap_or_default(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use oci_spec::runtime::{
+        LinuxBuilder, MountBuilder, ProcessBuilder, RootBuilder, SpecBuilder, UserBuilder,
+    };
+
+    use super::*;
+
+    fn field(fields: &[(u32, Value)], number: u32) -> Vec<&Value> {
+        fields
+            .iter()
+            .filter(|(f, _)| *f == number)
+            .map(|(_, value)| value)
+            .collect()
+    }
+
+    fn bytes(value: &str) -> Value {
+        Value::Bytes(value.as_bytes().to_vec())
+    }
+
+    fn nested(value: &Value) -> Vec<(u32, Value)> {
+        match value {
+            Value::Bytes(payload) => decode(payload).unwrap(),
+            Value::Varint(_) => panic!("Field is not a message"),
+        }
+    }
+
+    // The examples of the protobuf encoding guide
+    #[test]
+    fn encode_wire_format() {
+        assert_eq!(Message::default().uint(1, 150).0, [0x08, 0x96, 0x01]);
+        assert_eq!(
+            Message::default().string(2, "testing").0,
+            b"\x12\x07testing"
+        );
+        assert_eq!(
+            Message::default()
+                .message(3, Message::default().uint(1, 150))
+                .0,
+            [0x1a, 0x03, 0x08, 0x96, 0x01]
+        );
+    }
+
+    #[test]
+    fn encode_skips_empty_scalars() {
+        let message = Message::default()
+            .uint(1, 0)
+            .bool(2, false)
+            .string(3, "")
+            .message(4, Message::default());
+        assert_eq!(message.0, [0x22, 0x00]);
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let message = Message::default()
+            .uint(1, u64::MAX)
+            .string(2, "id")
+            .strings(3, &["a".to_string(), "b".to_string()])
+            .message(4, Message::default().uint(1, 7));
+        let fields = decode(&message.0).unwrap();
+        assert_eq!(field(&fields, 1), [&Value::Varint(u64::MAX)]);
+        assert_eq!(field(&fields, 2), [&bytes("id")]);
+        assert_eq!(field(&fields, 3), [&bytes("a"), &bytes("b")]);
+        assert_eq!(nested(field(&fields, 4)[0]), [(1, Value::Varint(7))]);
+        assert_eq!(varint_field(&message.0, 1).unwrap(), u64::MAX);
+        assert_eq!(bytes_field(&message.0, 2).unwrap(), b"id");
+        assert_eq!(varint_field(&message.0, 5).unwrap(), 0);
+    }
+
+    #[test]
+    fn decode_skips_fixed_fields() {
+        let payload = [
+            0x09, 1, 2, 3, 4, 5, 6, 7, 8, // field 1, fixed64
+            0x15, 1, 2, 3, 4, // field 2, fixed32
+            0x18, 0x05, // field 3, varint
+        ];
+        assert_eq!(decode(&payload).unwrap(), [(3, Value::Varint(5))]);
+    }
+
+    #[test]
+    fn decode_rejects_truncated() {
+        assert!(decode(&[0x08, 0x96]).is_err());
+        assert!(decode(&[0x12, 0x07, b't']).is_err());
+        assert!(decode(&[0x09, 1, 2]).is_err());
+        assert!(decode(&[0x0b]).is_err());
+    }
+
+    // oci.Process and oci.User of kata-agent
+    #[test]
+    fn encode_process_fields() {
+        let user = UserBuilder::default()
+            .uid(501u32)
+            .gid(20u32)
+            .additional_gids(vec![12u32])
+            .build()
+            .unwrap();
+        let process = ProcessBuilder::default()
+            .terminal(true)
+            .user(user)
+            .args(vec!["sh".to_string(), "-c".to_string()])
+            .env(vec!["PATH=/bin".to_string()])
+            .cwd("/work")
+            .no_new_privileges(true)
+            .build()
+            .unwrap();
+        let fields = decode(&encode_process(&process).0).unwrap();
+        assert_eq!(field(&fields, 1), [&Value::Varint(1)]);
+        assert_eq!(
+            nested(field(&fields, 3)[0]),
+            [
+                (1, Value::Varint(501)),
+                (2, Value::Varint(20)),
+                (3, Value::Varint(12))
+            ]
+        );
+        assert_eq!(field(&fields, 4), [&bytes("sh"), &bytes("-c")]);
+        assert_eq!(field(&fields, 5), [&bytes("PATH=/bin")]);
+        assert_eq!(field(&fields, 6), [&bytes("/work")]);
+        assert_eq!(field(&fields, 9), [&Value::Varint(1)]);
+    }
+
+    // oci.LinuxResources of kata-agent
+    #[test]
+    fn encode_resources_fields() {
+        let limits = ResourceLimits {
+            memory: Some(1 << 30),
+            cpu_shares: Some(512),
+            pids: Some(64),
+        };
+        let fields = decode(&encode_resources(&limits).0).unwrap();
+        assert_eq!(nested(field(&fields, 2)[0]), [(1, Value::Varint(1 << 30))]);
+        assert_eq!(nested(field(&fields, 3)[0]), [(1, Value::Varint(512))]);
+        assert_eq!(nested(field(&fields, 4)[0]), [(1, Value::Varint(64))]);
+    }
+
+    // oci.Spec of kata-agent
+    #[test]
+    fn encode_spec_fields() {
+        let spec = SpecBuilder::default()
+            .version("1.0.2")
+            .process(ProcessBuilder::default().build().unwrap())
+            .root(
+                RootBuilder::default()
+                    .path("/run/rootfs")
+                    .readonly(true)
+                    .build()
+                    .unwrap(),
+            )
+            .hostname("guest")
+            .mounts(vec![MountBuilder::default()
+                .destination("/data")
+                .source("/mnt/data")
+                .typ("bind")
+                .options(vec!["rbind".to_string()])
+                .build()
+                .unwrap()])
+            .annotations(HashMap::from([("key".to_string(), "value".to_string())]))
+            .linux(
+                LinuxBuilder::default()
+                    .cgroups_path("/akari/guest")
+                    .namespaces(vec![])
+                    .masked_paths(vec![])
+                    .readonly_paths(vec![])
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let fields = decode(&encode_spec(&spec).0).unwrap();
+        assert_eq!(field(&fields, 1), [&bytes("1.0.2")]);
+        assert_eq!(field(&fields, 2).len(), 1);
+        assert_eq!(
+            nested(field(&fields, 3)[0]),
+            [(1, bytes("/run/rootfs")), (2, Value::Varint(1))]
+        );
+        assert_eq!(field(&fields, 4), [&bytes("guest")]);
+        assert_eq!(
+            nested(field(&fields, 5)[0]),
+            [
+                (1, bytes("/data")),
+                (2, bytes("/mnt/data")),
+                (3, bytes("bind")),
+                (4, bytes("rbind"))
+            ]
+        );
+        assert_eq!(
+            nested(field(&fields, 7)[0]),
+            [(1, bytes("key")), (2, bytes("value"))]
+        );
+        assert_eq!(nested(field(&fields, 8)[0]), [(5, bytes("/akari/guest"))]);
+    }
+}
@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Endpoints the shim task service is served on.
+//! Besides the aux sockets, the server can serve on listening sockets inherited from the parent,
+//! e.g. bound by a test harness that runs the client and the server in one process.
+//! ttrpc only serves Unix domain and vsock sockets, so TCP is not supported.
+
+use std::{
+    fmt,
+    os::{fd::RawFd, unix::fs::PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use containerd_shim::Task as ShimTask;
+use containerd_shim_protos::shim_async::create_task;
+use libakari::trace::ProtocolTrace;
+use log::info;
+use ttrpc::asynchronous::Server;
+
+use crate::{
+    config::SocketPermissions, remove_stale_socket, restrict_socket, trace::traced,
+    ContainerService,
+};
+
+pub trait Listener: fmt::Debug + Send + Sync {
+    // Attach the endpoint to the server. A ttrpc server serves a single endpoint.
+    fn attach(&self, server: Server) -> Result<Server>;
+}
+
+// A Unix domain socket restricted to the current user unless the permissions open it up.
+#[derive(Debug)]
+pub struct UnixSocket {
+    path: PathBuf,
+    permissions: SocketPermissions,
+}
+
+impl UnixSocket {
+    pub fn new(path: PathBuf, permissions: SocketPermissions) -> Self {
+        Self { path, permissions }
+    }
+}
+
+// Apply the mode and the group of the server config to the socket.
+fn apply_permissions(path: &Path, permissions: &SocketPermissions) -> Result<()> {
+    if let Some(gid) = permissions.group()? {
+        std::os::unix::fs::chown(path, None, Some(gid))?;
+    }
+    match permissions.mode()? {
+        Some(mode) => std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?,
+        None => restrict_socket(path)?,
+    }
+    Ok(())
+}
+
+impl Listener for UnixSocket {
+    fn attach(&self, server: Server) -> Result<Server> {
+        remove_stale_socket(&self.path)?;
+        let path = self
+            .path
+            .to_str()
+            .ok_or_else(|| anyhow::anyhow!("Invalid socket path: {:?}", self.path))?;
+        let server = server.bind(path)?;
+        apply_permissions(&self.path, &self.permissions)?;
+        Ok(server)
+    }
+}
+
+// A listening socket inherited from the parent process.
+#[derive(Debug)]
+pub struct InheritedFd {
+    fd: RawFd,
+}
+
+impl InheritedFd {
+    pub fn new(fd: RawFd) -> Self {
+        Self { fd }
+    }
+}
+
+impl Listener for InheritedFd {
+    fn attach(&self, server: Server) -> Result<Server> {
+        Ok(server.add_listener(self.fd)?)
+    }
+}
+
+// Start serving the task service on each listener.
+// The servers stop when they are dropped, so the caller keeps them.
+pub async fn serve(
+    listeners: &[Box<dyn Listener>],
+    service: &ContainerService,
+    trace: Option<Arc<ProtocolTrace>>,
+) -> Result<Vec<Server>> {
+    let mut servers = Vec::new();
+    for listener in listeners {
+        info!("Listening on: {:?}", listener);
+        let task = Box::new(service.clone()) as Box<dyn ShimTask + Sync + Send>;
+        let mut services = create_task(task.into());
+        if let Some(trace) = &trace {
+            services = traced(services, trace);
+        }
+        let mut server = listener.attach(Server::new())?.register_service(services);
+        server.start().await?;
+        servers.push(server);
+    }
+    Ok(servers)
+}
@@ -5,25 +5,71 @@
 //!
 //! This is a daemon that manages a VM and the sockets connected to the agent.
 //! 1. Create a macOS guest VM that an agent runs inside.
-//! 2. Listen on a Unix domain socket (`aux.sock`) that accepts ttrpc containerd shim v2 requests.
+//! 2. Listen on Unix domain sockets (`aux.sock` by default) that accept ttrpc containerd shim v2 requests.
 //! 3. Forward the requests to the agent via the vsock, with some exceptions:
 //!   - When creating a container, the server does the following:
-//!     - Create a symbolic link of the container rootfs in the shared directory.
-//!     - Modify the `config.json` file to use the shared directory.
+//!     - Translate the bundle path to the guest path and reject the mounts outside the shared directories.
 //!     - Send a request to the agent.
 //!     - Wait for the agent to finish creating the container.
 //!         - The agent creates a listener socket for the container when it finishes creating the container.
 //!     - Connect to the listener socket and expose it as a Unix domain socket.
 //! 4. Forward the responses from the agent to the containerd shim v2 requests.
 
+mod agent;
+mod api;
+mod batch;
+mod config;
+mod console;
+mod deadline;
+mod events;
+mod guest_user;
+mod hooks;
+mod kata;
+mod listener;
+mod memory;
+mod mock_vm;
+mod nat;
+mod orphan;
+mod overrides;
+mod path_translator;
+mod port_forward;
+mod power;
+mod preflight;
+mod probe;
+mod provision;
+mod prune;
+mod reload;
+mod restart;
+mod run;
+mod scratch;
+mod secret;
+mod staging;
+mod state;
+mod stdio;
+mod stdio_buffer;
+mod supervisor;
+mod template;
+mod timeout;
+mod trace;
+mod transfer;
+mod validate;
+mod vm_handle;
+mod watcher;
+
 use std::{
-    collections::HashMap,
+    net::Ipv4Addr,
     os::{
-        fd::AsRawFd,
-        unix::{fs::FileTypeExt, net::UnixStream},
+        fd::{AsRawFd, RawFd},
+        unix::{
+            fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+            net::UnixStream,
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
     },
-    path::PathBuf,
-    sync::Arc,
 };
 
 use anyhow::Result;
@@ -32,50 +78,441 @@ use clap::Parser;
 use containerd_shim::{
     api::{
         ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
-        Empty, KillRequest, StartRequest, StartResponse, StateRequest, StateResponse,
+        Empty, ExecProcessRequest, KillRequest, StartRequest, StartResponse, StateRequest,
+        StateResponse, UpdateTaskRequest,
+    },
+    protos::protobuf::{
+        well_known_types::{any::Any, timestamp::Timestamp},
+        MessageField,
     },
     Context, DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
 };
-use containerd_shim_protos::shim_async::{create_task, TaskClient};
+use containerd_shim_protos::shim_async::TaskClient;
 use libakari::{
-    path::{aux_sock_path, root_path},
-    vm_config::{load_vm_config, MacosVmConfig, MacosVmSerial},
-    vm_rpc::{self, VmCommand},
+    container_id::ContainerId,
+    container_rpc::ContainerCommand,
+    event::{unix_timestamp, Event},
+    exec::ExecProcess,
+    guest_user::DEDICATED_USER_ANNOTATION,
+    layer::rootfs_layers,
+    network::guest_network_info,
+    network_group::NetworkMember,
+    path::{
+        api_sock_path, auth_path, aux_sock_path, restore_state_path, root_path, scratch_path,
+        server_config_path, staging_path, vm_config_path, volumes_path,
+    },
+    port_forward::{parse_port_mappings, PUBLISHED_PORTS_ANNOTATION},
+    probe::Probes,
+    progress::Progress,
+    provision::PROVISION_SHARE_NAME,
+    resources::ResourceLimits,
+    restart::{RestartPolicy, RESTART_POLICY_ANNOTATION},
+    scratch::SCRATCH_SHARE_NAME,
+    secret::{load_secrets, mask, Secret, SecretsVolume, SECRETS_VOLUME_ANNOTATION},
+    signal::{check_signal, is_stop_signal},
+    staging::{rootfs_share_name, StagingStrategy},
+    stdio::StdioStream,
+    storage::STORAGE_ANNOTATION,
+    task_options::{TaskOptions, TASK_OPTIONS_TYPE_URL},
+    timeout::{parse_max_runtime, MAX_RUNTIME_ANNOTATION},
+    trace::ProtocolTrace,
+    transfer::guest_bundle_path,
+    user::check_owner,
+    vm_config::{load_vm_config, GuestAgent, MacosVmConfig, MacosVmSerial, MacosVmSharedDirectory},
+    vm_rpc::{self, VmCommand, VmStatus},
+    volume::{cache_volumes, VOLUMES_SHARE_NAME},
+    vsock::{self, VsockPort},
+    vsock_auth::{AuthToken, AUTH_SHARE_NAME, AUTH_TOKEN_FILE},
+    watch::WATCH_ROOTFS_ANNOTATION,
 };
-use log::{debug, error, info};
+use log::{debug, error, info, warn, LevelFilter};
+use oci_spec::runtime::Spec;
 use tokio::{
+    net::UnixListener,
     runtime::Runtime,
-    sync::{mpsc, RwLock},
+    sync::{mpsc, watch, RwLock},
     task::JoinHandle,
 };
-use ttrpc::asynchronous::{Client, Server};
+use ttrpc::asynchronous::Client;
+use vmm::connection::ConnectionManager;
+
+use agent::{send_command, AgentAuth, AgentConsole};
+use config::{load_server_config, ServerConfig};
+use deadline::{forward_context, send_vm_command};
+use events::EventPublisher;
+use hooks::{HookInput, HookStage};
+use kata::KataAgent;
+use listener::{InheritedFd, Listener, UnixSocket};
+use path_translator::PathTranslator;
+use port_forward::PortForwarder;
+use power::SleepAction;
+use staging::{Stager, STAGING_SHARE_NAME};
+use state::{
+    load_state_map, spec_hash, vm_status, ContainerState, ContainerStateMap, StdioRedirect,
+};
+use supervisor::{VmHealth, VmThreadArgs};
+use vm_handle::{VmHandle, VmRequest};
+use watcher::RootfsWatcher;
 
 #[derive(clap::Parser)]
 struct Opts {
     /// root directory to store container state
     #[clap(short, long)]
     pub root: Option<PathBuf>,
-    /// Specify the path to the aux socket
+    /// Specify the path to the aux socket (repeat to serve on several sockets)
     #[clap(short, long)]
-    aux_sock: Option<PathBuf>,
+    aux_sock: Vec<PathBuf>,
+    /// Also serve on the listening socket inherited as the file descriptor
+    #[clap(long)]
+    listen_fd: Vec<RawFd>,
+    /// Run without a VM and connect the vsock ports to the fake guest sockets in the directory
+    #[clap(long, hide = true)]
+    mock_vm: Option<PathBuf>,
     /// Specify the path to the VM console socket
     #[clap(short, long)]
     console_sock: Option<PathBuf>,
+    /// Run the AppKit event loop to show the VM display with `akari vm gui`
+    #[clap(long)]
+    gui: bool,
+    /// What to do with the VM when the host sleeps
+    #[clap(long, value_enum, default_value_t = SleepAction::Pause)]
+    on_sleep: SleepAction,
+    /// Stop the server already serving the sockets instead of exiting
+    #[clap(long)]
+    replace: bool,
 }
 
-#[derive(Debug)]
-struct ContainerState {
-    bundle: PathBuf,
-    vsock_port: u32,
-    vsock_path: PathBuf,
-}
-
-type ContainerStateMap = HashMap<String, ContainerState>;
-
 #[derive(Clone)]
 struct ContainerService {
+    root_path: PathBuf,
+    gui: bool,
+    // Replaced when the configuration is reloaded.
+    config: Arc<watch::Sender<ServerConfig>>,
+    vm_config: MacosVmConfig,
+    path_translator: Arc<PathTranslator>,
+    // Unavailable with the mock VM.
+    agent_console: Option<Arc<AgentConsole>>,
+    // Set when the guest runs kata-agent instead of the akari agent.
+    kata: Option<Arc<KataAgent>>,
+    stager: Arc<Stager>,
     state_map: Arc<RwLock<ContainerStateMap>>,
-    cmd_tx: mpsc::Sender<VmCommand>,
+    port_forwarder: Arc<PortForwarder>,
+    // Shared with the VM to read the proxy metrics.
+    connections: ConnectionManager,
+    events: EventPublisher,
+    // Set while the host is under memory pressure.
+    refuse_create: Arc<AtomicBool>,
+    vm_health: VmHealth,
+    vm: VmHandle,
+    rootfs_watcher: RootfsWatcher,
+    agent_auth: Arc<AgentAuth>,
+}
+
+fn no_vsock_port(e: vsock::Error) -> ttrpc::Error {
+    ttrpc::Error::Others(format!("No vsock port is left: {}", e))
+}
+
+fn container_not_found(id: &str) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::NOT_FOUND,
+        format!("Container {} not found", id),
+    ))
+}
+
+// Connect to the task service of the container in the guest. The guest may not be listening, so
+// the failure is returned to the caller.
+fn task_client(vsock_path: &Path) -> TtrpcResult<TaskClient> {
+    let path = vsock_path
+        .to_str()
+        .ok_or_else(|| ttrpc::Error::Others(format!("Invalid socket path: {:?}", vsock_path)))?;
+    Ok(TaskClient::new(Client::connect(path)?))
+}
+
+impl ContainerService {
+    // Refuse the request instead of waiting for a VM that has failed.
+    fn check_vm(&self) -> TtrpcResult<()> {
+        self.vm_health
+            .check()
+            .map_err(|e| ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::UNAVAILABLE, e)))
+    }
+
+    // Return the address of the guest on the NAT network.
+    fn guest_ip(&self) -> anyhow::Result<Ipv4Addr> {
+        guest_network_info(&self.vm_config)?
+            .into_iter()
+            .find_map(|info| info.ip_address)
+            .ok_or_else(|| anyhow::anyhow!("Guest IP address is not available"))
+    }
+
+    fn vm_name(&self) -> String {
+        match (&self.vm_config.name, self.root_path.file_name()) {
+            (Some(name), _) => name.clone(),
+            (None, Some(name)) => name.to_string_lossy().into_owned(),
+            (None, None) => "akari".to_string(),
+        }
+    }
+
+    // Publish the container ports to the guest IP address.
+    async fn publish_ports(&self, id: &str, state: &ContainerState) -> anyhow::Result<()> {
+        if state.ports.is_empty() {
+            return Ok(());
+        }
+        self.port_forwarder
+            .publish(id, &state.ports, self.guest_ip()?)
+            .await
+    }
+
+    // Remove the storage of the container in the guest.
+    async fn remove_storage(&self, id: &ContainerId) {
+        let cmd = ContainerCommand::RemoveStorage(id.clone());
+        if let Err(e) = send_command(self, &cmd).await {
+            error!("Failed to remove the storage of {}: {}", id, e);
+        }
+    }
+
+    // Remove the composed rootfs of the container in the guest.
+    async fn unmount_layers(&self, id: &ContainerId) {
+        let cmd = ContainerCommand::UnmountLayers(id.clone());
+        if let Err(e) = send_command(self, &cmd).await {
+            error!("Failed to remove the rootfs of {}: {}", id, e);
+        }
+    }
+
+    // Resolve the aliases of the containers in the network group, including the one being
+    // created.
+    async fn update_hosts(
+        &self,
+        state_map: &ContainerStateMap,
+        group: &str,
+        creating: Option<&NetworkMember>,
+    ) -> anyhow::Result<()> {
+        let mut aliases: Vec<String> = state_map
+            .values()
+            .filter_map(|state| state.network.as_ref())
+            .chain(creating)
+            .filter(|member| member.group == group)
+            .flat_map(|member| member.aliases.iter().cloned())
+            .collect();
+        aliases.sort();
+        aliases.dedup();
+        let cmd = ContainerCommand::SetHosts(group.to_string(), aliases);
+        send_command(self, &cmd).await
+    }
+
+    // Share the directories of the VM config and the rootfs of the containers staged with
+    // `PerContainerShare`, including the one being created.
+    async fn update_shares(
+        &self,
+        state_map: &ContainerStateMap,
+        creating: Option<(&str, &Path)>,
+    ) -> anyhow::Result<()> {
+        let mut shares = self.vm_config.shares.clone().unwrap_or_default();
+        let containers = state_map
+            .iter()
+            .filter_map(|(id, state)| Some((id.as_str(), state.rootfs_share.as_deref()?)));
+        for (id, path) in containers.chain(creating) {
+            shares.push(MacosVmSharedDirectory {
+                name: Some(rootfs_share_name(id)),
+                path: path.to_path_buf(),
+                automount: true,
+                read_only: false,
+                tag: None,
+                mount_point: None,
+            });
+        }
+        self.vm.call(VmCommand::SetShares(shares)).await?;
+        Ok(())
+    }
+
+    // Return the first vsock port after the ports in use.
+    fn next_vsock_port(&self, state_map: &ContainerStateMap) -> TtrpcResult<VsockPort> {
+        let port_base = self.vm_config.vsock.container_port_base;
+        let last_port = state_map
+            .values()
+            .flat_map(|state| {
+                std::iter::once(state.vsock_port)
+                    .chain(state.stdio.iter().map(|redirect| redirect.port))
+            })
+            .filter(|&port| port >= port_base)
+            .max();
+        match last_port {
+            Some(port) => port.offset(1).map_err(no_vsock_port),
+            None => Ok(port_base),
+        }
+    }
+
+    // Serve the stdio of the process and record its session if asked.
+    async fn serve_stdio(&self, id: &str, exec_id: Option<&str>, redirects: &[StdioRedirect]) {
+        let recorder = match stdio::recorder(&self.root_path, id, exec_id, redirects) {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                error!("Failed to start the recording of {}: {}", id, e);
+                None
+            }
+        };
+        for redirect in redirects {
+            if let Err(e) = stdio::serve(self, id, redirect, recorder.as_ref()).await {
+                error!("Failed to serve the {:?} of {}: {}", redirect.stream, id, e);
+            }
+        }
+    }
+
+    // Delete the exec process in the guest and remove its record.
+    async fn delete_exec(&self, ctx: Context, req: &DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let mut state_map = self.state_map.write().await;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let res = client.delete(ctx, req).await?;
+        state.execs.remove(req.exec_id());
+        // Release the vsock ports of the exec stdio.
+        let (exec_stdio, stdio): (Vec<_>, Vec<_>) = std::mem::take(&mut state.stdio)
+            .into_iter()
+            .partition(|redirect| redirect.exec_id.as_deref() == Some(req.exec_id()));
+        state.stdio = stdio;
+        // The stdio of a kata guest stops when the process exits.
+        for redirect in exec_stdio.iter().filter(|_| self.kata.is_none()) {
+            if let Err(e) = self.vm.call(VmCommand::Disconnect(redirect.port)).await {
+                error!("Failed to disconnect vsock port {}: {}", redirect.port, e);
+            }
+        }
+        if let Err(e) = state.save(&self.root_path, req.id()) {
+            error!("Failed to save the container state: {}", e);
+        }
+        Ok(res)
+    }
+
+    // Delete the container in the guest and remove its bundle and state.
+    async fn delete_container(
+        &self,
+        ctx: Context,
+        req: &DeleteRequest,
+    ) -> TtrpcResult<DeleteResponse> {
+        let mut state_map = self.state_map.write().await;
+        let Some(state) = state_map.get_mut(req.id()) else {
+            if self.config.borrow().idempotent_delete {
+                info!("Container {} does not exist; nothing to delete", req.id());
+                return Ok(DeleteResponse::default());
+            }
+            return Err(container_not_found(req.id()));
+        };
+        let client = task_client(&state.vsock_path)?;
+        let res = client.delete(ctx, req).await?;
+        match state.bundle.try_exists() {
+            Ok(exist) => {
+                if exist
+                    && state
+                        .bundle
+                        .symlink_metadata()
+                        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+                {
+                    std::fs::remove_dir_all(&state.bundle).map_err(|e| {
+                        ttrpc::Error::Others(format!("Failed to remove the bundle: {}", e))
+                    })?;
+                } else {
+                    return Err(ttrpc::Error::Others("Bundle does not exist".to_string()));
+                }
+            }
+            Err(e) => {
+                return Err(ttrpc::Error::Others(format!(
+                    "Failed to check if the bundle exists: {}",
+                    e
+                )));
+            }
+        }
+        self.port_forwarder.unpublish(req.id()).await;
+        self.rootfs_watcher.unwatch(req.id());
+        scratch::remove(&self.root_path, req.id());
+        if !state.rootfs_layers.is_empty() {
+            match ContainerId::new(req.id()) {
+                Ok(id) => self.unmount_layers(&id).await,
+                Err(e) => error!("Failed to remove the rootfs of {}: {}", req.id(), e),
+            }
+        }
+        if state.annotations.contains_key(STORAGE_ANNOTATION) {
+            match ContainerId::new(req.id()) {
+                Ok(id) => self.remove_storage(&id).await,
+                Err(e) => error!("Failed to remove the storage of {}: {}", req.id(), e),
+            }
+        }
+        if self.kata.is_none() && probe::has_probes(&state.annotations) {
+            match ContainerId::new(req.id()) {
+                Ok(id) => probe::stop(self, &id).await,
+                Err(e) => error!("Failed to stop the probes of {}: {}", req.id(), e),
+            }
+        }
+        if state.annotations.contains_key(SECRETS_VOLUME_ANNOTATION) {
+            match ContainerId::new(req.id()) {
+                Ok(id) => secret::unmount(self, &id).await,
+                Err(e) => error!("Failed to remove the secrets volume of {}: {}", req.id(), e),
+            }
+        }
+        // After the secrets volume, which the user owns
+        if state
+            .annotations
+            .get(DEDICATED_USER_ANNOTATION)
+            .is_some_and(|value| value == "true")
+        {
+            match ContainerId::new(req.id()) {
+                Ok(id) => guest_user::remove(self, &id).await,
+                Err(e) => error!("Failed to remove the user of {}: {}", req.id(), e),
+            }
+        }
+        if state.streamed_bundle {
+            match ContainerId::new(req.id()) {
+                Ok(id) => {
+                    if let Err(e) = transfer::remove(self, &id).await {
+                        error!(
+                            "Failed to remove the streamed bundle of {}: {}",
+                            req.id(),
+                            e
+                        );
+                    }
+                }
+                Err(e) => error!("Failed to remove the bundle of {}: {}", req.id(), e),
+            }
+        }
+        // Drain the proxies of the container so that the in-flight data is not lost.
+        let ports = std::iter::once(state.vsock_port).chain(state.stdio.iter().map(|s| s.port));
+        match &self.kata {
+            Some(kata) => kata.release(req.id()).await,
+            None => {
+                for port in ports {
+                    if let Err(e) = self.vm.call(VmCommand::Disconnect(port)).await {
+                        error!("Failed to disconnect vsock port {}: {}", port, e);
+                    }
+                }
+            }
+        }
+        if let Err(e) = ContainerState::remove(&self.root_path, req.id()) {
+            error!("Failed to remove the container state: {}", e);
+        }
+        let input = HookInput::new(req.id(), state);
+        let shared_rootfs = state.rootfs_share.is_some();
+        let network = state.network.take();
+        hooks::spawn(self, HookStage::PostDelete, input);
+        state_map.remove(req.id());
+        self.events.publish(Event::ContainerDeleted {
+            id: req.id().to_string(),
+        });
+        if shared_rootfs {
+            if let Err(e) = self.update_shares(&state_map, None).await {
+                error!("Failed to unshare the rootfs of {}: {}", req.id(), e);
+            }
+        }
+        if let Some(network) = network {
+            if let Err(e) = self.update_hosts(&state_map, &network.group, None).await {
+                error!(
+                    "Failed to remove {} from its network group: {}",
+                    req.id(),
+                    e
+                );
+            }
+        }
+        self.stager.collect(&state_map);
+        Ok(res)
+    }
 }
 
 // Forwards the requests from the client or containerd shim v2 to the unix domain socket connected to the agent.
@@ -83,227 +520,1224 @@ struct ContainerService {
 impl ShimTask for ContainerService {
     async fn connect(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         req: ConnectRequest,
     ) -> TtrpcResult<ConnectResponse> {
+        self.check_vm()?;
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.connect(Context::default(), &req).await?;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let res = client.connect(forward_context(ctx), &req).await?;
         Ok(res)
     }
 
     async fn create(
         &self,
-        _ctx: &TtrpcContext,
-        req: CreateTaskRequest,
+        ctx: &TtrpcContext,
+        mut req: CreateTaskRequest,
     ) -> TtrpcResult<CreateTaskResponse> {
+        // The ID names the state directory and is sent to the guest.
+        let container_id = ContainerId::new(req.id()).map_err(|e| {
+            ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::INVALID_ARGUMENT,
+                e.to_string(),
+            ))
+        })?;
+        validate::create_request(&req, &self.vm_config)?;
+        self.check_vm()?;
+
         let mut state_map = self.state_map.write().await;
 
-        if state_map.contains_key(req.id()) {
-            return Err(ttrpc::Error::Others("Container already exists".to_string()));
+        let bundle = PathBuf::from(req.bundle());
+        let spec_hash = spec_hash(&bundle)
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to read the spec: {}", e)))?;
+
+        // A retry of the create with the same bundle returns the existing container.
+        if let Some(state) = state_map.get(req.id()) {
+            if state.bundle != bundle || state.spec_hash.as_ref() != Some(&spec_hash) {
+                return Err(ttrpc::Error::Others("Container already exists".to_string()));
+            }
+            info!("Container {} already exists with the same spec", req.id());
+            let client = task_client(&state.vsock_path)?;
+            let state_req = StateRequest {
+                id: req.id().to_string(),
+                ..Default::default()
+            };
+            let res = client.state(forward_context(ctx), &state_req).await?;
+            return Ok(CreateTaskResponse {
+                pid: res.pid,
+                ..Default::default()
+            });
         }
 
-        // TODO: Create a symbolic link of the container rootfs in the shared directory.
-        // TODO: Modify the `config.json` file to use the shared directory.
+        if self.refuse_create.load(Ordering::SeqCst) {
+            return Err(ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::RESOURCE_EXHAUSTED,
+                vm_rpc::Error::ResourceExhausted("The host is under memory pressure".to_string()),
+            )));
+        }
 
-        let bundle = PathBuf::from(req.bundle());
+        let mut spec = Spec::load(bundle.join("config.json"))
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to load the spec: {}", e)))?;
+        // The staged spec points at the staged tree, so keep the rootfs of the host.
+        let host_rootfs = spec.root().as_ref().map(|root| bundle.join(root.path()));
+        // Validated with the request
+        let rootfs_layers = rootfs_layers(&spec).unwrap_or_default();
+        let staging = validate::staging_strategy(&spec, self.config.borrow().staging)?;
+
+        // Let the operator hooks refuse the container before anything is prepared for it.
+        let input = HookInput::from_spec(req.id(), &bundle, &spec);
+        hooks::run(self, HookStage::PreCreate, &input)
+            .await
+            .map_err(|e| {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(
+                    ttrpc::Code::FAILED_PRECONDITION,
+                    format!("Refused by the pre-create hooks: {}", e),
+                ))
+            })?;
+
+        // Substitute the host metadata in the env so that the bundles are generic across machines.
+        let vars = template::vars(&self.vm_name(), req.id());
+        let templated = template::expand_env(&mut spec, &vars).map_err(|e| {
+            ttrpc::Error::RpcStatus(ttrpc::get_status(
+                ttrpc::Code::INVALID_ARGUMENT,
+                e.to_string(),
+            ))
+        })?;
+
+        // Stage the rootfs outside the shared directories, e.g. in a CI workspace, so that the
+        // guest can see it. The layered rootfs is always composed from the disk images, and the
+        // streamed bundle is sent from the staging share.
+        let streamed = staging == StagingStrategy::Stream && spec.root().is_some();
+        let (guest_bundle, spec, staged_rootfs, rootfs_share) = match spec.root() {
+            Some(root)
+                if staging == StagingStrategy::DiskImage
+                    || streamed
+                    || !self.path_translator.is_shared(&bundle.join(root.path())) =>
+            {
+                let stager = self.stager.clone();
+                let id = req.id().to_string();
+                let src = bundle.clone();
+                let staged =
+                    tokio::task::spawn_blocking(move || stager.stage(staging, &id, &src, &spec))
+                        .await
+                        .map_err(|e| ttrpc::Error::Others(e.to_string()))?
+                        .map_err(|e| {
+                            ttrpc::Error::Others(format!("Failed to stage the bundle: {}", e))
+                        })?;
+                (staged.bundle, staged.spec, staged.rootfs, staged.share)
+            }
+            // The bundle of the host is left as it is, so the expanded spec gets a bundle of its
+            // own.
+            _ if templated => {
+                let guest_rootfs = host_rootfs
+                    .as_deref()
+                    .map(|rootfs| self.path_translator.to_guest(rootfs))
+                    .transpose()
+                    .map_err(|e| ttrpc::Error::Others(format!("Invalid rootfs: {}", e)))?;
+                let rewritten = self
+                    .stager
+                    .rewrite(req.id(), &spec, guest_rootfs.as_deref())
+                    .map_err(|e| {
+                        ttrpc::Error::Others(format!("Failed to rewrite the bundle: {}", e))
+                    })?;
+                (rewritten, spec, None, None)
+            }
+            _ => (bundle.clone(), spec, None, None),
+        };
+
+        // The guest sees the bundle and the mounts through the shared directories only, except
+        // for the streamed bundle, which the agent unpacks in its own storage.
+        if streamed {
+            self.path_translator
+                .validate_mounts(&guest_bundle, &spec)
+                .map_err(|e| ttrpc::Error::Others(format!("Invalid mount: {}", e)))?;
+            req.bundle = guest_bundle_path(&container_id)
+                .to_string_lossy()
+                .into_owned();
+        } else {
+            self.path_translator
+                .validate_spec(&guest_bundle, &spec)
+                .map_err(|e| ttrpc::Error::Others(format!("Invalid mount: {}", e)))?;
+            req.bundle = self
+                .path_translator
+                .to_guest(&guest_bundle)
+                .map_err(|e| ttrpc::Error::Others(format!("Invalid bundle: {}", e)))?
+                .to_string_lossy()
+                .into_owned();
+        }
+
+        // Prepare the cache volumes referenced by the container.
+        let volumes = cache_volumes(&spec)
+            .map_err(|e| ttrpc::Error::Others(format!("Invalid cache volume: {}", e)))?;
+        for volume in volumes {
+            let host_path = volume.host_path(&self.root_path);
+            std::fs::create_dir_all(&host_path).map_err(|e| {
+                ttrpc::Error::Others(format!(
+                    "Failed to create cache volume {}: {}",
+                    volume.name, e
+                ))
+            })?;
+        }
 
-        // Create a unique vsock port for the container.
-        // Find the smallest used vsock port
-        const DEFAULT_MIN_PORT: u32 = 1234;
-        let mut vsock_port = DEFAULT_MIN_PORT - 1;
-        state_map.values().for_each(|state| {
-            vsock_port = std::cmp::max(vsock_port, state.vsock_port);
+        // Collect the task options from the annotations and the request options.
+        let annotations = spec.annotations().clone().unwrap_or_default();
+        // Validated with the request
+        let network = NetworkMember::from_annotations(req.id(), &annotations).unwrap_or_default();
+        let probes = Probes::from_annotations(&annotations).unwrap_or_default();
+        let watched_rootfs = host_rootfs.filter(|_| {
+            annotations
+                .get(WATCH_ROOTFS_ANNOTATION)
+                .is_some_and(|value| value == "true")
         });
-        vsock_port += 1;
+        let mut ports = match annotations.get(PUBLISHED_PORTS_ANNOTATION) {
+            Some(ports) => parse_port_mappings(ports)
+                .map_err(|e| ttrpc::Error::Others(format!("Invalid published ports: {}", e)))?,
+            None => Vec::new(),
+        };
+        let mut restart_policy = match annotations.get(RESTART_POLICY_ANNOTATION) {
+            Some(policy) => policy
+                .parse()
+                .map_err(|e| ttrpc::Error::Others(e.to_string()))?,
+            None => RestartPolicy::default(),
+        };
+        let mut options = match req.options.as_ref() {
+            Some(options) if options.type_url == TASK_OPTIONS_TYPE_URL => {
+                serde_json::from_slice(&options.value)
+                    .map_err(|e| ttrpc::Error::Others(format!("Invalid task options: {}", e)))?
+            }
+            _ => TaskOptions::default(),
+        };
+        ports.extend(options.ports.iter().cloned());
+        if let Some(policy) = options.restart_policy {
+            restart_policy = policy;
+        }
+        let mut max_runtime = match annotations.get(MAX_RUNTIME_ANNOTATION) {
+            Some(max_runtime) => Some(
+                parse_max_runtime(max_runtime)
+                    .map_err(|e| ttrpc::Error::Others(e.to_string()))?
+                    .as_secs(),
+            ),
+            None => None,
+        };
+        if options.max_runtime.is_some() {
+            max_runtime = options.max_runtime;
+        }
+
+        // Send the secrets with the task options so that they are never written to the bundle,
+        // or to the agent if the container has a secrets volume.
+        let secrets = load_secrets(&self.root_path, &annotations)
+            .map_err(|e| ttrpc::Error::Others(format!("Failed to load the secrets: {}", e)))?;
+        let secrets_volume = SecretsVolume::new(&annotations, &secrets)
+            .map_err(|e| ttrpc::Error::Others(e.to_string()))?;
+        let env_secrets = if secrets_volume.is_some() {
+            &[]
+        } else {
+            secrets.as_slice()
+        };
+        let has_secrets_volume = secrets_volume.is_some();
+        let scratch = scratch::enabled(&annotations);
+        let dedicated_user = guest_user::enabled(&annotations);
+        if !env_secrets.is_empty() || scratch || dedicated_user {
+            if req
+                .options
+                .as_ref()
+                .is_some_and(|options| options.type_url != TASK_OPTIONS_TYPE_URL)
+            {
+                return Err(ttrpc::Error::Others(
+                    "Secrets, scratch directories and dedicated users cannot be combined with \
+                     other runtime options"
+                        .to_string(),
+                ));
+            }
+            if !env_secrets.is_empty() {
+                info!("Injecting secrets into {}: {:?}", req.id(), env_secrets);
+                options
+                    .env
+                    .extend(env_secrets.iter().map(Secret::env_entry));
+            }
+            // The user and the directory are removed with the container.
+            if dedicated_user {
+                let user = guest_user::create(self, &container_id).await.map_err(|e| {
+                    ttrpc::Error::Others(format!("Failed to create the dedicated user: {}", e))
+                })?;
+                options.user = Some(user);
+            }
+            if scratch {
+                match scratch::create(&self.root_path, req.id()) {
+                    Ok(env) => options.env.push(env),
+                    Err(e) => {
+                        if dedicated_user {
+                            guest_user::remove(self, &container_id).await;
+                        }
+                        return Err(ttrpc::Error::Others(format!(
+                            "Failed to create the scratch directory: {}",
+                            e
+                        )));
+                    }
+                }
+            }
+            req.options = MessageField::some(Any {
+                type_url: TASK_OPTIONS_TYPE_URL.to_string(),
+                value: serde_json::to_vec(&options).map_err(|e| {
+                    ttrpc::Error::Others(format!("Failed to encode the task options: {}", e))
+                })?,
+                ..Default::default()
+            });
+        }
+        // After the user so that the secrets are owned by it. Removed with the container.
+        if let Some(volume) = secrets_volume {
+            if let Err(e) = secret::mount(self, &container_id, volume).await {
+                if scratch {
+                    scratch::remove(&self.root_path, req.id());
+                }
+                if dedicated_user {
+                    guest_user::remove(self, &container_id).await;
+                }
+                return Err(ttrpc::Error::Others(format!(
+                    "Failed to create the secrets volume: {}",
+                    e
+                )));
+            }
+        }
+
+        // Create a unique vsock port for the container after the ports in use.
+        let vsock_port = self.next_vsock_port(&state_map)?;
+
+        // Serve the stdio on the following vsock ports. The streams are exposed on the data
+        // sockets of the container, separate from aux.sock, and redirected to the host files.
+        let terminal = req.terminal;
+        let redirects = stdio::redirect_stdio(
+            [
+                (StdioStream::Stdin, &mut req.stdin),
+                (StdioStream::Stdout, &mut req.stdout),
+                (StdioStream::Stderr, &mut req.stderr),
+            ],
+            vsock_port.offset(1).map_err(no_vsock_port)?,
+            None,
+            terminal,
+        )
+        .map_err(no_vsock_port)?;
 
         // TODO: Use root_path
-        let vsock_path = PathBuf::from(format!("/tmp/akari_vsock_{}", vsock_port));
+        let mut vsock_path = PathBuf::from(format!("/tmp/akari_vsock_{}", vsock_port));
+        // The create retried after a timeout finds the proxy of the previous attempt.
+        let proxied = self
+            .connections
+            .path(vsock_port)
+            .filter(|path| path.exists());
+
+        // Resolve the others of the group before the container starts.
+        if let Some(network) = &network {
+            self.update_hosts(&state_map, &network.group, Some(network))
+                .await
+                .map_err(|e| {
+                    ttrpc::Error::Others(format!("Failed to join the network group: {}", e))
+                })?;
+        }
+        if let Some(share) = &rootfs_share {
+            self.update_shares(&state_map, Some((req.id(), share)))
+                .await
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to share the rootfs: {}", e)))?;
+        }
+        if !rootfs_layers.is_empty() {
+            let cmd = ContainerCommand::MountLayers(container_id.clone(), rootfs_layers.clone());
+            send_command(self, &cmd).await.map_err(|e| {
+                ttrpc::Error::Others(format!("Failed to compose the rootfs: {}", e))
+            })?;
+        }
+        if streamed {
+            transfer::send(self, &container_id)
+                .await
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to stream the bundle: {}", e)))?;
+        }
+
+        match &self.kata {
+            // The task socket of the container is served by the adapter of kata-agent.
+            Some(kata) => {
+                let guest_spec = kata::guest_spec(
+                    &self.path_translator,
+                    &guest_bundle,
+                    Path::new(&req.bundle),
+                    spec.clone(),
+                );
+                kata.serve_task(req.id(), guest_spec, &vsock_path)
+                    .await
+                    .map_err(|e| {
+                        ttrpc::Error::Others(format!("Failed to serve the kata task: {}", e))
+                    })?;
+            }
+            None => match proxied {
+                Some(path) => {
+                    info!(
+                        "Reusing the proxy of vsock port {} on {:?}",
+                        vsock_port, path
+                    );
+                    vsock_path = path;
+                }
+                None => {
+                    send_vm_command(
+                        self,
+                        ctx,
+                        VmCommand::ConnectAuthenticated(vsock_port, vsock_path.clone()),
+                    )
+                    .await?
+                }
+            },
+        }
 
-        self.cmd_tx
-            .send(VmCommand::Connect(vsock_port, vsock_path.clone()))
+        let client = task_client(&vsock_path)?;
+        // The error may echo the request, so mask the secrets before it is logged.
+        let res = client
+            .create(forward_context(ctx), &req)
             .await
-            .unwrap();
+            .map_err(|e| match e {
+                ttrpc::Error::Others(msg) => ttrpc::Error::Others(mask(&msg, &secrets)),
+                ttrpc::Error::RpcStatus(mut status) => {
+                    status.message = mask(&status.message, &secrets);
+                    ttrpc::Error::RpcStatus(status)
+                }
+                e => e,
+            });
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                if scratch {
+                    scratch::remove(&self.root_path, req.id());
+                }
+                if has_secrets_volume {
+                    secret::unmount(self, &container_id).await;
+                }
+                if dedicated_user {
+                    guest_user::remove(self, &container_id).await;
+                }
+                if !rootfs_layers.is_empty() {
+                    self.unmount_layers(&container_id).await;
+                }
+                if annotations.contains_key(STORAGE_ANNOTATION) {
+                    self.remove_storage(&container_id).await;
+                }
+                if streamed {
+                    if let Err(e) = transfer::remove(self, &container_id).await {
+                        error!(
+                            "Failed to remove the streamed bundle of {}: {}",
+                            req.id(),
+                            e
+                        );
+                    }
+                }
+                if rootfs_share.is_some() {
+                    if let Err(e) = self.update_shares(&state_map, None).await {
+                        error!("Failed to unshare the rootfs of {}: {}", req.id(), e);
+                    }
+                }
+                if let Some(network) = &network {
+                    if let Err(e) = self.update_hosts(&state_map, &network.group, None).await {
+                        error!(
+                            "Failed to remove {} from its network group: {}",
+                            req.id(),
+                            e
+                        );
+                    }
+                }
+                return Err(e);
+            }
+        };
 
-        let client =
-            TaskClient::new(Client::connect(vsock_path.clone().to_str().unwrap()).unwrap());
-        let res = client.create(Context::default(), &req).await?;
+        if self.kata.is_none() && !probes.is_empty() {
+            probe::start(self, &container_id, probes).await;
+        }
+        self.serve_stdio(req.id(), None, &redirects).await;
 
         let state = ContainerState {
             bundle,
             vsock_port,
             vsock_path,
+            status: VmStatus::Created,
+            ports,
+            restart_policy,
+            restart_count: 0,
+            exit_status: None,
+            created_at: Some(unix_timestamp()),
+            started_at: None,
+            finished_at: None,
+            exit_reason: None,
+            max_runtime,
+            deadline: None,
+            memory_limit: None,
+            stopped_by_user: false,
+            execs: Default::default(),
+            stdio: redirects,
+            spec_hash: Some(spec_hash),
+            staged_rootfs,
+            rootfs_share,
+            rootfs_layers,
+            streamed_bundle: streamed,
+            network,
+            annotations: annotations.into_iter().collect(),
+            watched_rootfs,
+            health: None,
         };
+        if let Some(rootfs) = &state.watched_rootfs {
+            if let Err(e) = self.rootfs_watcher.watch(req.id(), rootfs) {
+                warn!("Failed to watch the rootfs of {}: {}", req.id(), e);
+            }
+        }
+        if let Err(e) = state.save(&self.root_path, req.id()) {
+            error!("Failed to save the container state: {}", e);
+        }
         state_map.insert(req.id().to_string(), state);
+        self.events.publish(Event::ContainerStatusChanged {
+            id: req.id().to_string(),
+            status: VmStatus::Created,
+        });
 
         Ok(res)
     }
 
-    async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+    async fn delete(&self, ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        if !req.exec_id().is_empty() {
+            return self.delete_exec(forward_context(ctx), &req).await;
+        }
+        self.delete_container(forward_context(ctx), &req).await
+    }
+
+    async fn exec(&self, ctx: &TtrpcContext, mut req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        self.check_vm()?;
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.delete(Context::default(), &req).await?;
-        match state.bundle.try_exists() {
-            Ok(exist) => {
-                if exist
-                    && state
-                        .bundle
-                        .symlink_metadata()
-                        .unwrap()
-                        .file_type()
-                        .is_symlink()
-                {
-                    std::fs::remove_dir_all(&state.bundle).unwrap(); // TODO
-                } else {
-                    return Err(ttrpc::Error::Others("Bundle does not exist".to_string()));
-                }
-            }
-            Err(e) => {
-                return Err(ttrpc::Error::Others(format!(
-                    "Failed to check if the bundle exists: {}",
-                    e
-                )));
-            }
+        if state_map
+            .get(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?
+            .execs
+            .contains_key(req.exec_id())
+        {
+            return Err(ttrpc::Error::Others(format!(
+                "Exec {} already exists",
+                req.exec_id()
+            )));
+        }
+
+        // The stdio of the exec process is served like the one of the container, so that each
+        // exec can record its terminal session.
+        let first_port = self.next_vsock_port(&state_map)?;
+        let exec_id = req.exec_id().to_string();
+        let terminal = req.terminal;
+        let redirects = stdio::redirect_stdio(
+            [
+                (StdioStream::Stdin, &mut req.stdin),
+                (StdioStream::Stdout, &mut req.stdout),
+                (StdioStream::Stderr, &mut req.stderr),
+            ],
+            first_port,
+            Some(&exec_id),
+            terminal,
+        )
+        .map_err(no_vsock_port)?;
+
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let res = client.exec(forward_context(ctx), &req).await?;
+        self.serve_stdio(req.id(), Some(&exec_id), &redirects).await;
+        state.stdio.extend(redirects);
+        state
+            .execs
+            .insert(exec_id.clone(), ExecProcess::new(&exec_id));
+        if let Err(e) = state.save(&self.root_path, req.id()) {
+            error!("Failed to save the container state: {}", e);
         }
-        state_map.remove(req.id());
         Ok(res)
     }
 
-    async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+    async fn kill(&self, ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        self.check_vm()?;
+        // 0 sends the default signal of the guest.
+        if req.signal != 0 {
+            check_signal(req.signal, &self.vm_config.os).map_err(|e| {
+                ttrpc::Error::RpcStatus(ttrpc::get_status(
+                    ttrpc::Code::INVALID_ARGUMENT,
+                    e.to_string(),
+                ))
+            })?;
+        }
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.kill(Context::default(), &req).await?;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let res = client.kill(forward_context(ctx), &req).await?;
+        // Other signals, e.g. HUP to reload the configuration, keep the container running.
+        if !req.exec_id().is_empty() || !is_stop_signal(req.signal) {
+            return Ok(res);
+        }
+        // Do not restart the container that the user stopped.
+        state.stopped_by_user = true;
+        if let Err(e) = state.save(&self.root_path, req.id()) {
+            error!("Failed to save the container state: {}", e);
+        }
+        self.port_forwarder.unpublish(req.id()).await;
         Ok(res)
     }
 
-    async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+    async fn start(&self, ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        self.check_vm()?;
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.start(Context::default(), &req).await?;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let forwarded = if req.exec_id().is_empty() {
+            overrides::forward_start(self, ctx, req.id(), &state.bundle)?
+        } else {
+            forward_context(ctx)
+        };
+        let res = client.start(forwarded, &req).await?;
+        if !req.exec_id().is_empty() {
+            if let Some(exec) = state.execs.get_mut(req.exec_id()) {
+                exec.start(res.pid);
+            }
+            if let Err(e) = state.save(&self.root_path, req.id()) {
+                error!("Failed to save the container state: {}", e);
+            }
+            return Ok(res);
+        }
+        state.status = VmStatus::Running;
+        state.stopped_by_user = false;
+        state.exit_status = None;
+        state.started_at = Some(unix_timestamp());
+        state.finished_at = None;
+        state.exit_reason = None;
+        timeout::arm(self, req.id(), state).await;
+        if let Err(e) = state.save(&self.root_path, req.id()) {
+            error!("Failed to save the container state: {}", e);
+        }
+        self.events.publish(Event::ContainerStatusChanged {
+            id: req.id().to_string(),
+            status: VmStatus::Running,
+        });
+        if let Err(e) = self.publish_ports(req.id(), state).await {
+            error!("Failed to publish the ports of {}: {}", req.id(), e);
+        }
+        let mut input = HookInput::new(req.id(), state);
+        input.pid = Some(res.pid);
+        hooks::spawn(self, HookStage::PostStart, input);
+        tokio::spawn(restart::monitor(self.clone(), req.id().to_string()));
         Ok(res)
     }
 
-    async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+    async fn update(&self, ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        self.check_vm()?;
+        let limits = match req.resources.as_ref() {
+            Some(resources) => ResourceLimits::parse(&resources.type_url, &resources.value)
+                .map_err(|e| {
+                    ttrpc::Error::RpcStatus(ttrpc::get_status(
+                        ttrpc::Code::INVALID_ARGUMENT,
+                        e.to_string(),
+                    ))
+                })?,
+            None => ResourceLimits::default(),
+        };
+        let mut state_map = self.state_map.write().await;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        info!("Updating the resources of {}: {:?}", req.id(), limits);
+        // kata-agent applies the limits to the cgroups of the container.
+        if self.kata.is_some() {
+            let client = task_client(&state.vsock_path)?;
+            client.update(forward_context(ctx), &req).await?;
+        } else {
+            let id = ContainerId::new(req.id())
+                .map_err(|e| ttrpc::Error::Others(format!("Invalid container ID: {}", e)))?;
+            send_command(self, &ContainerCommand::UpdateResources(id, limits))
+                .await
+                .map_err(|e| {
+                    ttrpc::Error::Others(format!("Failed to update the resources: {}", e))
+                })?;
+        }
+        if limits.memory.is_some() {
+            state.memory_limit = limits.memory;
+            if let Err(e) = state.save(&self.root_path, req.id()) {
+                error!("Failed to save the container state: {}", e);
+            }
+            memory::apply_memory_limits(self, &state_map)
+                .await
+                .map_err(|e| ttrpc::Error::Others(format!("Failed to resize the VM: {}", e)))?;
+        }
+        Ok(Empty::default())
+    }
+
+    async fn state(&self, ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        self.check_vm()?;
         let mut state_map = self.state_map.write().await;
-        let state = state_map.get_mut(req.id()).unwrap(); // TODO
-        let client = TaskClient::new(Client::connect(state.vsock_path.to_str().unwrap()).unwrap());
-        let res = client.state(Context::default(), &req).await?;
+        let state = state_map
+            .get_mut(req.id())
+            .ok_or_else(|| container_not_found(req.id()))?;
+        let client = task_client(&state.vsock_path)?;
+        let mut res = client.state(forward_context(ctx), &req).await?;
+        let mut finished_at = state.finished_at;
+        if let Some(exec) = state.execs.get_mut(req.exec_id()) {
+            exec.status = vm_status(res.status.enum_value_or_default());
+            exec.pid = (res.pid != 0).then_some(res.pid);
+            if matches!(exec.status, VmStatus::Stopped) {
+                exec.exit_code = Some(res.exit_status);
+                exec.timestamps
+                    .finished_at
+                    .get_or_insert_with(unix_timestamp);
+            }
+            finished_at = exec.timestamps.finished_at;
+            if let Err(e) = state.save(&self.root_path, req.id()) {
+                error!("Failed to save the container state: {}", e);
+            }
+        }
+        // Report the exit time recorded by the server if the guest does not.
+        if res.exited_at.is_none() {
+            if let Some(finished_at) = finished_at {
+                res.exited_at = MessageField::some(Timestamp {
+                    seconds: finished_at as i64,
+                    ..Default::default()
+                });
+            }
+        }
         Ok(res)
     }
 }
 
-async fn handle_cmd(vm: &mut vmm::vm::Vm, cmd_rx: &mut mpsc::Receiver<VmCommand>) -> Result<()> {
-    debug!("Waiting for command...");
-    let cmd = cmd_rx
-        .recv()
-        .await
-        .ok_or_else(|| anyhow::anyhow!("Command channel closed"))?;
+// Report the start and the end of the VM operation, which cannot tell its progress.
+fn with_progress(
+    events: &EventPublisher,
+    operation: &str,
+    f: impl FnOnce() -> Result<(), vmm::vm::Error>,
+) -> Result<(), vmm::vm::Error> {
+    events.publish(Event::Progress(Progress::new(operation, "started")));
+    let res = f();
+    let phase = if res.is_ok() { "finished" } else { "failed" };
+    events.publish(Event::Progress(Progress {
+        completed: 1,
+        total: Some(1),
+        ..Progress::new(operation, phase)
+    }));
+    res
+}
+
+fn handle_cmd(vm: &mut vmm::vm::Vm, events: &EventPublisher, cmd: VmCommand) -> Result<()> {
     match cmd {
         vm_rpc::VmCommand::Start => vm.start()?,
         vm_rpc::VmCommand::Stop => vm.kill()?,
-        vm_rpc::VmCommand::Pause => todo!("Pause"),
-        vm_rpc::VmCommand::Resume => todo!("Resume"),
+        vm_rpc::VmCommand::Pause => vm.pause()?,
+        vm_rpc::VmCommand::Resume => vm.resume()?,
+        vm_rpc::VmCommand::Save(path) => with_progress(events, "save", || vm.save(&path))?,
+        vm_rpc::VmCommand::Restore(path) => with_progress(events, "restore", || vm.restore(&path))?,
         vm_rpc::VmCommand::Connect(port, path) => vm.connect(port, &path)?,
-        _ => todo!(),
+        vm_rpc::VmCommand::ConnectAuthenticated(port, path) => {
+            vm.connect_authenticated(port, &path)?
+        }
+        vm_rpc::VmCommand::Disconnect(port) => vm.disconnect(port)?,
+        vm_rpc::VmCommand::ShowWindow => vm.show_window()?,
+        vm_rpc::VmCommand::SetMemoryTarget(size) => vm.set_memory_target(size)?,
+        vm_rpc::VmCommand::SetShares(shares) => vm.set_shares(&shares)?,
+        _ => anyhow::bail!("Unsupported VM command"),
     }
     Ok(())
 }
 
-fn vm_thread(vm_config: MacosVmConfig, cmd_rx: &mut mpsc::Receiver<VmCommand>) -> Result<()> {
-    let serial_sock = match &vm_config.serial {
-        Some(serial) => Some(UnixStream::connect(&serial.path)?),
-        None => None,
+// Run the VM until the command channel is closed. The result of each command is sent back to
+// its caller. The VM is started first if it is created again.
+fn vm_thread(
+    args: &VmThreadArgs,
+    cmd_rx: &mut mpsc::Receiver<VmRequest>,
+    start: bool,
+) -> Result<()> {
+    if let Err(e) = vmm::queue::set_current_thread_qos(args.qos) {
+        error!("Failed to set the QoS class of the VM thread: {}", e);
+    }
+
+    // The multiplexer keeps its end of the console across the VM restarts.
+    let serial_sock = match (&args.serial_console, &args.vm_config.serial) {
+        (None, Some(serial)) => Some(UnixStream::connect(&serial.path)?),
+        _ => None,
     };
+    let serial_fd = args
+        .serial_console
+        .as_ref()
+        .or(serial_sock.as_ref())
+        .map(|s| s.as_raw_fd());
 
-    let config = vmm::config::Config::from_vm_config(vm_config)?
-        .console(serial_sock.as_ref().map(|s| s.as_raw_fd()))?
-        .build();
-    let mut vm = vmm::vm::Vm::new(config)?;
+    let mut config = vmm::config::Config::from_vm_config(args.vm_config.clone())?;
+    config.console(serial_fd)?;
+    if let Some(console) = &args.agent_console {
+        config.agent_console(console.as_raw_fd())?;
+    }
+    let config = config.build();
+    let mut vm = if args.gui {
+        vmm::vm::Vm::new_on_main_queue(config)?
+    } else {
+        vmm::vm::Vm::new_with_qos(config, args.qos)?
+    };
+    vm.set_connection_manager(args.connections.clone());
+    vm.set_auth_token(args.auth_token.clone());
+    if let Some(boot) = &args.vm_config.boot {
+        vm.set_recovery(boot.recovery);
+    }
+    if start {
+        vm.start()?;
+    }
 
     let rt = Runtime::new().expect("Failed to create a runtime.");
     rt.block_on(async {
-        loop {
-            if let Err(e) = handle_cmd(&mut vm, cmd_rx).await {
+        debug!("Waiting for command...");
+        while let Some(VmRequest { cmd, reply }) = cmd_rx.recv().await {
+            let res = handle_cmd(&mut vm, &args.events, cmd);
+            if let Err(e) = &res {
                 error!("Failed to handle command: {}", e);
-                break;
             }
+            let _ = reply.send(res);
+            debug!("Waiting for command...");
         }
-    });
-
-    Ok(())
+        Ok(())
+    })
 }
 
 async fn create_vm(
-    vm_config: MacosVmConfig,
-) -> Result<(
-    JoinHandle<Result<(), anyhow::Error>>,
-    mpsc::Sender<VmCommand>,
-)> {
-    let (cmd_tx, mut cmd_rx) = mpsc::channel::<vm_rpc::VmCommand>(8);
+    args: VmThreadArgs,
+    mock_vm: Option<PathBuf>,
+    health: VmHealth,
+    config: Arc<watch::Sender<ServerConfig>>,
+) -> Result<(JoinHandle<Result<(), anyhow::Error>>, VmHandle)> {
+    let (vm, cmd_rx) = VmHandle::channel(8);
 
-    let thread = tokio::spawn(async move { vm_thread(vm_config, &mut cmd_rx) });
+    let thread = match mock_vm {
+        Some(dir) => tokio::spawn(mock_vm::run(dir, cmd_rx, args.auth_token)),
+        None => tokio::spawn(supervisor::supervise(args, cmd_rx, health, config)),
+    };
 
-    Ok((thread, cmd_tx))
+    Ok((thread, vm))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    env_logger::init();
-
-    let opts = Opts::parse();
-
-    let root_path = root_path(opts.root)?;
-    let aux_sock_path = aux_sock_path(&root_path, opts.aux_sock);
-
-    match aux_sock_path.try_exists() {
+// Remove the socket file left by the previous server.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match path.try_exists() {
         Ok(exist) => {
             if exist {
-                let metadata = std::fs::metadata(&aux_sock_path)?;
+                let metadata = std::fs::metadata(path)?;
                 if metadata.file_type().is_socket() {
-                    std::fs::remove_file(&aux_sock_path)?;
+                    std::fs::remove_file(path)?;
                 } else {
-                    anyhow::bail!("The aux socket path exists and is not a socket");
+                    anyhow::bail!("The socket path {:?} exists and is not a socket", path);
                 }
             }
         }
         Err(e) => {
-            anyhow::bail!("Failed to check if the aux socket path exists: {}", e);
+            anyhow::bail!("Failed to check if the socket path exists: {}", e);
         }
     }
+    Ok(())
+}
+
+// Resume the VM cloned from a golden VM from the saved state, or boot it. The state is used once
+// as the guest moves on from it.
+async fn start_vm(vm: &VmHandle, root_path: &Path) -> Result<()> {
+    let state_path = restore_state_path(root_path);
+    if state_path.exists() {
+        info!("Restoring VM from {:?}", state_path);
+        let res = match vm
+            .call(vm_rpc::VmCommand::Restore(state_path.clone()))
+            .await
+        {
+            Ok(_) => vm.call(vm_rpc::VmCommand::Resume).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = std::fs::remove_file(&state_path) {
+            warn!("Failed to remove {:?}: {}", state_path, e);
+        }
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) => warn!("Failed to restore the VM; booting it instead: {}", e),
+        }
+    }
+    info!("Starting VM");
+    vm.call(vm_rpc::VmCommand::Start).await?;
+    Ok(())
+}
+
+// Create the root directory that only the current user can access.
+fn prepare_root(root_path: &Path) -> Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(root_path)?;
+    check_owner(root_path)?;
+    std::fs::set_permissions(root_path, std::fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+// Let the group reach the aux socket in the root directory without listing it.
+fn share_root(root_path: &Path, gid: u32) -> Result<()> {
+    std::os::unix::fs::chown(root_path, None, Some(gid))?;
+    std::fs::set_permissions(root_path, std::fs::Permissions::from_mode(0o710))?;
+    Ok(())
+}
+
+// Restrict the socket to the current user.
+fn restrict_socket(path: &Path) -> Result<()> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+async fn serve(opts: Opts) -> Result<()> {
+    let root_path = root_path(opts.root)?;
+    prepare_root(&root_path)?;
+    let api_sock_path = api_sock_path(&root_path);
+    let mut aux_socks = opts.aux_sock;
+    if aux_socks.is_empty() {
+        aux_socks.push(aux_sock_path(&root_path, None));
+    }
+
+    if let Some(running) = preflight::find_running(&api_sock_path, &aux_socks).await {
+        if !opts.replace {
+            match running.pid {
+                Some(pid) => warn!(
+                    "Another server (pid: {}, version: {}) is running; use --replace to take over",
+                    pid,
+                    running.version.as_deref().unwrap_or("unknown")
+                ),
+                None => warn!("Another server is running; use --replace to take over"),
+            }
+            return Ok(());
+        }
+        preflight::replace(&running, &api_sock_path, &aux_socks).await?;
+    }
+    remove_stale_socket(&api_sock_path)?;
+
+    let config = load_server_config(&server_config_path(&root_path))?;
+    reload::apply_log_level(&config)?;
+    if let Some(gid) = config.aux_socket.group()? {
+        share_root(&root_path, gid)?;
+    }
+
+    let listeners: Vec<Box<dyn Listener>> = aux_socks
+        .into_iter()
+        .map(|path| Box::new(UnixSocket::new(path, config.aux_socket.clone())) as Box<dyn Listener>)
+        .chain(
+            opts.listen_fd
+                .into_iter()
+                .map(|fd| Box::new(InheritedFd::new(fd)) as Box<dyn Listener>),
+        )
+        .collect();
 
     let console_path = opts
         .console_sock
         .unwrap_or_else(|| root_path.join("console.sock"));
 
-    let vm_config_path = root_path.join("vm.json");
+    let vm_config_path = vm_config_path(&root_path);
     let mut vm_config = load_vm_config(&vm_config_path)?;
-    vm_config.serial = Some(MacosVmSerial { path: console_path });
+    // The server serves the console socket itself if the console is multiplexed.
+    let serial_console = match opts.mock_vm {
+        None if config.console.is_enabled() => {
+            Some(console::start(&config.console, &console_path)?)
+        }
+        _ => {
+            vm_config.serial = Some(MacosVmSerial { path: console_path });
+            None
+        }
+    };
+
+    // Share the cache volumes with the guest.
+    let volumes_path = volumes_path(&root_path);
+    std::fs::create_dir_all(&volumes_path)?;
+    vm_config
+        .shares
+        .get_or_insert_with(Vec::new)
+        .push(MacosVmSharedDirectory {
+            name: Some(VOLUMES_SHARE_NAME.to_string()),
+            path: volumes_path,
+            automount: true,
+            read_only: false,
+            tag: None,
+            mount_point: None,
+        });
+    // Share the scratch directories of the containers.
+    let scratch_path = scratch_path(&root_path);
+    std::fs::create_dir_all(&scratch_path)?;
+    vm_config
+        .shares
+        .get_or_insert_with(Vec::new)
+        .push(MacosVmSharedDirectory {
+            name: Some(SCRATCH_SHARE_NAME.to_string()),
+            path: scratch_path,
+            automount: true,
+            read_only: false,
+            tag: None,
+            mount_point: None,
+        });
+    // Share a new token of the VM read-only. The agent and the guest ends of the container ports
+    // read it in the guest.
+    let auth_path = auth_path(&root_path);
+    std::fs::create_dir_all(&auth_path)?;
+    std::fs::set_permissions(&auth_path, std::fs::Permissions::from_mode(0o700))?;
+    let auth_token = Arc::new(AuthToken::generate()?);
+    auth_token.save(&auth_path.join(AUTH_TOKEN_FILE))?;
+    vm_config
+        .shares
+        .get_or_insert_with(Vec::new)
+        .push(MacosVmSharedDirectory {
+            name: Some(AUTH_SHARE_NAME.to_string()),
+            path: auth_path,
+            automount: true,
+            read_only: true,
+            tag: None,
+            mount_point: None,
+        });
+    // Share the staged bundles read-only as the rootfs trees are hard links to the sources.
+    let stager = Stager::new(staging_path(&root_path))?;
+    vm_config
+        .shares
+        .get_or_insert_with(Vec::new)
+        .push(MacosVmSharedDirectory {
+            name: Some(STAGING_SHARE_NAME.to_string()),
+            path: stager.dir().to_path_buf(),
+            automount: true,
+            read_only: true,
+            tag: None,
+            mount_point: None,
+        });
+    // Share the script bundle until the VM is provisioned.
+    let provision_pending = provision::pending(&root_path, vm_config.provision.as_deref());
+    if let Some(bundle) = vm_config.provision.clone().filter(|_| provision_pending) {
+        vm_config
+            .shares
+            .get_or_insert_with(Vec::new)
+            .push(MacosVmSharedDirectory {
+                name: Some(PROVISION_SHARE_NAME.to_string()),
+                path: bundle,
+                automount: true,
+                read_only: true,
+                tag: None,
+                mount_point: None,
+            });
+    }
 
     info!("Creating VM from config file: {:?}", vm_config_path);
-    let (thread, cmd_tx) = create_vm(vm_config).await?;
+    let qos = config.scheduling.qos_class();
+    info!("Using QoS class {:?} for the VM", qos);
+    let connections = ConnectionManager::default();
+    let events = EventPublisher::new();
+    if config.clamp_cpus {
+        let cpu_counts = vmm::config::cpu_count_range();
+        let cpus = vm_config.cpus.clamp(*cpu_counts.start(), *cpu_counts.end());
+        if cpus != vm_config.cpus {
+            warn!(
+                "vm.json requests {} CPUs, but the VMs of this host support {:?}; using {}",
+                vm_config.cpus, cpu_counts, cpus
+            );
+            // The event is published before any subscriber can connect, so it is retained.
+            events.publish_retained(Event::VmCpusClamped {
+                requested: vm_config.cpus,
+                cpus,
+            });
+            vm_config.cpus = cpus;
+        }
+    }
+    // The VM keeps one end of the agent console port and the server the other.
+    let (agent_console, guest_console) = match opts.mock_vm {
+        Some(_) => (None, None),
+        None => {
+            let (host, guest) = UnixStream::pair()?;
+            host.set_nonblocking(true)?;
+            let host = tokio::net::UnixStream::from_std(host)?;
+            (Some(Arc::new(AgentConsole::new(host))), Some(guest))
+        }
+    };
+    let config = Arc::new(watch::Sender::new(config));
+    let vm_health = VmHealth::default();
+    let (thread, vm) = create_vm(
+        VmThreadArgs {
+            vm_config: vm_config.clone(),
+            gui: opts.gui,
+            qos,
+            connections: connections.clone(),
+            events: events.clone(),
+            agent_console: guest_console,
+            serial_console,
+            auth_token: auth_token.clone(),
+        },
+        opts.mock_vm,
+        vm_health.clone(),
+        config.clone(),
+    )
+    .await?;
 
-    info!("Starting VM");
-    cmd_tx.send(vm_rpc::VmCommand::Start).await?;
+    start_vm(&vm, &root_path).await?;
 
-    info!("Listening on: {:?}", aux_sock_path);
-    let v = Box::new(ContainerService {
-        state_map: Arc::new(RwLock::new(HashMap::new())),
-        cmd_tx,
-    }) as Box<dyn ShimTask + Sync + Send>;
-    let vservice = create_task(v.into());
+    let (rootfs_watcher, rootfs_change_rx) = RootfsWatcher::new();
+    let mut service = ContainerService {
+        state_map: Arc::new(RwLock::new(load_state_map(&root_path)?)),
+        agent_console,
+        kata: None,
+        stager: Arc::new(stager),
+        root_path,
+        gui: opts.gui,
+        config,
+        path_translator: Arc::new(PathTranslator::new(
+            vm_config.shares.as_deref().unwrap_or_default(),
+        )),
+        vm_config,
+        port_forwarder: Arc::new(PortForwarder::default()),
+        connections,
+        events,
+        refuse_create: Arc::new(AtomicBool::new(false)),
+        vm_health,
+        vm,
+        rootfs_watcher,
+        agent_auth: Arc::new(AgentAuth::new(auth_token)),
+    };
+
+    // The mock VM runs the akari agent only.
+    if service.vm_config.guest_agent == GuestAgent::Kata && service.agent_console.is_some() {
+        info!(
+            "Using kata-agent on vsock port {}",
+            service.vm_config.vsock.agent_port
+        );
+        service.kata = Some(Arc::new(KataAgent::new(
+            service.vm.clone(),
+            service.vm_config.vsock.agent_port,
+            &service.vm_name(),
+        )));
+    }
 
-    let mut server = Server::new()
-        .bind(aux_sock_path.as_path().to_str().unwrap())
-        .unwrap()
-        .register_service(vservice);
+    // Remove what was staged for the containers deleted while the server was down.
+    service.stager.collect(&*service.state_map.read().await);
+    scratch::collect(&service.root_path, &*service.state_map.read().await);
 
-    server.start().await?;
+    tokio::spawn(prune::run_policy(service.clone()));
+
+    let reload_service = service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = reload::handle_sighup(reload_service).await {
+            error!("Failed to handle SIGHUP: {}", e);
+        }
+    });
+
+    match service.kata.clone() {
+        Some(kata) => tokio::spawn(kata::wait_ready(service.clone(), kata)),
+        None => {
+            let service = service.clone();
+            tokio::spawn(async move {
+                // The shares and the provisioning need the akari agent.
+                if !agent::verify_ports(service.clone()).await {
+                    return;
+                }
+                agent::configure(&service).await;
+                agent::mount_shares(&service).await;
+                probe::restore(&service).await;
+                guest_user::restore(&service).await;
+                if provision_pending {
+                    provision::run(service).await;
+                }
+            })
+        }
+    };
+
+    let memory_pressure_rx = vmm::pressure::watch_memory_pressure();
+    tokio::spawn(memory::handle_memory_pressure(
+        service.clone(),
+        memory_pressure_rx,
+    ));
+    tokio::spawn(memory::monitor_guest_memory(service.clone()));
+    tokio::spawn(probe::monitor(service.clone()));
+
+    if opts.on_sleep == SleepAction::Pause {
+        let power_rx = power::watch()?;
+        tokio::spawn(power::handle_power_events(service.clone(), power_rx));
+    }
+
+    tokio::spawn(watcher::handle_changes(service.clone(), rootfs_change_rx));
+
+    if let Err(e) = nat::restore(&service).await {
+        error!("Failed to restore the NAT rules: {}", e);
+    }
+
+    // Restore the rootfs watches, and the port forwards and the monitors of the running containers.
+    for (id, state) in service.state_map.read().await.iter() {
+        if let Some(rootfs) = &state.watched_rootfs {
+            if let Err(e) = service.rootfs_watcher.watch(id, rootfs) {
+                warn!("Failed to watch the rootfs of {}: {}", id, e);
+            }
+        }
+        if matches!(state.status, VmStatus::Running) {
+            if let Err(e) = service.publish_ports(id, state).await {
+                error!("Failed to restore the ports of {}: {}", id, e);
+            }
+            tokio::spawn(restart::monitor(service.clone(), id.clone()));
+        }
+    }
+
+    info!("Listening on: {:?}", api_sock_path);
+    let api_listener = UnixListener::bind(&api_sock_path)?;
+    restrict_socket(&api_sock_path)?;
+    let api_service = service.clone();
+    tokio::spawn(async move {
+        if let Err(e) = api::serve(api_listener, api_service).await {
+            error!("API server stopped: {}", e);
+        }
+    });
+
+    let trace = match ProtocolTrace::from_env()? {
+        Some(trace) => Some(trace),
+        None => match &service.config.borrow().protocol_trace {
+            Some(path) => Some(ProtocolTrace::open(path)?),
+            None => None,
+        },
+    };
+    if trace.is_some() {
+        info!("Tracing the task RPCs");
+    }
+    let _servers = listener::serve(&listeners, &service, trace.map(Arc::new)).await?;
 
     thread.await??;
+    // Keep answering the requests with VmUnavailable after the VM has failed.
+    if service.vm_health.is_failed() {
+        std::future::pending::<()>().await;
+    }
 
     Ok(())
 }
+
+fn init_logger() {
+    let rust_log = std::env::var_os("RUST_LOG").is_some();
+    let mut builder = env_logger::Builder::from_default_env();
+    // Let `logLevel` in server.json raise the level unless RUST_LOG is set.
+    if !rust_log {
+        builder.filter_level(LevelFilter::Trace);
+    }
+    builder.init();
+    if !rust_log {
+        log::set_max_level(LevelFilter::Error);
+    }
+}
+
+fn main() -> Result<()> {
+    init_logger();
+
+    let opts = Opts::parse();
+
+    if opts.gui {
+        // AppKit must run on the main thread, so the server runs on another thread.
+        std::thread::spawn(move || {
+            let rt = Runtime::new().expect("Failed to create a runtime.");
+            if let Err(e) = rt.block_on(serve(opts)) {
+                error!("Server stopped: {}", e);
+                std::process::exit(1);
+            }
+            std::process::exit(0);
+        });
+        vmm::gui::run_app();
+        return Ok(());
+    }
+
+    let rt = Runtime::new()?;
+    rt.block_on(serve(opts))
+}
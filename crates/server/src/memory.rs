@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Host memory pressure handling.
+//! The changes of the memory pressure and of the limits are published as events, so that the
+//! autoscalers outside the server can react to them.
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use libakari::{
+    container_rpc::{ContainerCommand, ContainerResponse},
+    event::{Event, MemoryPressureLevel},
+    metrics::GuestStats,
+    vm_rpc::{VmCommand, VmStatus},
+};
+use log::{debug, error, info};
+use tokio::sync::mpsc;
+
+use crate::{
+    agent::request,
+    config::{MemoryPressureAction, MemoryPressurePolicy},
+    state::ContainerStateMap,
+    ContainerService,
+};
+
+const MIB: u64 = 1024 * 1024;
+// Memory left to the guest OS on top of the limits of the containers
+const GUEST_RESERVE: u64 = 512 * MIB;
+
+// Resize the VM to the memory limits of the containers, so that they hold in the macOS guests
+// without cgroups too. The VM keeps its full memory while a container has no limit.
+pub async fn apply_memory_limits(
+    service: &ContainerService,
+    state_map: &ContainerStateMap,
+) -> anyhow::Result<()> {
+    let full_memory = service.vm_config.ram as u64;
+    let limits: Option<u64> = state_map
+        .values()
+        .filter(|state| !matches!(state.status, VmStatus::Stopped))
+        .map(|state| state.memory_limit)
+        .sum();
+    let target = limits.map_or(full_memory, |limits| {
+        std::cmp::min(limits.saturating_add(GUEST_RESERVE), full_memory)
+    });
+    service.vm.call(VmCommand::SetMemoryTarget(target)).await?;
+    info!("Memory target of the VM: {} MiB", target / MIB);
+    service.events.publish(Event::MemoryTargetChanged(target));
+    Ok(())
+}
+
+// Apply the memory pressure policy to protect the host from being swapped to death.
+pub async fn handle_memory_pressure(
+    service: ContainerService,
+    mut rx: mpsc::UnboundedReceiver<MemoryPressureLevel>,
+) {
+    let full_memory = service.vm_config.ram as u64;
+    let mut current_target = full_memory;
+    let mut paused = false;
+
+    while let Some(level) = rx.recv().await {
+        // Use the latest policy as the configuration may have been reloaded.
+        let policy = service.config.borrow().memory_pressure.clone();
+        service.events.publish(Event::MemoryPressure(level));
+
+        let actions = match level {
+            MemoryPressureLevel::Normal => Vec::new(),
+            MemoryPressureLevel::Warning => policy.warning.clone(),
+            MemoryPressureLevel::Critical => policy.critical.clone(),
+        };
+
+        // Restore the memory target when the pressure goes away.
+        let target = if actions.contains(&MemoryPressureAction::Balloon) {
+            std::cmp::min(policy.balloon_target_mib * MIB, full_memory)
+        } else {
+            full_memory
+        };
+        if target != current_target {
+            match service.vm.call(VmCommand::SetMemoryTarget(target)).await {
+                Ok(()) => {
+                    current_target = target;
+                    service.events.publish(Event::MemoryTargetChanged(target));
+                }
+                Err(e) => error!("Failed to set the memory target: {}", e),
+            }
+        }
+
+        let refuse_create = actions.contains(&MemoryPressureAction::RefuseCreate);
+        if service.refuse_create.swap(refuse_create, Ordering::SeqCst) != refuse_create {
+            info!("Refusing new containers: {}", refuse_create);
+            service.events.publish(Event::RefuseCreateChanged {
+                refuse: refuse_create,
+            });
+        }
+
+        if actions.contains(&MemoryPressureAction::PauseIdle) {
+            let idle = !service
+                .state_map
+                .read()
+                .await
+                .values()
+                .any(|state| matches!(state.status, VmStatus::Running));
+            if idle && !paused {
+                match service.vm.call(VmCommand::Pause).await {
+                    Ok(()) => {
+                        paused = true;
+                        service.events.publish(Event::VmPaused);
+                    }
+                    Err(e) => error!("Failed to pause the VM: {}", e),
+                }
+            }
+        } else if paused {
+            match service.vm.call(VmCommand::Resume).await {
+                Ok(()) => {
+                    paused = false;
+                    service.events.publish(Event::VmResumed);
+                }
+                Err(e) => error!("Failed to resume the VM: {}", e),
+            }
+        }
+    }
+}
+
+fn guest_level(stats: &GuestStats, policy: &MemoryPressurePolicy) -> MemoryPressureLevel {
+    if stats.memory_total == 0 {
+        return MemoryPressureLevel::Normal;
+    }
+    let free_percent = stats.memory_free * 100 / stats.memory_total;
+    if free_percent < policy.guest_critical_percent {
+        MemoryPressureLevel::Critical
+    } else if free_percent < policy.guest_warning_percent {
+        MemoryPressureLevel::Warning
+    } else {
+        MemoryPressureLevel::Normal
+    }
+}
+
+// Poll the free memory reported by the agent and publish the changes of its pressure level.
+pub async fn monitor_guest_memory(service: ContainerService) {
+    let mut level = MemoryPressureLevel::Normal;
+    loop {
+        // Use the latest policy as the configuration may have been reloaded.
+        let policy = service.config.borrow().memory_pressure.clone();
+        let interval = match policy.guest_poll_interval {
+            0 => Duration::from_secs(60),
+            secs => Duration::from_secs(secs),
+        };
+        tokio::time::sleep(interval).await;
+        if policy.guest_poll_interval == 0 {
+            continue;
+        }
+
+        let stats = match request(&service, &ContainerCommand::Stats).await {
+            Ok(ContainerResponse::Stats(stats)) => stats,
+            Ok(res) => {
+                debug!("Unexpected response from the agent: {:?}", res);
+                continue;
+            }
+            // The agent is unavailable while the guest boots.
+            Err(e) => {
+                debug!("Failed to get the guest stats: {}", e);
+                continue;
+            }
+        };
+        let new_level = guest_level(&stats, &policy);
+        if new_level != level {
+            info!("Guest memory pressure: {:?}", new_level);
+            level = new_level;
+            service.events.publish(Event::GuestMemoryPressure {
+                level,
+                memory_free: stats.memory_free,
+                memory_total: stats.memory_total,
+            });
+        }
+    }
+}
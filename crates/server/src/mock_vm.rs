@@ -0,0 +1,195 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! VM backend for the integration tests. It runs no VM and connects the vsock ports to the Unix
+//! domain sockets of a fake guest in the directory: `<port>.sock` if it exists, or `guest.sock`.
+//! The lifecycle commands are appended to `vm.log` in the directory for the tests to check.
+//! The fake guest answers the challenge on the authenticated ports like the guest does.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use libakari::{
+    vm_rpc::VmCommand,
+    vsock::VsockPort,
+    vsock_auth::{self, AuthToken},
+};
+use log::{debug, error, info};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::vm_handle::VmRequest;
+
+// Socket of the fake guest that serves the ports without their own socket.
+const GUEST_SOCK_NAME: &str = "guest.sock";
+
+// Log of the lifecycle commands, one per line
+const VM_LOG_NAME: &str = "vm.log";
+
+fn log_command(dir: &Path, cmd: &str) -> Result<()> {
+    let mut log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(VM_LOG_NAME))?;
+    writeln!(log, "{}", cmd)?;
+    Ok(())
+}
+
+// Run the lifecycle command. The saved state is an empty file.
+fn lifecycle(dir: &Path, cmd: &VmCommand) -> Result<()> {
+    let name = match cmd {
+        VmCommand::Start => "start",
+        VmCommand::Stop => "stop",
+        VmCommand::Pause => "pause",
+        VmCommand::Resume => "resume",
+        VmCommand::Save(path) => {
+            std::fs::write(path, [])?;
+            "save"
+        }
+        VmCommand::Restore(path) => {
+            if !path.exists() {
+                anyhow::bail!("Saved state {:?} not found", path);
+            }
+            "restore"
+        }
+        _ => unreachable!("Not a lifecycle command"),
+    };
+    log_command(dir, name)
+}
+
+fn guest_sock_path(dir: &Path, port: VsockPort) -> PathBuf {
+    let path = dir.join(format!("{}.sock", port));
+    if path.exists() {
+        path
+    } else {
+        dir.join(GUEST_SOCK_NAME)
+    }
+}
+
+// Challenge the fake guest before the connection is proxied.
+async fn authenticate(guest: UnixStream, token: Arc<AuthToken>) -> Result<UnixStream> {
+    let mut guest = guest.into_std()?;
+    guest.set_nonblocking(false)?;
+    let guest = tokio::task::spawn_blocking(move || {
+        vsock_auth::challenge(&mut guest, &token)?;
+        Ok::<_, anyhow::Error>(guest)
+    })
+    .await??;
+    guest.set_nonblocking(true)?;
+    Ok(UnixStream::from_std(guest)?)
+}
+
+// Forward the connections on the host socket to the fake guest.
+async fn proxy(
+    listener: UnixListener,
+    dir: PathBuf,
+    port: VsockPort,
+    token: Option<Arc<AuthToken>>,
+) {
+    loop {
+        let mut client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(e) => {
+                error!("Failed to accept a connection on port {}: {}", port, e);
+                return;
+            }
+        };
+        let target = guest_sock_path(&dir, port);
+        let token = token.clone();
+        tokio::spawn(async move {
+            let mut guest = match UnixStream::connect(&target).await {
+                Ok(guest) => guest,
+                Err(e) => {
+                    error!("Failed to connect to the fake guest {:?}: {}", target, e);
+                    return;
+                }
+            };
+            if let Some(token) = token {
+                guest = match authenticate(guest, token).await {
+                    Ok(guest) => guest,
+                    Err(e) => {
+                        error!(
+                            "The fake guest on port {} failed to authenticate: {}",
+                            port, e
+                        );
+                        return;
+                    }
+                };
+            }
+            if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut guest).await {
+                debug!("Proxy of port {} stopped: {}", port, e);
+            }
+        });
+    }
+}
+
+pub async fn run(
+    dir: PathBuf,
+    mut cmd_rx: mpsc::Receiver<VmRequest>,
+    auth_token: Arc<AuthToken>,
+) -> Result<()> {
+    info!("Running the mock VM with the fake guest in {:?}", dir);
+    let mut proxies: HashMap<VsockPort, (JoinHandle<()>, PathBuf)> = HashMap::new();
+    while let Some(req) = cmd_rx.recv().await {
+        match &req.cmd {
+            VmCommand::Connect(port, path) | VmCommand::ConnectAuthenticated(port, path) => {
+                // Reuse the proxy of the port like the VM.
+                if proxies.get(port).is_some_and(|(handle, proxied)| {
+                    !handle.is_finished() && proxied == path && path.exists()
+                }) {
+                    req.respond(Ok(()));
+                    continue;
+                }
+                let _ = std::fs::remove_file(path);
+                let listener = match UnixListener::bind(path) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        req.respond(Err(e.into()));
+                        continue;
+                    }
+                };
+                let token = matches!(req.cmd, VmCommand::ConnectAuthenticated(..))
+                    .then(|| auth_token.clone());
+                let handle = tokio::spawn(proxy(listener, dir.clone(), *port, token));
+                if let Some((old, _)) = proxies.insert(*port, (handle, path.clone())) {
+                    old.abort();
+                }
+            }
+            VmCommand::Disconnect(port) => {
+                if let Some((handle, path)) = proxies.remove(port) {
+                    handle.abort();
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            VmCommand::Stop => {
+                let res = lifecycle(&dir, &req.cmd);
+                req.respond(res);
+                break;
+            }
+            VmCommand::Start
+            | VmCommand::Pause
+            | VmCommand::Resume
+            | VmCommand::Save(_)
+            | VmCommand::Restore(_) => {
+                let res = lifecycle(&dir, &req.cmd);
+                req.respond(res);
+                continue;
+            }
+            _ => debug!("The mock VM ignores the command"),
+        }
+        req.respond(Ok(()));
+    }
+    for (handle, path) in proxies.into_values() {
+        handle.abort();
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! NAT rules of the VM.
+//! The rules forward the host ports to the guest independent of the containers. They are kept in
+//! vm.json so that they are restored with the VM and moved with its archive.
+
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use libakari::{
+    path::vm_config_path,
+    port_forward::PortMapping,
+    vm_config::{load_vm_config, MacosVmConfig},
+};
+use log::info;
+
+use crate::ContainerService;
+
+// Serializes the updates of vm.json.
+static VM_CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+// The forwards of the rules share the forwarder with the containers. Container IDs cannot
+// contain a slash, so the keys never clash.
+fn forward_key(mapping: &PortMapping) -> String {
+    format!("nat/{}", mapping)
+}
+
+fn same_host_port(a: &PortMapping, b: &PortMapping) -> bool {
+    a.host_ip == b.host_ip && a.host_port == b.host_port
+}
+
+// Apply the change to the rules in vm.json.
+fn update(
+    service: &ContainerService,
+    f: impl FnOnce(&mut Vec<PortMapping>) -> Result<()>,
+) -> Result<()> {
+    let _lock = VM_CONFIG_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let path = vm_config_path(&service.root_path);
+    let mut vm_config: MacosVmConfig = load_vm_config(&path)?;
+    f(&mut vm_config.nat_rules)?;
+    std::fs::write(&path, serde_json::to_string_pretty(&vm_config)?)?;
+    Ok(())
+}
+
+pub fn list(service: &ContainerService) -> Result<Vec<PortMapping>> {
+    Ok(load_vm_config(&vm_config_path(&service.root_path))?.nat_rules)
+}
+
+// Forward the rules of vm.json when the server starts.
+pub async fn restore(service: &ContainerService) -> Result<()> {
+    if service.vm_config.nat_rules.is_empty() {
+        return Ok(());
+    }
+    let guest_ip = service.guest_ip()?;
+    for mapping in &service.vm_config.nat_rules {
+        service
+            .port_forwarder
+            .publish(
+                &forward_key(mapping),
+                std::slice::from_ref(mapping),
+                guest_ip,
+            )
+            .await?;
+    }
+    info!("Restored {} NAT rules", service.vm_config.nat_rules.len());
+    Ok(())
+}
+
+pub async fn add(service: &ContainerService, mapping: PortMapping) -> Result<()> {
+    if list(service)?
+        .iter()
+        .any(|rule| same_host_port(rule, &mapping))
+    {
+        bail!(
+            "NAT rule for {}:{} already exists",
+            mapping.host_ip,
+            mapping.host_port
+        );
+    }
+    // Binding the host port first reports a port in use without touching vm.json.
+    let key = forward_key(&mapping);
+    service
+        .port_forwarder
+        .publish(&key, std::slice::from_ref(&mapping), service.guest_ip()?)
+        .await?;
+    let res = update(service, |rules| {
+        rules.push(mapping.clone());
+        Ok(())
+    });
+    if let Err(e) = res {
+        service.port_forwarder.unpublish(&key).await;
+        return Err(e);
+    }
+    info!("Added NAT rule {}", mapping);
+    Ok(())
+}
+
+pub async fn delete(service: &ContainerService, mapping: PortMapping) -> Result<()> {
+    update(service, |rules| {
+        let len = rules.len();
+        rules.retain(|rule| rule != &mapping);
+        if rules.len() == len {
+            bail!("NAT rule {} does not exist", mapping);
+        }
+        Ok(())
+    })?;
+    service
+        .port_forwarder
+        .unpublish(&forward_key(&mapping))
+        .await;
+    info!("Deleted NAT rule {}", mapping);
+    Ok(())
+}
@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Cleanup of the exec processes whose client has gone.
+//! With `execOrphans.signal` in server.json, the server proxies the stdout of the execs on the
+//! data sockets and notices when the attached client closes it before the process exits. The
+//! orphaned process is signalled after the grace period, killed if it does not exit in time and
+//! deleted, so that interactive shells do not pile up in the guest.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{DeleteRequest, KillRequest, WaitRequest},
+    Context, Task as ShimTask,
+};
+use libakari::{stdio::StdioStream, vm_rpc::VmStatus};
+use log::{error, info, warn};
+
+use crate::{deadline::local_context, state::StdioRedirect, task_client, ContainerService};
+
+// Exec process watched for its client
+pub struct Session {
+    service: ContainerService,
+    id: String,
+    exec_id: String,
+}
+
+impl Session {
+    // Watch the stdout served on the data socket if the policy signals the orphans. The
+    // terminal merges the stderr into the stdout, so one stream is enough.
+    pub fn new(service: &ContainerService, id: &str, redirect: &StdioRedirect) -> Option<Self> {
+        let exec_id = redirect.exec_id.as_ref()?;
+        let watched = redirect.stream == StdioStream::Stdout
+            && redirect.path.is_none()
+            && service.config.borrow().exec_orphans.signal.is_some();
+        watched.then(|| Self {
+            service: service.clone(),
+            id: id.to_string(),
+            exec_id: exec_id.clone(),
+        })
+    }
+
+    // The client has disconnected from the running process.
+    pub fn orphaned(self) {
+        tokio::spawn(async move {
+            if let Err(e) = cleanup(&self.service, &self.id, &self.exec_id).await {
+                error!(
+                    "Failed to clean up exec {} of {}: {}",
+                    self.exec_id, self.id, e
+                );
+            }
+        });
+    }
+}
+
+// Signal the process and kill it if it does not exit in time.
+async fn terminate(
+    vsock_path: &Path,
+    id: &str,
+    exec_id: &str,
+    signal: u32,
+    kill_timeout: u64,
+) -> Result<()> {
+    let client = task_client(vsock_path)?;
+    let kill = |signal| KillRequest {
+        id: id.to_string(),
+        exec_id: exec_id.to_string(),
+        signal,
+        ..Default::default()
+    };
+    let wait = WaitRequest {
+        id: id.to_string(),
+        exec_id: exec_id.to_string(),
+        ..Default::default()
+    };
+    if let Err(e) = client.kill(Context::default(), &kill(signal)).await {
+        // The process has exited on its own.
+        info!("Failed to signal exec {} of {}: {}", exec_id, id, e);
+    }
+    let timeout = Duration::from_secs(kill_timeout);
+    if tokio::time::timeout(timeout, client.wait(Context::default(), &wait))
+        .await
+        .is_err()
+    {
+        warn!(
+            "Exec {} of {} did not exit in {:?}; killing it",
+            exec_id, id, timeout
+        );
+        client
+            .kill(Context::default(), &kill(libc::SIGKILL as u32))
+            .await?;
+        client.wait(Context::default(), &wait).await?;
+    }
+    Ok(())
+}
+
+async fn cleanup(service: &ContainerService, id: &str, exec_id: &str) -> Result<()> {
+    let policy = service.config.borrow().exec_orphans.clone();
+    let Some(signal) = policy.signal else {
+        return Ok(());
+    };
+    tokio::time::sleep(Duration::from_secs(policy.grace_period)).await;
+
+    // The process may have exited or been deleted in the meantime.
+    let exec = service.state_map.read().await.get(id).and_then(|state| {
+        let exec = state.execs.get(exec_id)?;
+        Some((state.vsock_path.clone(), exec.status != VmStatus::Stopped))
+    });
+    let Some((vsock_path, running)) = exec else {
+        return Ok(());
+    };
+    if running {
+        info!(
+            "The client of exec {} of {} has gone; sending signal {}",
+            exec_id, id, signal
+        );
+        terminate(&vsock_path, id, exec_id, signal, policy.kill_timeout).await?;
+    }
+
+    let delete = DeleteRequest {
+        id: id.to_string(),
+        exec_id: exec_id.to_string(),
+        ..Default::default()
+    };
+    service.delete(&local_context(), delete).await?;
+    info!("Deleted the orphaned exec {} of {}", exec_id, id);
+    Ok(())
+}
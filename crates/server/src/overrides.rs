@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Merges the start overrides of the client with the spec of the container.
+//! The guest receives the whole environment with the templates expanded, so that it does not
+//! have to know the spec of the host bundle.
+
+use std::path::Path;
+
+use containerd_shim::{Context, TtrpcContext, TtrpcResult};
+use libakari::start::StartOverrides;
+use log::info;
+use oci_spec::runtime::Spec;
+
+use crate::{deadline::forward_context, template, ContainerService};
+
+fn invalid(message: String) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INVALID_ARGUMENT, message))
+}
+
+// Context of the start of the container with the merged overrides in place of those of the client.
+pub fn forward_start(
+    service: &ContainerService,
+    ctx: &TtrpcContext,
+    id: &str,
+    bundle: &Path,
+) -> TtrpcResult<Context> {
+    let mut forwarded = forward_context(ctx);
+    let overrides = StartOverrides::from_metadata(&ctx.metadata)
+        .map_err(|e| invalid(e.to_string()))?
+        .filter(|overrides| !overrides.is_empty());
+    let Some(overrides) = overrides else {
+        return Ok(forwarded);
+    };
+
+    let spec_path = bundle.join("config.json");
+    let mut spec = Spec::load(&spec_path)
+        .map_err(|e| invalid(format!("Failed to load {:?}: {}", spec_path, e)))?;
+    let Some(process) = spec.process_mut().as_mut() else {
+        return Err(invalid("The spec has no process".to_string()));
+    };
+    let env = process.env().clone().unwrap_or_default();
+    process.set_env(Some(overrides.merge_env(&env)));
+    let vars = template::vars(&service.vm_name(), id);
+    template::expand_env(&mut spec, &vars).map_err(|e| invalid(e.to_string()))?;
+
+    // Validated above
+    let process = spec.process().as_ref().unwrap();
+    let merged = StartOverrides {
+        env: process.env().clone().unwrap_or_default(),
+        cwd: overrides.cwd.clone(),
+    };
+    info!(
+        "Starting {} with {} env overrides and the cwd {:?}",
+        id,
+        overrides.env.len(),
+        overrides.cwd
+    );
+    merged.to_metadata(&mut forwarded.metadata);
+    Ok(forwarded)
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Translates the host paths of the bundles and the mounts to the guest virtiofs paths.
+//! Only the paths inside the shared directories of the VM are visible to the guest, so the
+//! containers cannot mount the other host paths such as `/etc`.
+
+use std::path::{Component, Path, PathBuf};
+
+use libakari::vm_config::MacosVmSharedDirectory;
+use log::warn;
+use oci_spec::runtime::Spec;
+
+// Mount types and options that take a host path as the source.
+const BIND_MOUNT_TYPE: &str = "bind";
+const BIND_MOUNT_OPTIONS: [&str; 2] = ["bind", "rbind"];
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Path must be absolute: {0:?}")]
+    RelativePath(PathBuf),
+    #[error("Path {0:?} is outside the shared directories")]
+    NotShared(PathBuf),
+    #[error("Failed to resolve {0:?}: {1}")]
+    Resolve(PathBuf, std::io::Error),
+}
+
+// A shared directory and where it appears in the guest.
+struct SharedRoot {
+    host: PathBuf,
+    guest: PathBuf,
+}
+
+pub struct PathTranslator {
+    // Sorted from the deepest so that the nested shares take precedence.
+    roots: Vec<SharedRoot>,
+}
+
+impl PathTranslator {
+    pub fn new(shares: &[MacosVmSharedDirectory]) -> Self {
+        let mut roots: Vec<SharedRoot> = shares
+            .iter()
+            .filter_map(|share| {
+                let Some(guest) = share.guest_path() else {
+                    warn!(
+                        "Skipping the shared directory without a guest path: {:?}",
+                        share.path
+                    );
+                    return None;
+                };
+                // Symlinks are resolved so that the translated paths do not depend on them.
+                let host = match share.path.canonicalize() {
+                    Ok(host) => host,
+                    Err(e) => {
+                        warn!("Skipping the shared directory {:?}: {}", share.path, e);
+                        return None;
+                    }
+                };
+                Some(SharedRoot { host, guest })
+            })
+            .collect();
+        roots.sort_by_key(|root| std::cmp::Reverse(root.host.components().count()));
+        Self { roots }
+    }
+
+    // Return the guest path of the host path. The path is resolved first so that neither `..`
+    // nor symlinks can escape the shared directories.
+    pub fn to_guest(&self, host: &Path) -> Result<PathBuf, Error> {
+        if host.is_relative() {
+            return Err(Error::RelativePath(host.to_path_buf()));
+        }
+        let resolved = host
+            .canonicalize()
+            .map_err(|e| Error::Resolve(host.to_path_buf(), e))?;
+        self.roots
+            .iter()
+            .find_map(|root| {
+                let relative = resolved.strip_prefix(&root.host).ok()?;
+                Some(root.guest.join(relative))
+            })
+            .ok_or_else(|| Error::NotShared(host.to_path_buf()))
+    }
+
+    // Return whether the host path is visible to the guest.
+    pub fn is_shared(&self, host: &Path) -> bool {
+        self.to_guest(host).is_ok()
+    }
+
+    // Check that the rootfs and the bind mount sources of the container are visible to the guest.
+    pub fn validate_spec(&self, bundle: &Path, spec: &Spec) -> Result<(), Error> {
+        if let Some(root) = spec.root() {
+            self.to_guest(&bundle.join(root.path()))?;
+        }
+        self.validate_mounts(bundle, spec)
+    }
+
+    // Check that the bind mount sources of the container are visible to the guest.
+    pub fn validate_mounts(&self, bundle: &Path, spec: &Spec) -> Result<(), Error> {
+        for mount in spec.mounts().iter().flatten() {
+            let is_bind = mount.typ().as_deref() == Some(BIND_MOUNT_TYPE)
+                || mount
+                    .options()
+                    .iter()
+                    .flatten()
+                    .any(|option| BIND_MOUNT_OPTIONS.contains(&option.as_str()));
+            if !is_bind {
+                continue;
+            }
+            if let Some(source) = mount.source() {
+                // Relative sources are resolved against the bundle as runc does.
+                let source = match source.components().next() {
+                    Some(Component::RootDir) => source.clone(),
+                    _ => bundle.join(source),
+                };
+                self.to_guest(&source)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Port forwarding subsystem.
+//! Forwards the published host ports to the guest over the NAT network.
+
+use std::{collections::HashMap, net::Ipv4Addr, time::Duration};
+
+use anyhow::Result;
+use libakari::port_forward::PortMapping;
+use log::{debug, error, info};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    task::JoinHandle,
+};
+
+// Wait after a failed accept, e.g. when the file descriptors run out, instead of spinning.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+pub struct PortForwarder {
+    forwards: Mutex<HashMap<String, Vec<JoinHandle<()>>>>,
+}
+
+impl PortForwarder {
+    // Start forwarding the ports of the container. Any existing forwards are replaced.
+    pub async fn publish(
+        &self,
+        id: &str,
+        mappings: &[PortMapping],
+        guest_ip: Ipv4Addr,
+    ) -> Result<()> {
+        let mut handles = Vec::new();
+        for mapping in mappings {
+            match forward(mapping, guest_ip).await {
+                Ok(handle) => handles.push(handle),
+                Err(e) => {
+                    handles.iter().for_each(|handle| handle.abort());
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(old) = self.forwards.lock().await.insert(id.to_string(), handles) {
+            old.iter().for_each(|handle| handle.abort());
+        }
+
+        Ok(())
+    }
+
+    // Stop forwarding the ports of the container.
+    pub async fn unpublish(&self, id: &str) {
+        if let Some(handles) = self.forwards.lock().await.remove(id) {
+            info!("Removing port forwards of {}", id);
+            handles.iter().for_each(|handle| handle.abort());
+        }
+    }
+}
+
+async fn forward(mapping: &PortMapping, guest_ip: Ipv4Addr) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind((mapping.host_ip, mapping.host_port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to publish {}: {}", mapping, e))?;
+    info!("Forwarding {} to {}", mapping, guest_ip);
+
+    let guest_port = mapping.guest_port;
+    Ok(tokio::spawn(async move {
+        loop {
+            let (mut inbound, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            debug!("Accepted a connection from {}", peer);
+            tokio::spawn(async move {
+                match TcpStream::connect((guest_ip, guest_port)).await {
+                    Ok(mut outbound) => {
+                        let _ = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await;
+                    }
+                    Err(e) => error!("Failed to connect to {}:{}: {}", guest_ip, guest_port, e),
+                }
+            });
+        }
+    }))
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Host sleep and wake notifications.
+//! Registers for the IOKit system power notifications on a dedicated thread
+//! that runs a CFRunLoop, and forwards them to the server.
+
+use std::{
+    ffi::c_void,
+    sync::mpsc::{sync_channel, SyncSender},
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{event::Event, vm_rpc::VmCommand};
+use log::{error, info};
+use tokio::sync::mpsc;
+
+use crate::ContainerService;
+
+type IoConnect = u32;
+type IoObject = u32;
+type IoNotificationPortRef = *mut c_void;
+type CfRunLoopSourceRef = *mut c_void;
+type CfRunLoopRef = *mut c_void;
+type CfStringRef = *const c_void;
+type IoServiceInterestCallback =
+    extern "C" fn(refcon: *mut c_void, service: IoObject, message_type: u32, argument: *mut c_void);
+
+#[link(name = "IOKit", kind = "framework")]
+extern "C" {
+    fn IORegisterForSystemPower(
+        refcon: *mut c_void,
+        the_port_ref: *mut IoNotificationPortRef,
+        callback: IoServiceInterestCallback,
+        notifier: *mut IoObject,
+    ) -> IoConnect;
+    fn IONotificationPortGetRunLoopSource(notify: IoNotificationPortRef) -> CfRunLoopSourceRef;
+    fn IOAllowPowerChange(kernel_port: IoConnect, notification_id: isize) -> i32;
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFRunLoopCommonModes: CfStringRef;
+    fn CFRunLoopGetCurrent() -> CfRunLoopRef;
+    fn CFRunLoopAddSource(rl: CfRunLoopRef, source: CfRunLoopSourceRef, mode: CfStringRef);
+    fn CFRunLoopRun();
+}
+
+const IO_MESSAGE_CAN_SYSTEM_SLEEP: u32 = 0xe0000270;
+const IO_MESSAGE_SYSTEM_WILL_SLEEP: u32 = 0xe0000280;
+const IO_MESSAGE_SYSTEM_HAS_POWERED_ON: u32 = 0xe0000300;
+
+// How long the host sleep is delayed to let the server pause the VM.
+const SLEEP_ACK_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub enum PowerEvent {
+    // The host is about to sleep. Send to the sender when ready to sleep.
+    WillSleep(SyncSender<()>),
+    HasPoweredOn,
+}
+
+struct Context {
+    root_port: IoConnect,
+    tx: mpsc::UnboundedSender<PowerEvent>,
+}
+
+extern "C" fn power_callback(
+    refcon: *mut c_void,
+    _service: IoObject,
+    message_type: u32,
+    argument: *mut c_void,
+) {
+    let context = unsafe { &*(refcon as *const Context) };
+    match message_type {
+        IO_MESSAGE_CAN_SYSTEM_SLEEP => unsafe {
+            IOAllowPowerChange(context.root_port, argument as isize);
+        },
+        IO_MESSAGE_SYSTEM_WILL_SLEEP => {
+            let (ack_tx, ack_rx) = sync_channel(1);
+            if context.tx.send(PowerEvent::WillSleep(ack_tx)).is_ok() {
+                let _ = ack_rx.recv_timeout(SLEEP_ACK_TIMEOUT);
+            }
+            unsafe {
+                IOAllowPowerChange(context.root_port, argument as isize);
+            }
+        }
+        IO_MESSAGE_SYSTEM_HAS_POWERED_ON => {
+            let _ = context.tx.send(PowerEvent::HasPoweredOn);
+        }
+        _ => {}
+    }
+}
+
+// Start watching the host power events.
+pub fn watch() -> Result<mpsc::UnboundedReceiver<PowerEvent>> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (result_tx, result_rx) = sync_channel::<Result<()>>(1);
+
+    std::thread::spawn(move || {
+        // The context must outlive the run loop, which runs forever.
+        let context = Box::into_raw(Box::new(Context { root_port: 0, tx }));
+        let mut port: IoNotificationPortRef = std::ptr::null_mut();
+        let mut notifier: IoObject = 0;
+        let root_port = unsafe {
+            IORegisterForSystemPower(
+                context as *mut c_void,
+                &mut port,
+                power_callback,
+                &mut notifier,
+            )
+        };
+        if root_port == 0 {
+            let _ = result_tx.send(Err(anyhow::anyhow!(
+                "Failed to register for the system power notifications"
+            )));
+            return;
+        }
+        unsafe {
+            (*context).root_port = root_port;
+            CFRunLoopAddSource(
+                CFRunLoopGetCurrent(),
+                IONotificationPortGetRunLoopSource(port),
+                kCFRunLoopCommonModes,
+            );
+        }
+        let _ = result_tx.send(Ok(()));
+
+        info!("Watching the host power events");
+        unsafe { CFRunLoopRun() };
+        error!("The power event run loop exited");
+    });
+
+    result_rx.recv()??;
+
+    Ok(rx)
+}
+
+// What to do with the VM when the host sleeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SleepAction {
+    // Pause the VM on sleep and resume it on wake.
+    Pause,
+    // Leave the VM running.
+    Ignore,
+}
+
+// Pause the VM while the host sleeps so that the guest doesn't wake up with hung vsock
+// connections and a large clock drift.
+pub async fn handle_power_events(
+    service: ContainerService,
+    mut rx: mpsc::UnboundedReceiver<PowerEvent>,
+) {
+    while let Some(event) = rx.recv().await {
+        match event {
+            PowerEvent::WillSleep(ack) => {
+                service.events.publish(Event::HostWillSleep);
+                match service.vm.call(VmCommand::Pause).await {
+                    Ok(()) => service.events.publish(Event::VmPaused),
+                    Err(e) => error!("Failed to pause the VM: {}", e),
+                }
+                let _ = ack.send(());
+            }
+            PowerEvent::HasPoweredOn => {
+                service.events.publish(Event::HostDidWake);
+                match service.vm.call(VmCommand::Resume).await {
+                    Ok(()) => service.events.publish(Event::VmResumed),
+                    Err(e) => error!("Failed to resume the VM: {}", e),
+                }
+            }
+        }
+    }
+}
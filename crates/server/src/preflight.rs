@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Detects another server that serves the same sockets before they are replaced.
+//! Unlinking the sockets of a live server would leave it running but unreachable.
+
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use libakari::{
+    api::{ApiRequest, ApiResponse},
+    framing,
+};
+use log::info;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    time::timeout,
+};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+// How long to wait for the replaced server to exit.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Server that accepts connections on one of the sockets.
+pub struct RunningServer {
+    // None if the server is older than the Version request.
+    pub pid: Option<u32>,
+    pub version: Option<String>,
+}
+
+async fn is_live(path: &Path) -> bool {
+    matches!(
+        timeout(QUERY_TIMEOUT, UnixStream::connect(path)).await,
+        Ok(Ok(_))
+    )
+}
+
+async fn any_live(api_sock_path: &Path, aux_socks: &[PathBuf]) -> bool {
+    for path in std::iter::once(api_sock_path).chain(aux_socks.iter().map(PathBuf::as_path)) {
+        if is_live(path).await {
+            return true;
+        }
+    }
+    false
+}
+
+async fn query_version(api_sock_path: &Path) -> Result<(u32, String)> {
+    let mut stream = UnixStream::connect(api_sock_path).await?;
+    stream
+        .write_all(&framing::encode(&ApiRequest::Version)?)
+        .await?;
+    let len = framing::check_frame_len(stream.read_u32().await?)?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+    match framing::decode(&payload)? {
+        ApiResponse::Version { version, pid } => Ok((pid, version)),
+        res => anyhow::bail!("Unexpected response: {:?}", res),
+    }
+}
+
+// Return the server that is still serving the sockets, if any.
+pub async fn find_running(api_sock_path: &Path, aux_socks: &[PathBuf]) -> Option<RunningServer> {
+    if !any_live(api_sock_path, aux_socks).await {
+        return None;
+    }
+    match timeout(QUERY_TIMEOUT, query_version(api_sock_path)).await {
+        Ok(Ok((pid, version))) => Some(RunningServer {
+            pid: Some(pid),
+            version: Some(version),
+        }),
+        _ => Some(RunningServer {
+            pid: None,
+            version: None,
+        }),
+    }
+}
+
+// Stop the running server and wait until its sockets are closed.
+pub async fn replace(
+    server: &RunningServer,
+    api_sock_path: &Path,
+    aux_socks: &[PathBuf],
+) -> Result<()> {
+    let pid = server.pid.ok_or_else(|| {
+        anyhow::anyhow!("The running server did not report its pid, so it cannot be replaced")
+    })?;
+    info!("Stopping the running server (pid: {})", pid);
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let deadline = Instant::now() + REPLACE_TIMEOUT;
+    while any_live(api_sock_path, aux_socks).await {
+        if Instant::now() >= deadline {
+            anyhow::bail!(
+                "The server (pid: {}) did not stop in {:?}",
+                pid,
+                REPLACE_TIMEOUT
+            );
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    Ok(())
+}
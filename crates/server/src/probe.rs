@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Health of the containers with probes.
+//! The agent runs the probes, and the server polls their results to report them in the states
+//! and to publish their changes as events.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use libakari::{
+    container_id::ContainerId,
+    container_rpc::{ContainerCommand, ContainerResponse},
+    event::Event,
+    probe::{Probes, LIVENESS_PROBE_ANNOTATION, READINESS_PROBE_ANNOTATION},
+};
+use log::{debug, error, info};
+
+use crate::{
+    agent::{request, send_command},
+    ContainerService,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+pub fn has_probes(annotations: &BTreeMap<String, String>) -> bool {
+    annotations.contains_key(READINESS_PROBE_ANNOTATION)
+        || annotations.contains_key(LIVENESS_PROBE_ANNOTATION)
+}
+
+// Ask the agent to run the probes of the container.
+pub async fn start(service: &ContainerService, id: &ContainerId, probes: Probes) {
+    let cmd = ContainerCommand::StartProbes(id.clone(), probes);
+    if let Err(e) = send_command(service, &cmd).await {
+        error!("Failed to start the probes of {}: {}", id, e);
+    }
+}
+
+pub async fn stop(service: &ContainerService, id: &ContainerId) {
+    let cmd = ContainerCommand::StopProbes(id.clone());
+    if let Err(e) = send_command(service, &cmd).await {
+        error!("Failed to stop the probes of {}: {}", id, e);
+    }
+}
+
+// Start the probes of the containers again, e.g. after the server restarts with the VM.
+pub async fn restore(service: &ContainerService) {
+    let containers: Vec<_> = service
+        .state_map
+        .read()
+        .await
+        .iter()
+        .filter(|(_, state)| has_probes(&state.annotations))
+        .map(|(id, state)| (id.clone(), state.annotations.clone()))
+        .collect();
+    for (id, annotations) in containers {
+        let annotations: HashMap<_, _> = annotations.into_iter().collect();
+        // Validated when the container was created
+        let probes = Probes::from_annotations(&annotations).unwrap_or_default();
+        match ContainerId::new(&id) {
+            Ok(id) => start(service, &id, probes).await,
+            Err(e) => error!("Failed to start the probes of {}: {}", id, e),
+        }
+    }
+}
+
+// Poll the results of the probes and publish their changes.
+pub async fn monitor(service: ContainerService) {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        // kata-agent has no probes.
+        if service.kata.is_some() {
+            return;
+        }
+        let probed = service
+            .state_map
+            .read()
+            .await
+            .values()
+            .any(|state| has_probes(&state.annotations));
+        if !probed {
+            continue;
+        }
+
+        let health = match request(&service, &ContainerCommand::Health).await {
+            Ok(ContainerResponse::Health(health)) => health,
+            Ok(res) => {
+                debug!("Unexpected response from the agent: {:?}", res);
+                continue;
+            }
+            // The agent is unavailable while the guest boots.
+            Err(e) => {
+                debug!("Failed to get the health of the containers: {}", e);
+                continue;
+            }
+        };
+        let mut state_map = service.state_map.write().await;
+        for (id, health) in health {
+            let Some(state) = state_map.get_mut(&id) else {
+                continue;
+            };
+            if state.health == Some(health) {
+                continue;
+            }
+            info!("Health of {}: {:?}", id, health);
+            state.health = Some(health);
+            service
+                .events
+                .publish(Event::ContainerHealthChanged { id, health });
+        }
+    }
+}
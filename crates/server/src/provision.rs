@@ -0,0 +1,92 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Runs the provisioning script of vm.json in the guest on the first boot.
+//! The output is appended to `provision.log` and the result is saved to `provision.json`, so
+//! the script runs again on the next start only if it failed.
+
+use std::path::Path;
+
+use anyhow::Result;
+use libakari::{
+    container_rpc::{ContainerCommand, ContainerResponse},
+    event::{unix_timestamp, Event},
+    provision::{
+        guest_script_path, load_provision_status, provision_log_path, provision_status_path,
+        ProvisionStatus, PROVISION_SCRIPT,
+    },
+};
+use log::{error, info, warn};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    agent::{open_stream, read_frame},
+    ContainerService,
+};
+
+// Return true if the script bundle is set and the VM has not been provisioned successfully.
+pub fn pending(root_path: &Path, bundle: Option<&Path>) -> bool {
+    let Some(bundle) = bundle else {
+        return false;
+    };
+    if !bundle.join(PROVISION_SCRIPT).is_file() {
+        warn!(
+            "{:?} has no {}; skipping provisioning",
+            bundle, PROVISION_SCRIPT
+        );
+        return false;
+    }
+    match load_provision_status(root_path) {
+        Ok(status) => !status.is_some_and(|status| status.succeeded()),
+        Err(e) => {
+            warn!("Failed to load the provisioning status: {}", e);
+            true
+        }
+    }
+}
+
+// Run the script and save its result. Called once the agent is ready.
+pub async fn run(service: ContainerService) {
+    info!("Provisioning the VM");
+    let started_at = unix_timestamp();
+    let res = exec(&service).await;
+    let status = ProvisionStatus {
+        exit_code: res.as_ref().ok().copied(),
+        error: res.as_ref().err().map(|e| e.to_string()),
+        started_at,
+        finished_at: unix_timestamp(),
+    };
+    match &res {
+        Ok(0) => info!("Provisioned the VM"),
+        Ok(code) => error!("Provisioning script exited with {}", code),
+        Err(e) => error!("Failed to provision the VM: {}", e),
+    }
+    let path = provision_status_path(&service.root_path);
+    let saved = serde_json::to_string_pretty(&status)
+        .map_err(anyhow::Error::from)
+        .and_then(|json| Ok(std::fs::write(path, json)?));
+    if let Err(e) = saved {
+        error!("Failed to save the provisioning status: {}", e);
+    }
+    service.events.publish(Event::VmProvisioned {
+        exit_code: status.exit_code,
+    });
+}
+
+// Stream the output of the script to the log and return its exit code.
+async fn exec(service: &ContainerService) -> Result<i32> {
+    let mut log = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(provision_log_path(&service.root_path))
+        .await?;
+    let mut agent = open_stream(service, &ContainerCommand::Provision(guest_script_path())).await?;
+    loop {
+        match read_frame(&mut agent).await? {
+            ContainerResponse::Output(_, data) => log.write_all(&data).await?,
+            ContainerResponse::Exited(code) => return Ok(code),
+            ContainerResponse::Error(e) => anyhow::bail!("Agent error: {}", e),
+            res => anyhow::bail!("Unexpected response from the agent: {:?}", res),
+        }
+    }
+}
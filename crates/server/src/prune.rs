@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Deletes the stopped containers to keep the root directory bounded.
+
+use std::time::Duration;
+
+use containerd_shim::{api::DeleteRequest, Context};
+use libakari::{event::unix_timestamp, vm_rpc::VmStatus};
+use log::{error, info};
+
+use crate::ContainerService;
+
+// Delete the stopped containers that exited at least `older_than` seconds ago.
+// Return the IDs of the deleted containers.
+pub async fn prune(service: &ContainerService, older_than: u64) -> Vec<String> {
+    let now = unix_timestamp();
+    let ids = service
+        .state_map
+        .read()
+        .await
+        .iter()
+        .filter(|(_, state)| matches!(state.status, VmStatus::Stopped))
+        .filter(|(_, state)| {
+            state
+                .finished_at
+                .is_some_and(|finished_at| now.saturating_sub(finished_at) >= older_than)
+        })
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+
+    let mut pruned = Vec::new();
+    for id in ids {
+        let req = DeleteRequest {
+            id: id.clone(),
+            ..Default::default()
+        };
+        match service.delete_container(Context::default(), &req).await {
+            Ok(_) => {
+                info!("Pruned container {}", id);
+                pruned.push(id);
+            }
+            Err(e) => error!("Failed to prune container {}: {}", id, e),
+        }
+    }
+    pruned
+}
+
+// Prune the containers periodically according to the server policy.
+// The policy is reapplied when the configuration is reloaded.
+pub async fn run_policy(service: ContainerService) {
+    let mut config_rx = service.config.subscribe();
+    loop {
+        let policy = config_rx.borrow_and_update().prune.clone();
+        let Some(ttl) = policy.ttl else {
+            // Nothing is pruned until a TTL is configured.
+            if config_rx.changed().await.is_err() {
+                return;
+            }
+            continue;
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(policy.interval.max(1)));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    prune(&service, ttl).await;
+                }
+                res = config_rx.changed() => {
+                    if res.is_err() {
+                        return;
+                    }
+                    if config_rx.borrow().prune != policy {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
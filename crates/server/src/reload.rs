@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Reloads the server configuration without restarting the server.
+
+use anyhow::Result;
+use libakari::path::server_config_path;
+use log::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    config::{load_server_config, ServerConfig},
+    ContainerService,
+};
+
+// Apply the log level of the configuration.
+pub fn apply_log_level(config: &ServerConfig) -> Result<()> {
+    if let Some(level) = config.log_level()? {
+        log::set_max_level(level);
+    }
+    Ok(())
+}
+
+// Reload `server.json` and apply the new configuration.
+// Return the names of the settings that require a restart to take effect.
+pub fn reload(service: &ContainerService) -> Result<Vec<String>> {
+    let config = load_server_config(&server_config_path(&service.root_path))?;
+    apply_log_level(&config)?;
+    let restart_required = service.config.borrow().restart_required(&config);
+    service.config.send_replace(config);
+
+    info!("Reloaded the server configuration");
+    for setting in &restart_required {
+        warn!("Restart the server to apply the changes to {}", setting);
+    }
+    Ok(restart_required)
+}
+
+// Reload the configuration on SIGHUP.
+pub async fn handle_sighup(service: ContainerService) -> Result<()> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    while sighup.recv().await.is_some() {
+        if let Err(e) = reload(&service) {
+            error!("Failed to reload the server configuration: {}", e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Restarts the containers according to their restart policies.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{StartRequest, WaitRequest},
+    Context,
+};
+use containerd_shim_protos::shim_async::TaskClient;
+use libakari::{
+    event::{unix_timestamp, Event},
+    timeout::ExitReason,
+    vm_rpc::VmStatus,
+};
+use log::{error, info};
+use ttrpc::asynchronous::Client;
+
+use crate::{timeout, ContainerService};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// Double the delay on every restart so that a crash loop does not hog the VM.
+fn backoff(restart_count: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << restart_count.min(16))
+        .min(MAX_BACKOFF)
+}
+
+async fn wait(vsock_path: &Path, id: &str) -> Result<u32> {
+    let client = TaskClient::new(Client::connect(vsock_path.to_str().unwrap())?);
+    let req = WaitRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    let res = client.wait(Context::default(), &req).await?;
+    Ok(res.exit_status)
+}
+
+async fn start(vsock_path: &Path, id: &str) -> Result<()> {
+    let client = TaskClient::new(Client::connect(vsock_path.to_str().unwrap())?);
+    let req = StartRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    client.start(Context::default(), &req).await?;
+    Ok(())
+}
+
+// Wait for the container to exit and restart it if its restart policy says so.
+pub async fn monitor(service: ContainerService, id: String) {
+    loop {
+        let Some(vsock_path) = service
+            .state_map
+            .read()
+            .await
+            .get(&id)
+            .map(|state| state.vsock_path.clone())
+        else {
+            return;
+        };
+
+        let exit_status = match wait(&vsock_path, &id).await {
+            Ok(exit_status) => exit_status,
+            Err(e) => {
+                error!("Failed to wait for container {}: {}", id, e);
+                return;
+            }
+        };
+
+        let restart_count = {
+            let mut state_map = service.state_map.write().await;
+            let Some(state) = state_map.get_mut(&id) else {
+                return;
+            };
+            let finished_at = unix_timestamp();
+            let reason = timeout::exit_reason(state, finished_at);
+            service.events.publish(Event::ContainerExited {
+                id: id.clone(),
+                exit_status,
+                reason,
+            });
+            service.events.publish(Event::ContainerStatusChanged {
+                id: id.clone(),
+                status: VmStatus::Stopped,
+            });
+            state.status = VmStatus::Stopped;
+            state.exit_status = Some(exit_status);
+            state.exit_reason = Some(reason);
+            state.finished_at = Some(finished_at);
+            state.deadline = None;
+            if let Err(e) = state.save(&service.root_path, &id) {
+                error!("Failed to save the container state: {}", e);
+            }
+            // Restarting a hung workload would only hang again.
+            if state.stopped_by_user
+                || reason == ExitReason::Timeout
+                || !state
+                    .restart_policy
+                    .should_restart(exit_status, state.restart_count)
+            {
+                return;
+            }
+            state.restart_count
+        };
+
+        let delay = backoff(restart_count);
+        info!(
+            "Container {} exited with {}, restarting in {:?}",
+            id, exit_status, delay
+        );
+        tokio::time::sleep(delay).await;
+
+        let mut state_map = service.state_map.write().await;
+        // The container may be killed or deleted while waiting.
+        let Some(state) = state_map.get_mut(&id) else {
+            return;
+        };
+        if state.stopped_by_user {
+            return;
+        }
+        if let Err(e) = start(&state.vsock_path, &id).await {
+            error!("Failed to restart container {}: {}", id, e);
+            return;
+        }
+        state.status = VmStatus::Running;
+        state.started_at = Some(unix_timestamp());
+        state.finished_at = None;
+        state.exit_reason = None;
+        state.restart_count += 1;
+        timeout::arm(&service, &id, state).await;
+        if let Err(e) = state.save(&service.root_path, &id) {
+            error!("Failed to save the container state: {}", e);
+        }
+        service.events.publish(Event::ContainerRestarted {
+            id: id.clone(),
+            restart_count: state.restart_count,
+        });
+        service.events.publish(Event::ContainerStatusChanged {
+            id: id.clone(),
+            status: VmStatus::Running,
+        });
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Composite create and start of a container in one admin API request.
+//! Saves the round trips of the separate shim requests, and the container is deleted again if
+//! it fails to start.
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{CreateTaskRequest, DeleteRequest, KillRequest, StartRequest},
+    protos::protobuf::{well_known_types::any::Any, MessageField},
+    Task as ShimTask,
+};
+use libakari::{api::RunRequest, task_options::TASK_OPTIONS_TYPE_URL};
+use log::{error, info, warn};
+
+use crate::{deadline::local_context, ContainerService};
+
+pub async fn run(service: &ContainerService, req: RunRequest) -> Result<u32> {
+    let options = if req.options.is_empty() {
+        MessageField::none()
+    } else {
+        MessageField::some(Any {
+            type_url: TASK_OPTIONS_TYPE_URL.to_string(),
+            value: serde_json::to_vec(&req.options)?,
+            ..Default::default()
+        })
+    };
+    let create = CreateTaskRequest {
+        id: req.id.clone(),
+        bundle: req.bundle.to_string_lossy().into_owned(),
+        terminal: req.terminal,
+        stdin: req.stdin,
+        stdout: req.stdout,
+        stderr: req.stderr,
+        options,
+        ..Default::default()
+    };
+    let ctx = local_context();
+    service.create(&ctx, create).await?;
+
+    let start = StartRequest {
+        id: req.id.clone(),
+        ..Default::default()
+    };
+    match service.start(&ctx, start).await {
+        Ok(res) => Ok(res.pid),
+        Err(e) => {
+            info!("Rolling back {} that failed to start: {}", req.id, e);
+            rollback(service, &req.id).await;
+            Err(e.into())
+        }
+    }
+}
+
+// Delete the created container. The process may not exist yet, so the kill may fail.
+async fn rollback(service: &ContainerService, id: &str) {
+    let ctx = local_context();
+    let kill = KillRequest {
+        id: id.to_string(),
+        signal: libc::SIGKILL as u32,
+        all: true,
+        ..Default::default()
+    };
+    if let Err(e) = service.kill(&ctx, kill).await {
+        warn!("Failed to kill {}: {}", id, e);
+    }
+    let delete = DeleteRequest {
+        id: id.to_string(),
+        ..Default::default()
+    };
+    if let Err(e) = service.delete(&ctx, delete).await {
+        error!("Failed to delete {}: {}", id, e);
+    }
+}
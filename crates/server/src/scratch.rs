@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Scratch directories of the containers on the host.
+
+use std::{collections::HashMap, io, path::Path};
+
+use libakari::{
+    path::scratch_path,
+    scratch::{env_entry, host_path, is_enabled},
+};
+use log::{error, info};
+
+use crate::state::ContainerStateMap;
+
+pub fn enabled(annotations: &HashMap<String, String>) -> bool {
+    // Validated with the request
+    is_enabled(annotations).unwrap_or_default()
+}
+
+// Create the scratch directory of the container and return the env entry that points at it.
+pub fn create(root_path: &Path, id: &str) -> io::Result<String> {
+    std::fs::create_dir_all(host_path(root_path, id))?;
+    Ok(env_entry(id))
+}
+
+pub fn remove(root_path: &Path, id: &str) {
+    let path = host_path(root_path, id);
+    match std::fs::remove_dir_all(&path) {
+        Ok(()) => info!("Removed the scratch directory of {}", id),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => error!("Failed to remove the scratch directory {:?}: {}", path, e),
+    }
+}
+
+// Remove the scratch directories of the containers deleted while the server was down.
+pub fn collect(root_path: &Path, state_map: &ContainerStateMap) {
+    let entries = match std::fs::read_dir(scratch_path(root_path)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            error!("Failed to read the scratch directories: {}", e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(id) = name.to_str() else {
+            continue;
+        };
+        if !state_map.contains_key(id) {
+            remove(root_path, id);
+        }
+    }
+}
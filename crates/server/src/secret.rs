@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Secrets volumes of the containers.
+//! The server sends the secrets to the agent, which writes them to an in-memory volume of the
+//! guest instead of the task options, so they are never in a directory shared with the host.
+
+use anyhow::Result;
+use libakari::{container_id::ContainerId, container_rpc::ContainerCommand, secret::SecretsVolume};
+use log::{error, info};
+
+use crate::{agent::send_command, ContainerService};
+
+pub async fn mount(
+    service: &ContainerService,
+    id: &ContainerId,
+    volume: SecretsVolume,
+) -> Result<()> {
+    info!("Mounting the secrets volume of {}: {:?}", id, volume);
+    send_command(service, &ContainerCommand::MountSecrets(id.clone(), volume)).await
+}
+
+pub async fn unmount(service: &ContainerService, id: &ContainerId) {
+    let cmd = ContainerCommand::UnmountSecrets(id.clone());
+    if let Err(e) = send_command(service, &cmd).await {
+        error!("Failed to remove the secrets volume of {}: {}", id, e);
+    }
+}
@@ -0,0 +1,422 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Stages the bundles outside the shared directories into the staging share.
+//! Each staged bundle holds the spec and a symlink to the rootfs as the guest sees it. How the
+//! rootfs gets there is the `Strategy` chosen for the container, see `StagingStrategy` for
+//! the tradeoffs. The trees in the staging share are removed when no container references them.
+//! A streamed bundle links the rootfs of the host instead, as the guest never reads it there.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    layer::{guest_rootfs_path, rootfs_layers},
+    staging::{guest_rootfs_share_path, StagingStrategy},
+};
+use log::{info, warn};
+use oci_spec::runtime::Spec;
+use sha2::{Digest, Sha256};
+
+use crate::state::ContainerStateMap;
+
+// Name of the staging share in the guest.
+pub const STAGING_SHARE_NAME: &str = "staging";
+
+const CONFIG_FILE: &str = "config.json";
+const ROOTFS_DIR: &str = "rootfs";
+const BUNDLES_DIR: &str = "bundles";
+// Prefix of the trees copied for a container. The others are named by their content hash.
+const COPY_PREFIX: &str = "copy-";
+
+pub struct Staged {
+    pub bundle: PathBuf,
+    // Name of the rootfs tree in the staging share
+    pub rootfs: Option<String>,
+    // Host directory to share with the guest while the container exists
+    pub share: Option<PathBuf>,
+    pub spec: Spec,
+}
+
+// Rootfs prepared by a strategy
+struct StagedRootfs {
+    // Target of the rootfs symlink in the staged bundle
+    link: PathBuf,
+    tree: Option<String>,
+    share: Option<PathBuf>,
+}
+
+// Prepares the rootfs of the host for the guest.
+trait Strategy {
+    fn stage(&self, stager: &Stager, id: &str, rootfs: &Path, spec: &Spec) -> Result<StagedRootfs>;
+}
+
+struct SymlinkIntoShare;
+struct CopyIntoShare;
+struct PerContainerShare;
+struct DiskImage;
+struct Stream;
+
+fn strategy(strategy: StagingStrategy) -> &'static dyn Strategy {
+    match strategy {
+        StagingStrategy::SymlinkIntoShare => &SymlinkIntoShare,
+        StagingStrategy::CopyIntoShare => &CopyIntoShare,
+        StagingStrategy::PerContainerShare => &PerContainerShare,
+        StagingStrategy::DiskImage => &DiskImage,
+        StagingStrategy::Stream => &Stream,
+    }
+}
+
+impl Strategy for SymlinkIntoShare {
+    fn stage(
+        &self,
+        stager: &Stager,
+        id: &str,
+        rootfs: &Path,
+        _spec: &Spec,
+    ) -> Result<StagedRootfs> {
+        let mut hasher = Sha256::new();
+        hash_tree(rootfs, &mut hasher)?;
+        let hash: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        if stager.rootfs_path(&hash).exists() {
+            info!("Reusing the staged rootfs {} for {}", hash, id);
+        } else {
+            stager.add_tree(id, rootfs, &hash)?;
+            info!("Staged the rootfs {:?} as {}", rootfs, hash);
+        }
+        Ok(StagedRootfs {
+            link: Stager::tree_link(&hash),
+            tree: Some(hash),
+            share: None,
+        })
+    }
+}
+
+impl Strategy for CopyIntoShare {
+    fn stage(
+        &self,
+        stager: &Stager,
+        id: &str,
+        rootfs: &Path,
+        _spec: &Spec,
+    ) -> Result<StagedRootfs> {
+        // The create may be retried with the same ID.
+        let name = format!("{}{}", COPY_PREFIX, id);
+        let _ = std::fs::remove_dir_all(stager.rootfs_path(&name));
+        stager.add_tree(id, rootfs, &name)?;
+        info!("Copied the rootfs {:?} for {}", rootfs, id);
+        Ok(StagedRootfs {
+            link: Stager::tree_link(&name),
+            tree: Some(name),
+            share: None,
+        })
+    }
+}
+
+impl Strategy for PerContainerShare {
+    fn stage(
+        &self,
+        _stager: &Stager,
+        id: &str,
+        rootfs: &Path,
+        _spec: &Spec,
+    ) -> Result<StagedRootfs> {
+        Ok(StagedRootfs {
+            link: guest_rootfs_share_path(id),
+            tree: None,
+            share: Some(rootfs.to_path_buf()),
+        })
+    }
+}
+
+impl Strategy for DiskImage {
+    // The agent composes the rootfs from the layers when the container is created.
+    fn stage(
+        &self,
+        _stager: &Stager,
+        id: &str,
+        _rootfs: &Path,
+        spec: &Spec,
+    ) -> Result<StagedRootfs> {
+        if rootfs_layers(spec)?.is_empty() {
+            anyhow::bail!("The disk images need the layers of the rootfs");
+        }
+        Ok(StagedRootfs {
+            link: guest_rootfs_path(&ContainerId::new(id)?),
+            tree: None,
+            share: None,
+        })
+    }
+}
+
+impl Strategy for Stream {
+    // The staged bundle links the rootfs of the host, which takes its place in the archive
+    // streamed to the guest.
+    fn stage(
+        &self,
+        _stager: &Stager,
+        _id: &str,
+        rootfs: &Path,
+        _spec: &Spec,
+    ) -> Result<StagedRootfs> {
+        Ok(StagedRootfs {
+            link: rootfs.canonicalize()?,
+            tree: None,
+            share: None,
+        })
+    }
+}
+
+pub struct Stager {
+    dir: PathBuf,
+}
+
+// Hash the names, the modes, the link targets and the contents of the tree in a stable order.
+fn hash_tree(dir: &Path, hasher: &mut Sha256) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        hasher.update(entry.file_name().as_bytes());
+        hasher.update([0]);
+        hasher.update(metadata.mode().to_le_bytes());
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            hasher.update(std::fs::read_link(&path)?.as_os_str().as_bytes());
+            hasher.update([0]);
+        } else if file_type.is_dir() {
+            hash_tree(&path, hasher)?;
+            // Mark the end of the directory so that the nesting is part of the hash.
+            hasher.update([0xff]);
+        } else if file_type.is_file() {
+            hasher.update(metadata.len().to_le_bytes());
+            std::io::copy(&mut File::open(&path)?, hasher)?;
+        }
+    }
+    Ok(())
+}
+
+// Mirror the file with a hard link, or a copy if it is on another filesystem.
+fn link_file(src: &Path, dst: &Path, copy: bool) -> Result<()> {
+    if copy || std::fs::hard_link(src, dst).is_err() {
+        std::fs::copy(src, dst)?;
+    }
+    Ok(())
+}
+
+fn link_tree(src: &Path, dst: &Path, copy: bool) -> Result<()> {
+    std::fs::create_dir(dst)?;
+    std::fs::set_permissions(dst, std::fs::metadata(src)?.permissions())?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let src = entry.path();
+        let dst = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&src)?, &dst)?;
+        } else if file_type.is_dir() {
+            link_tree(&src, &dst, copy)?;
+        } else if file_type.is_file() {
+            link_file(&src, &dst, copy)?;
+        }
+    }
+    Ok(())
+}
+
+impl Stager {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(dir.join(ROOTFS_DIR))?;
+        std::fs::create_dir_all(dir.join(BUNDLES_DIR))?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn rootfs_path(&self, hash: &str) -> PathBuf {
+        self.dir.join(ROOTFS_DIR).join(hash)
+    }
+
+    fn bundle_path(&self, id: &str) -> PathBuf {
+        self.dir.join(BUNDLES_DIR).join(id)
+    }
+
+    // The symlink to a tree is relative so that it resolves in the guest too.
+    fn tree_link(name: &str) -> PathBuf {
+        Path::new("..").join("..").join(ROOTFS_DIR).join(name)
+    }
+
+    // Mirror the rootfs into the tree with the name.
+    fn add_tree(&self, id: &str, rootfs: &Path, name: &str) -> Result<()> {
+        let rootfs_path = self.rootfs_path(name);
+        // Link into a private directory first so that the others never see a partial tree.
+        let tmp_path = self.dir.join(ROOTFS_DIR).join(format!(".{}-{}", name, id));
+        let _ = std::fs::remove_dir_all(&tmp_path);
+        link_tree(rootfs, &tmp_path, name.starts_with(COPY_PREFIX))?;
+        if let Err(e) = std::fs::rename(&tmp_path, &rootfs_path) {
+            std::fs::remove_dir_all(&tmp_path)?;
+            // Another container has staged the same tree in the meantime.
+            if !rootfs_path.exists() {
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Stage the bundle of the container with the strategy and return the staged bundle and
+    // spec.
+    pub fn stage(
+        &self,
+        staging: StagingStrategy,
+        id: &str,
+        bundle: &Path,
+        spec: &Spec,
+    ) -> Result<Staged> {
+        let mut root = spec
+            .root()
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Root path is not specified"))?;
+        let rootfs = bundle.join(root.path());
+        let staged = strategy(staging).stage(self, id, &rootfs, spec)?;
+
+        let bundle_path = self.bundle_path(id);
+        let _ = std::fs::remove_dir_all(&bundle_path);
+        std::fs::create_dir_all(&bundle_path)?;
+        std::os::unix::fs::symlink(&staged.link, bundle_path.join(ROOTFS_DIR))?;
+        let mut spec = spec.clone();
+        root.set_path(PathBuf::from(ROOTFS_DIR));
+        spec.set_root(Some(root));
+        spec.save(bundle_path.join(CONFIG_FILE))?;
+
+        Ok(Staged {
+            bundle: bundle_path,
+            rootfs: staged.tree,
+            share: staged.share,
+            spec,
+        })
+    }
+
+    // Write the spec rewritten by the server to a bundle of its own. The rootfs stays in the
+    // shared directory, so the symlink points at its guest path.
+    pub fn rewrite(&self, id: &str, spec: &Spec, guest_rootfs: Option<&Path>) -> Result<PathBuf> {
+        let bundle_path = self.bundle_path(id);
+        let _ = std::fs::remove_dir_all(&bundle_path);
+        std::fs::create_dir_all(&bundle_path)?;
+        let mut spec = spec.clone();
+        if let (Some(mut root), Some(guest_rootfs)) = (spec.root().clone(), guest_rootfs) {
+            std::os::unix::fs::symlink(guest_rootfs, bundle_path.join(ROOTFS_DIR))?;
+            root.set_path(PathBuf::from(ROOTFS_DIR));
+            spec.set_root(Some(root));
+        }
+        spec.save(bundle_path.join(CONFIG_FILE))?;
+        Ok(bundle_path)
+    }
+
+    // Write the staged bundle of the container as a tar with the rootfs it links in place of
+    // the symlink. The symlinks in the rootfs are kept as they are.
+    pub fn archive<W: Write>(&self, id: &str, writer: W) -> Result<W> {
+        let bundle_path = self.bundle_path(id);
+        let rootfs = std::fs::read_link(bundle_path.join(ROOTFS_DIR))?;
+        let mut builder = tar::Builder::new(writer);
+        builder.follow_symlinks(false);
+        builder.append_path_with_name(bundle_path.join(CONFIG_FILE), CONFIG_FILE)?;
+        builder.append_dir_all(ROOTFS_DIR, rootfs)?;
+        Ok(builder.into_inner()?)
+    }
+
+    // Mirror the change of the host rootfs into the staged tree. The tree keeps its name, so the
+    // containers that share it see the change too.
+    pub fn refresh(&self, name: &str, rootfs: &Path, relative: &Path) -> Result<()> {
+        let copy = name.starts_with(COPY_PREFIX);
+        let src = rootfs.join(relative);
+        let dst = self.rootfs_path(name).join(relative);
+        let metadata = match std::fs::symlink_metadata(&src) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                match std::fs::symlink_metadata(&dst) {
+                    Ok(staged) if staged.is_dir() => std::fs::remove_dir_all(&dst)?,
+                    Ok(_) => std::fs::remove_file(&dst)?,
+                    Err(_) => {}
+                }
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let file_type = metadata.file_type();
+        if file_type.is_dir() {
+            if !dst.exists() {
+                link_tree(&src, &dst, copy)?;
+            }
+            return Ok(());
+        }
+        // A file written in place is already seen through the hard link.
+        if let Ok(staged) = std::fs::symlink_metadata(&dst) {
+            if !copy && staged.ino() == metadata.ino() && staged.dev() == metadata.dev() {
+                return Ok(());
+            }
+            if staged.is_dir() {
+                std::fs::remove_dir_all(&dst)?;
+            } else {
+                std::fs::remove_file(&dst)?;
+            }
+        }
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&src)?, &dst)?;
+        } else {
+            link_file(&src, &dst, copy)?;
+        }
+        Ok(())
+    }
+
+    // Remove the entries of the directory that are not in use. The hidden ones are being staged.
+    fn remove_unused(&self, dir: &str, in_use: impl Fn(&str) -> bool) {
+        let entries = match std::fs::read_dir(self.dir.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read the staged {}: {}", dir, e);
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.starts_with('.') || in_use(name) {
+                continue;
+            }
+            info!("Removing the unused staged {} {}", dir, name);
+            if let Err(e) = std::fs::remove_dir_all(entry.path()) {
+                warn!("Failed to remove {:?}: {}", entry.path(), e);
+            }
+        }
+    }
+
+    // Remove the staged bundles of the deleted containers and the trees that no container uses.
+    pub fn collect(&self, state_map: &ContainerStateMap) {
+        let mut refcounts: HashMap<&str, usize> = HashMap::new();
+        for state in state_map.values() {
+            if let Some(name) = state.staged_rootfs.as_deref() {
+                *refcounts.entry(name).or_default() += 1;
+            }
+        }
+        self.remove_unused(BUNDLES_DIR, |id| state_map.contains_key(id));
+        self.remove_unused(ROOTFS_DIR, |name| refcounts.contains_key(name));
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use containerd_shim::api::Status;
+use libakari::{
+    exec::ExecProcess,
+    network_group::NetworkMember,
+    path::{containers_path, data_sock_path, exec_data_sock_path},
+    port_forward::PortMapping,
+    probe::ContainerHealth,
+    restart::RestartPolicy,
+    stdio::StdioStream,
+    timeout::ExitReason,
+    vm_rpc::VmStatus,
+    vsock::VsockPort,
+};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// A guest stdio stream served on the vsock port and exposed on the data socket of the container.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StdioRedirect {
+    pub stream: StdioStream,
+    pub port: VsockPort,
+    // Host file connected to the stream. Clients attach to the data socket if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    // Exec process of the stream, or the init process if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exec_id: Option<String>,
+    // Record the terminal session on the data socket
+    #[serde(default)]
+    pub record: bool,
+}
+
+impl StdioRedirect {
+    pub fn data_sock_path(&self, root_path: &Path, id: &str) -> PathBuf {
+        match &self.exec_id {
+            Some(exec_id) => exec_data_sock_path(root_path, id, exec_id, self.stream),
+            None => data_sock_path(root_path, id, self.stream),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ContainerState {
+    pub bundle: PathBuf,
+    pub vsock_port: VsockPort,
+    pub vsock_path: PathBuf,
+    pub status: VmStatus,
+    #[serde(default)]
+    pub ports: Vec<PortMapping>,
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    #[serde(default)]
+    pub restart_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<u32>,
+    // Seconds since the Unix epoch when the container was created and last started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<u64>,
+    // Seconds since the Unix epoch when the container exited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_reason: Option<ExitReason>,
+    // Seconds after the start to kill the container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_runtime: Option<u64>,
+    // Seconds since the Unix epoch when the agent kills the container
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    // Memory limit in bytes set by the update of the resources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_limit: Option<u64>,
+    #[serde(default)]
+    pub stopped_by_user: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub execs: BTreeMap<String, ExecProcess>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stdio: Vec<StdioRedirect>,
+    // SHA-256 of the spec to detect the retries of the create
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spec_hash: Option<String>,
+    // Name of the rootfs tree staged for the guest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub staged_rootfs: Option<String>,
+    // Host rootfs shared with the guest as a directory of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rootfs_share: Option<PathBuf>,
+    // Layers of the rootfs composed in the guest, lowest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rootfs_layers: Vec<String>,
+    // Bundle streamed into guest-local storage
+    #[serde(default)]
+    pub streamed_bundle: bool,
+    // Network group where the others resolve the container by its aliases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkMember>,
+    // Annotations of config.json to find the container by its labels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    // Host rootfs watched for the changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub watched_rootfs: Option<PathBuf>,
+    // Results of the probes reported by the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<ContainerHealth>,
+}
+
+pub type ContainerStateMap = HashMap<String, ContainerState>;
+
+// Convert the status reported by the guest.
+pub fn vm_status(status: Status) -> VmStatus {
+    match status {
+        Status::CREATED => VmStatus::Created,
+        Status::RUNNING | Status::PAUSING | Status::PAUSED => VmStatus::Running,
+        Status::STOPPED => VmStatus::Stopped,
+        Status::UNKNOWN => VmStatus::Creating,
+    }
+}
+
+// Return the hex SHA-256 of the spec in the bundle.
+pub fn spec_hash(bundle: &Path) -> Result<String> {
+    let spec = std::fs::read(bundle.join("config.json"))?;
+    Ok(Sha256::digest(&spec)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+fn state_path(root_path: &Path, id: &str) -> PathBuf {
+    containers_path(root_path).join(id).join("state.json")
+}
+
+impl ContainerState {
+    // Persist the state so that it can be restored after the server restarts.
+    pub fn save(&self, root_path: &Path, id: &str) -> Result<()> {
+        let path = state_path(root_path, id);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(root_path: &Path, id: &str) -> Result<()> {
+        let path = containers_path(root_path).join(id);
+        if path.exists() {
+            std::fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    }
+}
+
+// Load the persisted container states. Broken states are skipped.
+pub fn load_state_map(root_path: &Path) -> Result<ContainerStateMap> {
+    let mut state_map = ContainerStateMap::new();
+
+    let containers_path = containers_path(root_path);
+    if !containers_path.exists() {
+        return Ok(state_map);
+    }
+
+    for entry in std::fs::read_dir(containers_path)? {
+        let entry = entry?;
+        let Some(id) = entry.file_name().to_str().map(|id| id.to_string()) else {
+            continue;
+        };
+        let path = state_path(root_path, &id);
+        let state = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|json| serde_json::from_str::<ContainerState>(&json).map_err(Into::into));
+        match state {
+            Ok(state) => {
+                state_map.insert(id, state);
+            }
+            Err(e) => warn!("Failed to load container state {:?}: {}", path, e),
+        }
+    }
+
+    Ok(state_map)
+}
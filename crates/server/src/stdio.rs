@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Serves the task stdio on the data sockets and redirects it to host files.
+//! The terminal sessions on the data sockets can be recorded in the asciicast format.
+//! The output streams are buffered so that a slow consumer does not block the container unless
+//! the server config asks for it.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use libakari::{
+    asciicast::{EventCode, Recorder},
+    path::sessions_path,
+    stdio::{is_record_uri, is_socket_uri, parse_file_uri, vsock_uri, StdioStream},
+    vm_rpc::VmCommand,
+    vsock::{self, VsockPort},
+};
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::{
+    config::{StdioBufferPolicy, StdioOverflow},
+    orphan,
+    state::StdioRedirect,
+    stdio_buffer, ContainerService,
+};
+
+// Rewrite the URIs of the streams served by the server to the vsock ports from the first port.
+pub fn redirect_stdio(
+    streams: [(StdioStream, &mut String); 3],
+    first_port: VsockPort,
+    exec_id: Option<&str>,
+    terminal: bool,
+) -> Result<Vec<StdioRedirect>, vsock::Error> {
+    let mut redirects = Vec::new();
+    for (stream, uri) in streams {
+        let path = match parse_file_uri(uri) {
+            Some(path) => Some(path),
+            None if is_socket_uri(uri) => None,
+            None => continue,
+        };
+        // Only the terminal sessions are recorded. The plain streams can be redirected to files.
+        let record = is_record_uri(uri) && terminal;
+        let port = first_port.offset(redirects.len() as u32)?;
+        *uri = vsock_uri(port);
+        redirects.push(StdioRedirect {
+            stream,
+            port,
+            path,
+            exec_id: exec_id.map(str::to_string),
+            record,
+        });
+    }
+    Ok(redirects)
+}
+
+// Start the recording if any of the streams asks for it.
+pub fn recorder(
+    root_path: &Path,
+    id: &str,
+    exec_id: Option<&str>,
+    redirects: &[StdioRedirect],
+) -> Result<Option<Arc<Recorder>>> {
+    if !redirects.iter().any(|redirect| redirect.record) {
+        return Ok(None);
+    }
+    let dir = sessions_path(root_path, id);
+    std::fs::create_dir_all(&dir)?;
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let name = exec_id.unwrap_or("init");
+    let path = dir.join(format!("{}-{}.cast", name, started));
+    let recorder = Recorder::create(&path, &format!("{} {}", id, name))?;
+    info!("Recording the session of {} {} to {:?}", id, name, path);
+    Ok(Some(Arc::new(recorder)))
+}
+
+// Expose the stream that the agent serves on the vsock port on the data socket of the container.
+// The stream is connected to the host file if it is redirected.
+pub async fn serve(
+    service: &ContainerService,
+    id: &str,
+    redirect: &StdioRedirect,
+    recorder: Option<&Arc<Recorder>>,
+) -> Result<()> {
+    let data_sock_path = redirect.data_sock_path(&service.root_path, id);
+    if let Some(parent) = data_sock_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let _ = std::fs::remove_file(&data_sock_path);
+
+    // The recorded, buffered or watched stream is served on the guest socket and proxied to the
+    // data socket.
+    let policy = service.config.borrow().stdio_buffer.clone();
+    let recorder = recorder.filter(|_| redirect.record && redirect.path.is_none());
+    let buffered = redirect.stream != StdioStream::Stdin
+        && redirect.path.is_none()
+        && policy.overflow != StdioOverflow::Block;
+    let session = orphan::Session::new(service, id, redirect);
+    let proxied = recorder.is_some() || buffered || session.is_some();
+    let guest_sock_path = match proxied {
+        true => data_sock_path.with_extension("guest.sock"),
+        false => data_sock_path.clone(),
+    };
+    let _ = std::fs::remove_file(&guest_sock_path);
+    match &service.kata {
+        Some(kata) => {
+            let exec_id = redirect.exec_id.as_deref();
+            kata.serve_stdio(id, exec_id, redirect.stream, &guest_sock_path)
+                .await?
+        }
+        None => {
+            service
+                .vm
+                .call(VmCommand::ConnectAuthenticated(
+                    redirect.port,
+                    guest_sock_path.clone(),
+                ))
+                .await?
+        }
+    }
+    info!(
+        "Serving {:?} of vsock port {} on {:?}",
+        redirect.stream, redirect.port, data_sock_path
+    );
+
+    if proxied {
+        return proxy(
+            data_sock_path,
+            guest_sock_path,
+            redirect.stream,
+            recorder.cloned(),
+            policy,
+            session,
+        );
+    }
+    match &redirect.path {
+        Some(path) => redirect_to_file(&data_sock_path, redirect.stream, path, policy).await,
+        None => Ok(()),
+    }
+}
+
+fn observer(recorder: Option<&Recorder>, code: EventCode) -> impl FnMut(&[u8]) + '_ {
+    move |data| {
+        // A broken recording must not break the session.
+        if let Some(Err(e)) = recorder.map(|recorder| recorder.record(code, data)) {
+            warn!("Failed to record the session: {}", e);
+        }
+    }
+}
+
+// Wait until the client closes the output stream, which it never writes to.
+async fn hangup(mut reader: impl AsyncRead + Unpin) {
+    let mut buf = [0; 64];
+    while let Ok(n) = reader.read(&mut buf).await {
+        if n == 0 {
+            return;
+        }
+    }
+}
+
+// Proxy the session of the client that attaches to the data socket, recording and buffering the
+// stream. The session ends when the client or the guest closes the stream.
+fn proxy(
+    data_sock_path: PathBuf,
+    guest_sock_path: PathBuf,
+    stream: StdioStream,
+    recorder: Option<Arc<Recorder>>,
+    policy: StdioBufferPolicy,
+    session: Option<orphan::Session>,
+) -> Result<()> {
+    let listener = UnixListener::bind(&data_sock_path)?;
+    let spill_path = data_sock_path.with_extension("spill");
+    tokio::spawn(async move {
+        let result = async {
+            let (client, _) = listener.accept().await?;
+            let guest = UnixStream::connect(&guest_sock_path).await?;
+            let (client_rx, client_tx) = client.into_split();
+            let (guest_rx, guest_tx) = guest.into_split();
+            let recorder = recorder.as_deref();
+            if stream == StdioStream::Stdin {
+                tee(client_rx, guest_tx, observer(recorder, EventCode::Input)).await
+            } else {
+                let observe = observer(recorder, EventCode::Output);
+                let pump = stdio_buffer::pump(guest_rx, client_tx, &policy, &spill_path, observe);
+                let Some(session) = session else {
+                    return pump.await;
+                };
+                // The guest closes the output when the process exits, so a client that closes
+                // it first leaves the process behind.
+                tokio::select! {
+                    result = pump => result,
+                    () = hangup(client_rx) => {
+                        session.orphaned();
+                        Ok(())
+                    }
+                }
+            }
+        };
+        if let Err(e) = result.await {
+            error!("Failed to proxy the {:?} session: {}", stream, e);
+        }
+        let _ = std::fs::remove_file(&data_sock_path);
+    });
+    Ok(())
+}
+
+async fn tee(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    mut observe: impl FnMut(&[u8]),
+) -> std::io::Result<()> {
+    let mut buf = vec![0; 8192];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return writer.shutdown().await;
+        }
+        observe(&buf[..n]);
+        writer.write_all(&buf[..n]).await?;
+    }
+}
+
+// The input is half-closed at the end of the file so that the process sees EOF.
+async fn redirect_to_file(
+    data_sock_path: &Path,
+    stream: StdioStream,
+    path: &Path,
+    policy: StdioBufferPolicy,
+) -> Result<()> {
+    let mut socket = UnixStream::connect(data_sock_path).await?;
+    let path = path.to_path_buf();
+    info!("Redirecting {:?} to {:?}", stream, path);
+
+    if stream == StdioStream::Stdin {
+        let mut file = tokio::fs::File::open(&path).await?;
+        tokio::spawn(async move {
+            let result = async {
+                tokio::io::copy(&mut file, &mut socket).await?;
+                socket.shutdown().await
+            };
+            if let Err(e) = result.await {
+                error!("Failed to redirect the stdin from {:?}: {}", path, e);
+            }
+        });
+        return Ok(());
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    let file = tokio::fs::File::from_std(file);
+    let spill_path = data_sock_path.with_extension("spill");
+    tokio::spawn(async move {
+        let result = stdio_buffer::pump(socket, file, &policy, &spill_path, |_| {}).await;
+        if let Err(e) = result {
+            error!("Failed to redirect the stdio to {:?}: {}", path, e);
+        }
+    });
+    Ok(())
+}
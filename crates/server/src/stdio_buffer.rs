@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Bounded buffer between the guest and the consumer of an output stream.
+//! The guest is read as fast as it writes. The output is kept in memory up to the limit and then
+//! spilled to the disk or dropped per the policy. The spilled output is read back in order once
+//! the consumer catches up.
+
+use std::{
+    collections::VecDeque,
+    fs::File,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use log::warn;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Notify,
+};
+
+use crate::config::{StdioBufferPolicy, StdioOverflow};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+fn drop_marker(dropped: u64) -> Vec<u8> {
+    format!("\n[akari: dropped {} bytes of output]\n", dropped).into_bytes()
+}
+
+struct Spill {
+    file: File,
+    path: PathBuf,
+    read_pos: u64,
+    write_pos: u64,
+}
+
+impl Spill {
+    fn len(&self) -> u64 {
+        self.write_pos - self.read_pos
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+enum Pop {
+    Data(Vec<u8>),
+    Empty,
+    Eof,
+}
+
+struct State {
+    memory: VecDeque<Vec<u8>>,
+    memory_bytes: usize,
+    spill: Option<Spill>,
+    // Bytes dropped since the last marker
+    dropped: u64,
+    eof: bool,
+}
+
+impl State {
+    fn spilling(&self) -> bool {
+        self.spill.as_ref().is_some_and(|spill| spill.len() > 0)
+    }
+
+    fn push_memory(&mut self, data: &[u8]) {
+        self.memory_bytes += data.len();
+        self.memory.push_back(data.to_vec());
+    }
+
+    fn push_spill(&mut self, data: &[u8]) -> std::io::Result<()> {
+        let spill = self.spill.as_mut().expect("spill file is open");
+        spill.file.write_all_at(data, spill.write_pos)?;
+        spill.write_pos += data.len() as u64;
+        Ok(())
+    }
+
+    // Queue the data after the marker of the dropped output, if any.
+    fn accept(&mut self, data: &[u8], spill: bool) -> std::io::Result<()> {
+        let mut chunks = Vec::new();
+        if self.dropped > 0 {
+            chunks.push(drop_marker(self.dropped));
+            self.dropped = 0;
+        }
+        chunks.push(data.to_vec());
+        for chunk in chunks {
+            if spill {
+                self.push_spill(&chunk)?;
+            } else {
+                self.push_memory(&chunk);
+            }
+        }
+        Ok(())
+    }
+
+    // Return false if the producer has to wait for the consumer.
+    fn push(
+        &mut self,
+        data: &[u8],
+        policy: &StdioBufferPolicy,
+        spill_path: &Path,
+    ) -> std::io::Result<bool> {
+        // Once spilled, the output goes to the disk until the consumer reads it all, so that the
+        // order is kept.
+        if !self.spilling() && self.memory_bytes + data.len() <= policy.memory_limit {
+            self.accept(data, false)?;
+            return Ok(true);
+        }
+        match policy.overflow {
+            StdioOverflow::Block => return Ok(false),
+            StdioOverflow::Spill => {
+                if self.spill.is_none() {
+                    self.spill = Some(Spill {
+                        file: File::options()
+                            .read(true)
+                            .write(true)
+                            .create(true)
+                            .truncate(true)
+                            .open(spill_path)?,
+                        path: spill_path.to_path_buf(),
+                        read_pos: 0,
+                        write_pos: 0,
+                    });
+                }
+                let spilled = self.spill.as_ref().map_or(0, Spill::len);
+                if spilled + data.len() as u64 <= policy.disk_limit {
+                    self.accept(data, true)?;
+                    return Ok(true);
+                }
+            }
+            StdioOverflow::Drop => {}
+        }
+        self.dropped += data.len() as u64;
+        Ok(true)
+    }
+
+    fn pop(&mut self) -> std::io::Result<Pop> {
+        if let Some(chunk) = self.memory.pop_front() {
+            self.memory_bytes -= chunk.len();
+            return Ok(Pop::Data(chunk));
+        }
+        if let Some(spill) = self.spill.as_mut().filter(|spill| spill.len() > 0) {
+            let mut chunk = vec![0; spill.len().min(CHUNK_SIZE as u64) as usize];
+            let n = spill.file.read_at(&mut chunk, spill.read_pos)?;
+            chunk.truncate(n);
+            spill.read_pos += n as u64;
+            // Reuse the file from the start once the consumer has caught up.
+            if spill.len() == 0 {
+                spill.file.set_len(0)?;
+                spill.read_pos = 0;
+                spill.write_pos = 0;
+            }
+            return Ok(Pop::Data(chunk));
+        }
+        if !self.eof {
+            return Ok(Pop::Empty);
+        }
+        if self.dropped > 0 {
+            let marker = drop_marker(self.dropped);
+            self.dropped = 0;
+            return Ok(Pop::Data(marker));
+        }
+        Ok(Pop::Eof)
+    }
+}
+
+// Copy the output from the guest to the consumer through the buffer. `observe` sees the output
+// as it is read, e.g. to record the session.
+pub async fn pump(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    policy: &StdioBufferPolicy,
+    spill_path: &Path,
+    mut observe: impl FnMut(&[u8]),
+) -> std::io::Result<()> {
+    let state = Mutex::new(State {
+        memory: VecDeque::new(),
+        memory_bytes: 0,
+        spill: None,
+        dropped: 0,
+        eof: false,
+    });
+    let lock = || state.lock().unwrap_or_else(|e| e.into_inner());
+    let readable = Notify::new();
+    let writable = Notify::new();
+
+    let produce = async {
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                lock().eof = true;
+                readable.notify_one();
+                return Ok::<_, std::io::Error>(());
+            }
+            observe(&buf[..n]);
+            while !lock().push(&buf[..n], policy, spill_path)? {
+                writable.notified().await;
+            }
+            readable.notify_one();
+        }
+    };
+    let consume = async {
+        loop {
+            let pop = lock().pop()?;
+            match pop {
+                Pop::Data(chunk) => {
+                    writer.write_all(&chunk).await?;
+                    writable.notify_one();
+                }
+                Pop::Empty => readable.notified().await,
+                Pop::Eof => return writer.shutdown().await,
+            }
+        }
+    };
+    tokio::try_join!(produce, consume)?;
+
+    let dropped = lock().dropped;
+    if dropped > 0 {
+        warn!("Dropped {} bytes of output", dropped);
+    }
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Supervises the VM thread.
+//! When the thread exits, the VM is marked failed and the requests are refused with
+//! `VmUnavailable` instead of waiting on the command channel. The VM is restarted if the policy
+//! allows it. The containers of the failed VM are not recovered.
+
+use std::{
+    os::unix::net::UnixStream,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{
+    event::Event, scheduling::QosClass, vm_config::MacosVmConfig, vm_rpc, vsock_auth::AuthToken,
+};
+use log::{error, info};
+use tokio::sync::{mpsc, watch};
+use vmm::connection::ConnectionManager;
+
+use crate::{config::ServerConfig, events::EventPublisher, vm_handle::VmRequest, vm_thread};
+
+// Everything the VM thread needs to create the VM again.
+pub struct VmThreadArgs {
+    pub vm_config: MacosVmConfig,
+    pub gui: bool,
+    pub qos: QosClass,
+    pub connections: ConnectionManager,
+    pub events: EventPublisher,
+    // Guest end of the agent console port
+    pub agent_console: Option<UnixStream>,
+    // VM end of the serial console multiplexer
+    pub serial_console: Option<UnixStream>,
+    // Token that the guest ends of the container ports prove to know
+    pub auth_token: Arc<AuthToken>,
+}
+
+// Why the VM failed, shared with the request handlers.
+#[derive(Clone, Default)]
+pub struct VmHealth {
+    failure: Arc<Mutex<Option<String>>>,
+}
+
+impl VmHealth {
+    fn set(&self, failure: Option<String>) {
+        *self.failure.lock().unwrap_or_else(|e| e.into_inner()) = failure;
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    // Return `VmUnavailable` if the VM has failed.
+    pub fn check(&self) -> Result<(), vm_rpc::Error> {
+        match self
+            .failure
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            Some(reason) => Err(vm_rpc::Error::VmUnavailable(reason.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+// Run the VM thread and restart it per the policy when it fails.
+pub async fn supervise(
+    args: VmThreadArgs,
+    mut cmd_rx: mpsc::Receiver<VmRequest>,
+    health: VmHealth,
+    config: Arc<watch::Sender<ServerConfig>>,
+) -> Result<()> {
+    let mut restarts = 0;
+    loop {
+        let reason = match vm_thread(&args, &mut cmd_rx, restarts > 0) {
+            // The server has closed the command channel.
+            Ok(()) => return Ok(()),
+            Err(e) => e.to_string(),
+        };
+        error!("VM thread exited: {}", reason);
+        health.set(Some(reason.clone()));
+        args.events.publish(Event::VmFailed { reason });
+
+        // Read the policy now as the configuration may have been reloaded.
+        let policy = config.borrow().vm_restart.clone();
+        if !policy.enabled || restarts >= policy.max_restarts {
+            // Closing the channel fails the pending and the later commands immediately.
+            error!("VM is unavailable until the server restarts");
+            return Ok(());
+        }
+        restarts += 1;
+        tokio::time::sleep(Duration::from_secs(policy.delay)).await;
+
+        // The commands for the failed VM are stale. Dropping them fails their callers.
+        while cmd_rx.try_recv().is_ok() {}
+        info!("Restarting the VM ({}/{})", restarts, policy.max_restarts);
+        health.set(None);
+        args.events.publish(Event::VmRestarted {
+            restart_count: restarts,
+        });
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Expands the host metadata in the env of the containers.
+//! The bundles refer to the metadata as `${AKARI_CONTAINER_ID}` and so on, so that one bundle runs
+//! on any machine. The other references are left to the shell of the container.
+
+use std::collections::BTreeMap;
+
+use oci_spec::runtime::Spec;
+
+// Names with the prefix are reserved for the server, so a typo is an error.
+const RESERVED_PREFIX: &str = "AKARI_";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unknown variable ${{{0}}} in the env {1}")]
+    UnknownVariable(String, String),
+}
+
+pub type Vars = BTreeMap<&'static str, String>;
+
+pub fn vars(vm_name: &str, id: &str) -> Vars {
+    BTreeMap::from([
+        ("AKARI_VM_NAME", vm_name.to_string()),
+        ("AKARI_CONTAINER_ID", id.to_string()),
+        ("AKARI_HOST_ARCH", std::env::consts::ARCH.to_string()),
+    ])
+}
+
+fn expand(key: &str, value: &str, vars: &Vars) -> Result<String, Error> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + len];
+        expanded.push_str(&rest[..start]);
+        match vars.get(name) {
+            Some(value) => expanded.push_str(value),
+            None if name.starts_with(RESERVED_PREFIX) => {
+                return Err(Error::UnknownVariable(name.to_string(), key.to_string()));
+            }
+            None => expanded.push_str(&rest[start..=start + len]),
+        }
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+// Expand the variables in the values of process.env. Return true if the spec changed.
+pub fn expand_env(spec: &mut Spec, vars: &Vars) -> Result<bool, Error> {
+    let Some(env) = spec
+        .process_mut()
+        .as_mut()
+        .and_then(|process| process.env_mut().as_mut())
+    else {
+        return Ok(false);
+    };
+    let mut changed = false;
+    for entry in env.iter_mut() {
+        let Some((key, value)) = entry.split_once('=') else {
+            continue;
+        };
+        let value = expand(key, value, vars)?;
+        let expanded = format!("{}={}", key, value);
+        if expanded != *entry {
+            *entry = expanded;
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
@@ -0,0 +1,44 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Arms the maximum runtime of the containers in the agent, which kills the timed-out workloads.
+
+use libakari::{
+    container_id::ContainerId, container_rpc::ContainerCommand, event::unix_timestamp,
+    timeout::ExitReason,
+};
+use log::{error, info};
+
+use crate::{agent::send_command, state::ContainerState, ContainerService};
+
+// Ask the agent to kill the container when its maximum runtime elapsed.
+pub async fn arm(service: &ContainerService, id: &str, state: &mut ContainerState) {
+    state.deadline = None;
+    let Some(max_runtime) = state.max_runtime else {
+        return;
+    };
+    // The ID was validated when the container was created.
+    let cmd = match ContainerId::new(id) {
+        Ok(id) => ContainerCommand::SetMaxRuntime(id, max_runtime),
+        Err(e) => {
+            error!("Failed to set the max runtime of {}: {}", id, e);
+            return;
+        }
+    };
+    match send_command(service, &cmd).await {
+        Ok(()) => {
+            info!("Container {} is killed after {}s", id, max_runtime);
+            state.deadline = Some(unix_timestamp() + max_runtime);
+        }
+        Err(e) => error!("Failed to set the max runtime of {}: {}", id, e),
+    }
+}
+
+// The wait response only carries the exit status, so a container that exited after its
+// deadline is reported as timed out.
+pub fn exit_reason(state: &ContainerState, finished_at: u64) -> ExitReason {
+    match state.deadline {
+        Some(deadline) if finished_at >= deadline => ExitReason::Timeout,
+        _ => ExitReason::Exited,
+    }
+}
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Traces the task RPCs served by the server.
+//! Each method handler of the ttrpc service is wrapped so that the decoded frame is recorded
+//! after the handler answers.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use libakari::trace::{ProtocolTrace, TraceRecord};
+use log::warn;
+use ttrpc::{
+    asynchronous::{MethodHandler, Service, TtrpcContext},
+    proto::{Request, Response},
+};
+
+struct TracedHandler {
+    method: String,
+    inner: Box<dyn MethodHandler + Send + Sync>,
+    trace: Arc<ProtocolTrace>,
+}
+
+#[async_trait]
+impl MethodHandler for TracedHandler {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        let mut record =
+            TraceRecord::new("server", &self.method, ctx.mh.stream_id, SystemTime::now());
+        record.request_bytes = req.payload.len();
+        let started = Instant::now();
+        let res = self.inner.handler(ctx, req).await;
+        let mut record = record.finish(started.elapsed());
+        match &res {
+            Ok(res) => {
+                record.response_bytes = res.payload.len();
+                // The errors of the methods are answered as the status of the response.
+                if let Some(status) = res.status.as_ref() {
+                    record.status = format!("{:?}", status.code.enum_value_or_default());
+                    if !status.message.is_empty() {
+                        record.message = Some(status.message.clone());
+                    }
+                }
+            }
+            Err(e) => {
+                record.status = "ERROR".to_string();
+                record.message = Some(e.to_string());
+            }
+        }
+        if let Err(e) = self.trace.write(&record) {
+            warn!("Failed to write the protocol trace: {}", e);
+        }
+        res
+    }
+}
+
+// Wrap the method handlers of the services to trace them.
+pub fn traced(
+    services: HashMap<String, Service>,
+    trace: &Arc<ProtocolTrace>,
+) -> HashMap<String, Service> {
+    services
+        .into_iter()
+        .map(|(name, service)| {
+            let methods = service
+                .methods
+                .into_iter()
+                .map(|(method, inner)| {
+                    let handler = TracedHandler {
+                        method: format!("{}/{}", name, method),
+                        inner,
+                        trace: trace.clone(),
+                    };
+                    (
+                        method,
+                        Box::new(handler) as Box<dyn MethodHandler + Send + Sync>,
+                    )
+                })
+                .collect();
+            let service = Service {
+                methods,
+                streams: service.streams,
+            };
+            (name, service)
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Streams the staged bundles to the agent for the `stream` staging strategy.
+
+use std::io::BufWriter;
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId,
+    container_rpc::{ContainerCommand, ContainerResponse},
+    framing::{ReadFrom, CHUNK_SIZE},
+    transfer::ChunkWriter,
+};
+use log::info;
+
+use crate::{
+    agent::{open_stream, send_command},
+    ContainerService,
+};
+
+// Send the staged bundle of the container and wait until the agent has unpacked it.
+pub async fn send(service: &ContainerService, id: &ContainerId) -> Result<()> {
+    let stream = open_stream(service, &ContainerCommand::ReceiveBundle(id.clone())).await?;
+    let mut stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let stager = service.stager.clone();
+    let id = id.clone();
+    let res = tokio::task::spawn_blocking(move || -> Result<ContainerResponse> {
+        // Buffer the small files of the rootfs into full chunks.
+        let writer = BufWriter::with_capacity(CHUNK_SIZE, ChunkWriter::new(&stream));
+        let writer = stager.archive(&id, writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        info!("Streamed the bundle of {} to the guest", id);
+        Ok(ContainerResponse::read_from(&mut stream)?)
+    })
+    .await??;
+    match res {
+        ContainerResponse::Error(e) => Err(anyhow::anyhow!("Agent error: {}", e)),
+        _ => Ok(()),
+    }
+}
+
+// Remove the streamed bundle from the guest.
+pub async fn remove(service: &ContainerService, id: &ContainerId) -> Result<()> {
+    send_command(service, &ContainerCommand::RemoveBundle(id.clone())).await
+}
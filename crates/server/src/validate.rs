@@ -0,0 +1,154 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Validation of the create requests before the server touches the VM or the state map.
+//! A bad request fails with INVALID_ARGUMENT and the details instead of a half-created container.
+
+use std::path::Path;
+
+use containerd_shim::{api::CreateTaskRequest, TtrpcResult};
+use libakari::{
+    guest_user,
+    layer::{check_attached, rootfs_layers},
+    network_group::NetworkMember,
+    priority::ProcessPriority,
+    probe::Probes,
+    scratch,
+    secret::secrets_volume_destination,
+    staging::StagingStrategy,
+    stdio::{is_file_uri, is_vsock_uri, parse_file_uri, StdioStream},
+    storage::ContainerStorage,
+    vm_config::MacosVmConfig,
+};
+use oci_spec::runtime::Spec;
+
+fn invalid(message: String) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(ttrpc::Code::INVALID_ARGUMENT, message))
+}
+
+pub fn create_request(req: &CreateTaskRequest, vm_config: &MacosVmConfig) -> TtrpcResult<()> {
+    let bundle = Path::new(req.bundle());
+    if !bundle.is_absolute() {
+        return Err(invalid(format!(
+            "Bundle {:?} is not an absolute path",
+            bundle
+        )));
+    }
+    if !bundle.is_dir() {
+        return Err(invalid(format!("Bundle {:?} is not a directory", bundle)));
+    }
+    let spec_path = bundle.join("config.json");
+    let spec = Spec::load(&spec_path)
+        .map_err(|e| invalid(format!("Failed to load {:?}: {}", spec_path, e)))?;
+    if spec.process().is_none() {
+        return Err(invalid("The spec has no process".to_string()));
+    }
+    // The agent applies the priority, the network group and the storage, so check them before
+    // the container is created.
+    if let Some(annotations) = spec.annotations() {
+        ProcessPriority::from_annotations(annotations).map_err(|e| invalid(e.to_string()))?;
+        NetworkMember::from_annotations(req.id(), annotations)
+            .map_err(|e| invalid(e.to_string()))?;
+        ContainerStorage::from_annotations(annotations).map_err(|e| invalid(e.to_string()))?;
+        Probes::from_annotations(annotations).map_err(|e| invalid(e.to_string()))?;
+        scratch::is_enabled(annotations).map_err(|e| invalid(e.to_string()))?;
+        guest_user::is_enabled(annotations).map_err(|e| invalid(e.to_string()))?;
+        secrets_volume_destination(annotations).map_err(|e| invalid(e.to_string()))?;
+    }
+    // The layered rootfs is composed in the guest, so the bundle has no tree for it.
+    let layers = rootfs_layers(&spec).map_err(|e| invalid(e.to_string()))?;
+    match spec.root() {
+        Some(_) if !layers.is_empty() => {
+            check_attached(&layers, vm_config).map_err(|e| invalid(e.to_string()))?
+        }
+        Some(root) => {
+            let rootfs = bundle.join(root.path());
+            if !rootfs.is_dir() {
+                return Err(invalid(format!("Rootfs {:?} is not a directory", rootfs)));
+            }
+        }
+        None if !layers.is_empty() => {
+            return Err(invalid(
+                "The layered rootfs needs a root in the spec".to_string(),
+            ))
+        }
+        None => {}
+    }
+    staging_strategy(&spec, StagingStrategy::default())?;
+
+    // The terminal merges the stderr into the stdout.
+    if req.terminal && !req.stderr.is_empty() {
+        return Err(invalid(
+            "The stderr cannot be set with a terminal".to_string(),
+        ));
+    }
+    for (stream, uri) in [
+        (StdioStream::Stdin, req.stdin()),
+        (StdioStream::Stdout, req.stdout()),
+        (StdioStream::Stderr, req.stderr()),
+    ] {
+        stdio_uri(stream, uri)?;
+    }
+    Ok(())
+}
+
+// Return the strategy to stage the rootfs of the spec. The layers are only composed from the
+// disk images, which in turn need the layers.
+pub fn staging_strategy(spec: &Spec, default: StagingStrategy) -> TtrpcResult<StagingStrategy> {
+    let layers = rootfs_layers(spec).map_err(|e| invalid(e.to_string()))?;
+    let strategy = match spec.annotations() {
+        Some(annotations) => {
+            StagingStrategy::from_annotations(annotations).map_err(|e| invalid(e.to_string()))?
+        }
+        None => None,
+    };
+    match strategy {
+        None if !layers.is_empty() => Ok(StagingStrategy::DiskImage),
+        Some(StagingStrategy::DiskImage) if layers.is_empty() => Err(invalid(
+            "The disk-image staging needs the layers of the rootfs".to_string(),
+        )),
+        Some(strategy) if strategy != StagingStrategy::DiskImage && !layers.is_empty() => {
+            Err(invalid(format!(
+                "The layered rootfs cannot be staged with {}",
+                strategy
+            )))
+        }
+        Some(strategy) => Ok(strategy),
+        // Only the layered rootfs can use the disk images of the default.
+        None if default == StagingStrategy::DiskImage => Ok(StagingStrategy::SymlinkIntoShare),
+        None => Ok(default),
+    }
+}
+
+fn stdio_uri(stream: StdioStream, uri: &str) -> TtrpcResult<()> {
+    if is_vsock_uri(uri) {
+        return Err(invalid(format!(
+            "The {} URI {} is reserved for the server",
+            stream.name(),
+            uri
+        )));
+    }
+    if !is_file_uri(uri) {
+        return Ok(());
+    }
+    let path = parse_file_uri(uri).ok_or_else(|| {
+        invalid(format!(
+            "The {} URI {} is not an absolute path",
+            stream.name(),
+            uri
+        ))
+    })?;
+    // The input is read from the file and the output is written to a new or existing file.
+    let exists = match stream {
+        StdioStream::Stdin => path.is_file(),
+        _ => path.parent().is_some_and(Path::is_dir),
+    };
+    if !exists {
+        return Err(invalid(format!(
+            "The {} file {:?} does not exist",
+            stream.name(),
+            path
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,46 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Request/response channel to the VM thread.
+//! Each command carries the sender of its result, so the caller sees the error of the VM
+//! instead of only the log.
+
+use anyhow::{anyhow, Result};
+use libakari::vm_rpc::VmCommand;
+use tokio::sync::{mpsc, oneshot};
+
+// Command to the VM thread with the sender of its result.
+pub struct VmRequest {
+    pub cmd: VmCommand,
+    pub reply: oneshot::Sender<Result<()>>,
+}
+
+impl VmRequest {
+    // Send the result back. The caller may have given up already.
+    pub fn respond(self, res: Result<()>) {
+        let _ = self.reply.send(res);
+    }
+}
+
+#[derive(Clone)]
+pub struct VmHandle {
+    tx: mpsc::Sender<VmRequest>,
+}
+
+impl VmHandle {
+    pub fn channel(buffer: usize) -> (Self, mpsc::Receiver<VmRequest>) {
+        let (tx, rx) = mpsc::channel(buffer);
+        (Self { tx }, rx)
+    }
+
+    // Send the command to the VM thread and wait for its result.
+    pub async fn call(&self, cmd: VmCommand) -> Result<()> {
+        let (reply, rx) = oneshot::channel();
+        self.tx
+            .send(VmRequest { cmd, reply })
+            .await
+            .map_err(|_| anyhow!("VM thread is not running"))?;
+        rx.await
+            .map_err(|_| anyhow!("VM thread exited before answering the command"))?
+    }
+}
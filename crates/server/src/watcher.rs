@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Watches the host rootfs of the containers with FSEvents.
+//! The changed files are mirrored into the staged tree, and the agent drops their cached
+//! contents, so that a running container picks up the rebuilt binaries without being created
+//! again.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::{c_char, c_void, CStr, CString, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use libakari::{
+    container_id::ContainerId, container_rpc::ContainerCommand, staging::guest_rootfs_share_path,
+};
+use log::{error, info, warn};
+use tokio::sync::mpsc;
+
+use crate::{agent::send_command, ContainerService};
+
+type FsEventStreamRef = *mut c_void;
+type DispatchQueueRef = *mut c_void;
+type CfStringRef = *const c_void;
+type CfArrayRef = *const c_void;
+type FsEventStreamCallback = extern "C" fn(
+    stream: FsEventStreamRef,
+    info: *mut c_void,
+    num_events: usize,
+    event_paths: *mut c_void,
+    event_flags: *const u32,
+    event_ids: *const u64,
+);
+
+#[repr(C)]
+struct FsEventStreamContext {
+    version: isize,
+    info: *mut c_void,
+    retain: *const c_void,
+    release: Option<extern "C" fn(info: *const c_void)>,
+    copy_description: *const c_void,
+}
+
+#[repr(C)]
+struct CfArrayCallBacks {
+    _private: [u8; 0],
+}
+
+#[link(name = "CoreServices", kind = "framework")]
+extern "C" {
+    fn FSEventStreamCreate(
+        allocator: *const c_void,
+        callback: FsEventStreamCallback,
+        context: *const FsEventStreamContext,
+        paths_to_watch: CfArrayRef,
+        since_when: u64,
+        latency: f64,
+        flags: u32,
+    ) -> FsEventStreamRef;
+    fn FSEventStreamSetDispatchQueue(stream: FsEventStreamRef, queue: DispatchQueueRef);
+    fn FSEventStreamStart(stream: FsEventStreamRef) -> u8;
+    fn FSEventStreamStop(stream: FsEventStreamRef);
+    fn FSEventStreamInvalidate(stream: FsEventStreamRef);
+    fn FSEventStreamRelease(stream: FsEventStreamRef);
+}
+
+#[link(name = "CoreFoundation", kind = "framework")]
+extern "C" {
+    static kCFTypeArrayCallBacks: CfArrayCallBacks;
+    fn CFStringCreateWithCString(
+        allocator: *const c_void,
+        c_str: *const c_char,
+        encoding: u32,
+    ) -> CfStringRef;
+    fn CFArrayCreate(
+        allocator: *const c_void,
+        values: *const *const c_void,
+        num_values: isize,
+        callbacks: *const CfArrayCallBacks,
+    ) -> CfArrayRef;
+    fn CFRelease(cf: *const c_void);
+}
+
+extern "C" {
+    fn dispatch_queue_create(label: *const c_char, attr: *const c_void) -> DispatchQueueRef;
+}
+
+const FS_EVENT_STREAM_EVENT_ID_SINCE_NOW: u64 = u64::MAX;
+const FS_EVENT_STREAM_CREATE_FLAG_NO_DEFER: u32 = 0x02;
+const FS_EVENT_STREAM_CREATE_FLAG_FILE_EVENTS: u32 = 0x10;
+const CF_STRING_ENCODING_UTF8: u32 = 0x08000100;
+
+// Seconds that FSEvents coalesces the events of a stream.
+const LATENCY: f64 = 0.2;
+// A build writes many files, so the changes are sent to the agent at once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+// Files that changed under the watched rootfs of the container.
+pub struct RootfsChange {
+    pub id: String,
+    pub rootfs: PathBuf,
+    pub paths: Vec<PathBuf>,
+}
+
+struct StreamContext {
+    id: String,
+    rootfs: PathBuf,
+    tx: mpsc::UnboundedSender<RootfsChange>,
+}
+
+extern "C" fn stream_callback(
+    _stream: FsEventStreamRef,
+    info: *mut c_void,
+    num_events: usize,
+    event_paths: *mut c_void,
+    _event_flags: *const u32,
+    _event_ids: *const u64,
+) {
+    let context = unsafe { &*(info as *const StreamContext) };
+    let event_paths =
+        unsafe { std::slice::from_raw_parts(event_paths as *const *const c_char, num_events) };
+    let paths = event_paths
+        .iter()
+        .map(|&path| {
+            let path = unsafe { CStr::from_ptr(path) };
+            PathBuf::from(OsStr::from_bytes(path.to_bytes()))
+        })
+        .collect();
+    let _ = context.tx.send(RootfsChange {
+        id: context.id.clone(),
+        rootfs: context.rootfs.clone(),
+        paths,
+    });
+}
+
+// FSEvents releases the context with the stream.
+extern "C" fn release_context(info: *const c_void) {
+    drop(unsafe { Box::from_raw(info as *mut StreamContext) });
+}
+
+struct Stream(FsEventStreamRef);
+
+// FSEvents allows stopping the stream from any thread.
+unsafe impl Send for Stream {}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        unsafe {
+            FSEventStreamStop(self.0);
+            FSEventStreamInvalidate(self.0);
+            FSEventStreamRelease(self.0);
+        }
+    }
+}
+
+struct Queue(DispatchQueueRef);
+
+// The dispatch queues are thread-safe.
+unsafe impl Send for Queue {}
+unsafe impl Sync for Queue {}
+
+#[derive(Clone)]
+pub struct RootfsWatcher {
+    // Serial queue that delivers the events of all the streams
+    queue: Arc<Queue>,
+    streams: Arc<Mutex<HashMap<String, Stream>>>,
+    tx: mpsc::UnboundedSender<RootfsChange>,
+}
+
+impl RootfsWatcher {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<RootfsChange>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let queue = unsafe { dispatch_queue_create(c"akari.watcher".as_ptr(), std::ptr::null()) };
+        let watcher = Self {
+            queue: Arc::new(Queue(queue)),
+            streams: Default::default(),
+            tx,
+        };
+        (watcher, rx)
+    }
+
+    // Start watching the rootfs of the container.
+    pub fn watch(&self, id: &str, rootfs: &Path) -> Result<()> {
+        // FSEvents reports the resolved paths, e.g. under /private/var.
+        let rootfs = rootfs.canonicalize()?;
+        let c_path = CString::new(rootfs.as_os_str().as_bytes())?;
+        let context = Box::into_raw(Box::new(StreamContext {
+            id: id.to_string(),
+            rootfs: rootfs.clone(),
+            tx: self.tx.clone(),
+        }));
+        let stream_context = FsEventStreamContext {
+            version: 0,
+            info: context as *mut c_void,
+            retain: std::ptr::null(),
+            release: Some(release_context),
+            copy_description: std::ptr::null(),
+        };
+        let stream = unsafe {
+            let path = CFStringCreateWithCString(
+                std::ptr::null(),
+                c_path.as_ptr(),
+                CF_STRING_ENCODING_UTF8,
+            );
+            let paths = CFArrayCreate(std::ptr::null(), &path, 1, &kCFTypeArrayCallBacks);
+            let stream = FSEventStreamCreate(
+                std::ptr::null(),
+                stream_callback,
+                &stream_context,
+                paths,
+                FS_EVENT_STREAM_EVENT_ID_SINCE_NOW,
+                LATENCY,
+                FS_EVENT_STREAM_CREATE_FLAG_NO_DEFER | FS_EVENT_STREAM_CREATE_FLAG_FILE_EVENTS,
+            );
+            CFRelease(paths);
+            CFRelease(path);
+            stream
+        };
+        if stream.is_null() {
+            drop(unsafe { Box::from_raw(context) });
+            anyhow::bail!("Failed to create the event stream of {:?}", rootfs);
+        }
+        unsafe { FSEventStreamSetDispatchQueue(stream, self.queue.0) };
+        if unsafe { FSEventStreamStart(stream) } == 0 {
+            unsafe {
+                FSEventStreamInvalidate(stream);
+                FSEventStreamRelease(stream);
+            }
+            anyhow::bail!("Failed to start the event stream of {:?}", rootfs);
+        }
+        info!("Watching the rootfs {:?} of {}", rootfs, id);
+        // Watching again replaces the previous stream.
+        self.streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_string(), Stream(stream));
+        Ok(())
+    }
+
+    pub fn unwatch(&self, id: &str) {
+        if self
+            .streams
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id)
+            .is_some()
+        {
+            info!("Stopped watching the rootfs of {}", id);
+        }
+    }
+}
+
+// Return the guest paths of the changed files after mirroring them into the staged tree.
+async fn apply_changes(
+    service: &ContainerService,
+    id: &str,
+    rootfs: &Path,
+    paths: &BTreeSet<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    let (staged_rootfs, shared) = match service.state_map.read().await.get(id) {
+        Some(state) => (state.staged_rootfs.clone(), state.rootfs_share.is_some()),
+        // The container has been deleted in the meantime.
+        None => return Ok(Vec::new()),
+    };
+    let guest_root = match &staged_rootfs {
+        Some(name) => service
+            .path_translator
+            .to_guest(&service.stager.rootfs_path(name))?,
+        // The share is the rootfs of the host, so nothing is mirrored.
+        None if shared => guest_rootfs_share_path(id),
+        None => service.path_translator.to_guest(rootfs)?,
+    };
+
+    let mut guest_paths = Vec::new();
+    for path in paths {
+        let Ok(relative) = path.strip_prefix(rootfs) else {
+            continue;
+        };
+        if let Some(name) = &staged_rootfs {
+            if let Err(e) = service.stager.refresh(name, rootfs, relative) {
+                warn!(
+                    "Failed to refresh the staged {:?} of {}: {}",
+                    relative, id, e
+                );
+                continue;
+            }
+        }
+        guest_paths.push(guest_root.join(relative));
+    }
+    Ok(guest_paths)
+}
+
+// Forward the changes of the watched rootfs to the agent.
+pub async fn handle_changes(
+    service: ContainerService,
+    mut rx: mpsc::UnboundedReceiver<RootfsChange>,
+) {
+    while let Some(change) = rx.recv().await {
+        let mut changes: BTreeMap<String, (PathBuf, BTreeSet<PathBuf>)> = BTreeMap::new();
+        let mut add = |change: RootfsChange| {
+            changes
+                .entry(change.id)
+                .or_insert_with(|| (change.rootfs, BTreeSet::new()))
+                .1
+                .extend(change.paths);
+        };
+        add(change);
+        tokio::time::sleep(DEBOUNCE).await;
+        while let Ok(change) = rx.try_recv() {
+            add(change);
+        }
+
+        for (id, (rootfs, paths)) in changes {
+            let guest_paths = match apply_changes(&service, &id, &rootfs, &paths).await {
+                Ok(guest_paths) if guest_paths.is_empty() => continue,
+                Ok(guest_paths) => guest_paths,
+                Err(e) => {
+                    error!("Failed to apply the rootfs changes of {}: {}", id, e);
+                    continue;
+                }
+            };
+            let Ok(container_id) = id.parse::<ContainerId>() else {
+                continue;
+            };
+            info!("Reloading {} changed paths of {}", guest_paths.len(), id);
+            let cmd = ContainerCommand::InvalidateCache(container_id, guest_paths);
+            if let Err(e) = send_command(&service, &cmd).await {
+                error!("Failed to invalidate the cache of {}: {}", id, e);
+            }
+        }
+    }
+}
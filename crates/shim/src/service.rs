@@ -1,42 +1,134 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
-use std::sync::Arc;
+use std::{
+    os::{
+        fd::AsRawFd,
+        unix::{net::UnixListener, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
 use containerd_shim::{
-    protos::shim_async::{Client, TaskClient},
-    publisher::RemotePublisher,
-    spawn, Config, DeleteResponse, Error, ExitSignal, Flags, Shim, StartOpts,
+    publisher::RemotePublisher, Config, DeleteResponse, Error, ExitSignal, Flags, Shim, StartOpts,
+};
+use libakari::{
+    path::{aux_sock_path, root_path, shim_sock_path},
+    trace::ProtocolTrace,
 };
-use libakari::path::{aux_sock_path, root_path};
+use log::{info, warn};
+
+use crate::task::{ServerClient, Task};
+
+// The shim serves the task service on the listener inherited as this descriptor.
+const SOCKET_FD: i32 = 3;
+
+fn other(context: &str, e: impl std::fmt::Display) -> Error {
+    Error::Other(format!("{}: {}", context, e))
+}
+
+fn socket_path(namespace: &str, id: &str) -> Result<PathBuf, Error> {
+    let root_path = root_path(None).map_err(|e| other("Failed to get the root path", e))?;
+    Ok(shim_sock_path(&root_path, namespace, id))
+}
+
+// Start the shim daemon that serves on the listener.
+fn spawn_shim(opts: &StartOpts, listener: &UnixListener) -> Result<(), Error> {
+    let exe = std::env::current_exe().map_err(|e| other("Failed to get the executable", e))?;
+    let cwd = std::env::current_dir().map_err(|e| other("Failed to get the bundle", e))?;
+
+    let mut command = Command::new(exe);
+    command
+        .current_dir(cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .args([
+            "-namespace",
+            &opts.namespace,
+            "-id",
+            &opts.id,
+            "-address",
+            &opts.address,
+        ])
+        // Keep the shim running when containerd is stopped.
+        .process_group(0);
+    if opts.debug {
+        command.arg("-debug");
+    }
+    let fd = listener.as_raw_fd();
+    unsafe {
+        command.pre_exec(move || {
+            if libc::dup2(fd, SOCKET_FD) < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command
+        .spawn()
+        .map_err(|e| other("Failed to spawn the shim", e))?;
+    Ok(())
+}
 
-use crate::task::Task;
+fn remove_socket(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    // The directories of the container and the namespace are removed once they are empty.
+    for dir in path.ancestors().skip(1).take(2) {
+        if std::fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
 
 pub struct Service {
     exit: Arc<ExitSignal>,
+    namespace: String,
+    id: String,
 }
 
 #[async_trait]
 impl Shim for Service {
     type T = Task;
 
-    async fn new(_runtime_id: &str, _args: &Flags, _config: &mut Config) -> Self {
+    async fn new(runtime_id: &str, args: &Flags, _config: &mut Config) -> Self {
         Service {
             exit: Arc::new(ExitSignal::default()),
+            namespace: args.namespace.clone(),
+            id: runtime_id.to_string(),
         }
     }
 
+    // Each group of containers gets its own shim at unix://<root>/shims/<namespace>/<id>/shim.sock.
+    // containerd passes the grouping ID of a pod sandbox, so its containers reuse the shim.
     async fn start_shim(&mut self, opts: StartOpts) -> Result<String, Error> {
-        // TODO: Check if the VM server is running
-        // TODO: Connect to the VM server and request a connection to the VM agent
-        // The agent will create a listener socket for the shim
         let grouping = opts.id.clone();
-        let address = spawn(opts, &grouping, Vec::new()).await?;
+        let path = socket_path(&opts.namespace, &grouping)?;
+        let address = format!("unix://{}", path.display());
+
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            info!("Reusing the shim on {}", address);
+        } else {
+            let _ = std::fs::remove_file(&path);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| other("Failed to create the shim directory", e))?;
+            }
+            let listener = UnixListener::bind(&path)
+                .map_err(|e| other("Failed to bind the shim socket", e))?;
+            spawn_shim(&opts, &listener)?;
+        }
+        // containerd reads the address of the shim from the bundle.
+        std::fs::write("address", &address)
+            .map_err(|e| other("Failed to write the shim address", e))?;
         Ok(address)
     }
 
     async fn delete_shim(&mut self) -> Result<DeleteResponse, Error> {
+        remove_socket(&socket_path(&self.namespace, &self.id)?);
         Ok(DeleteResponse::default())
     }
 
@@ -49,8 +141,22 @@ impl Shim for Service {
         let root_path = root_path(None).unwrap();
         let aux_sock_path = aux_sock_path(&root_path, None);
 
-        let client = TaskClient::new(Client::connect(aux_sock_path.to_str().unwrap()).unwrap());
+        let client = ServerClient::new(&root_path, &aux_sock_path);
 
-        Task { client }
+        let trace = match ProtocolTrace::from_env() {
+            Ok(trace) => trace.map(Arc::new),
+            Err(e) => {
+                warn!("Failed to open the protocol trace: {}", e);
+                None
+            }
+        };
+
+        Task {
+            client,
+            id: self.id.clone(),
+            socket: shim_sock_path(&root_path, &self.namespace, &self.id),
+            exit: self.exit.clone(),
+            trace,
+        }
     }
 }
@@ -1,51 +1,287 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
 use async_trait::async_trait;
 use containerd_shim::{
     api::{
         ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
         Empty, KillRequest, StartRequest, StartResponse, StateRequest, StateResponse,
+        UpdateTaskRequest,
     },
-    protos::shim_async::TaskClient,
-    Context, DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
+    protos::{
+        protobuf::Message,
+        shim_async::{Client, TaskClient},
+    },
+    Context, DeleteResponse, ExitSignal, Task as ShimTask, TtrpcContext, TtrpcResult,
+};
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    path::api_sock_path,
+    trace::{ProtocolTrace, TraceRecord},
 };
+use log::{info, warn};
+
+const TASK_SERVICE: &str = "containerd.task.v2.Task";
+
+// Interval of the pings that detect a restart of the server
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// Forward the deadline and the metadata of containerd so that the server can give up in time.
+fn forward_context(ctx: &TtrpcContext) -> Context {
+    let mut forwarded = Context::default();
+    forwarded.metadata = ctx.metadata.clone();
+    forwarded.timeout_nano = ctx.timeout_nano;
+    forwarded
+}
+
+// The connection is lost and the next call re-dials.
+fn is_disconnected(e: &ttrpc::Error) -> bool {
+    matches!(
+        e,
+        ttrpc::Error::Socket(_)
+            | ttrpc::Error::LocalClosed
+            | ttrpc::Error::RemoteClosed
+            | ttrpc::Error::Eof
+    )
+}
+
+struct Connection {
+    // Incremented on each dial so that a stale failure does not drop a new connection
+    generation: u64,
+    client: TaskClient,
+}
+
+// Task client of the server that re-dials after the server restarts. The connection is made
+// on the first call, so the shim starts even while the server is down.
+pub struct ServerClient {
+    aux_sock_path: PathBuf,
+    api_sock_path: PathBuf,
+    connection: Mutex<Option<Connection>>,
+    generation: AtomicU64,
+    // Pid of the server at the last keepalive ping
+    server_pid: Mutex<Option<u32>>,
+}
+
+impl ServerClient {
+    pub fn new(root_path: &Path, aux_sock_path: &Path) -> Arc<Self> {
+        let client = Arc::new(Self {
+            aux_sock_path: aux_sock_path.to_path_buf(),
+            api_sock_path: api_sock_path(root_path),
+            connection: Mutex::new(None),
+            generation: AtomicU64::new(0),
+            server_pid: Mutex::new(None),
+        });
+        tokio::spawn(keepalive(Arc::downgrade(&client)));
+        client
+    }
+
+    fn connect(&self) -> TtrpcResult<(u64, TaskClient)> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(connection) = connection.as_ref() {
+            return Ok((connection.generation, connection.client.clone()));
+        }
+        let path = self.aux_sock_path.to_str().ok_or_else(|| {
+            ttrpc::Error::Others(format!("Invalid socket path {:?}", self.aux_sock_path))
+        })?;
+        let client = TaskClient::new(Client::connect(path)?);
+        let generation = self.generation.fetch_add(1, Ordering::Relaxed) + 1;
+        *connection = Some(Connection {
+            generation,
+            client: client.clone(),
+        });
+        Ok((generation, client))
+    }
+
+    fn disconnect(&self, generation: Option<u64>) {
+        let mut connection = self.connection.lock().unwrap();
+        let current = match (connection.as_ref(), generation) {
+            (Some(connection), Some(generation)) => connection.generation == generation,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if current {
+            *connection = None;
+        }
+    }
+
+    // Forward the request to the server. A request that failed before it was sent is sent
+    // again on a new connection.
+    async fn call<'a, Req, Res, Fut>(
+        &self,
+        ctx: &TtrpcContext,
+        req: &'a Req,
+        f: impl Fn(TaskClient, Context, &'a Req) -> Fut,
+    ) -> TtrpcResult<Res>
+    where
+        Fut: Future<Output = TtrpcResult<Res>>,
+    {
+        let (generation, client) = self.connect()?;
+        let res = match f(client, forward_context(ctx), req).await {
+            Err(ttrpc::Error::LocalClosed) => {
+                info!("Reconnecting to the server");
+                self.disconnect(Some(generation));
+                let (generation, client) = self.connect()?;
+                (generation, f(client, forward_context(ctx), req).await)
+            }
+            res => (generation, res),
+        };
+        match res {
+            (generation, Err(e)) if is_disconnected(&e) => {
+                warn!("Lost the connection to the server: {}", e);
+                self.disconnect(Some(generation));
+                Err(e)
+            }
+            (_, res) => res,
+        }
+    }
+}
+
+// Ping the server and drop the connection when the server is gone or has restarted, so that
+// the next call does not wait on the dead one. Ends with the client.
+async fn keepalive(client: Weak<ServerClient>) {
+    let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(client) = client.upgrade() else {
+            return;
+        };
+        let api_sock_path = client.api_sock_path.clone();
+        let res =
+            tokio::task::spawn_blocking(move || api::call(&api_sock_path, &ApiRequest::Version))
+                .await;
+        let pid = match res {
+            Ok(Ok(ApiResponse::Version { pid, .. })) => Some(pid),
+            _ => None,
+        };
+        let mut server_pid = client.server_pid.lock().unwrap();
+        if pid.is_none() || (server_pid.is_some() && *server_pid != pid) {
+            if client.connection.lock().unwrap().is_some() {
+                info!("The server is gone or has restarted; reconnecting on the next call");
+            }
+            client.disconnect(None);
+        }
+        *server_pid = pid;
+    }
+}
 
 pub struct Task {
-    pub client: TaskClient,
+    pub client: Arc<ServerClient>,
+    // Grouping ID that the shim was started for
+    pub id: String,
+    pub socket: PathBuf,
+    pub exit: Arc<ExitSignal>,
+    // Set by AKARI_PROTOCOL_TRACE
+    pub trace: Option<Arc<ProtocolTrace>>,
+}
+
+impl Task {
+    // Await the forwarded request and trace it if enabled.
+    async fn traced<Res: Message>(
+        &self,
+        method: &str,
+        ctx: &TtrpcContext,
+        req: &impl Message,
+        call: impl Future<Output = TtrpcResult<Res>>,
+    ) -> TtrpcResult<Res> {
+        let Some(trace) = &self.trace else {
+            return call.await;
+        };
+        let method = format!("{}/{}", TASK_SERVICE, method);
+        let mut record = TraceRecord::new("shim", &method, ctx.mh.stream_id, SystemTime::now());
+        record.request_bytes = req.compute_size() as usize;
+        let started = Instant::now();
+        let res = call.await;
+        let mut record = record.finish(started.elapsed());
+        match &res {
+            Ok(res) => record.response_bytes = res.compute_size() as usize,
+            Err(ttrpc::Error::RpcStatus(status)) => {
+                record.status = format!("{:?}", status.code.enum_value_or_default());
+                record.message = Some(status.message.clone());
+            }
+            Err(e) => {
+                record.status = "ERROR".to_string();
+                record.message = Some(e.to_string());
+            }
+        }
+        if let Err(e) = trace.write(&record) {
+            warn!("Failed to write the protocol trace: {}", e);
+        }
+        res
+    }
 }
 
 #[async_trait]
 impl ShimTask for Task {
     async fn connect(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         req: ConnectRequest,
     ) -> TtrpcResult<ConnectResponse> {
-        Ok(self.client.connect(Context::default(), &req).await?)
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.connect(ctx, req).await
+        });
+        self.traced("Connect", ctx, &req, call).await
     }
 
     async fn create(
         &self,
-        _ctx: &TtrpcContext,
+        ctx: &TtrpcContext,
         req: CreateTaskRequest,
     ) -> TtrpcResult<CreateTaskResponse> {
-        Ok(self.client.create(Context::default(), &req).await?)
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.create(ctx, req).await
+        });
+        self.traced("Create", ctx, &req, call).await
+    }
+
+    async fn delete(&self, ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.delete(ctx, req).await
+        });
+        let res = self.traced("Delete", ctx, &req, call).await?;
+        // The shim exits with the task it was started for and removes its socket.
+        if req.id() == self.id && req.exec_id().is_empty() {
+            let _ = std::fs::remove_file(&self.socket);
+            self.exit.signal();
+        }
+        Ok(res)
     }
 
-    async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
-        Ok(self.client.delete(Context::default(), &req).await?)
+    async fn kill(&self, ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.kill(ctx, req).await
+        });
+        self.traced("Kill", ctx, &req, call).await
     }
 
-    async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
-        Ok(self.client.kill(Context::default(), &req).await?)
+    async fn start(&self, ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.start(ctx, req).await
+        });
+        self.traced("Start", ctx, &req, call).await
     }
 
-    async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
-        Ok(self.client.start(Context::default(), &req).await?)
+    async fn state(&self, ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.state(ctx, req).await
+        });
+        self.traced("State", ctx, &req, call).await
     }
 
-    async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
-        Ok(self.client.state(Context::default(), &req).await?)
+    async fn update(&self, ctx: &TtrpcContext, req: UpdateTaskRequest) -> TtrpcResult<Empty> {
+        let call = self.client.call(ctx, &req, |client, ctx, req| async move {
+            client.update(ctx, req).await
+        });
+        self.traced("Update", ctx, &req, call).await
     }
 }
@@ -0,0 +1,64 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Latency of the container lifecycle and throughput of the stdio through the server and the
+//! mock VM. Build the server first: `cargo build -p server && cargo bench -p testing`.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use testing::Harness;
+use tokio::runtime::Runtime;
+
+const STDIO_SIZES: [usize; 3] = [64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+fn container_id(prefix: &str) -> String {
+    format!("{}-{}", prefix, NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+// create -> start -> exit -> delete of the init process.
+fn lifecycle(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let harness = rt.block_on(Harness::start()).unwrap();
+
+    c.bench_function("lifecycle", |b| {
+        b.to_async(&rt).iter(|| async {
+            let id = container_id("lifecycle");
+            harness.create(&id).await.unwrap();
+            harness.start(&id, "").await.unwrap();
+            harness.task.exit(&id, "", 0).unwrap();
+            harness.wait(&id, "").await.unwrap();
+            harness.delete(&id, "").await.unwrap();
+        })
+    });
+}
+
+// Stream the stdin from a host file to the fake guest, which drains it.
+fn stdio(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let harness = rt.block_on(Harness::start()).unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut group = c.benchmark_group("stdio");
+    for size in STDIO_SIZES {
+        let stdin = dir.path().join(format!("stdin-{}", size));
+        std::fs::write(&stdin, vec![0xa5; size]).unwrap();
+
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("stdin/{}", size), |b| {
+            b.to_async(&rt).iter(|| async {
+                let id = container_id("stdio");
+                harness.create_with_stdin(&id, &stdin).await.unwrap();
+                let received = harness.task.stdin_eof(&id).await.unwrap();
+                assert_eq!(received, size as u64);
+                harness.delete(&id, "").await.unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lifecycle, stdio);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Fake guest agent that records the commands and answers them successfully.
+
+use std::{
+    io::ErrorKind,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use libakari::{
+    console::AgentTransport,
+    container_rpc::{ContainerCommand, ContainerResponse},
+    framing::{self, read_chunked, WriteTo, MAX_MESSAGE_SIZE},
+    guest_user::GuestUser,
+    metrics::GuestStats,
+    vsock::{Handshake, VsockPorts},
+    vsock_auth::{self, AuthToken},
+};
+
+pub struct FakeAgent {
+    commands: Arc<Mutex<Vec<ContainerCommand>>>,
+}
+
+impl FakeAgent {
+    // Listen on `<agent_port>.sock` in the fake guest directory and authenticate with the token
+    // that the server writes to the path.
+    pub fn start(guest_dir: &Path, ports: VsockPorts, token_path: PathBuf) -> Result<Self> {
+        let listener = UnixListener::bind(guest_dir.join(format!("{}.sock", ports.agent_port)))?;
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let agent_commands = commands.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream
+                    .map_err(Into::into)
+                    .and_then(|stream| handle(stream, ports, &token_path, &agent_commands));
+                if let Err(e) = result {
+                    log::error!("Fake agent failed: {}", e);
+                }
+            }
+        });
+        Ok(Self { commands })
+    }
+
+    // Return the commands received so far.
+    pub fn commands(&self) -> Vec<ContainerCommand> {
+        self.commands
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+fn handle(
+    mut stream: UnixStream,
+    ports: VsockPorts,
+    token_path: &Path,
+    commands: &Mutex<Vec<ContainerCommand>>,
+) -> Result<()> {
+    Handshake {
+        ports,
+        transports: vec![AgentTransport::Vsock],
+        auth: true,
+    }
+    .write_to(&mut stream)?;
+    vsock_auth::answer(&mut stream, &AuthToken::load(token_path)?)?;
+    let cmd: ContainerCommand = match read_chunked(&mut stream, MAX_MESSAGE_SIZE) {
+        Ok(cmd) => cmd,
+        // The server closed the connection after the handshake.
+        Err(framing::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let res = match cmd {
+        ContainerCommand::Stats => ContainerResponse::Stats(GuestStats::default()),
+        ContainerCommand::Health => ContainerResponse::Health(Default::default()),
+        ContainerCommand::CreateUser(_) => ContainerResponse::User(GuestUser {
+            name: "_akari30000".to_string(),
+            uid: 30000,
+        }),
+        ContainerCommand::DebugExec(_) | ContainerCommand::Provision(_) => {
+            ContainerResponse::Exited(0)
+        }
+        _ => ContainerResponse::Ok,
+    };
+    commands.lock().unwrap_or_else(|e| e.into_inner()).push(cmd);
+    res.write_to(&mut stream)?;
+    Ok(())
+}
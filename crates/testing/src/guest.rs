@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Guest end of the authenticated container ports. The fake services answer the challenge of the
+//! VM token that the server shares, like the guest does, before they serve the connection.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use libakari::vsock_auth::{self, AuthToken};
+use tokio::net::{UnixListener, UnixStream};
+
+// Answer the challenge with the token that the server has written.
+pub async fn answer(stream: UnixStream, token_path: &Path) -> Result<UnixStream> {
+    let token = AuthToken::load(token_path)?;
+    let mut stream = stream.into_std()?;
+    stream.set_nonblocking(false)?;
+    let stream = tokio::task::spawn_blocking(move || {
+        vsock_auth::answer(&mut stream, &token)?;
+        Ok::<_, anyhow::Error>(stream)
+    })
+    .await??;
+    stream.set_nonblocking(true)?;
+    Ok(UnixStream::from_std(stream)?)
+}
+
+// Answer the challenge on each connection and forward it to the service on the target socket.
+pub fn serve(listener: UnixListener, target: PathBuf, token_path: PathBuf) {
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let target = target.clone();
+            let token_path = token_path.clone();
+            tokio::spawn(async move {
+                let result = async {
+                    let mut stream = answer(stream, &token_path).await?;
+                    let mut service = UnixStream::connect(&target).await?;
+                    tokio::io::copy_bidirectional(&mut stream, &mut service).await?;
+                    Ok::<_, anyhow::Error>(())
+                }
+                .await;
+                if let Err(e) = result {
+                    log::debug!("Fake guest connection stopped: {}", e);
+                }
+            });
+        }
+    });
+}
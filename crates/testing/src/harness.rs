@@ -0,0 +1,326 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Runs the server against the mock VM and drives it like the client.
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    sync::atomic::{AtomicU32, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use containerd_shim::{
+    api::{
+        CreateTaskRequest, DeleteRequest, ExecProcessRequest, KillRequest, StartRequest,
+        StateRequest, StateResponse, WaitRequest,
+    },
+    Context, DeleteResponse, Task as ShimTask,
+};
+use containerd_shim_protos::shim_async::{create_task, TaskClient};
+use libakari::{
+    api::{self, ApiRequest, ApiResponse},
+    path::{api_sock_path, auth_path, aux_sock_path, vm_config_path},
+    vm_config::{load_vm_config, MacosVmConfig, MacosVmSharedDirectory, MacosVmStorage},
+    vsock::{VsockPort, VsockPorts},
+    vsock_auth::AUTH_TOKEN_FILE,
+};
+use oci_spec::runtime::Spec;
+use tempfile::TempDir;
+use ttrpc::asynchronous::{Client, Server};
+
+use crate::{guest, FakeAgent, FakeTask};
+
+// Path to the server binary. Defaults to `server` in the target directory of the test binary.
+pub const SERVER_BIN_ENV: &str = "AKARI_SERVER_BIN";
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static NEXT_INSTANCE: AtomicU32 = AtomicU32::new(0);
+
+// The server reaches the vsock ports via fixed paths in /tmp, so every harness in the host
+// uses its own range of ports.
+fn vsock_ports() -> Result<VsockPorts> {
+    let instance = NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed) % 8;
+    let slot = (std::process::id() % 1024) * 8 + instance;
+    let agent_port = VsockPort::new(0x10000 + slot * 256)?;
+    Ok(VsockPorts {
+        agent_port,
+        container_port_base: agent_port.offset(1)?,
+    })
+}
+
+fn server_bin() -> Result<PathBuf> {
+    if let Some(path) = std::env::var_os(SERVER_BIN_ENV) {
+        return Ok(PathBuf::from(path));
+    }
+    // Test binaries are built in `target/<profile>/deps`.
+    let exe = std::env::current_exe()?;
+    exe.parent()
+        .and_then(Path::parent)
+        .map(|dir| dir.join("server"))
+        .ok_or_else(|| anyhow::anyhow!("Failed to locate the server binary"))
+}
+
+// Empty disk image of the VM, which the mock VM does not open
+const DISK_NAME: &str = "disk.img";
+
+fn write_vm_config(
+    root_path: &Path,
+    bundles_path: &Path,
+    ports: VsockPorts,
+    storage: Vec<MacosVmStorage>,
+) -> Result<()> {
+    let vm_config = MacosVmConfig {
+        version: 1,
+        name: None,
+        serial: None,
+        os: "darwin".to_string(),
+        hardware_model: String::new(),
+        machine_id: String::new(),
+        cpus: 2,
+        ram: 4 * 1024 * 1024 * 1024,
+        storage,
+        networks: Vec::new(),
+        shares: Some(vec![MacosVmSharedDirectory {
+            name: Some("bundles".to_string()),
+            path: bundles_path.to_path_buf(),
+            automount: true,
+            read_only: false,
+            tag: None,
+            mount_point: None,
+        }]),
+        displays: Vec::new(),
+        audio: false,
+        boot: None,
+        protected: false,
+        vsock: ports,
+        guest_agent: Default::default(),
+        nat_rules: Vec::new(),
+        provision: None,
+        plugins: Default::default(),
+    };
+    std::fs::write(
+        vm_config_path(root_path),
+        serde_json::to_string_pretty(&vm_config)?,
+    )?;
+    Ok(())
+}
+
+pub struct Harness {
+    dir: TempDir,
+    ports: VsockPorts,
+    server: Child,
+    client: TaskClient,
+    pub agent: FakeAgent,
+    pub task: FakeTask,
+    _task_server: Server,
+}
+
+impl Harness {
+    pub async fn start() -> Result<Self> {
+        Self::start_with(&server_bin()?).await
+    }
+
+    pub async fn start_with(server_bin: &Path) -> Result<Self> {
+        Self::start_in(server_bin, None).await
+    }
+
+    // Start the server of a VM cloned from the golden VM.
+    pub async fn start_from_golden(golden: &Path) -> Result<Self> {
+        Self::start_in(&server_bin()?, Some(golden)).await
+    }
+
+    async fn start_in(server_bin: &Path, golden: Option<&Path>) -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let root_path = dir.path().join("root");
+        let guest_path = dir.path().join("guest");
+        let bundles_path = dir.path().join("bundles");
+        for path in [&root_path, &guest_path, &bundles_path] {
+            std::fs::create_dir_all(path)?;
+        }
+
+        // Keep the disks of the clone but use the ports and the shares of this harness.
+        let storage = match golden {
+            Some(golden) => {
+                vmm::clone::clone_vm(golden, &root_path)?;
+                load_vm_config(&vm_config_path(&root_path))?.storage
+            }
+            None => {
+                let disk = root_path.join(DISK_NAME);
+                std::fs::write(&disk, [])?;
+                vec![MacosVmStorage {
+                    r#type: "disk".to_string(),
+                    file: disk,
+                    name: None,
+                }]
+            }
+        };
+        let ports = vsock_ports()?;
+        write_vm_config(&root_path, &bundles_path, ports, storage)?;
+
+        // The server writes the token when it starts.
+        let token_path = auth_path(&root_path).join(AUTH_TOKEN_FILE);
+        let agent = FakeAgent::start(&guest_path, ports, token_path.clone())?;
+        let task = FakeTask::new(guest_path.clone(), token_path.clone());
+        let service = Box::new(task.clone()) as Box<dyn ShimTask + Send + Sync>;
+        let task_sock_path = guest_path.join("task.sock");
+        let mut task_server = Server::new()
+            .bind(task_sock_path.to_str().unwrap())?
+            .register_service(create_task(service.into()));
+        task_server.start().await?;
+        // The container ports are authenticated before the task service is reached.
+        let listener = tokio::net::UnixListener::bind(guest_path.join("guest.sock"))?;
+        guest::serve(listener, task_sock_path, token_path);
+
+        let mut server = Command::new(server_bin)
+            .arg("--root")
+            .arg(&root_path)
+            .arg("--mock-vm")
+            .arg(&guest_path)
+            .spawn()?;
+
+        // Wait for the server to listen on the aux socket.
+        let aux_sock_path = aux_sock_path(&root_path, None);
+        let started = Instant::now();
+        while !aux_sock_path.exists() {
+            if let Some(status) = server.try_wait()? {
+                anyhow::bail!("The server exited during the startup: {}", status);
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                let _ = server.kill();
+                anyhow::bail!("The server did not start in {:?}", STARTUP_TIMEOUT);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        let client = TaskClient::new(Client::connect(aux_sock_path.to_str().unwrap())?);
+
+        Ok(Self {
+            dir,
+            ports,
+            server,
+            client,
+            agent,
+            task,
+            _task_server: task_server,
+        })
+    }
+
+    pub fn root_path(&self) -> PathBuf {
+        self.dir.path().join("root")
+    }
+
+    pub fn ports(&self) -> VsockPorts {
+        self.ports
+    }
+
+    // Return the lifecycle commands that the mock VM has run.
+    pub fn vm_commands(&self) -> Result<Vec<String>> {
+        let log = std::fs::read_to_string(self.dir.path().join("guest").join("vm.log"))?;
+        Ok(log.lines().map(str::to_string).collect())
+    }
+
+    // Write a bundle with the default spec in the shared directory.
+    pub fn bundle(&self, id: &str) -> Result<PathBuf> {
+        let bundle = self.dir.path().join("bundles").join(id);
+        std::fs::create_dir_all(bundle.join("rootfs"))?;
+        Spec::default().save(bundle.join("config.json"))?;
+        Ok(bundle)
+    }
+
+    // Send the admin API request to the server.
+    pub fn api(&self, req: &ApiRequest) -> Result<ApiResponse> {
+        Ok(api::call(&api_sock_path(&self.root_path()), req)?)
+    }
+
+    pub async fn create(&self, id: &str) -> Result<u32> {
+        self.create_task(id, String::new()).await
+    }
+
+    // Create the container with the stdin redirected from the host file.
+    pub async fn create_with_stdin(&self, id: &str, stdin: &Path) -> Result<u32> {
+        self.create_task(id, format!("file://{}", stdin.display()))
+            .await
+    }
+
+    async fn create_task(&self, id: &str, stdin: String) -> Result<u32> {
+        let req = CreateTaskRequest {
+            id: id.to_string(),
+            bundle: self.bundle(id)?.to_string_lossy().into_owned(),
+            stdin,
+            ..Default::default()
+        };
+        Ok(self.client.create(Context::default(), &req).await?.pid)
+    }
+
+    pub async fn start(&self, id: &str, exec_id: &str) -> Result<u32> {
+        let req = StartRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(self.client.start(Context::default(), &req).await?.pid)
+    }
+
+    pub async fn exec(&self, id: &str, exec_id: &str) -> Result<()> {
+        let req = ExecProcessRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        self.client.exec(Context::default(), &req).await?;
+        Ok(())
+    }
+
+    pub async fn kill(&self, id: &str, exec_id: &str, signal: u32) -> Result<()> {
+        let req = KillRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            signal,
+            ..Default::default()
+        };
+        self.client.kill(Context::default(), &req).await?;
+        Ok(())
+    }
+
+    pub async fn state(&self, id: &str, exec_id: &str) -> Result<StateResponse> {
+        let req = StateRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(self.client.state(Context::default(), &req).await?)
+    }
+
+    // Wait for the process to exit and return the exit status.
+    pub async fn wait(&self, id: &str, exec_id: &str) -> Result<u32> {
+        let req = WaitRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(self
+            .client
+            .wait(Context::default(), &req)
+            .await?
+            .exit_status)
+    }
+
+    pub async fn delete(&self, id: &str, exec_id: &str) -> Result<DeleteResponse> {
+        let req = DeleteRequest {
+            id: id.to_string(),
+            exec_id: exec_id.to_string(),
+            ..Default::default()
+        };
+        Ok(self.client.delete(Context::default(), &req).await?)
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = self.server.kill();
+        let _ = self.server.wait();
+    }
+}
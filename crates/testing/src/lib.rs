@@ -0,0 +1,18 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! # Akari Testing
+//!
+//! Helpers for the end-to-end tests of the server.
+//! 1. Run the server binary with the mock VM, which connects the vsock ports to a fake guest.
+//! 2. Serve the agent protocol and the task service of the guest in the test process.
+//! 3. Drive the server through the aux socket and the admin API like the client does.
+
+pub mod agent;
+pub mod guest;
+pub mod harness;
+pub mod task;
+
+pub use agent::FakeAgent;
+pub use harness::Harness;
+pub use task::FakeTask;
@@ -0,0 +1,324 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Fake task service of the guest. The containers run no process and exit when the test or a
+//! signal says so. The stdin redirected to a vsock port is drained like `cat > /dev/null`.
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use async_trait::async_trait;
+use containerd_shim::{
+    api::{
+        ConnectRequest, ConnectResponse, CreateTaskRequest, CreateTaskResponse, DeleteRequest,
+        Empty, ExecProcessRequest, KillRequest, StartRequest, StartResponse, StateRequest,
+        StateResponse, Status, WaitRequest, WaitResponse,
+    },
+    DeleteResponse, Task as ShimTask, TtrpcContext, TtrpcResult,
+};
+use tokio::{io::AsyncReadExt, net::UnixListener, sync::watch};
+
+use crate::guest;
+
+const VSOCK_SCHEME: &str = "vsock://";
+
+struct FakeProcess {
+    pid: u32,
+    status: Status,
+    // Set when the process exits.
+    exit_tx: watch::Sender<Option<u32>>,
+}
+
+impl FakeProcess {
+    fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            status: Status::CREATED,
+            exit_tx: watch::Sender::new(None),
+        }
+    }
+
+    fn exit_status(&self) -> u32 {
+        self.exit_tx.borrow().unwrap_or(0)
+    }
+
+    fn exit(&mut self, exit_status: u32) {
+        self.status = Status::STOPPED;
+        self.exit_tx.send_replace(Some(exit_status));
+    }
+}
+
+struct FakeContainer {
+    bundle: String,
+    init: FakeProcess,
+    execs: HashMap<String, FakeProcess>,
+    // Socket of the stdin and the number of the bytes read, set when the stdin reaches EOF.
+    stdin: Option<(PathBuf, watch::Receiver<Option<u64>>)>,
+}
+
+impl FakeContainer {
+    fn process(&mut self, exec_id: &str) -> TtrpcResult<&mut FakeProcess> {
+        if exec_id.is_empty() {
+            return Ok(&mut self.init);
+        }
+        self.execs
+            .get_mut(exec_id)
+            .ok_or_else(|| not_found(&format!("Exec {}", exec_id)))
+    }
+}
+
+fn not_found(what: &str) -> ttrpc::Error {
+    ttrpc::Error::RpcStatus(ttrpc::get_status(
+        ttrpc::Code::NOT_FOUND,
+        format!("{} not found", what),
+    ))
+}
+
+// Clones share the containers so that the test can inspect the served instance.
+#[derive(Clone, Default)]
+pub struct FakeTask {
+    containers: Arc<Mutex<HashMap<String, FakeContainer>>>,
+    last_pid: Arc<AtomicU32>,
+    // Directory of the fake guest sockets. The stdin is not served if unset.
+    guest_dir: Option<PathBuf>,
+    // Token that the stdin answers the challenge of the server with
+    token_path: PathBuf,
+}
+
+// Serve the stdin on `<port>.sock` of the fake guest and count the bytes until EOF.
+fn drain_stdin(listener: UnixListener, token_path: PathBuf) -> watch::Receiver<Option<u64>> {
+    let (tx, rx) = watch::channel(None);
+    tokio::spawn(async move {
+        let stream = async {
+            let (stream, _) = listener.accept().await?;
+            guest::answer(stream, &token_path).await
+        };
+        let mut stream = match stream.await {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Failed to accept the stdin: {}", e);
+                return;
+            }
+        };
+        let mut buf = vec![0; 64 * 1024];
+        let mut total = 0;
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => total += n as u64,
+                Err(e) => {
+                    log::error!("Failed to read the stdin: {}", e);
+                    break;
+                }
+            }
+        }
+        tx.send_replace(Some(total));
+    });
+    rx
+}
+
+impl FakeTask {
+    pub fn new(guest_dir: PathBuf, token_path: PathBuf) -> Self {
+        Self {
+            guest_dir: Some(guest_dir),
+            token_path,
+            ..Default::default()
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, FakeContainer>> {
+        self.containers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn next_pid(&self) -> u32 {
+        self.last_pid.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn with_process<T>(
+        &self,
+        id: &str,
+        exec_id: &str,
+        f: impl FnOnce(&mut FakeProcess) -> T,
+    ) -> TtrpcResult<T> {
+        let mut containers = self.lock();
+        let container = containers
+            .get_mut(id)
+            .ok_or_else(|| not_found(&format!("Container {}", id)))?;
+        Ok(f(container.process(exec_id)?))
+    }
+
+    // Make the process of the container exit as if the workload finished.
+    pub fn exit(&self, id: &str, exec_id: &str, exit_status: u32) -> TtrpcResult<()> {
+        self.with_process(id, exec_id, |process| process.exit(exit_status))
+    }
+
+    // Return the status of the process of the container.
+    pub fn status(&self, id: &str, exec_id: &str) -> Option<Status> {
+        self.with_process(id, exec_id, |process| process.status)
+            .ok()
+    }
+
+    // Wait for the stdin of the container to reach EOF and return the number of the bytes read.
+    pub async fn stdin_eof(&self, id: &str) -> TtrpcResult<u64> {
+        let mut stdin_rx = self
+            .lock()
+            .get(id)
+            .and_then(|container| container.stdin.as_ref().map(|(_, rx)| rx.clone()))
+            .ok_or_else(|| not_found(&format!("Stdin of {}", id)))?;
+        match stdin_rx.wait_for(Option::is_some).await {
+            Ok(total) => Ok(total.unwrap_or(0)),
+            Err(_) => Err(not_found(&format!("Stdin of {}", id))),
+        }
+    }
+}
+
+#[async_trait]
+impl ShimTask for FakeTask {
+    async fn connect(
+        &self,
+        _ctx: &TtrpcContext,
+        req: ConnectRequest,
+    ) -> TtrpcResult<ConnectResponse> {
+        let task_pid = self.with_process(req.id(), "", |process| process.pid)?;
+        Ok(ConnectResponse {
+            shim_pid: std::process::id(),
+            task_pid,
+            ..Default::default()
+        })
+    }
+
+    async fn create(
+        &self,
+        _ctx: &TtrpcContext,
+        req: CreateTaskRequest,
+    ) -> TtrpcResult<CreateTaskResponse> {
+        let pid = self.next_pid();
+        let mut containers = self.lock();
+        if containers.contains_key(req.id()) {
+            return Err(ttrpc::Error::Others("Container already exists".to_string()));
+        }
+        // The server connects to the port right after the creation, so bind it now.
+        let stdin_port = req.stdin().strip_prefix(VSOCK_SCHEME);
+        let stdin = match (&self.guest_dir, stdin_port) {
+            (Some(guest_dir), Some(port)) => {
+                let path = guest_dir.join(format!("{}.sock", port));
+                let _ = std::fs::remove_file(&path);
+                let listener = UnixListener::bind(&path).map_err(|e| {
+                    ttrpc::Error::Others(format!("Failed to serve the stdin: {}", e))
+                })?;
+                Some((path, drain_stdin(listener, self.token_path.clone())))
+            }
+            _ => None,
+        };
+        containers.insert(
+            req.id().to_string(),
+            FakeContainer {
+                bundle: req.bundle().to_string(),
+                init: FakeProcess::new(pid),
+                execs: HashMap::new(),
+                stdin,
+            },
+        );
+        Ok(CreateTaskResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    async fn delete(&self, _ctx: &TtrpcContext, req: DeleteRequest) -> TtrpcResult<DeleteResponse> {
+        let mut containers = self.lock();
+        let process = if req.exec_id().is_empty() {
+            let container = containers
+                .remove(req.id())
+                .ok_or_else(|| not_found(&format!("Container {}", req.id())))?;
+            // The port may be assigned to another container.
+            if let Some((path, _)) = container.stdin {
+                let _ = std::fs::remove_file(path);
+            }
+            container.init
+        } else {
+            containers
+                .get_mut(req.id())
+                .ok_or_else(|| not_found(&format!("Container {}", req.id())))?
+                .execs
+                .remove(req.exec_id())
+                .ok_or_else(|| not_found(&format!("Exec {}", req.exec_id())))?
+        };
+        Ok(DeleteResponse {
+            pid: process.pid,
+            exit_status: process.exit_status(),
+            ..Default::default()
+        })
+    }
+
+    async fn exec(&self, _ctx: &TtrpcContext, req: ExecProcessRequest) -> TtrpcResult<Empty> {
+        let pid = self.next_pid();
+        let mut containers = self.lock();
+        let container = containers
+            .get_mut(req.id())
+            .ok_or_else(|| not_found(&format!("Container {}", req.id())))?;
+        container
+            .execs
+            .insert(req.exec_id().to_string(), FakeProcess::new(pid));
+        Ok(Empty::new())
+    }
+
+    async fn kill(&self, _ctx: &TtrpcContext, req: KillRequest) -> TtrpcResult<Empty> {
+        // A process killed by a signal exits with 128 + signal.
+        self.with_process(req.id(), req.exec_id(), |process| {
+            process.exit(128 + req.signal())
+        })?;
+        Ok(Empty::new())
+    }
+
+    async fn start(&self, _ctx: &TtrpcContext, req: StartRequest) -> TtrpcResult<StartResponse> {
+        let pid = self.with_process(req.id(), req.exec_id(), |process| {
+            process.status = Status::RUNNING;
+            process.exit_tx.send_replace(None);
+            process.pid
+        })?;
+        Ok(StartResponse {
+            pid,
+            ..Default::default()
+        })
+    }
+
+    async fn state(&self, _ctx: &TtrpcContext, req: StateRequest) -> TtrpcResult<StateResponse> {
+        let mut containers = self.lock();
+        let container = containers
+            .get_mut(req.id())
+            .ok_or_else(|| not_found(&format!("Container {}", req.id())))?;
+        let bundle = container.bundle.clone();
+        let process = container.process(req.exec_id())?;
+        Ok(StateResponse {
+            id: req.id().to_string(),
+            bundle,
+            pid: process.pid,
+            status: process.status.into(),
+            exit_status: process.exit_status(),
+            exec_id: req.exec_id().to_string(),
+            ..Default::default()
+        })
+    }
+
+    async fn wait(&self, _ctx: &TtrpcContext, req: WaitRequest) -> TtrpcResult<WaitResponse> {
+        let mut exit_rx = self.with_process(req.id(), req.exec_id(), |process| {
+            process.exit_tx.subscribe()
+        })?;
+        // The sender is dropped if the container is deleted while waiting.
+        let exit_status = match exit_rx.wait_for(Option::is_some).await {
+            Ok(exit_status) => exit_status.unwrap_or(0),
+            Err(_) => return Err(not_found(&format!("Container {}", req.id()))),
+        };
+        Ok(WaitResponse {
+            exit_status,
+            ..Default::default()
+        })
+    }
+}
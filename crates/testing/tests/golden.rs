@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Golden VM saved by a server and cloned for another one through the mock VM.
+//! Build the server first: `cargo build -p server && cargo test -p testing`.
+
+use libakari::{
+    api::{ApiRequest, ApiResponse},
+    path::{golden_path, restore_state_path, vm_config_path},
+    vm_config::load_vm_config,
+};
+use testing::Harness;
+use vmm::clone::GOLDEN_STATE_FILE;
+
+#[tokio::test]
+async fn clone_resumes_from_golden() {
+    let golden = Harness::start().await.unwrap();
+    let res = golden.api(&ApiRequest::SaveGolden).unwrap();
+    assert!(matches!(res, ApiResponse::Ok));
+    // The disks are cloned while the VM is paused.
+    assert_eq!(
+        golden.vm_commands().unwrap(),
+        ["start", "pause", "save", "resume"]
+    );
+    let golden_dir = golden_path(&golden.root_path());
+    assert!(golden_dir.join(GOLDEN_STATE_FILE).exists());
+    assert!(golden_dir.join("disk.img").exists());
+
+    let clone = Harness::start_from_golden(&golden_dir).await.unwrap();
+    assert_eq!(clone.vm_commands().unwrap(), ["restore", "resume"]);
+    assert!(!restore_state_path(&clone.root_path()).exists());
+    let vm_config = load_vm_config(&vm_config_path(&clone.root_path())).unwrap();
+    assert_eq!(
+        vm_config.storage[0].file,
+        clone.root_path().join("disk.img")
+    );
+
+    // The clone runs containers like a booted VM.
+    let pid = clone.create("cloned").await.unwrap();
+    assert_eq!(clone.start("cloned", "").await.unwrap(), pid);
+}
+
+#[tokio::test]
+async fn golden_without_containers() {
+    let harness = Harness::start().await.unwrap();
+    harness.create("busy").await.unwrap();
+
+    assert!(harness.api(&ApiRequest::SaveGolden).is_err());
+    assert_eq!(harness.vm_commands().unwrap(), ["start"]);
+    assert!(!golden_path(&harness.root_path()).exists());
+}
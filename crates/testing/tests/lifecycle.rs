@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Lifecycle of a container through the server and the mock VM.
+//! Build the server first: `cargo build -p server && cargo test -p testing`.
+
+use containerd_shim::api::Status;
+use testing::Harness;
+
+// SIGTERM, which has the same number on macOS and Linux guests
+const SIGTERM: u32 = 15;
+
+#[tokio::test]
+async fn create_start_exec_kill_delete() {
+    let harness = Harness::start().await.unwrap();
+    let id = "lifecycle";
+
+    let pid = harness.create(id).await.unwrap();
+    assert_eq!(harness.task.status(id, ""), Some(Status::CREATED));
+    assert_eq!(harness.state(id, "").await.unwrap().pid, pid);
+
+    assert_eq!(harness.start(id, "").await.unwrap(), pid);
+    assert_eq!(harness.task.status(id, ""), Some(Status::RUNNING));
+
+    harness.exec(id, "exec").await.unwrap();
+    assert_eq!(harness.task.status(id, "exec"), Some(Status::CREATED));
+    harness.start(id, "exec").await.unwrap();
+    harness.task.exit(id, "exec", 3).unwrap();
+    assert_eq!(harness.wait(id, "exec").await.unwrap(), 3);
+    harness.delete(id, "exec").await.unwrap();
+    assert_eq!(harness.task.status(id, "exec"), None);
+
+    harness.kill(id, "", SIGTERM).await.unwrap();
+    assert_eq!(harness.wait(id, "").await.unwrap(), 128 + SIGTERM);
+
+    let res = harness.delete(id, "").await.unwrap();
+    assert_eq!(res.pid, pid);
+    assert_eq!(res.exit_status, 128 + SIGTERM);
+    assert_eq!(harness.task.status(id, ""), None);
+}
+
+#[tokio::test]
+async fn missing_container() {
+    let harness = Harness::start().await.unwrap();
+
+    assert!(harness.start("missing", "").await.is_err());
+    assert!(harness.kill("missing", "", SIGTERM).await.is_err());
+    assert!(harness.state("missing", "").await.is_err());
+    assert!(harness.delete("missing", "").await.is_err());
+    // The server keeps serving after the errors.
+    harness.create("present").await.unwrap();
+    assert!(harness.kill("present", "missing", SIGTERM).await.is_err());
+}
+
+#[tokio::test]
+async fn exec_in_missing_container() {
+    let harness = Harness::start().await.unwrap();
+
+    assert!(harness.exec("missing", "exec").await.is_err());
+    assert!(harness.delete("missing", "exec").await.is_err());
+}
@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Instant clone of a saved "golden" VM.
+//! The disk images are cloned with APFS copy-on-write, and the server of the clone restores the
+//! saved machine state instead of booting the guest. Each container can then get a VM of its own,
+//! in a root directory of its own, in about a second.
+
+use std::{ffi::CString, os::unix::ffi::OsStrExt, path::Path};
+
+use anyhow::Result;
+use libakari::{
+    path::{restore_state_path, vm_config_path},
+    vm_config::{load_vm_config, MacosVmConfig},
+};
+
+// Saved machine state in the directory of the golden VM
+pub const GOLDEN_STATE_FILE: &str = "state.vzvmsave";
+
+// Clone the file with APFS copy-on-write.
+pub fn clone_file(src: &Path, dst: &Path) -> Result<()> {
+    let src_c = CString::new(src.as_os_str().as_bytes())?;
+    let dst_c = CString::new(dst.as_os_str().as_bytes())?;
+
+    let ret = unsafe { libc::clonefile(src_c.as_ptr(), dst_c.as_ptr(), 0) };
+    if ret != 0 {
+        return Err(anyhow::anyhow!(
+            "Failed to clone {:?} to {:?}: {}",
+            src,
+            dst,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// Clone the disk images into the directory and return the VM config that uses the clones.
+pub fn clone_disks(vm_config: &MacosVmConfig, dest_dir: &Path) -> Result<MacosVmConfig> {
+    std::fs::create_dir_all(dest_dir)?;
+    let mut clone_config = vm_config.clone();
+    for storage in clone_config.storage.iter_mut() {
+        let file_name = storage
+            .file
+            .file_name()
+            .ok_or(anyhow::anyhow!("Invalid storage path: {:?}", storage.file))?;
+        let dst = dest_dir.join(file_name);
+        clone_file(&storage.file, &dst)?;
+        storage.file = dst;
+    }
+    Ok(clone_config)
+}
+
+// Clone the golden VM into the root directory. The machine identifier is kept because the saved
+// state is bound to it.
+pub fn clone_vm(golden_dir: &Path, root_path: &Path) -> Result<()> {
+    let vm_config = load_vm_config(&vm_config_path(golden_dir))?;
+    let clone_config = clone_disks(&vm_config, root_path)?;
+    clone_file(
+        &golden_dir.join(GOLDEN_STATE_FILE),
+        &restore_state_path(root_path),
+    )?;
+    std::fs::write(
+        vm_config_path(root_path),
+        serde_json::to_string_pretty(&clone_config)?,
+    )?;
+    Ok(())
+}
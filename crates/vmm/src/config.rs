@@ -1,31 +1,116 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
-use std::path::Path;
+use std::{ops::RangeInclusive, path::Path};
 
 use anyhow::Result;
 use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
-use libakari::vm_config::MacosVmConfig;
+use libakari::{
+    console::AGENT_CONSOLE_PORT_NAME,
+    layer::LAYER_STORAGE_TYPE,
+    vm_config::{MacosVmConfig, MacosVmDisplayType},
+};
 use objc2::{rc::Retained, AllocAnyThread, ClassType};
-use objc2_foundation::{NSArray, NSData, NSFileHandle, NSString, NSURL};
+use objc2_foundation::{NSArray, NSData, NSDictionary, NSFileHandle, NSString, NSURL};
 use objc2_virtualization::{
-    VZDiskImageStorageDeviceAttachment, VZFileHandleSerialPortAttachment, VZMacAuxiliaryStorage,
-    VZMacGraphicsDeviceConfiguration, VZMacGraphicsDisplayConfiguration, VZMacHardwareModel,
-    VZMacMachineIdentifier, VZMacOSBootLoader, VZMacPlatformConfiguration, VZSharedDirectory,
-    VZSingleDirectoryShare, VZVirtioBlockDeviceConfiguration,
-    VZVirtioConsoleDeviceSerialPortConfiguration, VZVirtioEntropyDeviceConfiguration,
-    VZVirtioFileSystemDeviceConfiguration, VZVirtioSocketDeviceConfiguration,
+    VZDiskImageStorageDeviceAttachment, VZFileHandleSerialPortAttachment,
+    VZGenericPlatformConfiguration, VZGraphicsDeviceConfiguration, VZLinuxBootLoader, VZMACAddress,
+    VZMacAuxiliaryStorage, VZMacGraphicsDeviceConfiguration, VZMacGraphicsDisplayConfiguration,
+    VZMacHardwareModel, VZMacMachineIdentifier, VZMacOSBootLoader, VZMacPlatformConfiguration,
+    VZMultipleDirectoryShare, VZNATNetworkDeviceAttachment, VZSharedDirectory,
+    VZSingleDirectoryShare, VZVirtioBlockDeviceConfiguration, VZVirtioConsoleDeviceConfiguration,
+    VZVirtioConsoleDeviceSerialPortConfiguration, VZVirtioConsolePortConfiguration,
+    VZVirtioEntropyDeviceConfiguration, VZVirtioFileSystemDeviceConfiguration,
+    VZVirtioGraphicsDeviceConfiguration, VZVirtioGraphicsScanoutConfiguration,
+    VZVirtioNetworkDeviceConfiguration, VZVirtioSocketDeviceConfiguration,
     VZVirtioTraditionalMemoryBalloonDeviceConfiguration, VZVirtualMachineConfiguration,
 };
 
+use crate::{
+    host::{host_arch, HostArch},
+    plugin,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("macOS guests require an arm64 host, but the host is {0}")]
+    UnsupportedHostArchitecture(HostArch),
+    #[error("Invalid hardware model")]
+    InvalidHardwareModel,
+    #[error("The hardware model is not supported on this {0} host")]
+    UnsupportedHardwareModelForHost(HostArch),
+    #[error("{0} is missing in vm.json; run `akari vm init` to generate it")]
+    MissingIdentity(&'static str),
+    #[error("Virtio graphics requires a Linux guest, but the guest OS is {0}")]
+    UnsupportedVirtioGraphics(String),
+    #[error("boot.kernel is missing in vm.json; {0} guests boot the kernel directly")]
+    MissingKernel(String),
+    #[error("boot.{0} is not supported for {1} guests")]
+    UnsupportedBootOption(&'static str, String),
+    #[error("Layer storage {0:?} has no name")]
+    MissingLayerName(std::path::PathBuf),
+    #[error("Shared directory {0:?} is not automounted, so it needs a mountPoint")]
+    MissingMountPoint(std::path::PathBuf),
+    #[error("Shared directory {0:?} is automounted, so it cannot have a tag")]
+    AutomountTag(std::path::PathBuf),
+    #[error("Invalid tag of shared directory: {0}")]
+    InvalidTag(String),
+    #[error("vm.json requests {0} CPUs, but the VMs of this host support {1:?}")]
+    UnsupportedCpuCount(usize, RangeInclusive<usize>),
+}
+
+// Expose the directories under their names through one share.
+pub(crate) fn directory_share(
+    shared_dirs: &[(String, Retained<VZSharedDirectory>)],
+) -> Retained<VZMultipleDirectoryShare> {
+    let names = shared_dirs
+        .iter()
+        .map(|(name, _)| NSString::from_str(name))
+        .collect::<Vec<_>>();
+    let names = names.iter().map(|n| &**n).collect::<Vec<_>>();
+    let dirs = shared_dirs
+        .iter()
+        .map(|(_, dir)| &**dir)
+        .collect::<Vec<_>>();
+    let directories = NSDictionary::from_slices(names.as_slice(), dirs.as_slice());
+    unsafe {
+        VZMultipleDirectoryShare::initWithDirectories(
+            VZMultipleDirectoryShare::alloc(),
+            &directories,
+        )
+    }
+}
+
+// Check that the host can run macOS guests.
+pub fn check_host() -> Result<(), Error> {
+    match host_arch() {
+        HostArch::Arm64 => Ok(()),
+        arch => Err(Error::UnsupportedHostArchitecture(arch)),
+    }
+}
+
+// Return the CPU counts that the VMs of this host support.
+pub fn cpu_count_range() -> RangeInclusive<usize> {
+    unsafe {
+        VZVirtualMachineConfiguration::minimumAllowedCPUCount()
+            ..=VZVirtualMachineConfiguration::maximumAllowedCPUCount()
+    }
+}
+
 pub struct Config {
     cpu_count: usize,
     ram_size: u64,
     platform: Retained<VZMacPlatformConfiguration>,
+    // Boots a Linux guest on the generic platform instead of macOS.
+    linux_boot: Option<Retained<VZLinuxBootLoader>>,
     storages: Vec<Retained<VZVirtioBlockDeviceConfiguration>>,
     consoles: Vec<Retained<VZVirtioConsoleDeviceSerialPortConfiguration>>,
-    shared_dirs: Vec<Retained<VZVirtioFileSystemDeviceConfiguration>>,
-    graphics: Option<Retained<VZMacGraphicsDeviceConfiguration>>,
+    console_devices: Vec<Retained<VZVirtioConsoleDeviceConfiguration>>,
+    networks: Vec<Retained<VZVirtioNetworkDeviceConfiguration>>,
+    shared_dirs: Vec<(String, Retained<VZSharedDirectory>)>,
+    // Directories on the devices of their own by tag
+    tagged_dirs: Vec<(String, Retained<VZSharedDirectory>)>,
+    graphics: Option<Retained<VZGraphicsDeviceConfiguration>>,
     socket: Option<Retained<VZVirtioSocketDeviceConfiguration>>,
     entropy: Option<Retained<VZVirtioEntropyDeviceConfiguration>>,
     memory_ballon: Option<Retained<VZVirtioTraditionalMemoryBalloonDeviceConfiguration>>,
@@ -37,9 +122,13 @@ impl Config {
             cpu_count,
             ram_size,
             platform: unsafe { VZMacPlatformConfiguration::new() },
+            linux_boot: None,
             storages: Vec::new(),
             consoles: Vec::new(),
+            console_devices: Vec::new(),
+            networks: Vec::new(),
             shared_dirs: Vec::new(),
+            tagged_dirs: Vec::new(),
             graphics: None,
             socket: None,
             entropy: None,
@@ -48,21 +137,62 @@ impl Config {
     }
 
     pub fn from_vm_config(vm_config: MacosVmConfig) -> Result<Self> {
-        let hw_model = BASE64_STANDARD
-            .decode(vm_config.hardware_model.as_bytes())
-            .map_err(|e| anyhow::anyhow!("Failed to decode hardware model: {}", e))?;
-        let machine_id = BASE64_STANDARD
-            .decode(vm_config.machine_id.as_bytes())
-            .map_err(|e| anyhow::anyhow!("Failed to decode machine id: {}", e))?;
-
+        let cpu_counts = cpu_count_range();
+        if !cpu_counts.contains(&vm_config.cpus) {
+            return Err(Error::UnsupportedCpuCount(vm_config.cpus, cpu_counts).into());
+        }
         let mut config = Self::new(vm_config.cpus, vm_config.ram as u64);
+        let boot = vm_config.boot.clone().unwrap_or_default();
 
-        config.hw_model(hw_model)?.machine_id(machine_id)?;
+        if vm_config.os == "darwin" {
+            check_host()?;
+            if boot.kernel.is_some() || boot.initrd.is_some() || boot.cmdline.is_some() {
+                return Err(Error::UnsupportedBootOption("kernel", vm_config.os).into());
+            }
+            if boot.debug_console {
+                return Err(Error::UnsupportedBootOption("debugConsole", vm_config.os).into());
+            }
+
+            if vm_config.hardware_model.is_empty() {
+                return Err(Error::MissingIdentity("hardwareModel").into());
+            }
+            if vm_config.machine_id.is_empty() {
+                return Err(Error::MissingIdentity("machineId").into());
+            }
+            let hw_model = BASE64_STANDARD
+                .decode(vm_config.hardware_model.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Failed to decode hardware model: {}", e))?;
+            let machine_id = BASE64_STANDARD
+                .decode(vm_config.machine_id.as_bytes())
+                .map_err(|e| anyhow::anyhow!("Failed to decode machine id: {}", e))?;
+
+            config.hw_model(hw_model)?.machine_id(machine_id)?;
+        } else {
+            if boot.boot_args.is_some() {
+                return Err(Error::UnsupportedBootOption("bootArgs", vm_config.os).into());
+            }
+            if boot.recovery {
+                return Err(Error::UnsupportedBootOption("recovery", vm_config.os).into());
+            }
+            let kernel = boot
+                .kernel
+                .as_deref()
+                .ok_or_else(|| Error::MissingKernel(vm_config.os.clone()))?;
+            config.linux_boot(kernel, boot.initrd.as_deref(), &boot.linux_cmdline())?;
+        }
 
-        for storage in vm_config.storage {
+        for storage in &vm_config.storage {
             match storage.r#type.as_str() {
                 "disk" => {
-                    config.storage(&storage.file, false)?;
+                    config.storage(&storage.file, false, storage.name.as_deref())?;
+                }
+                // The agent finds the layer by its name.
+                LAYER_STORAGE_TYPE => {
+                    let name = storage
+                        .name
+                        .as_deref()
+                        .ok_or_else(|| Error::MissingLayerName(storage.file.clone()))?;
+                    config.storage(&storage.file, true, Some(name))?;
                 }
                 "aux" => {
                     config.aux(&storage.file)?;
@@ -71,33 +201,73 @@ impl Config {
             }
         }
 
+        for network in &vm_config.networks {
+            if network.r#type == "nat" {
+                config.nat_network(network.mac_address.as_deref())?;
+            }
+        }
+
         config.socket()?;
         config.entropy()?;
         config.memory_balloon()?;
 
-        if let Some(shared_dirs) = vm_config.shares {
+        if let Some(shared_dirs) = &vm_config.shares {
             for shared_dir in shared_dirs {
-                config.shared_dir(&shared_dir.path, shared_dir.read_only)?;
+                let name = shared_dir
+                    .guest_name()
+                    .ok_or(anyhow::anyhow!("Failed to get shared directory name"))?;
+                if shared_dir.automount && shared_dir.tag.is_some() {
+                    return Err(Error::AutomountTag(shared_dir.path.clone()).into());
+                }
+                if !shared_dir.automount && shared_dir.mount_point.is_none() {
+                    return Err(Error::MissingMountPoint(shared_dir.path.clone()).into());
+                }
+                match shared_dir.tag() {
+                    Some(tag) => config.tagged_dir(tag, &shared_dir.path, shared_dir.read_only)?,
+                    None => config.shared_dir(name, &shared_dir.path, shared_dir.read_only)?,
+                };
             }
         }
 
-        config.graphics(2560, 1600, 200)?;
+        // The virtio GPU is opt-in per display and the Mac display stays the default.
+        let virtio_displays: Vec<_> = vm_config
+            .displays
+            .iter()
+            .filter(|display| display.r#type == MacosVmDisplayType::Virtio)
+            .map(|display| (display.width, display.height))
+            .collect();
+        if virtio_displays.is_empty() {
+            config.graphics(2560, 1600, 200)?;
+        } else {
+            if vm_config.os == "darwin" {
+                return Err(Error::UnsupportedVirtioGraphics(vm_config.os).into());
+            }
+            config.virtio_graphics(&virtio_displays)?;
+        }
+
+        plugin::configure(&vm_config, &mut config)?;
 
         Ok(config)
     }
 
     pub fn build(&mut self) -> Retained<VZVirtualMachineConfiguration> {
-        let boot_loader = unsafe { VZMacOSBootLoader::new() };
-
         let config = unsafe {
             let config = VZVirtualMachineConfiguration::new();
-            config.setPlatform(&self.platform);
+            match &self.linux_boot {
+                Some(boot_loader) => {
+                    config.setPlatform(&VZGenericPlatformConfiguration::new());
+                    config.setBootLoader(Some(boot_loader.as_super()));
+                }
+                None => {
+                    config.setPlatform(&self.platform);
+                    config.setBootLoader(Some(&VZMacOSBootLoader::new()));
+                }
+            }
             config.setCPUCount(self.cpu_count);
             config.setMemorySize(self.ram_size);
-            config.setBootLoader(Some(&boot_loader));
 
             if let Some(graphics) = &self.graphics {
-                config.setGraphicsDevices(&NSArray::from_slice(&[graphics.as_super()]));
+                config.setGraphicsDevices(&NSArray::from_slice(&[&**graphics]));
             };
 
             if let Some(socket) = &self.socket {
@@ -122,12 +292,51 @@ impl Config {
                 .collect::<Vec<_>>();
             config.setSerialPorts(&NSArray::from_slice(consoles.as_slice()));
 
-            let shared_dirs = self
-                .shared_dirs
+            let console_devices = self
+                .console_devices
                 .iter()
-                .map(|s| s.as_super())
+                .map(|c| c.as_super())
+                .collect::<Vec<_>>();
+            config.setConsoleDevices(&NSArray::from_slice(console_devices.as_slice()));
+
+            let networks = self
+                .networks
+                .iter()
+                .map(|n| n.as_super())
                 .collect::<Vec<_>>();
-            config.setDirectorySharingDevices(&NSArray::from_slice(shared_dirs.as_slice()));
+            config.setNetworkDevices(&NSArray::from_slice(networks.as_slice()));
+
+            // The automounted directories are exposed through a single device, where each
+            // directory appears under its name in the guest. The device comes first so that the
+            // running VM can find it to change the directories.
+            let mut sharing_devices = Vec::new();
+            if !self.shared_dirs.is_empty() {
+                let dir_share = directory_share(&self.shared_dirs);
+
+                let shared_dir = VZVirtioFileSystemDeviceConfiguration::initWithTag(
+                    VZVirtioFileSystemDeviceConfiguration::alloc(),
+                    &VZVirtioFileSystemDeviceConfiguration::macOSGuestAutomountTag(),
+                );
+                shared_dir.setShare(Some(&dir_share));
+                sharing_devices.push(shared_dir);
+            }
+            for (tag, dir) in &self.tagged_dirs {
+                let dir_share =
+                    VZSingleDirectoryShare::initWithDirectory(VZSingleDirectoryShare::alloc(), dir);
+                let shared_dir = VZVirtioFileSystemDeviceConfiguration::initWithTag(
+                    VZVirtioFileSystemDeviceConfiguration::alloc(),
+                    &NSString::from_str(tag),
+                );
+                shared_dir.setShare(Some(&dir_share));
+                sharing_devices.push(shared_dir);
+            }
+            if !sharing_devices.is_empty() {
+                let sharing_devices = sharing_devices
+                    .iter()
+                    .map(|d| d.as_super())
+                    .collect::<Vec<_>>();
+                config.setDirectorySharingDevices(&NSArray::from_slice(sharing_devices.as_slice()));
+            }
 
             config
         };
@@ -140,11 +349,11 @@ impl Config {
 
         let hw_model = unsafe {
             VZMacHardwareModel::initWithDataRepresentation(VZMacHardwareModel::alloc(), &model)
-                .ok_or(anyhow::anyhow!("Failed to create hardware model"))?
+                .ok_or(Error::InvalidHardwareModel)?
         };
 
         if unsafe { !hw_model.isSupported() } {
-            return Err(anyhow::anyhow!("Hardware model is not supported"));
+            return Err(Error::UnsupportedHardwareModelForHost(host_arch()).into());
         }
 
         unsafe {
@@ -182,7 +391,35 @@ impl Config {
         Ok(self)
     }
 
-    pub fn storage(&mut self, path: &Path, read_only: bool) -> Result<&mut Self> {
+    pub fn linux_boot(
+        &mut self,
+        kernel: &Path,
+        initrd: Option<&Path>,
+        cmdline: &str,
+    ) -> Result<&mut Self> {
+        let kernel_url = Self::path_to_nsurl(kernel)?;
+
+        let boot_loader = unsafe {
+            VZLinuxBootLoader::initWithKernelURL(VZLinuxBootLoader::alloc(), &kernel_url)
+        };
+
+        if let Some(initrd) = initrd {
+            let initrd_url = Self::path_to_nsurl(initrd)?;
+            unsafe { boot_loader.setInitialRamdiskURL(Some(&initrd_url)) };
+        }
+        unsafe { boot_loader.setCommandLine(&NSString::from_str(cmdline)) };
+
+        self.linux_boot = Some(boot_loader);
+
+        Ok(self)
+    }
+
+    pub fn storage(
+        &mut self,
+        path: &Path,
+        read_only: bool,
+        identifier: Option<&str>,
+    ) -> Result<&mut Self> {
         let url = Self::path_to_nsurl(path)?;
 
         let block_attachment = unsafe {
@@ -200,6 +437,14 @@ impl Config {
                 &block_attachment,
             )
         };
+        if let Some(identifier) = identifier {
+            let identifier = NSString::from_str(identifier);
+            unsafe {
+                VZVirtioBlockDeviceConfiguration::validateBlockDeviceIdentifier_error(&identifier)
+                    .map_err(|e| anyhow::anyhow!(e))?;
+                storage.setBlockDeviceIdentifier(&identifier);
+            }
+        }
 
         self.storages.push(storage);
 
@@ -230,25 +475,94 @@ impl Config {
         Ok(self)
     }
 
-    pub fn shared_dir(&mut self, path: &Path, read_only: bool) -> Result<&mut Self> {
+    // Add the named console port that the agent serves when vsock is unavailable.
+    pub fn agent_console(&mut self, fd: i32) -> Result<&mut Self> {
+        self.console_port(AGENT_CONSOLE_PORT_NAME, fd)
+    }
+
+    // Add a named console port on a device of its own, attached to the file descriptor.
+    pub fn console_port(&mut self, name: &str, fd: i32) -> Result<&mut Self> {
+        let file_handle =
+            unsafe { NSFileHandle::initWithFileDescriptor(NSFileHandle::alloc(), fd) };
+
+        let attachment = unsafe {
+            VZFileHandleSerialPortAttachment::initWithFileHandleForReading_fileHandleForWriting(
+                VZFileHandleSerialPortAttachment::alloc(),
+                Some(&file_handle),
+                Some(&file_handle),
+            )
+        };
+
+        let port = unsafe { VZVirtioConsolePortConfiguration::new() };
+        unsafe {
+            port.setName(Some(&NSString::from_str(name)));
+            port.setAttachment(Some(&attachment));
+        }
+
+        let device = unsafe { VZVirtioConsoleDeviceConfiguration::new() };
+        unsafe { device.ports().setObject_atIndexedSubscript(Some(&port), 0) };
+
+        self.console_devices.push(device);
+
+        Ok(self)
+    }
+
+    pub fn nat_network(&mut self, mac_address: Option<&str>) -> Result<&mut Self> {
+        let attachment = unsafe { VZNATNetworkDeviceAttachment::new() };
+
+        let network = unsafe { VZVirtioNetworkDeviceConfiguration::new() };
+        unsafe { network.setAttachment(Some(&attachment)) };
+
+        // A fixed MAC address is required to discover the guest IP address from the DHCP leases.
+        if let Some(mac_address) = mac_address {
+            let mac = unsafe {
+                VZMACAddress::initWithString(
+                    VZMACAddress::alloc(),
+                    &NSString::from_str(mac_address),
+                )
+                .ok_or(anyhow::anyhow!("Invalid MAC address: {}", mac_address))?
+            };
+            unsafe { network.setMACAddress(&mac) };
+        }
+
+        self.networks.push(network);
+
+        Ok(self)
+    }
+
+    pub fn shared_dir(&mut self, name: &str, path: &Path, read_only: bool) -> Result<&mut Self> {
+        if self.shared_dirs.iter().any(|(n, _)| n == name) {
+            return Err(anyhow::anyhow!("Duplicate shared directory name: {}", name));
+        }
+
         let url = Self::path_to_nsurl(path)?;
 
         let shared_dir = unsafe {
             VZSharedDirectory::initWithURL_readOnly(VZSharedDirectory::alloc(), &url, read_only)
         };
-        let dir_share = unsafe {
-            VZSingleDirectoryShare::initWithDirectory(VZSingleDirectoryShare::alloc(), &shared_dir)
-        };
+
+        self.shared_dirs.push((name.to_string(), shared_dir));
+
+        Ok(self)
+    }
+
+    // Share the directory on a device of its own that the guest mounts by the tag.
+    pub fn tagged_dir(&mut self, tag: &str, path: &Path, read_only: bool) -> Result<&mut Self> {
+        if self.tagged_dirs.iter().any(|(t, _)| t == tag) {
+            return Err(anyhow::anyhow!("Duplicate shared directory tag: {}", tag));
+        }
+        unsafe {
+            VZVirtioFileSystemDeviceConfiguration::validateTag_error(&NSString::from_str(tag))
+        }
+        .map_err(|_| Error::InvalidTag(tag.to_string()))?;
+
+        let url = Self::path_to_nsurl(path)?;
 
         let shared_dir = unsafe {
-            VZVirtioFileSystemDeviceConfiguration::initWithTag(
-                VZVirtioFileSystemDeviceConfiguration::alloc(),
-                &VZVirtioFileSystemDeviceConfiguration::macOSGuestAutomountTag(),
-            )
+            VZSharedDirectory::initWithURL_readOnly(VZSharedDirectory::alloc(), &url, read_only)
         };
-        unsafe { shared_dir.setShare(Some(&dir_share)) };
 
-        self.shared_dirs.push(shared_dir);
+        self.tagged_dirs.push((tag.to_string(), shared_dir));
 
         Ok(self)
     }
@@ -266,7 +580,29 @@ impl Config {
         let graphics = unsafe { VZMacGraphicsDeviceConfiguration::new() };
         unsafe { graphics.setDisplays(&NSArray::from_slice(&[display.as_ref()])) };
 
-        self.graphics = Some(graphics);
+        self.graphics = Some(graphics.into_super());
+
+        Ok(self)
+    }
+
+    // Each display is a scanout of the virtio GPU.
+    pub fn virtio_graphics(&mut self, displays: &[(usize, usize)]) -> Result<&mut Self> {
+        let scanouts: Vec<_> = displays
+            .iter()
+            .map(|&(width, height)| unsafe {
+                VZVirtioGraphicsScanoutConfiguration::initWithWidthInPixels_heightInPixels(
+                    VZVirtioGraphicsScanoutConfiguration::alloc(),
+                    width as isize,
+                    height as isize,
+                )
+            })
+            .collect();
+        let scanouts: Vec<_> = scanouts.iter().map(|scanout| &**scanout).collect();
+
+        let graphics = unsafe { VZVirtioGraphicsDeviceConfiguration::new() };
+        unsafe { graphics.setScanouts(&NSArray::from_slice(&scanouts)) };
+
+        self.graphics = Some(graphics.into_super());
 
         Ok(self)
     }
@@ -295,6 +631,39 @@ impl Config {
         Ok(self)
     }
 
+    // Add the devices that the device plugins configure by themselves.
+    pub fn console_device(
+        &mut self,
+        device: Retained<VZVirtioConsoleDeviceConfiguration>,
+    ) -> &mut Self {
+        self.console_devices.push(device);
+        self
+    }
+
+    pub fn serial_port(
+        &mut self,
+        port: Retained<VZVirtioConsoleDeviceSerialPortConfiguration>,
+    ) -> &mut Self {
+        self.consoles.push(port);
+        self
+    }
+
+    pub fn storage_device(
+        &mut self,
+        device: Retained<VZVirtioBlockDeviceConfiguration>,
+    ) -> &mut Self {
+        self.storages.push(device);
+        self
+    }
+
+    pub fn network_device(
+        &mut self,
+        device: Retained<VZVirtioNetworkDeviceConfiguration>,
+    ) -> &mut Self {
+        self.networks.push(device);
+        self
+    }
+
     fn path_to_nsstring(path: &Path) -> Result<Retained<NSString>> {
         let path = path.canonicalize().map_err(|e| anyhow::anyhow!(e))?;
         let path = path
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Tracks the Unix domain socket proxies of the vsock connections so that they can be drained.
+//! The proxies also count the connections and the transferred bytes of each port.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use libakari::{event::unix_timestamp, metrics::ProxyMetrics, vsock::VsockPort};
+use tokio::sync::watch;
+
+// How long a draining proxy may take to flush the pending data.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Connection {
+    // Distinguishes the proxies that were registered for the same port.
+    id: u64,
+    path: PathBuf,
+    drain_tx: watch::Sender<bool>,
+}
+
+// Counters of a port. They are kept while the port is reconnected.
+#[derive(Default)]
+pub struct PortMetrics {
+    connections: AtomicU64,
+    bytes_to_guest: AtomicU64,
+    bytes_from_guest: AtomicU64,
+    last_activity: AtomicU64,
+}
+
+impl PortMetrics {
+    fn touch(&self) {
+        self.last_activity
+            .store(unix_timestamp(), Ordering::Relaxed);
+    }
+
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn sent_to_guest(&self, bytes: u64) {
+        self.bytes_to_guest.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    pub fn received_from_guest(&self, bytes: u64) {
+        self.bytes_from_guest.fetch_add(bytes, Ordering::Relaxed);
+        self.touch();
+    }
+
+    fn snapshot(&self) -> ProxyMetrics {
+        let last_activity = self.last_activity.load(Ordering::Relaxed);
+        ProxyMetrics {
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_to_guest: self.bytes_to_guest.load(Ordering::Relaxed),
+            bytes_from_guest: self.bytes_from_guest.load(Ordering::Relaxed),
+            last_activity: (last_activity != 0).then_some(last_activity),
+        }
+    }
+}
+
+// Handle of a registered proxy.
+pub struct Registration {
+    pub id: u64,
+    pub drain_rx: watch::Receiver<bool>,
+    pub metrics: Arc<PortMetrics>,
+}
+
+#[derive(Clone, Default)]
+pub struct ConnectionManager {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    connections: HashMap<VsockPort, Connection>,
+    metrics: HashMap<VsockPort, Arc<PortMetrics>>,
+}
+
+impl ConnectionManager {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // The map stays consistent even if a holder panicked.
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Register the proxy that serves the port on the socket path.
+    // The previous proxy of the port is drained.
+    pub fn register(&self, port: VsockPort, path: &Path) -> Registration {
+        let (drain_tx, drain_rx) = watch::channel(false);
+        let mut inner = self.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let old = inner.connections.insert(
+            port,
+            Connection {
+                id,
+                path: path.to_path_buf(),
+                drain_tx,
+            },
+        );
+        if let Some(old) = old {
+            let _ = old.drain_tx.send(true);
+        }
+        let metrics = inner.metrics.entry(port).or_default().clone();
+        Registration {
+            id,
+            drain_rx,
+            metrics,
+        }
+    }
+
+    // Return the socket path of the proxy of the port unless it is draining.
+    pub fn path(&self, port: VsockPort) -> Option<PathBuf> {
+        self.lock()
+            .connections
+            .get(&port)
+            .filter(|connection| !*connection.drain_tx.borrow())
+            .map(|connection| connection.path.clone())
+    }
+
+    // Return the metrics of the port if it has been proxied.
+    pub fn metrics(&self, port: VsockPort) -> Option<ProxyMetrics> {
+        self.lock()
+            .metrics
+            .get(&port)
+            .map(|metrics| metrics.snapshot())
+    }
+
+    // Ask the proxy of the port to stop accepting and flush the pending data.
+    // Return the socket path of the proxy if it was registered.
+    pub fn drain(&self, port: VsockPort) -> Option<PathBuf> {
+        let inner = self.lock();
+        let connection = inner.connections.get(&port)?;
+        let _ = connection.drain_tx.send(true);
+        Some(connection.path.clone())
+    }
+
+    // Unregister the proxy after it stopped.
+    // Return false if another proxy has been registered for the port since.
+    pub fn unregister(&self, port: VsockPort, id: u64) -> bool {
+        let mut inner = self.lock();
+        match inner.connections.get(&port) {
+            Some(connection) if connection.id == id => {
+                inner.connections.remove(&port);
+                // The port may be assigned to another container later.
+                inner.metrics.remove(&port);
+                true
+            }
+            _ => false,
+        }
+    }
+}
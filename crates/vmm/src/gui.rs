@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Interactive GUI window mode.
+//! AppKit must run on the main thread, so the VM must be created with the main queue
+//! and `run_app` must be called from the main thread.
+
+use std::cell::RefCell;
+
+use block2::RcBlock;
+use log::info;
+use objc2::{rc::Retained, MainThreadMarker, MainThreadOnly};
+use objc2_app_kit::{
+    NSApplication, NSApplicationActivationPolicy, NSBackingStoreType, NSWindow, NSWindowStyleMask,
+};
+use objc2_foundation::{NSPoint, NSRect, NSSize, NSString};
+use objc2_virtualization::{VZVirtualMachine, VZVirtualMachineView};
+
+use crate::vm::{Error, Vm};
+
+const WINDOW_WIDTH: f64 = 1280.0;
+const WINDOW_HEIGHT: f64 = 800.0;
+
+// Window that hosts the VM display
+struct Display {
+    window: Retained<NSWindow>,
+    view: Retained<VZVirtualMachineView>,
+}
+
+thread_local! {
+    // The window is created once and brought to the front by the later requests. Only the main
+    // thread touches it.
+    static DISPLAY: RefCell<Option<Display>> = const { RefCell::new(None) };
+}
+
+impl Display {
+    fn new(mtm: MainThreadMarker) -> Self {
+        let rect = NSRect::new(
+            NSPoint::new(0.0, 0.0),
+            NSSize::new(WINDOW_WIDTH, WINDOW_HEIGHT),
+        );
+        let style = NSWindowStyleMask::Titled
+            | NSWindowStyleMask::Closable
+            | NSWindowStyleMask::Miniaturizable
+            | NSWindowStyleMask::Resizable;
+        let window = unsafe {
+            NSWindow::initWithContentRect_styleMask_backing_defer(
+                NSWindow::alloc(mtm),
+                rect,
+                style,
+                NSBackingStoreType::Buffered,
+                false,
+            )
+        };
+        // Closing the window only hides it as it is owned here.
+        unsafe { window.setReleasedWhenClosed(false) };
+
+        let view = unsafe { VZVirtualMachineView::new(mtm) };
+        unsafe { view.setCapturesSystemKeys(true) };
+
+        window.setTitle(&NSString::from_str("Akari"));
+        window.setContentView(Some(view.as_super()));
+        window.center();
+        Self { window, view }
+    }
+
+    fn show(&self, vm: &VZVirtualMachine) {
+        // The VM is a new one after it restarts.
+        unsafe { self.view.setVirtualMachine(Some(vm)) };
+        self.window.makeKeyAndOrderFront(None);
+    }
+}
+
+// Run the AppKit event loop on the main thread. This function does not return.
+pub fn run_app() {
+    let mtm = MainThreadMarker::new().expect("run_app must be called on the main thread");
+    let app = NSApplication::sharedApplication(mtm);
+    app.setActivationPolicy(NSApplicationActivationPolicy::Regular);
+    info!("Running the GUI event loop");
+    app.run();
+}
+
+impl Vm {
+    // Open the window that hosts the VM display, or bring it to the front.
+    pub fn show_window(&self) -> Result<(), Error> {
+        if !self.queue.is_main() {
+            return Err(Error::NotOnMainQueue);
+        }
+
+        let vm = self.vm.clone();
+        let block = RcBlock::new(move || {
+            // The main queue always runs on the main thread.
+            let mtm = MainThreadMarker::new().unwrap();
+            let Ok(vm) = vm.read() else {
+                return;
+            };
+            DISPLAY.with_borrow_mut(|display| {
+                display.get_or_insert_with(|| Display::new(mtm)).show(&vm);
+            });
+        });
+        self.queue.exec_block_async(&block);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Host architecture detection.
+
+use std::{ffi::CString, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostArch {
+    Arm64,
+    X86_64,
+    // An x86_64 process translated by Rosetta on an arm64 host.
+    TranslatedX86_64,
+}
+
+impl fmt::Display for HostArch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostArch::Arm64 => write!(f, "arm64"),
+            HostArch::X86_64 => write!(f, "x86_64"),
+            HostArch::TranslatedX86_64 => write!(f, "x86_64 (Rosetta)"),
+        }
+    }
+}
+
+fn sysctl_i32(name: &str) -> Option<i32> {
+    let name = CString::new(name).ok()?;
+    let mut value: i32 = 0;
+    let mut size = std::mem::size_of::<i32>();
+    let ret = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            &mut value as *mut i32 as *mut libc::c_void,
+            &mut size,
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return None;
+    }
+    Some(value)
+}
+
+pub fn host_arch() -> HostArch {
+    if cfg!(target_arch = "aarch64") {
+        HostArch::Arm64
+    } else if sysctl_i32("sysctl.proc_translated") == Some(1) {
+        HostArch::TranslatedX86_64
+    } else {
+        HostArch::X86_64
+    }
+}
@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Platform identity of a macOS guest, stored in vm.json as base64 blobs.
+//! The machine identifier is generated for each VM. The hardware model is taken from the latest
+//! restore image that the host supports and cached as fetching it needs the network.
+
+use std::{path::Path, sync::mpsc};
+
+use base64::{engine::general_purpose::STANDARD as BASE64_STANDARD, Engine};
+use block2::RcBlock;
+use log::info;
+use objc2_foundation::NSError;
+use objc2_virtualization::{VZMacMachineIdentifier, VZMacOSRestoreImage};
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Failed to fetch the latest restore image: {0}")]
+    FetchRestoreImage(String),
+    #[error("The latest restore image supports no configuration of this host")]
+    NoSupportedConfiguration,
+    #[error(transparent)]
+    MpscRecv(#[from] mpsc::RecvError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// Generate a new machine identifier.
+pub fn generate_machine_id() -> String {
+    let data = unsafe { VZMacMachineIdentifier::new().dataRepresentation() };
+    BASE64_STANDARD.encode(data.to_vec())
+}
+
+// Fetch the hardware model of the most featureful configuration that the host supports.
+pub fn fetch_hardware_model() -> Result<String, Error> {
+    let (tx, rx) = mpsc::channel::<Result<Vec<u8>, Error>>();
+    // The handler is invoked on an arbitrary queue, so only the bytes are sent back.
+    let completion_handler = RcBlock::new(
+        move |image: *mut VZMacOSRestoreImage, error: *mut NSError| {
+            let res = match unsafe { image.as_ref() } {
+                Some(image) => match unsafe { image.mostFeaturefulSupportedConfiguration() } {
+                    Some(requirements) => {
+                        Ok(unsafe { requirements.hardwareModel().dataRepresentation().to_vec() })
+                    }
+                    None => Err(Error::NoSupportedConfiguration),
+                },
+                None => Err(Error::FetchRestoreImage(match unsafe { error.as_ref() } {
+                    Some(error) => error.localizedDescription().to_string(),
+                    None => "Unknown error".to_string(),
+                })),
+            };
+            let _ = tx.send(res);
+        },
+    );
+    unsafe { VZMacOSRestoreImage::fetchLatestSupportedWithCompletionHandler(&completion_handler) };
+
+    Ok(BASE64_STANDARD.encode(rx.recv()??))
+}
+
+// Return the cached hardware model, or fetch and cache it.
+pub fn cached_hardware_model(cache_path: &Path, refresh: bool) -> Result<String, Error> {
+    if !refresh {
+        match std::fs::read_to_string(cache_path) {
+            Ok(model) if !model.trim().is_empty() => {
+                info!("Using the hardware model cached in {:?}", cache_path);
+                return Ok(model.trim().to_string());
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    info!("Fetching the hardware model of the latest restore image");
+    let model = fetch_hardware_model()?;
+    if let Some(parent) = cache_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(cache_path, &model)?;
+    Ok(model)
+}
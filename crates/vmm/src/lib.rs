@@ -1,6 +1,16 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2024 Akira Moroo
 
+pub mod clone;
 pub mod config;
+pub mod connection;
+pub mod gui;
+pub mod host;
+pub mod identity;
+pub mod plugin;
+pub mod pressure;
 pub mod queue;
+pub mod retry;
 pub mod vm;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Device plugins registered by the code that embeds the vmm crate.
+//! A plugin attaches the devices that akari does not model, e.g. custom console ports, to each
+//! configuration built by `Config::from_vm_config`, so that the builder need not be forked.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use libakari::vm_config::MacosVmConfig;
+
+use crate::config::Config;
+
+pub trait DevicePlugin: Send + Sync {
+    // Name of the plugin, which also selects its settings in `plugins` of vm.json.
+    fn name(&self) -> &str;
+
+    // Add the devices to the configuration. Called after akari adds its own devices.
+    // `settings` is the value under the name of the plugin in vm.json, or null.
+    fn configure(
+        &self,
+        vm_config: &MacosVmConfig,
+        settings: &serde_json::Value,
+        config: &mut Config,
+    ) -> Result<()>;
+}
+
+static PLUGINS: Mutex<Vec<Arc<dyn DevicePlugin>>> = Mutex::new(Vec::new());
+
+// Register the plugin for the configurations built after this call.
+pub fn register(plugin: Arc<dyn DevicePlugin>) -> Result<()> {
+    let mut plugins = PLUGINS.lock().unwrap_or_else(|e| e.into_inner());
+    if plugins.iter().any(|p| p.name() == plugin.name()) {
+        anyhow::bail!("Device plugin {} is already registered", plugin.name());
+    }
+    plugins.push(plugin);
+    Ok(())
+}
+
+// Run the plugins in the order of the registration.
+pub(crate) fn configure(vm_config: &MacosVmConfig, config: &mut Config) -> Result<()> {
+    // The lock is not held while the plugins run so that they may register others.
+    let plugins = PLUGINS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    for plugin in plugins {
+        let settings = vm_config
+            .plugins
+            .get(plugin.name())
+            .unwrap_or(&serde_json::Value::Null);
+        plugin
+            .configure(vm_config, settings, config)
+            .map_err(|e| anyhow::anyhow!("Device plugin {} failed: {}", plugin.name(), e))?;
+    }
+    Ok(())
+}
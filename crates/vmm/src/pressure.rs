@@ -0,0 +1,76 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Host memory pressure notifications via a dispatch source.
+
+use std::os::raw::c_void;
+
+use libakari::event::MemoryPressureLevel;
+use tokio::sync::mpsc;
+
+use crate::queue::{
+    dispatch_function_t, dispatch_object_s, dispatch_object_t, dispatch_queue_t, dispatch_resume,
+    Queue, QueueAttribute,
+};
+
+const DISPATCH_MEMORYPRESSURE_NORMAL: usize = 0x01;
+const DISPATCH_MEMORYPRESSURE_WARN: usize = 0x02;
+const DISPATCH_MEMORYPRESSURE_CRITICAL: usize = 0x04;
+
+extern "C" {
+    static _dispatch_source_type_memorypressure: dispatch_object_s;
+
+    fn dispatch_source_create(
+        r#type: *const dispatch_object_s,
+        handle: usize,
+        mask: usize,
+        queue: dispatch_queue_t,
+    ) -> dispatch_object_t;
+    fn dispatch_source_set_event_handler_f(source: dispatch_object_t, handler: dispatch_function_t);
+    fn dispatch_source_get_data(source: dispatch_object_t) -> usize;
+    fn dispatch_set_context(object: dispatch_object_t, context: *mut c_void);
+}
+
+struct Context {
+    source: dispatch_object_t,
+    tx: mpsc::UnboundedSender<MemoryPressureLevel>,
+}
+
+extern "C" fn handle_event(context: *mut c_void) {
+    let context = unsafe { &*(context as *const Context) };
+    let data = unsafe { dispatch_source_get_data(context.source) };
+    let level = if data & DISPATCH_MEMORYPRESSURE_CRITICAL != 0 {
+        MemoryPressureLevel::Critical
+    } else if data & DISPATCH_MEMORYPRESSURE_WARN != 0 {
+        MemoryPressureLevel::Warning
+    } else {
+        MemoryPressureLevel::Normal
+    };
+    let _ = context.tx.send(level);
+}
+
+// Start watching the host memory pressure. The source lives until the process exits.
+pub fn watch_memory_pressure() -> mpsc::UnboundedReceiver<MemoryPressureLevel> {
+    let (tx, rx) = mpsc::unbounded_channel();
+
+    let queue = Queue::create("com.akari.memorypressure", QueueAttribute::Serial);
+    let source = unsafe {
+        dispatch_source_create(
+            &_dispatch_source_type_memorypressure,
+            0,
+            DISPATCH_MEMORYPRESSURE_NORMAL
+                | DISPATCH_MEMORYPRESSURE_WARN
+                | DISPATCH_MEMORYPRESSURE_CRITICAL,
+            queue.ptr,
+        )
+    };
+    let context = Box::into_raw(Box::new(Context { source, tx }));
+    unsafe {
+        dispatch_set_context(source, context as *mut c_void);
+        dispatch_source_set_event_handler_f(source, handle_event);
+        dispatch_resume(source);
+    }
+    std::mem::forget(queue);
+
+    rx
+}
@@ -12,6 +12,7 @@ use std::str;
 use std::time::Duration;
 
 use block2::Block;
+use libakari::scheduling::QosClass;
 use objc2::{Encode, Encoding, RefEncode};
 
 #[allow(non_camel_case_types)]
@@ -62,6 +63,14 @@ extern "C" {
     );
     pub fn dispatch_sync(queue: dispatch_queue_t, block: &Block<dyn Fn()>);
 
+    pub fn dispatch_queue_attr_make_with_qos_class(
+        attr: dispatch_queue_attr_t,
+        qos_class: u32,
+        relative_priority: i32,
+    ) -> dispatch_queue_attr_t;
+
+    fn pthread_set_qos_class_self_np(qos_class: u32, relative_priority: i32) -> i32;
+
     pub fn dispatch_release(object: dispatch_object_t);
     pub fn dispatch_resume(object: dispatch_object_t);
     pub fn dispatch_retain(object: dispatch_object_t);
@@ -74,6 +83,26 @@ pub const DISPATCH_QUEUE_SERIAL: dispatch_queue_attr_t = 0 as dispatch_queue_att
 pub static DISPATCH_QUEUE_CONCURRENT: &dispatch_object_s =
     unsafe { &_dispatch_queue_attr_concurrent };
 
+// Values of `qos_class_t` in <sys/qos.h>
+fn qos_class_as_raw(qos: QosClass) -> u32 {
+    match qos {
+        QosClass::UserInteractive => 0x21,
+        QosClass::UserInitiated => 0x19,
+        QosClass::Default => 0x15,
+        QosClass::Utility => 0x11,
+        QosClass::Background => 0x09,
+    }
+}
+
+/// Sets the QoS class of the current thread.
+pub fn set_current_thread_qos(qos: QosClass) -> std::io::Result<()> {
+    let ret = unsafe { pthread_set_qos_class_self_np(qos_class_as_raw(qos), 0) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
 /// An error indicating a wait timed out.
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug)]
@@ -172,6 +201,28 @@ impl Queue {
         Queue { ptr: queue }
     }
 
+    /// Creates a new dispatch `Queue` with the given QoS class.
+    pub fn create_with_qos(label: &str, attr: QueueAttribute, qos: QosClass) -> Self {
+        let label = CString::new(label).unwrap();
+        let queue = unsafe {
+            let attr =
+                dispatch_queue_attr_make_with_qos_class(attr.as_raw(), qos_class_as_raw(qos), 0);
+            dispatch_queue_create(label.as_ptr(), attr)
+        };
+        Queue { ptr: queue }
+    }
+
+    /// Returns the serial dispatch queue associated with the main thread.
+    pub fn main() -> Self {
+        let queue = unsafe { &_dispatch_main_q as *const dispatch_object_s as dispatch_queue_t };
+        Queue { ptr: queue }
+    }
+
+    /// Returns whether self is the main dispatch queue.
+    pub fn is_main(&self) -> bool {
+        self.ptr == Self::main().ptr
+    }
+
     /// Submits a closure for execution on self and waits until it completes.
     #[allow(dead_code)]
     pub fn exec_sync<T, F>(&self, work: F) -> T
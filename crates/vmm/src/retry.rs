@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Retries the Virtualization.framework operations that fail transiently.
+//! The framework reports e.g. a busy resource for a while after the previous VM exits, which
+//! makes the VMs started back to back in CI loops fail now and then.
+
+use std::time::Duration;
+
+use log::warn;
+
+use crate::vm::Error;
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // Including the first attempt
+    pub max_attempts: u32,
+    // Doubled after each attempt up to the maximum
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(4),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Fail on the first error.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    // Run the operation until it succeeds, fails permanently or runs out of attempts. The
+    // operation is given the number of the attempt from 1.
+    pub fn run<T>(
+        &self,
+        operation: &str,
+        mut f: impl FnMut(u32) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            match f(attempt) {
+                Err(e) if e.is_transient() && attempt < self.max_attempts => {
+                    warn!(
+                        "Failed to {} (attempt {}/{}), retrying in {:?}: {}",
+                        operation, attempt, self.max_attempts, delay, e
+                    );
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
@@ -2,33 +2,145 @@
 // Copyright (C) 2024 Akira Moroo
 
 use std::{
+    fmt,
     ops::Deref,
     os::{fd::FromRawFd, unix::net::UnixStream},
     path::Path,
     rc::Rc,
-    sync::{mpsc, RwLock},
+    sync::{mpsc, Arc, RwLock},
+    time::Duration,
 };
 
 use anyhow::Result;
-use block2::RcBlock;
-use log::info;
+use block2::{Block, RcBlock};
+use libakari::{
+    scheduling::QosClass,
+    vm_config::MacosVmSharedDirectory,
+    vsock::VsockPort,
+    vsock_auth::{self, AuthToken},
+};
+use log::{info, warn};
 use objc2::{msg_send, msg_send_id, rc::Retained, AllocAnyThread, ClassType};
-use objc2_foundation::NSError;
+use objc2_foundation::{NSError, NSString, NSURL};
 use objc2_virtualization::{
-    VZSocketDevice, VZVirtioSocketConnection, VZVirtualMachine, VZVirtualMachineConfiguration,
+    VZMacOSInstaller, VZMacOSVirtualMachineStartOptions, VZSharedDirectory, VZSocketDevice,
+    VZVirtioSocketConnection, VZVirtualMachine, VZVirtualMachineConfiguration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UnixListener,
+    runtime::Runtime,
+    sync::watch,
+    time::timeout,
+};
+
+use crate::{
+    config::directory_share,
+    connection::{ConnectionManager, PortMetrics, Registration, DRAIN_TIMEOUT},
+    queue::{Queue, QueueAttribute},
+    retry::RetryPolicy,
 };
-use tokio::{net::UnixListener, runtime::Runtime};
 
-use crate::queue::{Queue, QueueAttribute};
+const PROXY_BUFFER_SIZE: usize = 64 * 1024;
+// How often the progress of the installation is reported.
+const INSTALL_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// How long the guest end of a container port may take to answer the challenge
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const VZ_ERROR_DOMAIN: &str = "VZErrorDomain";
+const POSIX_ERROR_DOMAIN: &str = "NSPOSIXErrorDomain";
+// Codes of VZErrorDomain
+const VZ_ERROR_INTERNAL: isize = 1;
+const VZ_ERROR_VIRTUAL_MACHINE_LIMIT_EXCEEDED: isize = 6;
+const VZ_ERROR_NETWORK_ERROR: isize = 7;
+
+// Error reported by Virtualization.framework
+#[derive(Clone, Debug)]
+pub struct VzError {
+    pub domain: String,
+    pub code: isize,
+    pub description: String,
+}
+
+impl VzError {
+    // The completion handlers may report a failure without an error.
+    fn from_ptr(error: *mut NSError) -> Self {
+        match unsafe { error.as_ref() } {
+            Some(error) => Self {
+                domain: error.domain().to_string(),
+                code: error.code(),
+                description: error.localizedDescription().to_string(),
+            },
+            None => Self {
+                domain: String::new(),
+                code: 0,
+                description: "Unknown error".to_string(),
+            },
+        }
+    }
+
+    // The limit of the running VMs is hit until the previous VM is torn down, and the guest may
+    // not listen on the vsock port yet. The other errors do not go away by retrying.
+    pub fn is_transient(&self) -> bool {
+        match self.domain.as_str() {
+            VZ_ERROR_DOMAIN => matches!(
+                self.code,
+                VZ_ERROR_INTERNAL
+                    | VZ_ERROR_VIRTUAL_MACHINE_LIMIT_EXCEEDED
+                    | VZ_ERROR_NETWORK_ERROR
+            ),
+            POSIX_ERROR_DOMAIN => matches!(
+                self.code as i32,
+                libc::EBUSY
+                    | libc::EAGAIN
+                    | libc::EINTR
+                    | libc::ECONNREFUSED
+                    | libc::ECONNRESET
+                    | libc::ETIMEDOUT
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for VzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} {})", self.description, self.domain, self.code)
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Invalid configuration: {0}")]
     InvalidConfiguration(Retained<NSError>),
-    #[error("Failed to start VM")]
-    FailedToStartVm,
+    #[error("Failed to start VM: {0}")]
+    FailedToStartVm(VzError),
+    #[error("Failed to connect to vsock port {0}: {1}")]
+    FailedToConnect(VsockPort, VzError),
+    #[error("The guest on vsock port {0} failed to authenticate: {1}")]
+    Unauthenticated(VsockPort, vsock_auth::Error),
+    #[error("No token to authenticate the guest")]
+    NoAuthToken,
     #[error("Failed to stop VM")]
     FailedToStopVm,
+    #[error("Failed to pause VM")]
+    FailedToPauseVm,
+    #[error("Failed to resume VM")]
+    FailedToResumeVm,
+    #[error("Failed to save VM state")]
+    FailedToSaveVm,
+    #[error("Failed to restore VM state")]
+    FailedToRestoreVm,
+    #[error("Failed to install macOS: {0}")]
+    FailedToInstall(String),
+    #[error("Invalid path")]
+    InvalidPath,
+    #[error("The VM is not running on the main queue")]
+    NotOnMainQueue,
+    #[error("Memory balloon device not found")]
+    MemoryBalloonNotFound,
+    #[error("Directory sharing device not found")]
+    SharingDeviceNotFound,
     #[error(transparent)]
     MpscRecv(#[from] mpsc::RecvError),
     #[error("Lock poisoned")]
@@ -39,37 +151,119 @@ pub enum Error {
     Io(#[from] std::io::Error),
 }
 
+impl Error {
+    // Return true if the operation may succeed when it is retried.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::FailedToStartVm(e) | Error::FailedToConnect(_, e) => e.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+// Validate the configuration without creating the VM.
+pub fn validate(config: &VZVirtualMachineConfiguration) -> Result<(), Error> {
+    unsafe { config.validateWithError() }.map_err(Error::InvalidConfiguration)
+}
+
 pub struct Vm {
-    vm: Rc<RwLock<Retained<VZVirtualMachine>>>,
-    queue: Queue,
+    pub(crate) vm: Rc<RwLock<Retained<VZVirtualMachine>>>,
+    pub(crate) queue: Queue,
+    // Kept to create the VM again when the start fails.
+    config: Retained<VZVirtualMachineConfiguration>,
+    connections: ConnectionManager,
+    // Start macOS in recoveryOS instead of the installed system.
+    recovery: bool,
+    retry: RetryPolicy,
+    // Token that the guest ends of the authenticated connections prove to know
+    auth_token: Option<Arc<AuthToken>>,
 }
 
 impl Vm {
     pub fn new(config: Retained<VZVirtualMachineConfiguration>) -> Result<Self, Error> {
-        unsafe {
-            config
-                .validateWithError()
-                .map_err(Error::InvalidConfiguration)?;
-        }
         let queue = Queue::create("com.akari.vm.queue", QueueAttribute::Serial);
-        let vm: Rc<RwLock<Retained<VZVirtualMachine>>> = Rc::new(RwLock::new(unsafe {
-            msg_send_id![VZVirtualMachine::alloc(), initWithConfiguration: <Retained<VZVirtualMachineConfiguration> as AsRef<VZVirtualMachineConfiguration>>::as_ref(&config), queue: queue.ptr]
-        }));
-        let vm = Vm { vm, queue };
+        Self::with_queue(config, queue)
+    }
+
+    // Create the VM on a queue with the given QoS class. The vCPUs run in the
+    // Virtualization.framework XPC service, which inherits the QoS of the requests.
+    pub fn new_with_qos(
+        config: Retained<VZVirtualMachineConfiguration>,
+        qos: QosClass,
+    ) -> Result<Self, Error> {
+        let queue = Queue::create_with_qos("com.akari.vm.queue", QueueAttribute::Serial, qos);
+        Self::with_queue(config, queue)
+    }
+
+    // Create the VM on the main queue. This is required to show the VM in a window.
+    pub fn new_on_main_queue(
+        config: Retained<VZVirtualMachineConfiguration>,
+    ) -> Result<Self, Error> {
+        Self::with_queue(config, Queue::main())
+    }
+
+    fn with_queue(
+        config: Retained<VZVirtualMachineConfiguration>,
+        queue: Queue,
+    ) -> Result<Self, Error> {
+        validate(&config)?;
+        let vm = Rc::new(RwLock::new(Self::create(&config, &queue)));
+        let vm = Vm {
+            vm,
+            queue,
+            config,
+            connections: ConnectionManager::default(),
+            recovery: false,
+            retry: RetryPolicy::default(),
+            auth_token: None,
+        };
         Ok(vm)
     }
 
+    fn create(
+        config: &Retained<VZVirtualMachineConfiguration>,
+        queue: &Queue,
+    ) -> Retained<VZVirtualMachine> {
+        unsafe {
+            msg_send_id![VZVirtualMachine::alloc(), initWithConfiguration: <Retained<VZVirtualMachineConfiguration> as AsRef<VZVirtualMachineConfiguration>>::as_ref(config), queue: queue.ptr]
+        }
+    }
+
+    pub fn set_recovery(&mut self, recovery: bool) {
+        self.recovery = recovery;
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
+
+    pub fn set_auth_token(&mut self, token: Arc<AuthToken>) {
+        self.auth_token = Some(token);
+    }
+
     pub fn start(&self) -> Result<(), Error> {
+        self.retry.run("start VM", |attempt| {
+            // A failed start leaves the VM in the error state, so it is created again.
+            if attempt > 1 {
+                *self.vm.write().map_err(|_| Error::LockPoisoned)? =
+                    Self::create(&self.config, &self.queue);
+            }
+            self.start_once()
+        })
+    }
+
+    fn start_once(&self) -> Result<(), Error> {
         info!("Starting VM");
         let (tx, rx) = mpsc::channel::<Result<(), Error>>();
         let vm = self.vm.clone();
+        let recovery = self.recovery;
         let block = RcBlock::new(move || {
             let tx = tx.clone();
             let err_tx = tx.clone();
             let completion_handler = RcBlock::new(move |error: *mut NSError| {
                 if !error.is_null() {
                     err_tx
-                        .send(Err(Error::FailedToStartVm))
+                        .send(Err(Error::FailedToStartVm(VzError::from_ptr(error))))
                         .expect("Failed to send");
                 } else {
                     err_tx.send(Ok(())).expect("Failed to send");
@@ -77,6 +271,11 @@ impl Vm {
             });
 
             match vm.write() {
+                Ok(vm) if recovery => unsafe {
+                    let options = VZMacOSVirtualMachineStartOptions::new();
+                    options.setStartUpFromMacOSRecovery(true);
+                    vm.startWithOptions_completionHandler(options.as_super(), &completion_handler)
+                },
                 Ok(vm) => unsafe { vm.startWithCompletionHandler(&completion_handler) },
                 Err(_) => tx.send(Err(Error::LockPoisoned)).expect("Failed to send"),
             }
@@ -123,17 +322,299 @@ impl Vm {
         }
     }
 
+    pub fn pause(&self) -> Result<(), Error> {
+        info!("Pausing VM");
+        self.exec_with_completion(
+            |vm, completion_handler| unsafe { vm.pauseWithCompletionHandler(completion_handler) },
+            || Error::FailedToPauseVm,
+        )?;
+        info!("VM paused");
+        Ok(())
+    }
+
+    pub fn resume(&self) -> Result<(), Error> {
+        info!("Resuming VM");
+        self.exec_with_completion(
+            |vm, completion_handler| unsafe { vm.resumeWithCompletionHandler(completion_handler) },
+            || Error::FailedToResumeVm,
+        )?;
+        info!("VM resumed");
+        Ok(())
+    }
+
+    // Save the machine state to the file. The VM must be paused.
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        info!("Saving VM state to {:?}", path);
+        let url = Self::path_to_nsurl(path)?;
+        self.exec_with_completion(
+            move |vm, completion_handler| unsafe {
+                vm.saveMachineStateToURL_completionHandler(&url, completion_handler)
+            },
+            || Error::FailedToSaveVm,
+        )?;
+        info!("VM state saved");
+        Ok(())
+    }
+
+    // Restore the machine state from the file. The VM must be stopped and is paused after restoring.
+    pub fn restore(&self, path: &Path) -> Result<(), Error> {
+        info!("Restoring VM state from {:?}", path);
+        let url = Self::path_to_nsurl(path)?;
+        self.exec_with_completion(
+            move |vm, completion_handler| unsafe {
+                vm.restoreMachineStateFromURL_completionHandler(&url, completion_handler)
+            },
+            || Error::FailedToRestoreVm,
+        )?;
+        info!("VM state restored");
+        Ok(())
+    }
+
+    // Install macOS from the restore image onto the stopped VM. The installer boots the VM by
+    // itself. The fraction of the installation completed is reported periodically.
+    pub fn install(&self, ipsw: &Path, mut on_progress: impl FnMut(f64)) -> Result<(), Error> {
+        info!("Installing macOS from {:?}", ipsw);
+        let url = Self::path_to_nsurl(ipsw)?;
+        let installer: Rc<RwLock<Option<Retained<VZMacOSInstaller>>>> = Rc::new(RwLock::new(None));
+
+        let (tx, rx) = mpsc::channel::<Result<(), Error>>();
+        let vm = self.vm.clone();
+        let block_installer = installer.clone();
+        let block = RcBlock::new(move || {
+            let err_tx = tx.clone();
+            let completion_handler = RcBlock::new(move |error: *mut NSError| {
+                let res = match unsafe { error.as_ref() } {
+                    Some(error) => Err(Error::FailedToInstall(
+                        error.localizedDescription().to_string(),
+                    )),
+                    None => Ok(()),
+                };
+                let _ = err_tx.send(res);
+            });
+            let (Ok(vm), Ok(mut installer)) = (vm.write(), block_installer.write()) else {
+                tx.send(Err(Error::LockPoisoned)).expect("Failed to send");
+                return;
+            };
+            let new_installer = unsafe {
+                VZMacOSInstaller::initWithVirtualMachine_restoreImageURL(
+                    VZMacOSInstaller::alloc(),
+                    &vm,
+                    &url,
+                )
+            };
+            unsafe { new_installer.installWithCompletionHandler(&completion_handler) };
+            // Keep the installer alive until it completes.
+            *installer = Some(new_installer);
+        });
+        self.queue.exec_block_async(&block);
+
+        let (progress_tx, progress_rx) = mpsc::channel::<f64>();
+        loop {
+            match rx.recv_timeout(INSTALL_PROGRESS_INTERVAL) {
+                Ok(res) => {
+                    res?;
+                    break;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Err(mpsc::RecvError.into()),
+            }
+            // The installer is only touched on the VM queue.
+            let installer = installer.clone();
+            let progress_tx = progress_tx.clone();
+            let block = RcBlock::new(move || {
+                if let Ok(installer) = installer.read() {
+                    if let Some(installer) = installer.as_ref() {
+                        let fraction = unsafe { installer.progress().fractionCompleted() };
+                        let _ = progress_tx.send(fraction);
+                    }
+                }
+            });
+            self.queue.exec_block_async(&block);
+            if let Ok(fraction) = progress_rx.recv_timeout(INSTALL_PROGRESS_INTERVAL) {
+                on_progress(fraction);
+            }
+        }
+        on_progress(1.0);
+
+        info!("macOS installed");
+        Ok(())
+    }
+
+    // Set the target memory size of the guest via the memory balloon device.
+    pub fn set_memory_target(&self, size: u64) -> Result<(), Error> {
+        info!("Setting the memory target to {} bytes", size);
+        let (tx, rx) = mpsc::channel::<Result<(), Error>>();
+        let vm = self.vm.clone();
+        let block = RcBlock::new(move || {
+            let result = match vm.write() {
+                Ok(vm) => match unsafe { vm.memoryBalloonDevices().firstObject() } {
+                    Some(balloon) => {
+                        unsafe {
+                            let _: () =
+                                msg_send![&*balloon, setTargetVirtualMachineMemorySize: size];
+                        }
+                        Ok(())
+                    }
+                    None => Err(Error::MemoryBalloonNotFound),
+                },
+                Err(_) => Err(Error::LockPoisoned),
+            };
+            tx.send(result).expect("Failed to send");
+        });
+        self.queue.exec_block_async(&block);
+
+        rx.recv()?
+    }
+
+    // Replace the directories of the automount device of the running VM, e.g. to share the
+    // rootfs of a container while it exists.
+    pub fn set_shares(&self, shares: &[MacosVmSharedDirectory]) -> Result<(), Error> {
+        let mut dirs = Vec::new();
+        // The other directories are on the devices of their own, which cannot change.
+        for share in shares.iter().filter(|share| share.automount) {
+            let name = share.guest_name().ok_or(Error::InvalidPath)?;
+            let url = Self::path_to_nsurl(&share.path)?;
+            let dir = unsafe {
+                VZSharedDirectory::initWithURL_readOnly(
+                    VZSharedDirectory::alloc(),
+                    &url,
+                    share.read_only,
+                )
+            };
+            dirs.push((name.to_string(), dir));
+        }
+        let share = directory_share(&dirs);
+        info!("Sharing {} directories with the guest", dirs.len());
+        let (tx, rx) = mpsc::channel::<Result<(), Error>>();
+        let vm = self.vm.clone();
+        let block = RcBlock::new(move || {
+            let result = match vm.write() {
+                Ok(vm) => match unsafe { vm.directorySharingDevices().firstObject() } {
+                    Some(device) => {
+                        unsafe {
+                            let _: () = msg_send![&*device, setShare: &*share];
+                        }
+                        Ok(())
+                    }
+                    None => Err(Error::SharingDeviceNotFound),
+                },
+                Err(_) => Err(Error::LockPoisoned),
+            };
+            tx.send(result).expect("Failed to send");
+        });
+        self.queue.exec_block_async(&block);
+
+        rx.recv()?
+    }
+
+    // Run the operation on the VM queue and wait for its completion handler.
+    fn exec_with_completion(
+        &self,
+        op: impl Fn(&VZVirtualMachine, &Block<dyn Fn(*mut NSError)>) + 'static,
+        on_error: fn() -> Error,
+    ) -> Result<(), Error> {
+        let (tx, rx) = mpsc::channel::<Result<(), Error>>();
+        let vm = self.vm.clone();
+        let block = RcBlock::new(move || {
+            let err_tx = tx.clone();
+            let completion_handler = RcBlock::new(move |error: *mut NSError| {
+                if !error.is_null() {
+                    err_tx.send(Err(on_error())).expect("Failed to send");
+                } else {
+                    err_tx.send(Ok(())).expect("Failed to send");
+                }
+            });
+            match vm.write() {
+                Ok(vm) => op(&vm, &completion_handler),
+                Err(_) => tx.send(Err(Error::LockPoisoned)).expect("Failed to send"),
+            }
+        });
+        self.queue.exec_block_async(&block);
+
+        rx.recv()?
+    }
+
+    fn path_to_nsurl(path: &Path) -> Result<Retained<NSURL>, Error> {
+        let path = path.to_str().ok_or(Error::InvalidPath)?;
+        Ok(unsafe { NSURL::fileURLWithPath(&NSString::from_str(path)) })
+    }
+
     unsafe fn do_connect(
         socket: Retained<VZSocketDevice>,
-        port: u32,
+        port: VsockPort,
         completion_handler: RcBlock<dyn Fn(*mut VZVirtioSocketConnection, *mut NSError)>,
     ) {
-        let _: () = msg_send![socket.as_super(), connectToPort: port, completionHandler: completion_handler.deref()];
+        let _: () = msg_send![socket.as_super(), connectToPort: port.get(), completionHandler: completion_handler.deref()];
+    }
+
+    pub fn connect(&mut self, port: VsockPort, client_path: &Path) -> Result<(), Error> {
+        self.connect_with(port, client_path, None)
     }
 
-    pub fn connect(&mut self, port: u32, client_path: &Path) -> Result<(), Error> {
+    // Connect like `connect`, but forward nothing until the guest end proves that it knows the
+    // token.
+    pub fn connect_authenticated(
+        &mut self,
+        port: VsockPort,
+        client_path: &Path,
+    ) -> Result<(), Error> {
+        let token = self.auth_token.clone().ok_or(Error::NoAuthToken)?;
+        self.connect_with(port, client_path, Some(token))
+    }
+
+    fn connect_with(
+        &mut self,
+        port: VsockPort,
+        client_path: &Path,
+        token: Option<Arc<AuthToken>>,
+    ) -> Result<(), Error> {
+        // A retry of the caller, e.g. after a timeout, reuses the proxy instead of binding the
+        // socket again.
+        if self.connections.path(port).as_deref() == Some(client_path) && client_path.exists() {
+            info!("Port {} is already proxied on {:?}", port, client_path);
+            return Ok(());
+        }
         let listener = UnixListener::bind(client_path)?;
         let listener = Rc::new(tokio::sync::RwLock::new(listener));
+        let registration = Rc::new(self.connections.register(port, client_path));
+
+        // The guest may not listen on the port yet.
+        let res = self.retry.run("connect to the guest", |_| {
+            self.connect_once(
+                port,
+                listener.clone(),
+                registration.clone(),
+                client_path,
+                token.clone(),
+            )
+        });
+        match res {
+            Ok(()) => {
+                info!("VM connected");
+                Ok(())
+            }
+            Err(e) => {
+                drop(listener);
+                if self.connections.unregister(port, registration.id) {
+                    Self::remove_socket(client_path);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // Connect to the port of the guest and proxy the clients of the listener in the background.
+    // Return when the connection is established or refused.
+    fn connect_once(
+        &self,
+        port: VsockPort,
+        listener: Rc<tokio::sync::RwLock<UnixListener>>,
+        registration: Rc<Registration>,
+        client_path: &Path,
+        token: Option<Arc<AuthToken>>,
+    ) -> Result<(), Error> {
+        let connections = self.connections.clone();
+        let client_path = client_path.to_path_buf();
 
         let (tx, rx) = mpsc::channel::<Result<(), Error>>();
         let vm = self.vm.clone();
@@ -141,18 +622,16 @@ impl Vm {
             let tx = tx.clone();
             let err_tx = tx.clone();
             let listener = listener.clone();
+            let connections = connections.clone();
+            let registration = registration.clone();
+            let client_path = client_path.clone();
+            let token = token.clone();
             let completion_handler = RcBlock::new(
                 move |connection: *mut VZVirtioSocketConnection, error: *mut NSError| {
                     info!("Connected to VM: {:?}", connection);
                     if connection.is_null() {
-                        if !error.is_null() {
-                            unsafe {
-                                info!("error: {:?}", error.as_ref().unwrap());
-                            }
-                        }
-                        err_tx
-                            .send(Err(Error::FailedToStartVm))
-                            .expect("Failed to send");
+                        let error = VzError::from_ptr(error);
+                        let _ = err_tx.send(Err(Error::FailedToConnect(port, error)));
                         return;
                     }
                     let connection =
@@ -164,8 +643,23 @@ impl Vm {
                         info!("destinationPort: {}", connection.destinationPort());
                     }
                     let mut stream = unsafe { UnixStream::from_raw_fd(fd) };
-                    let result = Self::vsock_handler(&mut stream, port, listener.clone());
-                    err_tx.send(result).expect("Failed to send");
+                    if let Some(token) = &token {
+                        if let Err(e) = Self::authenticate(&mut stream, token) {
+                            let _ = err_tx.send(Err(Error::Unauthenticated(port, e)));
+                            return;
+                        }
+                    }
+                    let _ = err_tx.send(Ok(()));
+                    if let Err(e) = Self::vsock_handler(
+                        &mut stream,
+                        port,
+                        listener.clone(),
+                        &client_path,
+                        &connections,
+                        &registration,
+                    ) {
+                        warn!("Proxy of port {} failed: {}", port, e);
+                    }
                 },
             );
 
@@ -173,53 +667,130 @@ impl Vm {
                 Ok(vm) => unsafe {
                     let socket = vm.socketDevices().firstObject().unwrap();
                     Self::do_connect(socket, port, completion_handler);
-                    tx.send(Ok(())).expect("Failed to send");
                 },
                 Err(_) => tx.send(Err(Error::LockPoisoned)).expect("Failed to send"),
             }
         });
         self.queue.exec_block_async(&block);
 
-        match rx.recv()? {
-            Ok(()) => {
-                info!("VM connected");
-                Ok(())
-            }
-            Err(e) => Err(e),
+        rx.recv()?
+    }
+
+    // Share the connection manager so that the caller can read the proxy metrics.
+    pub fn set_connection_manager(&mut self, connections: ConnectionManager) {
+        self.connections = connections;
+    }
+
+    // Stop the proxy of the port after flushing the pending data.
+    pub fn disconnect(&self, port: VsockPort) -> Result<(), Error> {
+        match self.connections.drain(port) {
+            Some(path) => info!("Draining the proxy of port {} on {:?}", port, path),
+            None => info!("No proxy to drain for port {}", port),
         }
+        Ok(())
+    }
+
+    // Challenge the guest end before the connection is proxied.
+    fn authenticate(stream: &mut UnixStream, token: &AuthToken) -> Result<(), vsock_auth::Error> {
+        stream.set_read_timeout(Some(AUTH_TIMEOUT))?;
+        vsock_auth::challenge(stream, token)?;
+        stream.set_read_timeout(None)?;
+        Ok(())
     }
 
     fn vsock_handler(
         stream: &mut UnixStream,
-        port: u32,
+        port: VsockPort,
         listener: Rc<tokio::sync::RwLock<UnixListener>>,
+        client_path: &Path,
+        connections: &ConnectionManager,
+        registration: &Registration,
     ) -> Result<(), Error> {
         info!("vsock_handler: port={}", port);
+        let mut drain_rx = registration.drain_rx.clone();
         let rt = Runtime::new().expect("Failed to create a runtime.");
         rt.block_on(async {
             loop {
-                let _ = Self::proxy(stream, listener.clone()).await;
+                // Stop accepting new clients once draining starts.
+                let client = tokio::select! {
+                    res = async { listener.write().await.accept().await } => res,
+                    _ = drain_rx.wait_for(|drain| *drain) => break,
+                };
+                let Ok((client, _)) = client else {
+                    continue;
+                };
+                registration.metrics.connected();
+                let _ =
+                    Self::proxy(stream, client, &mut drain_rx, registration.metrics.clone()).await;
             }
         });
+        drop(listener);
+
+        // Keep the socket file if another proxy has been bound to the path.
+        if connections.unregister(port, registration.id) {
+            Self::remove_socket(client_path);
+        }
+        info!("Proxy of port {} stopped", port);
         Ok(())
     }
 
+    fn remove_socket(path: &Path) {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove the socket {:?}: {}", path, e);
+        }
+    }
+
+    // Copy until EOF and count the bytes for the metrics.
+    async fn copy<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+        reader: &mut R,
+        writer: &mut W,
+        count: impl Fn(u64),
+    ) -> std::io::Result<()> {
+        let mut buf = vec![0u8; PROXY_BUFFER_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            writer.write_all(&buf[..n]).await?;
+            count(n as u64);
+        }
+    }
+
     async fn proxy(
         stream: &mut UnixStream,
-        listener: Rc<tokio::sync::RwLock<tokio::net::UnixListener>>,
+        client: tokio::net::UnixStream,
+        drain_rx: &mut watch::Receiver<bool>,
+        metrics: Arc<PortMetrics>,
     ) -> Result<(), Error> {
-        let (client, _) = listener.write().await.accept().await?;
         let stream = tokio::net::UnixStream::from_std(stream.try_clone().unwrap())?;
 
         let (mut eread, mut ewrite) = client.into_split();
         let (mut oread, mut owrite) = stream.into_split();
 
-        let e2o = tokio::spawn(async move { tokio::io::copy(&mut eread, &mut owrite).await });
-        let o2e = tokio::spawn(async move { tokio::io::copy(&mut oread, &mut ewrite).await });
+        // Propagate EOF as a half-close so that the peer can still send the rest of its data,
+        // e.g. the output of a process after its stdin is closed.
+        let mut copy = tokio::spawn(async move {
+            let e2o = async {
+                Self::copy(&mut eread, &mut owrite, |n| metrics.sent_to_guest(n)).await?;
+                owrite.shutdown().await
+            };
+            let o2e = async {
+                Self::copy(&mut oread, &mut ewrite, |n| metrics.received_from_guest(n)).await?;
+                ewrite.shutdown().await
+            };
+            let _ = tokio::join!(e2o, o2e);
+        });
 
-        tokio::select! {
-            _ = e2o => Ok(()),
-            _ = o2e => Ok(()),
+        let drained = tokio::select! {
+            _ = &mut copy => false,
+            _ = drain_rx.wait_for(|drain| *drain) => true,
+        };
+        // Give the in-flight data a chance to be flushed before closing the connection.
+        if drained && timeout(DRAIN_TIMEOUT, &mut copy).await.is_err() {
+            warn!("Pending data was not flushed in {:?}", DRAIN_TIMEOUT);
+            copy.abort();
         }
+        Ok(())
     }
 }
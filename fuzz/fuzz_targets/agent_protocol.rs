@@ -0,0 +1,27 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Messages of the agent protocol. The host reads the handshakes and the responses from a guest
+//! that may be compromised, and the agent reads the chunked commands.
+
+#![no_main]
+
+use libakari::{
+    container_rpc::{ContainerCommand, ContainerResponse},
+    framing::{read_chunked, write_chunked, ReadFrom, MAX_MESSAGE_SIZE},
+    vsock::Handshake,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Handshake::read_from(&mut &data[..]);
+    let _ = ContainerResponse::read_from(&mut &data[..]);
+
+    // A command that was read must survive a round trip.
+    if let Ok(cmd) = read_chunked::<ContainerCommand, _>(&mut &data[..], MAX_MESSAGE_SIZE) {
+        let mut buf = Vec::new();
+        write_chunked(&cmd, &mut buf).expect("Failed to write the command");
+        read_chunked::<ContainerCommand, _>(&mut &buf[..], MAX_MESSAGE_SIZE)
+            .expect("Failed to read the written command");
+    }
+});
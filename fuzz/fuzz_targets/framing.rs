@@ -0,0 +1,17 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! Admin API frames read by the server and the client.
+
+#![no_main]
+
+use libakari::{
+    api::{ApiRequest, ApiResponse},
+    framing::ReadFrom,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ApiRequest::read_from(&mut &data[..]);
+    let _ = ApiResponse::read_from(&mut &data[..]);
+});
@@ -0,0 +1,26 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2024 Akira Moroo
+
+//! `vm.json` parsed by the server and the client, and the DHCP leases that the guest can
+//! influence through its host name.
+
+#![no_main]
+
+use libakari::{
+    network::{normalize_mac_address, parse_dhcp_leases},
+    vm_config::MacosVmConfig,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(vm_config) = serde_json::from_slice::<MacosVmConfig>(data) {
+        for network in &vm_config.networks {
+            if let Some(mac_address) = &network.mac_address {
+                let _ = normalize_mac_address(mac_address);
+            }
+        }
+    }
+    if let Ok(content) = std::str::from_utf8(data) {
+        let _ = parse_dhcp_leases(content);
+    }
+});